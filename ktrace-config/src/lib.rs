@@ -0,0 +1,104 @@
+//! The optional configuration file shared by `ktraced`, `ktrace`, the QEMU plugin, and
+//! the tools that stream to `ktraced` (`ktrace-import` and `ktrace-synth`).
+//!
+//! Each program reads the tables it cares about into a config type of its own, ignoring
+//! the others (so those types must not deny unknown fields). The tables themselves deny
+//! unknown fields, so that typos are reported rather than silently ignored.
+
+use std::{
+	fs, io,
+	path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, de::DeserializeOwned};
+
+/// The `[sockets]` table: the socket paths, shared by every program.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sockets {
+	/// The socket producers (e.g. the QEMU plugin) connect to.
+	pub trace: Option<String>,
+	/// The socket clients (e.g. `ktrace`) connect to.
+	pub query: Option<String>,
+}
+
+/// Loads the config file at `path`, or at the [default path](default_path) if `None`.
+/// It's not an error for there to be no file at the default path.
+pub fn load<T: DeserializeOwned + Default>(path: Option<&Path>) -> io::Result<T> {
+	let (path, required) = match path {
+		Some(path) => (path.to_owned(), true),
+		None => {
+			match default_path() {
+				Some(path) => (path, false),
+				None => return Ok(T::default()),
+			}
+		}
+	};
+
+	let contents = match fs::read_to_string(&path) {
+		Ok(contents) => contents,
+		Err(err) if !required && err.kind() == io::ErrorKind::NotFound => {
+			return Ok(T::default());
+		}
+		Err(err) => {
+			return Err(io::Error::new(
+				err.kind(),
+				format!("failed to read config '{}': {err}", path.display()),
+			));
+		}
+	};
+
+	toml::from_str(&contents).map_err(|err| {
+		io::Error::new(
+			io::ErrorKind::InvalidData,
+			format!("invalid config '{}': {err}", path.display()),
+		)
+	})
+}
+
+/// Returns the default config path, `$XDG_CONFIG_HOME/ktrace/config.toml`.
+pub fn default_path() -> Option<PathBuf> {
+	let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+		.map(PathBuf::from)
+		.or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+	Some(config_dir.join("ktrace").join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[derive(Debug, Default, Deserialize)]
+	struct Config {
+		#[serde(default)]
+		sockets: Sockets,
+	}
+
+	fn load_str(contents: &str) -> io::Result<Config> {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("config.toml");
+		fs::write(&path, contents).unwrap();
+		load(Some(&path))
+	}
+
+	#[test]
+	fn other_programs_tables_are_ignored() {
+		let config =
+			load_str("[sockets]\ntrace = \"/tmp/t.sock\"\n\n[daemon]\nretention = \"stop\"\n").unwrap();
+		assert_eq!(config.sockets.trace.as_deref(), Some("/tmp/t.sock"));
+		assert_eq!(config.sockets.query, None);
+	}
+
+	#[test]
+	fn unknown_socket_keys_are_rejected() {
+		let err = load_str("[sockets]\ntrcae = \"/tmp/t.sock\"\n").unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+	}
+
+	#[test]
+	fn missing_files_are_only_an_error_if_named() {
+		let dir = tempfile::tempdir().unwrap();
+		assert!(load::<Config>(Some(&dir.path().join("missing.toml"))).is_err());
+	}
+}
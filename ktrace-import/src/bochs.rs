@@ -0,0 +1,89 @@
+//! Parses Bochs' instruction traces, as logged by its debugger (`trace on`):
+//!
+//! ```text
+//! (0).[184] [0x0000000fe05b] f000:e05b (unk. ctxt): xor ax, ax              ; 31c0
+//! ```
+//!
+//! That is, the CPU, optionally the number of instructions it's executed so far, and
+//! the instruction's linear address, which is what's recorded. Other lines (such as
+//! the debugger's own output) are skipped.
+
+use crate::parse_hex;
+
+/// Parses a line of a Bochs trace. See [`crate::ParseLine`].
+pub fn parse_line(line: &str) -> Result<Option<(Option<u32>, u64)>, &'static str> {
+	let Some((cpu, rest)) = line.strip_prefix('(').and_then(|rest| rest.split_once(')')) else {
+		return Ok(None);
+	};
+
+	// Not an instruction, e.g. `(0) Magic breakpoint`.
+	let Ok(cpu) = cpu.parse() else {
+		return Ok(None);
+	};
+
+	// Skip the instruction count, if it's there.
+	let rest = match rest.strip_prefix(".[") {
+		Some(rest) => rest.split_once(']').ok_or("bad instruction count")?.1,
+		None => rest,
+	};
+
+	let Some(addr) = rest.trim_start().strip_prefix('[') else {
+		return Ok(None);
+	};
+	let (addr, _) = addr.split_once(']').ok_or("bad linear address")?;
+	let addr = parse_hex(addr).ok_or("bad linear address")?;
+
+	Ok(Some((Some(cpu), addr)))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn instructions_are_parsed() {
+		assert_eq!(
+			parse_line("(0).[184] [0x0000000fe05b] f000:e05b (unk. ctxt): xor ax, ax              ; 31c0"),
+			Ok(Some((Some(0), 0xFE05B)))
+		);
+		// Without the instruction count.
+		assert_eq!(
+			parse_line("(1) [0xffffffff81000000] 0010:ffffffff81000000 (unk. ctxt): nop ; 90"),
+			Ok(Some((Some(1), 0xFFFFFFFF81000000)))
+		);
+	}
+
+	#[test]
+	fn register_dumps_are_skipped() {
+		for line in [
+			"rax: 00000000_00000000",
+			"rip: 00000000_0000fff0",
+			"eflags 0x00000002: id vip vif ac vm rf nt IOPL=0 of df if tf sf zf af pf cf",
+			"cs:0xf000, dh=0xff0093ff, dl=0x0000ffff, valid=7",
+		] {
+			assert_eq!(parse_line(line), Ok(None), "{line}");
+		}
+	}
+
+	#[test]
+	fn debugger_output_is_skipped() {
+		for line in [
+			"",
+			"(0) Magic breakpoint",
+			"Next at t=0",
+			"<bochs:1> trace on",
+			"(0).[184] f000:e05b (unk. ctxt): xor ax, ax",
+		] {
+			assert_eq!(parse_line(line), Ok(None), "{line}");
+		}
+	}
+
+	#[test]
+	fn malformed_instructions_are_errors() {
+		assert_eq!(parse_line("(0).[184"), Err("bad instruction count"));
+		assert_eq!(
+			parse_line("(0).[184] [0xfe05bz] f000:e05b"),
+			Err("bad linear address")
+		);
+	}
+}
@@ -0,0 +1,251 @@
+//! Imports instruction traces logged by other tools into `ktraced`, by streaming them
+//! over `ktrace-plugin-protocol` as if their producer were running again.
+//!
+//! Each format is parsed into [`Record`]s, which an [`Importer`] sends to the daemon,
+//! connecting each vCPU as it's first seen. All of an import's vCPUs are recorded into
+//! one session.
+//!
+//! QEMU logs are parsed by [`qemu::QemuLog`]. Formats that log each instruction on a
+//! line of its own are parsed by a [`LineTrace`], with a format's `parse_line`.
+
+pub mod bochs;
+pub mod perf;
+pub mod ptxed;
+pub mod qemu;
+
+use std::{
+	collections::{BTreeMap, btree_map::Entry},
+	io::{self, BufRead, BufWriter, Write},
+	os::unix::net::UnixStream,
+};
+
+use ktrace_plugin_protocol::{Inst, Packet, TraceWrite, VcpuInit};
+
+/// A single executed instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record {
+	pub vcpu: u32,
+	pub addr: u64,
+}
+
+/// Parses a line of a trace, returning the address of the instruction it records (and
+/// the vCPU that executed it, if the line names one), or `None` if it doesn't record one.
+/// Lines that look like instructions, but can't be parsed, are returned as errors, which
+/// a [`LineTrace`] skips and counts.
+pub type ParseLine = fn(&str) -> Result<Option<(Option<u32>, u64)>, &'static str>;
+
+/// Parses a trace that logs each instruction on a line of its own.
+///
+/// Lines that can't be parsed are skipped (and counted), as tools tend to log more than
+/// just instructions, and one bad line shouldn't cost the rest of a long trace.
+pub struct LineTrace<R> {
+	reader:  R,
+	line:    String,
+	line_no: u64,
+	vcpu:    u32,
+	parse:   ParseLine,
+	stats:   LineTraceStats,
+}
+
+/// Statistics about a parsed trace.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LineTraceStats {
+	/// Lines read.
+	pub lines:         u64,
+	/// Instructions read.
+	pub instructions:  u64,
+	/// Lines that couldn't be parsed, which were skipped.
+	pub skipped:       u64,
+	/// The line number of the first skipped line, and why it couldn't be parsed.
+	pub first_skipped: Option<(u64, &'static str)>,
+}
+
+impl<R: BufRead> LineTrace<R> {
+	/// Creates a parser for the trace `reader` reads, recording instructions whose
+	/// lines don't name a vCPU as `vcpu`'s.
+	pub fn new(reader: R, vcpu: u32, parse: ParseLine) -> Self {
+		Self {
+			reader,
+			line: String::new(),
+			line_no: 0,
+			vcpu,
+			parse,
+			stats: LineTraceStats::default(),
+		}
+	}
+
+	/// Returns statistics about what's been parsed so far.
+	pub fn stats(&self) -> LineTraceStats {
+		self.stats
+	}
+}
+
+impl<R: BufRead> Iterator for LineTrace<R> {
+	type Item = io::Result<Record>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			self.line.clear();
+			self.line_no += 1;
+			match self.reader.read_line(&mut self.line) {
+				Ok(0) => return None,
+				Ok(_) => self.stats.lines += 1,
+				Err(err) => return Some(Err(err)),
+			}
+
+			match (self.parse)(self.line.trim()) {
+				Ok(Some((vcpu, addr))) => {
+					self.stats.instructions += 1;
+					return Some(Ok(Record {
+						vcpu: vcpu.unwrap_or(self.vcpu),
+						addr,
+					}));
+				}
+				Ok(None) => {}
+				Err(what) => {
+					self.stats.skipped += 1;
+					self.stats.first_skipped.get_or_insert((self.line_no, what));
+				}
+			}
+		}
+	}
+}
+
+/// Parses a hex number, with or without a `0x` prefix.
+fn parse_hex(value: &str) -> Option<u64> {
+	let value = value.strip_prefix("0x").unwrap_or(value);
+	u64::from_str_radix(value, 16).ok()
+}
+
+/// Sends records to a daemon, one connection per vCPU.
+pub struct Importer {
+	sock_path: String,
+	producer:  u64,
+	vcpus:     BTreeMap<u32, Vcpu>,
+}
+
+struct Vcpu {
+	stream: BufWriter<UnixStream>,
+	sent:   u64,
+}
+
+impl Importer {
+	/// Creates an importer for the daemon listening on `sock_path`. Nothing is
+	/// connected until the first record is sent.
+	pub fn new(sock_path: &str) -> Self {
+		Self {
+			sock_path: sock_path.to_string(),
+			// Shared by the vCPUs, so that they're recorded into one session.
+			producer:  ktrace_plugin_protocol::producer_id(),
+			vcpus:     BTreeMap::new(),
+		}
+	}
+
+	/// Sends a record, connecting its vCPU if it's the first of it.
+	pub fn send(&mut self, record: Record) -> io::Result<()> {
+		let vcpu = match self.vcpus.entry(record.vcpu) {
+			Entry::Occupied(entry) => entry.into_mut(),
+			Entry::Vacant(entry) => {
+				let mut stream = BufWriter::new(UnixStream::connect(&self.sock_path)?);
+				stream.write_packet(&Packet::VcpuInit(VcpuInit {
+					id:       record.vcpu,
+					producer: Some(self.producer),
+				}))?;
+				stream.write_packet(&Packet::VcpuResume)?;
+				entry.insert(Vcpu { stream, sent: 0 })
+			}
+		};
+
+		vcpu.stream
+			.write_packet(&Packet::Inst(Inst { addr: record.addr }))?;
+		vcpu.sent += 1;
+		Ok(())
+	}
+
+	/// Sends every record, stopping at the first error.
+	pub fn send_all(&mut self, records: impl IntoIterator<Item = io::Result<Record>>) -> io::Result<()> {
+		for record in records {
+			self.send(record?)?;
+		}
+		Ok(())
+	}
+
+	/// Exits every vCPU, returning the number of instructions sent for each.
+	pub fn finish(self) -> io::Result<Vec<(u32, u64)>> {
+		let mut sent = Vec::with_capacity(self.vcpus.len());
+
+		for (id, mut vcpu) in self.vcpus {
+			vcpu.stream.write_packet(&Packet::VcpuIdle)?;
+			vcpu.stream.write_packet(&Packet::VcpuExit)?;
+			vcpu.stream.flush()?;
+			sent.push((id, vcpu.sent));
+		}
+
+		Ok(sent)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse(trace: &str, parse_line: ParseLine) -> (Vec<Record>, LineTraceStats) {
+		let mut trace = LineTrace::new(trace.as_bytes(), 3, parse_line);
+		let records = trace.by_ref().collect::<io::Result<Vec<_>>>().unwrap();
+		(records, trace.stats())
+	}
+
+	#[test]
+	fn unparsable_lines_are_skipped_and_counted() {
+		let (records, stats) = parse(
+			"0000000000401000  mov rax, rbx\nbogus\n[enabled]\n0000000000401003  ret\nzzz\n",
+			ptxed::parse_line,
+		);
+
+		assert_eq!(
+			records,
+			[
+				Record {
+					vcpu: 3,
+					addr: 0x401000,
+				},
+				Record {
+					vcpu: 3,
+					addr: 0x401003,
+				},
+			]
+		);
+		assert_eq!(
+			stats,
+			LineTraceStats {
+				lines:         5,
+				instructions:  2,
+				skipped:       2,
+				first_skipped: Some((2, "bad address")),
+			}
+		);
+	}
+
+	#[test]
+	fn lines_naming_a_vcpu_are_recorded_as_it() {
+		let (records, stats) = parse(
+			"[001] ffffffff81000000\n ffffffff81000004\n",
+			perf::parse_line,
+		);
+
+		assert_eq!(
+			records,
+			[
+				Record {
+					vcpu: 1,
+					addr: 0xFFFFFFFF81000000,
+				},
+				Record {
+					vcpu: 3,
+					addr: 0xFFFFFFFF81000004,
+				},
+			]
+		);
+		assert_eq!(stats.skipped, 0);
+	}
+}
@@ -0,0 +1,153 @@
+use std::{
+	fs::File,
+	io::{self, BufRead, BufReader},
+	path::PathBuf,
+};
+
+use clap::{Parser, ValueEnum};
+use ktrace_config::Sockets;
+use ktrace_import::{Importer, LineTrace, ParseLine, bochs, perf, ptxed, qemu::QemuLog};
+use serde::Deserialize;
+
+/// Imports instruction traces logged by other tools into `ktraced`, as a session with
+/// a trace per vCPU.
+///
+/// The socket path, if not given on the command line, is read from the config file,
+/// `$XDG_CONFIG_HOME/ktrace/config.toml` by default.
+#[derive(Parser)]
+struct Args {
+	/// The config file to read (instead of the default one).
+	#[clap(short = 'c', long = "config")]
+	config:    Option<PathBuf>,
+	/// The socket `ktraced` listens on for trace connections [default: /tmp/ktrace.sock].
+	#[clap(short = 's', long = "trace-sock")]
+	sock_path: Option<String>,
+	/// The format of the traces.
+	#[clap(short = 'f', long = "format", value_enum, default_value_t = Format::Qemu)]
+	format:    Format,
+	/// The traces to import (`-` for standard input), in order, all into one session.
+	#[clap(required = true)]
+	paths:     Vec<PathBuf>,
+}
+
+/// The tables of the config file (see [`ktrace_config`]) `ktrace-import` reads.
+#[derive(Default, Deserialize)]
+struct Config {
+	#[serde(default)]
+	sockets: Sockets,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+	/// QEMU's own logs (`-d exec,nochain`, optionally with `in_asm` and `cpu`).
+	Qemu,
+	/// Bochs' debugger traces (`trace on`).
+	Bochs,
+	/// `perf script` instruction samples (e.g. `perf script --itrace=i0ns -F cpu,ip` of Intel PT traces).
+	Perf,
+	/// Intel PT traces decoded by libipt's `ptxed`. Each trace is imported as a vCPU of its own, in order.
+	Ptxed,
+}
+
+impl Format {
+	/// Returns the line parser of formats that log an instruction per line.
+	fn parse_line(self) -> Option<ParseLine> {
+		match self {
+			Format::Qemu => None,
+			Format::Bochs => Some(bochs::parse_line),
+			Format::Perf => Some(perf::parse_line),
+			Format::Ptxed => Some(ptxed::parse_line),
+		}
+	}
+}
+
+fn main() {
+	let args = Args::parse();
+
+	let Config { sockets } = ktrace_config::load(args.config.as_deref()).unwrap_or_else(|err| {
+		eprintln!("ktrace-import: {err}");
+		std::process::exit(1);
+	});
+
+	let sock_path = args
+		.sock_path
+		.or(sockets.trace)
+		.unwrap_or_else(|| ktrace_plugin_protocol::DEFAULT_SOCKET_PATH.to_string());
+
+	if let Err(err) = import(&sock_path, args.format, &args.paths) {
+		eprintln!("ktrace-import: {err}");
+		std::process::exit(1);
+	}
+}
+
+fn import(sock_path: &str, format: Format, paths: &[PathBuf]) -> io::Result<()> {
+	let mut importer = Importer::new(sock_path);
+
+	for (i, path) in paths.iter().enumerate() {
+		let reader: Box<dyn BufRead> = if path.as_os_str() == "-" {
+			Box::new(io::stdin().lock())
+		} else {
+			Box::new(BufReader::new(
+				File::open(path).map_err(|err| with_path(path, err))?,
+			))
+		};
+
+		if let Some(parse_line) = format.parse_line() {
+			let vcpu = match format {
+				Format::Ptxed => i as u32,
+				_ => 0,
+			};
+			let mut trace = LineTrace::new(reader, vcpu, parse_line);
+			importer
+				.send_all(&mut trace)
+				.map_err(|err| with_path(path, err))?;
+
+			let stats = trace.stats();
+			if let Some((line_no, what)) = stats.first_skipped {
+				eprintln!(
+					"ktrace-import: '{}': skipped {} of {} line(s) that couldn't be parsed (the first, line \
+					 {line_no}: {what})",
+					path.display(),
+					stats.skipped,
+					stats.lines
+				);
+			}
+			continue;
+		}
+
+		let mut log = QemuLog::new(reader);
+		importer
+			.send_all(&mut log)
+			.map_err(|err| with_path(path, err))?;
+
+		let stats = log.stats();
+		if stats.executions == 0 {
+			eprintln!(
+				"ktrace-import: '{}': no executions found (was QEMU run with `-d exec,nochain`?)",
+				path.display()
+			);
+		} else if stats.untranslated > 0 {
+			eprintln!(
+				"ktrace-import: '{}': {} of {} executions had no `in_asm` translation, so only their first \
+				 instruction was imported",
+				path.display(),
+				stats.untranslated,
+				stats.executions
+			);
+		}
+	}
+
+	let sent = importer.finish()?;
+	if sent.is_empty() {
+		eprintln!("ktrace-import: no instructions found");
+	}
+	for (id, sent) in sent {
+		println!("vcpu {id}: imported {sent} instruction(s)");
+	}
+
+	Ok(())
+}
+
+fn with_path(path: &std::path::Path, err: io::Error) -> io::Error {
+	io::Error::new(err.kind(), format!("'{}': {err}", path.display()))
+}
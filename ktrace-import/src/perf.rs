@@ -0,0 +1,77 @@
+//! Parses `perf script` output of instruction samples, e.g. of Intel PT traces decoded
+//! with `perf script --itrace=i0ns -F cpu,ip`:
+//!
+//! ```text
+//! [001]     ffffffff81000000
+//! ```
+//!
+//! The CPU is optional, and other fields may be included as long as they come before
+//! the address (such as the time and event, which end with a `:`) or after it (such as
+//! the symbol and DSO). Fields before the CPU (such as the command) are skipped.
+//! Comments and blank lines are skipped too.
+
+use crate::parse_hex;
+
+/// Parses a line of `perf script` output. See [`crate::ParseLine`].
+pub fn parse_line(line: &str) -> Result<Option<(Option<u32>, u64)>, &'static str> {
+	if line.is_empty() || line.starts_with('#') {
+		return Ok(None);
+	}
+
+	let fields = line.split_whitespace().collect::<Vec<_>>();
+
+	let cpu = fields.iter().enumerate().find_map(|(i, field)| {
+		let cpu = field
+			.strip_prefix('[')?
+			.strip_suffix(']')?
+			.parse::<u32>()
+			.ok()?;
+		Some((i, cpu))
+	});
+
+	// The address follows the CPU, and the last of the fields ending with a `:`.
+	let start = cpu.map_or(0, |(i, _)| i + 1);
+	let after = fields[start..]
+		.iter()
+		.rposition(|field| field.ends_with(':'))
+		.map_or(start, |i| start + i + 1);
+
+	let addr = fields.get(after).ok_or("missing address")?;
+	let addr = parse_hex(addr).ok_or("bad address")?;
+
+	Ok(Some((cpu.map(|(_, cpu)| cpu), addr)))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn addresses_are_parsed() {
+		assert_eq!(
+			parse_line("[001]     ffffffff81000000"),
+			Ok(Some((Some(1), 0xFFFFFFFF81000000)))
+		);
+		assert_eq!(
+			parse_line("ffffffff81000000"),
+			Ok(Some((None, 0xFFFFFFFF81000000)))
+		);
+		assert_eq!(
+			parse_line("qemu 1234 [002] 12.345: instructions: ffffffff81000010 do_syscall ([kernel])"),
+			Ok(Some((Some(2), 0xFFFFFFFF81000010)))
+		);
+	}
+
+	#[test]
+	fn comments_and_blank_lines_are_skipped() {
+		assert_eq!(parse_line(""), Ok(None));
+		assert_eq!(parse_line("# captured on: today"), Ok(None));
+	}
+
+	#[test]
+	fn unrecognised_lines_are_errors() {
+		assert_eq!(parse_line("[001]"), Err("missing address"));
+		assert_eq!(parse_line("[001] not-an-address"), Err("bad address"));
+		assert_eq!(parse_line("12.345: instructions:"), Err("missing address"));
+	}
+}
@@ -0,0 +1,54 @@
+//! Parses Intel PT traces decoded by libipt's `ptxed`:
+//!
+//! ```text
+//! [enabled]
+//! 0000000000401000  mov rax, rbx
+//! ```
+//!
+//! That is, an instruction's address followed by its disassembly, and events in
+//! brackets, which are skipped. `ptxed` decodes a single CPU's trace, which doesn't
+//! name it, so its instructions are recorded as whichever vCPU the file is imported as.
+
+use crate::parse_hex;
+
+/// Parses a line of `ptxed` output. See [`crate::ParseLine`].
+pub fn parse_line(line: &str) -> Result<Option<(Option<u32>, u64)>, &'static str> {
+	if line.is_empty() || line.starts_with('[') {
+		return Ok(None);
+	}
+
+	let addr = line.split_whitespace().next().and_then(parse_hex);
+	Ok(Some((None, addr.ok_or("bad address")?)))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn addresses_are_parsed() {
+		assert_eq!(
+			parse_line("0000000000401000  mov rax, rbx"),
+			Ok(Some((None, 0x401000)))
+		);
+		assert_eq!(
+			parse_line("ffffffff81000000"),
+			Ok(Some((None, 0xFFFFFFFF81000000)))
+		);
+	}
+
+	#[test]
+	fn events_and_blank_lines_are_skipped() {
+		assert_eq!(parse_line(""), Ok(None));
+		assert_eq!(parse_line("[enabled]"), Ok(None));
+		assert_eq!(parse_line("[disabled]"), Ok(None));
+	}
+
+	#[test]
+	fn unrecognised_lines_are_errors() {
+		assert_eq!(
+			parse_line("error: no memory mapped at this address"),
+			Err("bad address")
+		);
+	}
+}
@@ -0,0 +1,248 @@
+//! Parses QEMU's own logs (`-d exec,nochain`, optionally with `in_asm` and `cpu`).
+//!
+//! `exec` logs a line for each translation block (TB) executed, naming the vCPU and the
+//! TB's address. Without `nochain`, TBs chained to one another are only logged once,
+//! so most of the execution is missing.
+//!
+//! `in_asm` logs the instructions of each TB as it's translated, which are used to
+//! expand each execution into every instruction it executed. TBs executed without a
+//! logged translation (e.g. when `in_asm` was left out) are recorded as their first
+//! instruction only.
+//!
+//! `cpu` dumps the registers before each TB is executed. The daemon records addresses
+//! only, so just the program counter is taken from them: dumps logged without an
+//! `exec` line (e.g. with `-d cpu,in_asm`) are recorded as executions, of vCPU 0 unless
+//! an `exec` line has named another.
+
+use std::{
+	collections::{HashMap, VecDeque},
+	io::{self, BufRead},
+	mem,
+};
+
+use crate::{Record, parse_hex};
+
+/// Parses a QEMU log into the instructions it records, in order.
+pub struct QemuLog<R> {
+	reader:       R,
+	line:         String,
+	line_no:      u64,
+	/// The instructions of each translated TB, by address.
+	translations: HashMap<u64, Vec<u64>>,
+	/// The TB being translated, while its instructions are being read.
+	translating:  Option<Vec<u64>>,
+	/// The vCPU the last `exec` line named.
+	vcpu:         u32,
+	/// Whether or not the last `exec` line is yet to be followed by its register dump.
+	exec_dumped:  bool,
+	/// An x86 register dump's instruction pointer, until its code segment base is read.
+	dump_ip:      Option<u64>,
+	pending:      VecDeque<Record>,
+	stats:        QemuLogStats,
+}
+
+/// Statistics about a parsed log.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QemuLogStats {
+	/// TB executions read.
+	pub executions:   u64,
+	/// TB executions without a logged translation, recorded as their first instruction only.
+	pub untranslated: u64,
+	/// TB executions read from register dumps rather than `exec` lines.
+	pub from_dumps:   u64,
+}
+
+impl<R: BufRead> QemuLog<R> {
+	pub fn new(reader: R) -> Self {
+		Self {
+			reader,
+			line: String::new(),
+			line_no: 0,
+			translations: HashMap::new(),
+			translating: None,
+			vcpu: 0,
+			exec_dumped: false,
+			dump_ip: None,
+			pending: VecDeque::new(),
+			stats: QemuLogStats::default(),
+		}
+	}
+
+	/// Returns statistics about what's been parsed so far.
+	pub fn stats(&self) -> QemuLogStats {
+		self.stats
+	}
+
+	/// Reads the next line, returning `false` at the end of the log.
+	fn read_line(&mut self) -> io::Result<bool> {
+		self.line.clear();
+		self.line_no += 1;
+		Ok(self.reader.read_line(&mut self.line)? > 0)
+	}
+
+	fn error(&self, what: &str) -> io::Error {
+		io::Error::new(
+			io::ErrorKind::InvalidData,
+			format!("line {}: {what}: {}", self.line_no, self.line.trim_end()),
+		)
+	}
+
+	/// Parses the current line, queueing the instructions it records.
+	fn parse_line(&mut self) -> io::Result<()> {
+		let line = self.line.trim();
+
+		if self.translating.is_some() {
+			if let Some((addr, _)) = line
+				.strip_prefix("0x")
+				.and_then(|rest| rest.split_once(':'))
+			{
+				let addr = parse_hex(addr).ok_or_else(|| self.error("bad instruction address"))?;
+				self.translating.as_mut().unwrap().push(addr);
+				return Ok(());
+			}
+
+			// The translation ends at the first line that isn't an instruction.
+			let insts = self.translating.take().unwrap();
+			if let Some(&first) = insts.first() {
+				self.translations.insert(first, insts);
+			}
+		}
+
+		let line = self.line.trim();
+
+		if line == "IN:" || line.starts_with("IN: ") {
+			self.translating = Some(Vec::new());
+		} else if let Some(rest) = line
+			.strip_prefix("Trace ")
+			.or_else(|| line.strip_prefix("Chain "))
+		{
+			let (vcpu, addr) = parse_exec(rest).ok_or_else(|| self.error("bad exec line"))?;
+			self.vcpu = vcpu;
+			self.exec_dumped = true;
+			self.execute(addr);
+		} else {
+			self.parse_dump_line()?;
+		}
+
+		Ok(())
+	}
+
+	/// Looks for the program counter in a register dump line.
+	fn parse_dump_line(&mut self) -> io::Result<()> {
+		let line = self.line.trim();
+
+		// x86's instruction pointer is relative to the code segment, whose base follows it.
+		if let Some(ip) = register(line, "RIP=").or_else(|| register(line, "EIP=")) {
+			self.dump_ip = Some(ip.ok_or_else(|| self.error("bad instruction pointer"))?);
+			return Ok(());
+		}
+		if let (Some(ip), Some(segment)) = (self.dump_ip, line.strip_prefix("CS =")) {
+			self.dump_ip = None;
+			let base = segment
+				.split_whitespace()
+				.nth(1)
+				.and_then(parse_hex)
+				.ok_or_else(|| self.error("bad code segment"))?;
+			self.dumped(base.wrapping_add(ip));
+			return Ok(());
+		}
+
+		// Arm (64- and 32-bit), then RISC-V.
+		let pc = register(line, "PC=")
+			.or_else(|| register(line, "R15="))
+			.or_else(|| line.strip_prefix("pc ").map(|rest| parse_hex(rest.trim())));
+		if let Some(pc) = pc {
+			let pc = pc.ok_or_else(|| self.error("bad program counter"))?;
+			self.dumped(pc);
+		}
+
+		Ok(())
+	}
+
+	/// Handles a register dump's program counter.
+	fn dumped(&mut self, pc: u64) {
+		// Dumps following an `exec` line are of the execution it already recorded.
+		if mem::take(&mut self.exec_dumped) {
+			return;
+		}
+
+		self.stats.from_dumps += 1;
+		self.execute(pc);
+	}
+
+	/// Queues the instructions of an execution of the TB at `addr`.
+	fn execute(&mut self, addr: u64) {
+		self.stats.executions += 1;
+		let vcpu = self.vcpu;
+
+		match self.translations.get(&addr) {
+			Some(insts) => {
+				self.pending
+					.extend(insts.iter().map(|&addr| Record { vcpu, addr }));
+			}
+			None => {
+				self.stats.untranslated += 1;
+				self.pending.push_back(Record { vcpu, addr });
+			}
+		}
+	}
+}
+
+impl<R: BufRead> Iterator for QemuLog<R> {
+	type Item = io::Result<Record>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			if let Some(record) = self.pending.pop_front() {
+				return Some(Ok(record));
+			}
+
+			match self.read_line() {
+				Ok(true) => {}
+				Ok(false) => return None,
+				Err(err) => return Some(Err(err)),
+			}
+
+			if let Err(err) = self.parse_line() {
+				return Some(Err(err));
+			}
+		}
+	}
+}
+
+/// Parses what follows `Trace`/`Chain` on an `exec` line, returning the vCPU and the
+/// TB's address:
+///
+/// - `0: 0x7f... [cs_base/pc/flags/cflags] symbol` (QEMU 6 onwards)
+/// - `0: 0x7f... [cs_base/pc/flags] symbol`
+/// - `0x7f... [cs_base/pc/flags] symbol` (before vCPUs were named, so always vCPU 0)
+fn parse_exec(rest: &str) -> Option<(u32, u64)> {
+	let (vcpu, rest) = match rest.split_once(": ") {
+		Some((vcpu, rest)) if !vcpu.starts_with("0x") => (vcpu.parse().ok()?, rest),
+		_ => (0, rest),
+	};
+
+	let (_, tb) = rest.split_once('[')?;
+	let (tb, _) = tb.split_once(']')?;
+	let addr = tb.split('/').nth(1)?;
+
+	Some((vcpu, parse_hex(addr)?))
+}
+
+/// Returns the value of a `NAME=value` register within a dump line, if it's there.
+/// Returns `Some(None)` if it's there, but isn't a valid hex number.
+fn register(line: &str, name: &str) -> Option<Option<u64>> {
+	let start = line.find(name)?;
+
+	// Don't match the end of another register's name (e.g. `PC=` in `EPC=`).
+	if line[..start]
+		.chars()
+		.next_back()
+		.is_some_and(|c| c.is_ascii_alphanumeric())
+	{
+		return None;
+	}
+
+	let value = line[start + name.len()..].split_whitespace().next()?;
+	Some(parse_hex(value))
+}
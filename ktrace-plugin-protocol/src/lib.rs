@@ -1,9 +1,26 @@
-use std::io::{Read, Write};
+use std::{
+	io::{Read, Write},
+	time::{SystemTime, UNIX_EPOCH},
+};
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
 pub const DEFAULT_SOCKET_PATH: &str = "/tmp/ktrace.sock";
 
+/// An address that can't be recorded, as the daemon uses it to mark cleared traces on
+/// trace streams. Producers that send it in an [`Inst`] are disconnected.
+pub const RESERVED_ADDR: u64 = u64::MAX;
+
+/// Returns a new ID for the calling process to initialize its vCPUs with (see
+/// [`VcpuInit::producer`]), unique among the producers running at the same time.
+pub fn producer_id() -> u64 {
+	// The PID alone may be reused, or clash with a producer in another PID namespace.
+	let nanos = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map_or(0, |d| d.subsec_nanos());
+	(u64::from(std::process::id()) << 32) | u64::from(nanos)
+}
+
 #[derive(Debug)]
 #[repr(u8)]
 pub enum Packet {
@@ -12,6 +29,7 @@ pub enum Packet {
 	VcpuIdle,
 	VcpuExit,
 	Inst(Inst),
+	VcpuStats(VcpuStats),
 }
 
 impl EnDec for Packet {
@@ -23,6 +41,8 @@ impl EnDec for Packet {
 			3 => Ok(Packet::VcpuIdle),
 			4 => Ok(Packet::VcpuExit),
 			5 => Ok(Packet::Inst(Inst::read(r)?)),
+			6 => Ok(Packet::VcpuStats(VcpuStats::read(r)?)),
+			7 => Ok(Packet::VcpuInit(VcpuInit::read_with_producer(r)?)),
 			_ => {
 				Err(std::io::Error::new(
 					std::io::ErrorKind::InvalidData,
@@ -35,7 +55,7 @@ impl EnDec for Packet {
 	fn write<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
 		match self {
 			Packet::VcpuInit(v) => {
-				w.write_u8(1)?;
+				w.write_u8(if v.producer.is_some() { 7 } else { 1 })?;
 				v.write(w)
 			}
 			Packet::VcpuResume => w.write_u8(2),
@@ -45,31 +65,56 @@ impl EnDec for Packet {
 				w.write_u8(5)?;
 				v.write(w)
 			}
+			Packet::VcpuStats(v) => {
+				w.write_u8(6)?;
+				v.write(w)
+			}
 		}
 	}
 }
 
+/// Sent first by each vCPU. Without a producer ID, it's encoded the way producers
+/// that predate producer IDs send it (packet code 1); otherwise, with packet code 7.
 #[derive(Debug)]
-#[repr(C)]
 pub struct VcpuInit {
-	pub id: u32,
+	pub id:       u32,
+	/// Identifies the producer (e.g. the QEMU instance) the vCPU belongs to.
+	/// All of a producer's vCPUs are recorded into the same session, so the ID
+	/// must be shared by them, and unique among producers connected to the daemon.
+	/// Without one, the daemon groups vCPUs by the producer's PID.
+	pub producer: Option<u64>,
+}
+
+impl VcpuInit {
+	fn read_with_producer<R: Read>(r: &mut R) -> std::io::Result<Self> {
+		Ok(VcpuInit {
+			id:       r.read_u32::<LittleEndian>()?,
+			producer: Some(r.read_u64::<LittleEndian>()?),
+		})
+	}
 }
 
 impl EnDec for VcpuInit {
 	fn read<R: Read>(r: &mut R) -> std::io::Result<Self> {
 		Ok(VcpuInit {
-			id: r.read_u32::<LittleEndian>()?,
+			id:       r.read_u32::<LittleEndian>()?,
+			producer: None,
 		})
 	}
 
 	fn write<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
-		w.write_u32::<LittleEndian>(self.id)
+		w.write_u32::<LittleEndian>(self.id)?;
+		match self.producer {
+			Some(producer) => w.write_u64::<LittleEndian>(producer),
+			None => Ok(()),
+		}
 	}
 }
 
 #[derive(Debug)]
 #[repr(C)]
 pub struct Inst {
+	/// The instruction's address, which must not be [`RESERVED_ADDR`].
 	pub addr: u64,
 }
 
@@ -85,6 +130,42 @@ impl EnDec for Inst {
 	}
 }
 
+/// Statistics about a vCPU's tracing, kept by the producer and sent periodically.
+/// All counts are totals since the vCPU was initialized.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct VcpuStats {
+	/// The number of plugin callbacks fired for the vCPU.
+	pub callbacks:     u64,
+	/// The number of instructions sent.
+	pub instructions:  u64,
+	/// The number of bytes written to the socket.
+	pub bytes_written: u64,
+	/// The time spent blocked writing to the socket, in nanoseconds.
+	pub blocked_ns:    u64,
+	/// The number of instructions and lifecycle events that could not be sent.
+	pub dropped:       u64,
+}
+
+impl EnDec for VcpuStats {
+	fn read<R: Read>(r: &mut R) -> std::io::Result<Self> {
+		Ok(VcpuStats {
+			callbacks:     r.read_u64::<LittleEndian>()?,
+			instructions:  r.read_u64::<LittleEndian>()?,
+			bytes_written: r.read_u64::<LittleEndian>()?,
+			blocked_ns:    r.read_u64::<LittleEndian>()?,
+			dropped:       r.read_u64::<LittleEndian>()?,
+		})
+	}
+
+	fn write<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+		w.write_u64::<LittleEndian>(self.callbacks)?;
+		w.write_u64::<LittleEndian>(self.instructions)?;
+		w.write_u64::<LittleEndian>(self.bytes_written)?;
+		w.write_u64::<LittleEndian>(self.blocked_ns)?;
+		w.write_u64::<LittleEndian>(self.dropped)
+	}
+}
+
 pub trait EnDec: Sized {
 	fn write<W: Write>(&self, w: &mut W) -> std::io::Result<()>;
 	fn read<R: Read>(r: &mut R) -> std::io::Result<Self>;
@@ -107,28 +188,3 @@ pub trait TraceWrite: Write + Sized {
 }
 
 impl<T: Write + Sized> TraceWrite for T {}
-
-pub trait TracePackedWrite: Write {
-	fn write_packet_packed(&mut self, packet: &Packet) -> std::io::Result<()> {
-		let p = unsafe {
-			core::slice::from_raw_parts(
-				packet as *const Packet as *const u8,
-				core::mem::size_of::<Packet>(),
-			)
-		};
-		self.write_all(p)?;
-		Ok(())
-	}
-}
-
-impl<T: Write + Sized> TracePackedWrite for T {}
-
-pub trait TracePackedRead: Read {
-	fn read_packet_packed(&mut self) -> std::io::Result<Packet> {
-		let mut buf = [0u8; core::mem::size_of::<Packet>()];
-		self.read_exact(&mut buf)?;
-		Ok(unsafe { core::ptr::read(buf.as_ptr() as *const Packet) })
-	}
-}
-
-impl<T: Read + Sized> TracePackedRead for T {}
@@ -0,0 +1,122 @@
+//! The optional configuration file, shared with `ktraced` and `ktrace`
+//! (see [`ktrace_config`]).
+//!
+//! The plugin reads the `[sockets]` and `[plugin]` tables. Values take the same
+//! form as the corresponding plugin arguments (`sock=`, `start=` and `stop=`),
+//! which take precedence over them.
+
+use std::ops::Range;
+
+use ktrace_config::Sockets;
+use serde::{Deserialize, Deserializer, de::Error as _};
+
+use crate::RecordFilter;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+	#[serde(default)]
+	pub sockets: Sockets,
+	#[serde(default)]
+	pub plugin:  PluginConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct PluginConfig {
+	#[serde(deserialize_with = "ranges")]
+	pub ranges:   Vec<Range<u64>>,
+	#[serde(deserialize_with = "addr")]
+	pub start_at: Option<u64>,
+	#[serde(deserialize_with = "addr")]
+	pub stop_at:  Option<u64>,
+}
+
+impl PluginConfig {
+	/// Returns the filter each vCPU's recorder is given.
+	pub fn filter(&self) -> RecordFilter {
+		RecordFilter {
+			ranges:   self.ranges.clone(),
+			start_at: self.start_at,
+			stop_at:  self.stop_at,
+		}
+	}
+}
+
+/// Parses an address in hex (e.g. `0xffffffff80000000`, with or without the `0x`).
+pub fn parse_addr(s: &str) -> Result<u64, String> {
+	let hex = s
+		.strip_prefix("0x")
+		.or_else(|| s.strip_prefix("0X"))
+		.unwrap_or(s);
+
+	u64::from_str_radix(hex, 16).map_err(|_| format!("invalid address '{s}'"))
+}
+
+/// Parses a half-open address range, written as `start-end` (e.g. `0x1000-0x2000`).
+fn parse_range(s: &str) -> Result<Range<u64>, String> {
+	let (start, end) = s
+		.split_once('-')
+		.ok_or_else(|| format!("invalid address range '{s}' (expected 'start-end')"))?;
+	let range = parse_addr(start.trim())?..parse_addr(end.trim())?;
+
+	if range.is_empty() {
+		return Err(format!("empty address range '{s}'"));
+	}
+
+	Ok(range)
+}
+
+fn addr<'de, D: Deserializer<'de>>(de: D) -> Result<Option<u64>, D::Error> {
+	let s = String::deserialize(de)?;
+	parse_addr(&s).map(Some).map_err(D::Error::custom)
+}
+
+fn ranges<'de, D: Deserializer<'de>>(de: D) -> Result<Vec<Range<u64>>, D::Error> {
+	Vec::<String>::deserialize(de)?
+		.iter()
+		.map(|s| parse_range(s))
+		.collect::<Result<_, _>>()
+		.map_err(D::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn addresses_are_parsed() {
+		assert_eq!(parse_addr("0xffffffff80000000"), Ok(0xFFFF_FFFF_8000_0000));
+		assert_eq!(parse_addr("1000"), Ok(0x1000));
+		assert!(parse_addr("0x").is_err());
+		assert!(parse_addr("ktrace").is_err());
+	}
+
+	#[test]
+	fn ranges_are_parsed() {
+		assert_eq!(parse_range("0x1000-0x2000"), Ok(0x1000..0x2000));
+		assert_eq!(parse_range("0x1000 - 0x2000"), Ok(0x1000..0x2000));
+		assert!(parse_range("0x1000").is_err());
+		assert!(parse_range("0x2000-0x1000").is_err());
+	}
+
+	#[test]
+	fn the_plugin_table_is_read() {
+		let config: Config = toml::from_str(
+			r#"
+			[plugin]
+			ranges = ["0x0-0x1000", "0xffffffff80000000-0xffffffff80200000"]
+			start-at = "0xffffffff80100000"
+			"#,
+		)
+		.unwrap();
+
+		assert_eq!(
+			config.plugin.filter(),
+			RecordFilter {
+				ranges:   vec![0..0x1000, 0xFFFF_FFFF_8000_0000..0xFFFF_FFFF_8020_0000],
+				start_at: Some(0xFFFF_FFFF_8010_0000),
+				stop_at:  None,
+			}
+		);
+	}
+}
@@ -0,0 +1,166 @@
+//! The QEMU plugin, which records each vCPU with a [`Recorder`].
+
+use std::{
+	cell::SyncUnsafeCell,
+	collections::HashMap,
+	sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use ctor::ctor;
+use qemu_plugin::{
+	CallbackFlags, PluginId, TranslationBlock, VCPUIndex,
+	install::{Args, Info, Value},
+	plugin::{HasCallbacks, PLUGIN, Plugin, Register},
+};
+
+use crate::{
+	config::{self, Config},
+	recorder::{RecordFilter, Recorder},
+};
+
+struct Vcpu {
+	recorder: SyncUnsafeCell<Recorder>,
+}
+
+#[derive(Default)]
+struct Ktrace {
+	socket_path: String,
+	/// Groups this QEMU instance's vCPUs into one session in the daemon.
+	producer:    u64,
+	/// Given to each vCPU's recorder.
+	filter:      RecordFilter,
+	vcpus:       Arc<HashMap<VCPUIndex, Vcpu>>,
+}
+
+impl Register for Ktrace {
+	fn register(&mut self, _id: PluginId, args: &Args, _info: &Info) -> Result<()> {
+		let config = match args.parsed.get("config") {
+			Some(Value::String(path)) => ktrace_config::load::<Config>(Some(path.as_ref()))?,
+			_ => ktrace_config::load(None)?,
+		};
+
+		self.socket_path = if let Some(Value::String(v)) = args.parsed.get("sock") {
+			v.clone()
+		} else if let Some(path) = config.sockets.trace {
+			path
+		} else {
+			ktrace_plugin_protocol::DEFAULT_SOCKET_PATH.to_string()
+		};
+
+		println!("ktrace: socket path is {}", self.socket_path);
+
+		self.filter = config.plugin.filter();
+		for (arg, trigger) in [
+			("start", &mut self.filter.start_at),
+			("stop", &mut self.filter.stop_at),
+		] {
+			if let Some(Value::String(v)) = args.parsed.get(arg) {
+				*trigger = Some(config::parse_addr(v).map_err(|err| anyhow::anyhow!("{arg}=: {err}"))?);
+			}
+		}
+
+		self.producer = ktrace_plugin_protocol::producer_id();
+
+		Ok(())
+	}
+}
+
+impl HasCallbacks for Ktrace {
+	fn on_vcpu_init(&mut self, _id: PluginId, vcpu_id: VCPUIndex) -> Result<()> {
+		let mut recorder = Recorder::connect(&self.socket_path, vcpu_id, self.producer)?;
+		recorder.set_filter(self.filter.clone());
+
+		// A vCPU initialized again is recorded anew, in place, since translated blocks'
+		// callbacks (which share the map) may already refer to it.
+		if let Some(vcpu) = self.vcpus.get(&vcpu_id) {
+			let old = unsafe { vcpu.recorder.get().as_mut_unchecked() };
+			old.exit();
+			report_lost(vcpu_id, old);
+			*old = recorder;
+			return Ok(());
+		}
+
+		Arc::get_mut(&mut self.vcpus)
+			.expect("failed to get mutable reference to vcpus")
+			.insert(
+				vcpu_id,
+				Vcpu {
+					recorder: SyncUnsafeCell::new(recorder),
+				},
+			);
+
+		Ok(())
+	}
+
+	fn on_vcpu_resume(&mut self, _id: PluginId, vcpu_id: VCPUIndex) -> Result<()> {
+		let vcpu = self.vcpus.get(&vcpu_id).expect("vcpu not found");
+		let recorder = unsafe { vcpu.recorder.get().as_mut_unchecked() };
+		recorder.resume();
+		report_lost(vcpu_id, recorder);
+		Ok(())
+	}
+
+	fn on_vcpu_idle(&mut self, _id: PluginId, vcpu_id: VCPUIndex) -> Result<()> {
+		let vcpu = self.vcpus.get(&vcpu_id).expect("vcpu not found");
+		let recorder = unsafe { vcpu.recorder.get().as_mut_unchecked() };
+		recorder.idle();
+		report_lost(vcpu_id, recorder);
+		Ok(())
+	}
+
+	fn on_vcpu_exit(&mut self, _id: PluginId, vcpu_id: VCPUIndex) -> std::result::Result<(), anyhow::Error> {
+		let vcpu = self.vcpus.get(&vcpu_id).expect("vcpu not found");
+		let recorder = unsafe { vcpu.recorder.get().as_mut_unchecked() };
+		recorder.exit();
+		report_lost(vcpu_id, recorder);
+
+		let stats = recorder.stats();
+		if stats.dropped > 0 {
+			println!(
+				"ktrace: vcpu {vcpu_id}: {} of {} records could not be sent",
+				stats.dropped, stats.callbacks
+			);
+		}
+
+		Ok(())
+	}
+
+	fn on_translation_block_translate(&mut self, _id: PluginId, tb: TranslationBlock) -> Result<()> {
+		for insn in tb.instructions() {
+			let vcpus = self.vcpus.clone();
+			let addr = insn.vaddr();
+
+			insn.register_execute_callback_flags(
+				move |vcpu_idx| {
+					let vcpu = vcpus
+						.get(&vcpu_idx)
+						.expect("instruction executed on unregistered vcpu");
+
+					unsafe { vcpu.recorder.get().as_mut_unchecked() }.inst(addr);
+				},
+				CallbackFlags::QEMU_PLUGIN_CB_NO_REGS,
+			);
+		}
+
+		Ok(())
+	}
+}
+
+impl Plugin for Ktrace {}
+
+/// Reports a vCPU's lost connection, once. Connections lost while recording instructions
+/// are reported at the vCPU's next lifecycle event, to keep the instruction callback cheap.
+fn report_lost(vcpu_id: VCPUIndex, recorder: &mut Recorder) {
+	if let Some(err) = recorder.take_error() {
+		println!("ktrace: vcpu {vcpu_id}: lost connection to ktraced ({err}); dropping further records");
+	}
+}
+
+#[ctor]
+fn init() {
+	PLUGIN
+		.set(Mutex::new(Box::new(Ktrace::default())))
+		.map_err(|_| anyhow::anyhow!("failed to set plugin Ktrace"))
+		.expect("failed to set plugin Ktrace");
+}
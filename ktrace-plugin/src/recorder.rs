@@ -0,0 +1,225 @@
+//! Records a vCPU's execution, sending it to the daemon along with its statistics.
+//!
+//! Nothing here depends on QEMU, so other emulators (or tests driving a fake CPU)
+//! can record traces the same way the plugin does.
+
+use std::{
+	io::{self, BufWriter, Write},
+	ops::Range,
+	os::unix::net::UnixStream,
+	time::Instant,
+};
+
+use ktrace_plugin_protocol::{Inst, Packet, TraceWrite, VcpuInit, VcpuStats};
+
+/// The number of instructions sent between statistics updates.
+const STATS_INTERVAL: u64 = 1 << 20;
+
+/// Which of a vCPU's instructions a [`Recorder`] sends. Those it doesn't are
+/// skipped, rather than counted as dropped.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RecordFilter {
+	/// The address ranges of the instructions to record, or all of them if empty.
+	pub ranges:   Vec<Range<u64>>,
+	/// Recording is held off until an instruction at this address executes.
+	pub start_at: Option<u64>,
+	/// Recording stops once an instruction at this address executes, until the start
+	/// address (if any) executes again.
+	pub stop_at:  Option<u64>,
+}
+
+/// Records a vCPU's execution to the daemon (or any other writer).
+///
+/// Once writing fails, the connection is considered lost and all further records
+/// are dropped (and counted), rather than stopping the guest. The error is kept for
+/// the caller to report (see [`Recorder::take_error`]).
+pub struct Recorder<W: Write = UnixStream> {
+	writer:       BufWriter<TimedWriter<W>>,
+	filter:       RecordFilter,
+	/// Whether or not the filter's start address was reached (and its stop address not since).
+	triggered:    bool,
+	callbacks:    u64,
+	instructions: u64,
+	dropped:      u64,
+	lost:         bool,
+	/// The error the connection was lost to, until it's taken.
+	error:        Option<io::Error>,
+}
+
+impl Recorder {
+	/// Connects to the daemon and announces the vCPU.
+	pub fn connect(sock_path: &str, vcpu: u32, producer: u64) -> io::Result<Self> {
+		Self::new(UnixStream::connect(sock_path)?, vcpu, producer)
+	}
+}
+
+impl<W: Write> Recorder<W> {
+	/// Announces the vCPU on `writer`, which records are then written to.
+	pub fn new(writer: W, vcpu: u32, producer: u64) -> io::Result<Self> {
+		let mut this = Self {
+			writer:       BufWriter::new(TimedWriter {
+				inner:         writer,
+				bytes_written: 0,
+				blocked_ns:    0,
+			}),
+			filter:       RecordFilter::default(),
+			triggered:    true,
+			callbacks:    0,
+			instructions: 0,
+			dropped:      0,
+			lost:         false,
+			error:        None,
+		};
+
+		this.writer.write_packet(&Packet::VcpuInit(VcpuInit {
+			id:       vcpu,
+			producer: Some(producer),
+		}))?;
+		this.writer.flush()?;
+
+		Ok(this)
+	}
+
+	/// Records only the instructions `filter` lets through from now on, starting
+	/// over from before its start address (if any).
+	pub fn set_filter(&mut self, filter: RecordFilter) {
+		self.triggered = filter.start_at.is_none();
+		self.filter = filter;
+	}
+
+	/// Records an executed instruction (unless it's filtered out), sending updated
+	/// statistics every so often.
+	#[inline]
+	pub fn inst(&mut self, addr: u64) {
+		self.callbacks += 1;
+
+		if !self.passes_filter(addr) {
+			return;
+		}
+
+		if self.write(&Packet::Inst(Inst { addr })) {
+			self.instructions += 1;
+			if self.instructions % STATS_INTERVAL == 0 {
+				self.write(&Packet::VcpuStats(self.stats()));
+			}
+		} else {
+			self.dropped += 1;
+		}
+	}
+
+	/// Records that the vCPU started (or went back to) executing instructions.
+	pub fn resume(&mut self) {
+		self.event(&Packet::VcpuResume);
+	}
+
+	/// Records that the vCPU went idle.
+	pub fn idle(&mut self) {
+		self.event(&Packet::VcpuIdle);
+	}
+
+	/// Records that the vCPU exited, which ends its trace.
+	pub fn exit(&mut self) {
+		self.event(&Packet::VcpuExit);
+	}
+
+	/// Returns the vCPU's statistics so far.
+	pub fn stats(&self) -> VcpuStats {
+		let writer = self.writer.get_ref();
+		VcpuStats {
+			callbacks:     self.callbacks,
+			instructions:  self.instructions,
+			bytes_written: writer.bytes_written,
+			blocked_ns:    writer.blocked_ns,
+			dropped:       self.dropped,
+		}
+	}
+
+	/// Returns whether or not the connection was lost (and records are being dropped).
+	pub fn is_lost(&self) -> bool {
+		self.lost
+	}
+
+	/// Returns the error the connection was lost to, the first time it's called after
+	/// the connection was lost.
+	pub fn take_error(&mut self) -> Option<io::Error> {
+		self.error.take()
+	}
+
+	/// Returns whether or not an instruction is to be recorded, firing the filter's
+	/// triggers. The instructions at the start and stop addresses are both recorded.
+	#[inline]
+	fn passes_filter(&mut self, addr: u64) -> bool {
+		let filter = &self.filter;
+		if self.triggered {
+			if filter.stop_at == Some(addr) {
+				self.triggered = false;
+			}
+		} else if filter.start_at == Some(addr) {
+			self.triggered = true;
+		} else {
+			return false;
+		}
+
+		filter.ranges.is_empty() || filter.ranges.iter().any(|range| range.contains(&addr))
+	}
+
+	/// Sends a vCPU lifecycle event (along with the latest statistics) right away.
+	fn event(&mut self, packet: &Packet) {
+		self.callbacks += 1;
+		self.write(&Packet::VcpuStats(self.stats()));
+		if !self.write(packet) {
+			self.dropped += 1;
+		}
+		self.flush();
+	}
+
+	/// Writes a packet, returning whether or not it was sent. Only instructions and
+	/// lifecycle events are counted as dropped, so that the count never exceeds the
+	/// number of callbacks.
+	fn write(&mut self, packet: &Packet) -> bool {
+		if !self.lost {
+			match self.writer.write_packet(packet) {
+				Ok(()) => return true,
+				Err(err) => self.lose(err),
+			}
+		}
+
+		false
+	}
+
+	fn flush(&mut self) {
+		if !self.lost {
+			if let Err(err) = self.writer.flush() {
+				self.lose(err);
+			}
+		}
+	}
+
+	fn lose(&mut self, err: io::Error) {
+		self.lost = true;
+		self.error = Some(err);
+	}
+}
+
+/// Counts the bytes written and the time spent doing so.
+struct TimedWriter<W> {
+	inner:         W,
+	bytes_written: u64,
+	blocked_ns:    u64,
+}
+
+impl<W: Write> Write for TimedWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let start = Instant::now();
+		let written = self.inner.write(buf);
+		self.blocked_ns += start.elapsed().as_nanos() as u64;
+
+		let written = written?;
+		self.bytes_written += written as u64;
+		Ok(written)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.inner.flush()
+	}
+}
@@ -0,0 +1,205 @@
+//! Drives a `Recorder` with a fake CPU, checking what it sends.
+
+use std::{
+	io::BufReader,
+	os::unix::net::UnixStream,
+	thread::{self, JoinHandle},
+};
+
+use ktrace_plugin::{RecordFilter, Recorder};
+use ktrace_plugin_protocol::{Packet, TraceRead, VcpuStats};
+
+/// A CPU that counts down from `n` to zero in a loop, then halts.
+struct FakeCpu {
+	/// The next instruction, or `None` once halted.
+	pc: Option<u64>,
+	n:  u64,
+}
+
+impl FakeCpu {
+	fn new(n: u64) -> Self {
+		Self {
+			pc: Some(0x1000),
+			n,
+		}
+	}
+
+	/// Executes an instruction, returning its address, or `None` once halted.
+	fn step(&mut self) -> Option<u64> {
+		let pc = self.pc?;
+		self.pc = match pc {
+			0x1000 => Some(0x1004),
+			0x1004 => Some(0x1008),
+			0x1008 if self.n > 1 => {
+				self.n -= 1;
+				Some(0x1004)
+			}
+			0x1008 => Some(0x100C),
+			0x100C => None,
+			_ => unreachable!(),
+		};
+		Some(pc)
+	}
+}
+
+/// Returns the addresses of the instructions sent.
+fn addresses(packets: &[Packet]) -> Vec<u64> {
+	packets
+		.iter()
+		.filter_map(|packet| {
+			match packet {
+				Packet::Inst(inst) => Some(inst.addr),
+				_ => None,
+			}
+		})
+		.collect()
+}
+
+/// Reads everything sent on `stream`, until it's closed.
+fn receive(stream: UnixStream) -> JoinHandle<Vec<Packet>> {
+	thread::spawn(move || {
+		let mut stream = BufReader::new(stream);
+		let mut packets = Vec::new();
+		while let Ok(packet) = stream.read_packet() {
+			packets.push(packet);
+		}
+		packets
+	})
+}
+
+#[test]
+fn a_fake_cpu_is_recorded() {
+	let (ours, theirs) = UnixStream::pair().unwrap();
+	let received = receive(theirs);
+
+	let mut recorder = Recorder::new(ours, 2, 42).unwrap();
+	let mut cpu = FakeCpu::new(3);
+
+	recorder.resume();
+	while let Some(pc) = cpu.step() {
+		recorder.inst(pc);
+	}
+	recorder.idle();
+	recorder.exit();
+
+	let stats = recorder.stats();
+	assert_eq!(stats.instructions, 8);
+	// A callback for each instruction, and one for each event.
+	assert_eq!(stats.callbacks, 11);
+	assert_eq!(stats.dropped, 0);
+	drop(recorder);
+
+	let packets = received.join().unwrap();
+	let Packet::VcpuInit(init) = &packets[0] else {
+		panic!("expected VcpuInit");
+	};
+	assert_eq!((init.id, init.producer), (2, Some(42)));
+
+	// Events are preceded by the latest statistics.
+	let summary = packets[1..]
+		.iter()
+		.map(|packet| {
+			match packet {
+				Packet::Inst(inst) => format!("{:#x}", inst.addr),
+				Packet::VcpuStats(VcpuStats { instructions, .. }) => format!("stats {instructions}"),
+				packet => format!("{packet:?}"),
+			}
+		})
+		.collect::<Vec<_>>();
+	assert_eq!(
+		summary,
+		[
+			"stats 0",
+			"VcpuResume",
+			"0x1000",
+			"0x1004",
+			"0x1008",
+			"0x1004",
+			"0x1008",
+			"0x1004",
+			"0x1008",
+			"0x100c",
+			"stats 8",
+			"VcpuIdle",
+			"stats 8",
+			"VcpuExit",
+		]
+	);
+}
+
+#[test]
+fn records_are_dropped_once_the_connection_is_lost() {
+	let (ours, theirs) = UnixStream::pair().unwrap();
+	let mut recorder = Recorder::new(ours, 0, 1).unwrap();
+	drop(theirs);
+
+	recorder.resume();
+	assert!(recorder.is_lost());
+	// The error is left to the caller to report, once.
+	assert!(recorder.take_error().is_some());
+	assert!(recorder.take_error().is_none());
+
+	for addr in 0..10 {
+		recorder.inst(addr);
+	}
+	recorder.exit();
+
+	// The statistics sent along with the exit aren't counted.
+	let stats = recorder.stats();
+	assert_eq!(stats.instructions, 0);
+	assert_eq!(stats.dropped, 11);
+	assert_eq!(stats.callbacks, 12);
+}
+
+#[test]
+fn only_filtered_instructions_are_recorded() {
+	let (ours, theirs) = UnixStream::pair().unwrap();
+	let received = receive(theirs);
+
+	let mut recorder = Recorder::new(ours, 0, 1).unwrap();
+	recorder.set_filter(RecordFilter {
+		ranges: vec![0x1004..0x1005, 0x1008..0x1009],
+		..RecordFilter::default()
+	});
+
+	let mut cpu = FakeCpu::new(2);
+	while let Some(pc) = cpu.step() {
+		recorder.inst(pc);
+	}
+
+	// Filtered out instructions aren't dropped, just not recorded.
+	let stats = recorder.stats();
+	assert_eq!(stats.callbacks, 6);
+	assert_eq!(stats.instructions, 4);
+	assert_eq!(stats.dropped, 0);
+	drop(recorder);
+
+	let packets = received.join().unwrap();
+	assert_eq!(addresses(&packets), [0x1004, 0x1008, 0x1004, 0x1008]);
+}
+
+#[test]
+fn triggers_start_and_stop_recording() {
+	let (ours, theirs) = UnixStream::pair().unwrap();
+	let received = receive(theirs);
+
+	let mut recorder = Recorder::new(ours, 0, 1).unwrap();
+	recorder.set_filter(RecordFilter {
+		start_at: Some(0x1008),
+		stop_at: Some(0x1004),
+		..RecordFilter::default()
+	});
+
+	// Around the loop: start, stop, start again, then out of it.
+	let mut cpu = FakeCpu::new(3);
+	while let Some(pc) = cpu.step() {
+		recorder.inst(pc);
+	}
+	drop(recorder);
+
+	let packets = received.join().unwrap();
+	assert_eq!(
+		addresses(&packets),
+		[0x1008, 0x1004, 0x1008, 0x1004, 0x1008, 0x100C]
+	);
+}
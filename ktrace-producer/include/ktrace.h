@@ -0,0 +1,89 @@
+/*
+ * Streams instruction traces to ktraced, over the plugin protocol.
+ *
+ * Link against libktrace_producer.so (built from the ktrace-producer crate).
+ *
+ * Each vCPU has a connection of its own, which must only be used by one thread at a
+ * time. Functions returning int return 0 on success, or a negated errno value.
+ *
+ *     uint64_t producer = ktrace_producer_id();
+ *     ktrace_conn *conn = ktrace_connect(NULL);
+ *     ktrace_vcpu_init(conn, producer, 0);
+ *     ktrace_resume(conn);
+ *     ktrace_inst(conn, pc);
+ *     ...
+ *     ktrace_idle(conn);
+ *     ktrace_exit(conn);
+ *     ktrace_close(conn);
+ */
+
+#ifndef KTRACE_H
+#define KTRACE_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* The one instruction address that can't be recorded. */
+#define KTRACE_RESERVED_ADDR UINT64_MAX
+
+/* A vCPU's connection to the daemon. */
+typedef struct KtraceConn ktrace_conn;
+
+/*
+ * Returns a producer ID unique to the calling process (at least among those running),
+ * which all of its vCPUs should be initialized with, so that they're recorded into one
+ * session. The same ID is returned every time, so it needn't be passed around.
+ */
+uint64_t ktrace_producer_id(void);
+
+/*
+ * Connects to the daemon listening on sock_path (or the default socket if NULL).
+ * Returns NULL on failure, with errno set.
+ */
+ktrace_conn *ktrace_connect(const char *sock_path);
+
+/* Announces the connection's vCPU. Must be called once, before anything else is sent. */
+int ktrace_vcpu_init(ktrace_conn *conn, uint64_t producer, uint32_t vcpu_id);
+
+/*
+ * Records an executed instruction. Instructions are buffered, and sent once the buffer
+ * fills up, or the vCPU idles or is flushed.
+ *
+ * The address KTRACE_RESERVED_ADDR (all ones) can't be recorded: -EINVAL is returned,
+ * and nothing is recorded.
+ */
+int ktrace_inst(ktrace_conn *conn, uint64_t addr);
+
+/*
+ * Records count executed instructions, in order, as if by ktrace_inst(), stopping at
+ * the first that fails (having recorded those before it).
+ */
+int ktrace_insts(ktrace_conn *conn, const uint64_t *addrs, size_t count);
+
+/* Records that the vCPU started (or went back to) executing instructions. */
+int ktrace_resume(ktrace_conn *conn);
+
+/* Records that the vCPU went idle, sending the instructions buffered so far. */
+int ktrace_idle(ktrace_conn *conn);
+
+/* Sends the instructions buffered so far. */
+int ktrace_flush(ktrace_conn *conn);
+
+/* Records that the vCPU exited, which ends its trace. */
+int ktrace_exit(ktrace_conn *conn);
+
+/*
+ * Sends whatever's buffered and closes the connection, freeing it (even on failure).
+ * A vCPU closed without exiting is treated as having been disconnected.
+ */
+int ktrace_close(ktrace_conn *conn);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* KTRACE_H */
@@ -0,0 +1,239 @@
+//! A C ABI for streaming traces to `ktraced`, for producers that aren't written in Rust
+//! (e.g. other emulators, or instrumented firmware). See `include/ktrace.h`.
+//!
+//! Each vCPU has a connection of its own, which is not thread-safe: a connection must
+//! only be used by one thread at a time.
+//!
+//! Functions returning `int` return `0` on success, or a negated `errno` value.
+
+use std::{
+	ffi::{CStr, c_char, c_int},
+	io::{self, BufWriter, Write},
+	os::{fd::AsRawFd, unix::net::UnixStream},
+	ptr,
+	sync::OnceLock,
+};
+
+use ktrace_plugin_protocol::{Inst, Packet, RESERVED_ADDR, TraceWrite, VcpuInit};
+
+/// A vCPU's connection to the daemon.
+pub struct KtraceConn {
+	writer: BufWriter<Socket>,
+}
+
+/// Writes to a socket without raising `SIGPIPE` if the daemon goes away, which would
+/// otherwise kill the (C) process unless it ignores the signal.
+struct Socket(UnixStream);
+
+impl Write for Socket {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		// SAFETY: The buffer is valid for `buf.len()` bytes.
+		let written = unsafe {
+			libc::send(
+				self.0.as_raw_fd(),
+				buf.as_ptr().cast(),
+				buf.len(),
+				libc::MSG_NOSIGNAL,
+			)
+		};
+
+		if written < 0 {
+			Err(io::Error::last_os_error())
+		} else {
+			Ok(written as usize)
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+impl KtraceConn {
+	fn write(&mut self, packet: &Packet) -> c_int {
+		result(self.writer.write_packet(packet))
+	}
+
+	/// Writes an instruction, unless its address is reserved (which the daemon would
+	/// disconnect the vCPU for).
+	fn inst(&mut self, addr: u64) -> c_int {
+		if addr == RESERVED_ADDR {
+			return -libc::EINVAL;
+		}
+		self.write(&Packet::Inst(Inst { addr }))
+	}
+
+	/// Writes a packet that's worth seeing right away.
+	fn event(&mut self, packet: &Packet) -> c_int {
+		result(
+			self.writer
+				.write_packet(packet)
+				.and_then(|()| self.writer.flush()),
+		)
+	}
+}
+
+fn result(result: io::Result<()>) -> c_int {
+	match result {
+		Ok(()) => 0,
+		Err(err) => -err.raw_os_error().unwrap_or(libc::EIO),
+	}
+}
+
+/// Returns a producer ID unique to the calling process (at least among those running),
+/// which all of its vCPUs should be initialized with. The same ID is returned every time.
+#[no_mangle]
+pub extern "C" fn ktrace_producer_id() -> u64 {
+	static PRODUCER_ID: OnceLock<u64> = OnceLock::new();
+	*PRODUCER_ID.get_or_init(ktrace_plugin_protocol::producer_id)
+}
+
+/// Connects to the daemon listening on `sock_path` (or the default socket if `NULL`).
+///
+/// Returns `NULL` on failure, with `errno` set.
+///
+/// # Safety
+/// `sock_path` must be `NULL` or a valid, NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ktrace_connect(sock_path: *const c_char) -> *mut KtraceConn {
+	let sock_path = if sock_path.is_null() {
+		ktrace_plugin_protocol::DEFAULT_SOCKET_PATH
+	} else {
+		// SAFETY: The caller guarantees that the path is a valid string.
+		match unsafe { CStr::from_ptr(sock_path) }.to_str() {
+			Ok(path) => path,
+			Err(_) => {
+				set_errno(libc::EINVAL);
+				return ptr::null_mut();
+			}
+		}
+	};
+
+	match UnixStream::connect(sock_path) {
+		Ok(stream) => {
+			Box::into_raw(Box::new(KtraceConn {
+				writer: BufWriter::new(Socket(stream)),
+			}))
+		}
+		Err(err) => {
+			set_errno(err.raw_os_error().unwrap_or(libc::EIO));
+			ptr::null_mut()
+		}
+	}
+}
+
+/// Announces the connection's vCPU. Must be called once, before anything else is sent.
+///
+/// # Safety
+/// `conn` must have been returned by [`ktrace_connect`] and not yet closed.
+#[no_mangle]
+pub unsafe extern "C" fn ktrace_vcpu_init(conn: *mut KtraceConn, producer: u64, vcpu_id: u32) -> c_int {
+	// SAFETY: The caller guarantees that the connection is valid.
+	let conn = unsafe { &mut *conn };
+	conn.event(&Packet::VcpuInit(VcpuInit {
+		id:       vcpu_id,
+		producer: Some(producer),
+	}))
+}
+
+/// Records an executed instruction. Instructions are buffered, and sent once the buffer
+/// fills up, or the vCPU idles or is flushed.
+///
+/// Returns `-EINVAL`, recording nothing, if `addr` is [`RESERVED_ADDR`].
+///
+/// # Safety
+/// `conn` must have been returned by [`ktrace_connect`] and not yet closed.
+#[no_mangle]
+pub unsafe extern "C" fn ktrace_inst(conn: *mut KtraceConn, addr: u64) -> c_int {
+	// SAFETY: The caller guarantees that the connection is valid.
+	unsafe { &mut *conn }.inst(addr)
+}
+
+/// Records `count` executed instructions, in order, as if by [`ktrace_inst`], stopping
+/// at the first that fails (having recorded those before it).
+///
+/// # Safety
+/// `conn` must have been returned by [`ktrace_connect`] and not yet closed, and
+/// `addrs` must point to `count` addresses (or may be `NULL` if `count` is 0).
+#[no_mangle]
+pub unsafe extern "C" fn ktrace_insts(conn: *mut KtraceConn, addrs: *const u64, count: usize) -> c_int {
+	if count == 0 {
+		return 0;
+	}
+
+	// SAFETY: The caller guarantees that the connection and addresses are valid.
+	let (conn, addrs) = unsafe { (&mut *conn, std::slice::from_raw_parts(addrs, count)) };
+	for &addr in addrs {
+		let ret = conn.inst(addr);
+		if ret != 0 {
+			return ret;
+		}
+	}
+
+	0
+}
+
+/// Records that the vCPU started (or went back to) executing instructions.
+///
+/// # Safety
+/// `conn` must have been returned by [`ktrace_connect`] and not yet closed.
+#[no_mangle]
+pub unsafe extern "C" fn ktrace_resume(conn: *mut KtraceConn) -> c_int {
+	// SAFETY: The caller guarantees that the connection is valid.
+	unsafe { &mut *conn }.event(&Packet::VcpuResume)
+}
+
+/// Records that the vCPU went idle (e.g. is waiting for an interrupt), sending the
+/// instructions buffered so far.
+///
+/// # Safety
+/// `conn` must have been returned by [`ktrace_connect`] and not yet closed.
+#[no_mangle]
+pub unsafe extern "C" fn ktrace_idle(conn: *mut KtraceConn) -> c_int {
+	// SAFETY: The caller guarantees that the connection is valid.
+	unsafe { &mut *conn }.event(&Packet::VcpuIdle)
+}
+
+/// Sends the instructions buffered so far.
+///
+/// # Safety
+/// `conn` must have been returned by [`ktrace_connect`] and not yet closed.
+#[no_mangle]
+pub unsafe extern "C" fn ktrace_flush(conn: *mut KtraceConn) -> c_int {
+	// SAFETY: The caller guarantees that the connection is valid.
+	result(unsafe { &mut *conn }.writer.flush())
+}
+
+/// Records that the vCPU exited, which ends its trace.
+///
+/// # Safety
+/// `conn` must have been returned by [`ktrace_connect`] and not yet closed.
+#[no_mangle]
+pub unsafe extern "C" fn ktrace_exit(conn: *mut KtraceConn) -> c_int {
+	// SAFETY: The caller guarantees that the connection is valid.
+	unsafe { &mut *conn }.event(&Packet::VcpuExit)
+}
+
+/// Sends whatever's buffered and closes the connection, freeing it. A vCPU closed
+/// without exiting is treated as having been disconnected.
+///
+/// Returns the result of sending what was buffered; the connection is freed either way.
+///
+/// # Safety
+/// `conn` must be `NULL`, or have been returned by [`ktrace_connect`] and not yet closed.
+/// It must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn ktrace_close(conn: *mut KtraceConn) -> c_int {
+	if conn.is_null() {
+		return 0;
+	}
+
+	// SAFETY: The caller guarantees that the connection is valid, and gives up ownership of it.
+	let mut conn = unsafe { Box::from_raw(conn) };
+	result(conn.writer.flush())
+}
+
+fn set_errno(errno: c_int) {
+	// SAFETY: `__errno_location` always returns a valid pointer to the thread's `errno`.
+	unsafe { *libc::__errno_location() = errno };
+}
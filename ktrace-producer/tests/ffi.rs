@@ -0,0 +1,156 @@
+//! Drives the C ABI against a socket standing in for the daemon, checking what's sent.
+
+use std::{
+	ffi::CString,
+	io::BufReader,
+	os::unix::net::UnixListener,
+	ptr,
+	thread::{self, JoinHandle},
+};
+
+use ktrace_plugin_protocol::{Packet, RESERVED_ADDR, TraceRead};
+use ktrace_producer::{
+	ktrace_close, ktrace_connect, ktrace_exit, ktrace_flush, ktrace_idle, ktrace_inst, ktrace_insts,
+	ktrace_producer_id, ktrace_resume, ktrace_vcpu_init,
+};
+
+/// Accepts a single connection, returning everything sent on it.
+fn listen(listener: UnixListener) -> JoinHandle<Vec<Packet>> {
+	thread::spawn(move || {
+		let (stream, _) = listener.accept().unwrap();
+		let mut stream = BufReader::new(stream);
+		let mut packets = Vec::new();
+		while let Ok(packet) = stream.read_packet() {
+			packets.push(packet);
+		}
+		packets
+	})
+}
+
+#[test]
+fn the_producer_id_is_the_same_every_time() {
+	assert_eq!(ktrace_producer_id(), ktrace_producer_id());
+}
+
+#[test]
+fn packets_are_sent_in_order() {
+	let dir = tempfile::tempdir().unwrap();
+	let sock_path = dir.path().join("trace.sock");
+	let received = listen(UnixListener::bind(&sock_path).unwrap());
+	let sock_path = CString::new(sock_path.to_str().unwrap()).unwrap();
+
+	let producer = ktrace_producer_id();
+	unsafe {
+		let conn = ktrace_connect(sock_path.as_ptr());
+		assert!(!conn.is_null());
+
+		assert_eq!(ktrace_vcpu_init(conn, producer, 3), 0);
+		assert_eq!(ktrace_resume(conn), 0);
+		assert_eq!(ktrace_inst(conn, 0x1000), 0);
+		assert_eq!(ktrace_insts(conn, [0x1004, 0x1008].as_ptr(), 2), 0);
+		assert_eq!(ktrace_insts(conn, ptr::null(), 0), 0);
+		assert_eq!(ktrace_flush(conn), 0);
+		assert_eq!(ktrace_idle(conn), 0);
+		assert_eq!(ktrace_exit(conn), 0);
+		assert_eq!(ktrace_close(conn), 0);
+	}
+
+	let packets = received.join().unwrap();
+	let summary = packets
+		.iter()
+		.map(|packet| {
+			match packet {
+				Packet::VcpuInit(init) => {
+					assert_eq!(init.producer, Some(producer));
+					format!("init {}", init.id)
+				}
+				Packet::Inst(inst) => format!("{:#x}", inst.addr),
+				packet => format!("{packet:?}"),
+			}
+		})
+		.collect::<Vec<_>>();
+	assert_eq!(
+		summary,
+		[
+			"init 3",
+			"VcpuResume",
+			"0x1000",
+			"0x1004",
+			"0x1008",
+			"VcpuIdle",
+			"VcpuExit"
+		]
+	);
+}
+
+#[test]
+fn reserved_addresses_are_refused() {
+	let dir = tempfile::tempdir().unwrap();
+	let sock_path = dir.path().join("trace.sock");
+	let received = listen(UnixListener::bind(&sock_path).unwrap());
+	let sock_path = CString::new(sock_path.to_str().unwrap()).unwrap();
+
+	unsafe {
+		let conn = ktrace_connect(sock_path.as_ptr());
+		assert!(!conn.is_null());
+
+		assert_eq!(ktrace_inst(conn, RESERVED_ADDR), -libc::EINVAL);
+		assert_eq!(
+			ktrace_insts(conn, [0x1000, RESERVED_ADDR, 0x1004].as_ptr(), 3),
+			-libc::EINVAL
+		);
+		assert_eq!(ktrace_close(conn), 0);
+	}
+
+	let addrs = received
+		.join()
+		.unwrap()
+		.into_iter()
+		.map(|packet| {
+			match packet {
+				Packet::Inst(inst) => inst.addr,
+				packet => panic!("unexpected packet: {packet:?}"),
+			}
+		})
+		.collect::<Vec<_>>();
+	assert_eq!(addrs, [0x1000]);
+}
+
+#[test]
+fn failing_to_connect_sets_errno() {
+	let dir = tempfile::tempdir().unwrap();
+	let sock_path = CString::new(dir.path().join("missing.sock").to_str().unwrap()).unwrap();
+
+	let conn = unsafe { ktrace_connect(sock_path.as_ptr()) };
+	assert!(conn.is_null());
+	assert_eq!(
+		std::io::Error::last_os_error().raw_os_error(),
+		Some(libc::ENOENT)
+	);
+
+	// Closing nothing is harmless.
+	assert_eq!(unsafe { ktrace_close(conn) }, 0);
+}
+
+#[test]
+fn writes_to_a_closed_daemon_fail() {
+	let dir = tempfile::tempdir().unwrap();
+	let sock_path = dir.path().join("trace.sock");
+	let listener = UnixListener::bind(&sock_path).unwrap();
+	let sock_path = CString::new(sock_path.to_str().unwrap()).unwrap();
+
+	unsafe {
+		// As it is in C programs; the test harness ignores it.
+		libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+
+		let conn = ktrace_connect(sock_path.as_ptr());
+		assert!(!conn.is_null());
+		drop(listener.accept().unwrap());
+		drop(listener);
+
+		// Without raising `SIGPIPE`.
+		assert_eq!(ktrace_vcpu_init(conn, 1, 0), -libc::EPIPE);
+		// What couldn't be sent is still buffered, but the connection is freed anyway.
+		assert_eq!(ktrace_close(conn), -libc::EPIPE);
+	}
+}
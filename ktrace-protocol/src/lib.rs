@@ -5,23 +5,54 @@ use serde::{Deserialize, Serialize};
 
 pub const DEFAULT_SOCKET_PATH: &str = "/tmp/ktrace-query.sock";
 
+/// The maximum number of entries returned by a single [`Packet::GetRange`]
+/// or [`Packet::Search`] request, or by a [`Packet::GetHistogram`] request
+/// without buckets (the entries of the lowest addresses are kept).
+/// Larger counts are clamped by the daemon.
+pub const MAX_RANGE_COUNT: usize = 64 * 1024;
+
+/// Sent in place of an address on a trace stream when the thread's trace is cleared
+/// (with [`Packet::ClearTrace`]). Addresses following it start over at index 0.
+///
+/// The daemon refuses to record it as an instruction address, so it's never sent otherwise.
+pub const STREAM_CLEARED: u64 = u64::MAX;
+
+/// The maximum length of an annotation's text, in bytes.
+pub const MAX_ANNOTATION_LEN: usize = 4096;
+
 #[derive(Serialize, Deserialize, Clone, Copy, thiserror::Error, Debug)]
 #[repr(u8)]
 pub enum Error {
 	#[error("bad packet")]
-	BadPacket = 1,
+	BadPacket     = 1,
 	#[error("invalid thread id")]
-	BadThread = 2,
+	BadThread     = 2,
+	#[error("failed to read trace data")]
+	Io            = 3,
+	#[error("invalid session name")]
+	BadSession    = 4,
+	#[error("thread is not being recorded")]
+	NotRecording  = 5,
+	#[error("invalid annotation")]
+	BadAnnotation = 6,
 }
 
+/// A request or response sent over the query socket.
+///
+/// Requests about a thread name the session it belongs to. If the session is `None`,
+/// the one opened on the connection (with [`Packet::OpenSession`]) is used, or else
+/// the most recently started session. Sessions named in requests are kept from being
+/// deleted until the connection is closed, as opened sessions are.
 #[derive(Serialize, Deserialize, Clone)]
 #[repr(u8)]
 pub enum Packet {
 	Error(Error),
 	GetStatus {
+		session:   Option<String>,
 		thread_id: u32,
 	},
 	GetInstCount {
+		session:   Option<String>,
 		thread_id: u32,
 	},
 	Status {
@@ -31,29 +62,242 @@ pub enum Packet {
 		count: usize,
 	},
 	OpenStream {
+		session:   Option<String>,
 		thread_id: u32,
 		filter:    Option<TraceFilter>,
 	},
+	GetRange {
+		session:   Option<String>,
+		thread_id: u32,
+		start:     usize,
+		count:     usize,
+		filter:    Option<TraceFilter>,
+	},
+	Range {
+		entries: Vec<InstEntry>,
+	},
+	Search {
+		session:   Option<String>,
+		thread_id: u32,
+		range:     AddrRange,
+		op:        SearchOp,
+		limit:     usize,
+	},
+	SearchResult {
+		hits: Vec<InstEntry>,
+	},
+	SearchCount {
+		count: usize,
+	},
+	GetHistogram {
+		session:   Option<String>,
+		thread_id: u32,
+		start:     usize,
+		end:       Option<usize>,
+		buckets:   Option<Vec<AddrRange>>,
+	},
+	Histogram {
+		entries: Vec<HistogramEntry>,
+	},
+	GetStorageStats {
+		session:   Option<String>,
+		thread_id: u32,
+	},
+	StorageStats {
+		stats: StorageStats,
+	},
+	ListSessions,
+	Sessions {
+		sessions: Vec<SessionInfo>,
+	},
+	OpenSession {
+		name: String,
+	},
+	SessionOpened {
+		session: SessionInfo,
+	},
+	GetDaemonStats,
+	DaemonStats {
+		stats: DaemonStats,
+	},
+	/// Discards everything recorded so far for a thread (or, if `thread_id` is `None`,
+	/// every thread of the session still being recorded), which continues recording
+	/// from instruction index 0.
+	ClearTrace {
+		session:   Option<String>,
+		thread_id: Option<u32>,
+	},
+	TraceCleared {
+		threads: Vec<u32>,
+	},
+	/// Attaches a bookmark or note to an instruction, storing it with the session.
+	AddAnnotation {
+		session:   Option<String>,
+		thread_id: u32,
+		index:     usize,
+		kind:      AnnotationKind,
+		text:      String,
+	},
+	AnnotationAdded {
+		annotation: Annotation,
+	},
+	/// Lists the session's annotations (of one thread, or of all of them if `thread_id`
+	/// is `None`), ordered by thread and instruction index.
+	ListAnnotations {
+		session:   Option<String>,
+		thread_id: Option<u32>,
+	},
+	Annotations {
+		annotations: Vec<Annotation>,
+	},
+	RemoveAnnotation {
+		session: Option<String>,
+		id:      u64,
+	},
+	AnnotationRemoved {
+		id: u64,
+	},
 }
 
 impl fmt::Debug for Packet {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 		match self {
 			Packet::Error(err) => write!(f, "Error {{ err: {err:?} }}"),
-			Packet::GetStatus { thread_id } => {
-				write!(f, "GetStatus {{ thread_id: {thread_id:?} }}")
+			Packet::GetStatus { session, thread_id } => {
+				write!(
+					f,
+					"GetStatus {{ session: {session:?}, thread_id: {thread_id:?} }}"
+				)
 			}
-			Packet::GetInstCount { thread_id } => {
-				write!(f, "GetInstCount {{ thread_id: {thread_id:?} }}")
+			Packet::GetInstCount { session, thread_id } => {
+				write!(
+					f,
+					"GetInstCount {{ session: {session:?}, thread_id: {thread_id:?} }}"
+				)
 			}
 			Packet::Status { status } => write!(f, "Status {{ status: {status:?} }}"),
 			Packet::InstCount { count } => write!(f, "InstCount {{ count: {count:?} }}"),
-			Packet::OpenStream { filter, thread_id } => {
+			Packet::OpenStream {
+				session,
+				thread_id,
+				filter,
+			} => {
 				write!(
 					f,
-					"OpenStream {{ thread_id: {thread_id:?}, filter: {filter:?} }}"
+					"OpenStream {{ session: {session:?}, thread_id: {thread_id:?}, filter: {filter:?} }}"
 				)
 			}
+			Packet::GetRange {
+				session,
+				thread_id,
+				start,
+				count,
+				filter,
+			} => {
+				write!(
+					f,
+					"GetRange {{ session: {session:?}, thread_id: {thread_id:?}, start: {start:?}, count: \
+					 {count:?}, filter: {filter:?} }}"
+				)
+			}
+			Packet::Range { entries } => write!(f, "Range {{ entries: [{} entries] }}", entries.len()),
+			Packet::Search {
+				session,
+				thread_id,
+				range,
+				op,
+				limit,
+			} => {
+				write!(
+					f,
+					"Search {{ session: {session:?}, thread_id: {thread_id:?}, range: {range:?}, op: \
+					 {op:?}, limit: {limit:?} }}"
+				)
+			}
+			Packet::SearchResult { hits } => write!(f, "SearchResult {{ hits: [{} hits] }}", hits.len()),
+			Packet::SearchCount { count } => write!(f, "SearchCount {{ count: {count:?} }}"),
+			Packet::GetHistogram {
+				session,
+				thread_id,
+				start,
+				end,
+				buckets,
+			} => {
+				write!(
+					f,
+					"GetHistogram {{ session: {session:?}, thread_id: {thread_id:?}, start: {start:?}, end: \
+					 {end:?}, buckets: {:?} }}",
+					buckets.as_ref().map(|b| b.len())
+				)
+			}
+			Packet::Histogram { entries } => {
+				write!(f, "Histogram {{ entries: [{} entries] }}", entries.len())
+			}
+			Packet::GetStorageStats { session, thread_id } => {
+				write!(
+					f,
+					"GetStorageStats {{ session: {session:?}, thread_id: {thread_id:?} }}"
+				)
+			}
+			Packet::StorageStats { stats } => write!(f, "StorageStats {{ stats: {stats:?} }}"),
+			Packet::ListSessions => write!(f, "ListSessions"),
+			Packet::Sessions { sessions } => {
+				write!(f, "Sessions {{ sessions: [{} sessions] }}", sessions.len())
+			}
+			Packet::OpenSession { name } => write!(f, "OpenSession {{ name: {name:?} }}"),
+			Packet::SessionOpened { session } => {
+				write!(f, "SessionOpened {{ session: {:?} }}", session.name)
+			}
+			Packet::GetDaemonStats => write!(f, "GetDaemonStats"),
+			Packet::DaemonStats { stats } => {
+				write!(
+					f,
+					"DaemonStats {{ uptime_secs: {:?}, threads: [{} threads] }}",
+					stats.uptime_secs,
+					stats.threads.len()
+				)
+			}
+			Packet::ClearTrace { session, thread_id } => {
+				write!(
+					f,
+					"ClearTrace {{ session: {session:?}, thread_id: {thread_id:?} }}"
+				)
+			}
+			Packet::TraceCleared { threads } => write!(f, "TraceCleared {{ threads: {threads:?} }}"),
+			Packet::AddAnnotation {
+				session,
+				thread_id,
+				index,
+				kind,
+				text,
+			} => {
+				write!(
+					f,
+					"AddAnnotation {{ session: {session:?}, thread_id: {thread_id:?}, index: {index:?}, \
+					 kind: {kind:?}, text: [{} bytes] }}",
+					text.len()
+				)
+			}
+			Packet::AnnotationAdded { annotation } => {
+				write!(f, "AnnotationAdded {{ id: {:?} }}", annotation.id)
+			}
+			Packet::ListAnnotations { session, thread_id } => {
+				write!(
+					f,
+					"ListAnnotations {{ session: {session:?}, thread_id: {thread_id:?} }}"
+				)
+			}
+			Packet::Annotations { annotations } => {
+				write!(
+					f,
+					"Annotations {{ annotations: [{} annotations] }}",
+					annotations.len()
+				)
+			}
+			Packet::RemoveAnnotation { session, id } => {
+				write!(f, "RemoveAnnotation {{ session: {session:?}, id: {id:?} }}")
+			}
+			Packet::AnnotationRemoved { id } => write!(f, "AnnotationRemoved {{ id: {id:?} }}"),
 		}
 	}
 }
@@ -64,6 +308,222 @@ pub enum TraceFilter {
 	LowerHalf,
 }
 
+impl TraceFilter {
+	/// Returns whether or not the given address passes the filter.
+	#[inline]
+	pub fn matches(&self, addr: u64) -> bool {
+		match self {
+			TraceFilter::LowerHalf => addr & 0x8000_0000_0000_0000 == 0,
+		}
+	}
+
+	/// Returns the range of addresses that pass the filter (e.g. to [`Packet::Search`] for them).
+	#[inline]
+	pub const fn range(&self) -> AddrRange {
+		match self {
+			TraceFilter::LowerHalf => {
+				AddrRange {
+					start: 0,
+					end:   0x8000_0000_0000_0000,
+				}
+			}
+		}
+	}
+}
+
+/// A single instruction address along with its absolute index
+/// in the thread's trace.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct InstEntry {
+	pub index: usize,
+	pub addr:  u64,
+}
+
+/// A half-open range of addresses (`start..end`).
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct AddrRange {
+	pub start: u64,
+	pub end:   u64,
+}
+
+impl AddrRange {
+	/// Creates a range matching exactly one address.
+	#[inline]
+	pub const fn single(addr: u64) -> Self {
+		Self {
+			start: addr,
+			end:   addr.saturating_add(1),
+		}
+	}
+
+	/// Returns whether or not the range contains the given address.
+	#[inline]
+	pub const fn contains(&self, addr: u64) -> bool {
+		addr >= self.start && addr < self.end
+	}
+}
+
+/// The number of executions of addresses within a range, as returned
+/// by a [`Packet::GetHistogram`] request.
+///
+/// When no buckets are requested, each entry's range spans exactly one address.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct HistogramEntry {
+	pub range: AddrRange,
+	pub count: usize,
+}
+
+/// Selects which occurrences a [`Packet::Search`] returns.
+///
+/// All operations other than [`SearchOp::Count`] respond with a [`Packet::SearchResult`]
+/// containing at most `limit` hits, in ascending instruction index order.
+/// [`SearchOp::Count`] responds with a [`Packet::SearchCount`].
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum SearchOp {
+	/// The first occurrences in the trace.
+	First,
+	/// The last occurrences in the trace.
+	Last,
+	/// The occurrences immediately following (but not including) the given index.
+	Next { after: usize },
+	/// The occurrences immediately preceding (but not including) the given index.
+	Prev { before: usize },
+	/// The `n`th (zero-based) occurrence in the trace. Ignores `limit`.
+	Nth { n: usize },
+	/// The total number of occurrences in the trace. Ignores `limit`.
+	Count,
+}
+
+/// Statistics about how a thread's trace is stored by the daemon.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub struct StorageStats {
+	/// The number of instructions recorded.
+	pub instructions: usize,
+	/// The index of the earliest instruction still stored. Instructions before
+	/// it were dropped to stay within the daemon's storage limits.
+	pub first_index: usize,
+	/// The number of chunks the (stored) instructions are stored in.
+	pub chunks: usize,
+	/// The size the stored instructions would take up uncompressed, in bytes.
+	pub raw_bytes: u64,
+	/// The size the stored instructions take up on disk, in bytes.
+	pub stored_bytes: u64,
+	/// Whether recording was stopped because a storage limit was reached.
+	pub recording_stopped: bool,
+}
+
+impl StorageStats {
+	/// Returns the ratio of raw to stored bytes (higher is better).
+	pub fn compression_ratio(&self) -> f64 {
+		if self.stored_bytes == 0 {
+			return 1.0;
+		}
+
+		self.raw_bytes as f64 / self.stored_bytes as f64
+	}
+}
+
+/// Self-diagnostics of the daemon, as returned by a [`Packet::GetDaemonStats`] request.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct DaemonStats {
+	/// How long the daemon has been running, in seconds.
+	pub uptime_secs: u64,
+	/// The threads of every session the daemon has in memory (those recorded
+	/// or opened since it started).
+	pub threads:     Vec<ThreadStats>,
+}
+
+/// Statistics about a thread's recording and the clients reading it.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct ThreadStats {
+	/// The name of the session the thread belongs to.
+	pub session:      String,
+	pub thread_id:    u32,
+	pub status:       ThreadStatus,
+	/// The number of instructions recorded.
+	pub instructions: usize,
+	/// The number of instructions recorded per second, averaged over the last second.
+	pub ingest_rate:  f64,
+	/// The size the thread's trace takes up on disk, in bytes.
+	pub stored_bytes: u64,
+	/// The number of streams open on the thread.
+	pub open_streams: usize,
+	/// The number of recorded instructions the slowest stream has yet to be sent.
+	pub stream_lag:   usize,
+	/// The number of instructions that were dropped (or never recorded) to stay
+	/// within the daemon's storage limits.
+	pub dropped:      usize,
+	/// The number of times the producer connected this thread again, after its
+	/// previous connection ended.
+	pub reconnects:   u32,
+	/// The latest statistics sent by the producer, if it sends any.
+	pub producer:     Option<ProducerStats>,
+}
+
+/// Statistics about a thread's tracing, as kept by its producer (e.g. the QEMU plugin).
+/// All counts are totals since the thread started.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub struct ProducerStats {
+	/// The number of plugin callbacks fired for the thread.
+	pub callbacks:     u64,
+	/// The number of instructions sent to the daemon.
+	pub instructions:  u64,
+	/// The number of bytes written to the daemon's socket.
+	pub bytes_written: u64,
+	/// The time spent blocked writing to the socket, in nanoseconds.
+	pub blocked_ns:    u64,
+	/// The number of instructions and lifecycle events that could not be sent.
+	pub dropped:       u64,
+}
+
+/// Describes a recorded trace session (typically, one run of QEMU).
+///
+/// Sessions are kept by the daemon after the producer exits, and can be
+/// reopened with [`Packet::OpenSession`], after which thread queries on the
+/// same connection that don't name a session refer to that session. Connections
+/// that don't open a session query the most recently started one.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SessionInfo {
+	/// The unique name of the session.
+	pub name:         String,
+	/// When the session started, in seconds since the Unix epoch.
+	pub started_at:   u64,
+	/// When the last thread of the session exited, in seconds since the Unix epoch.
+	pub ended_at:     Option<u64>,
+	/// The command line of the producer, if known.
+	pub command_line: Vec<String>,
+	/// The IDs of the threads (vCPUs) recorded in the session, in ascending order.
+	pub threads:      Vec<u32>,
+	/// Whether or not the producer is still connected.
+	pub live:         bool,
+}
+
+/// A bookmark or note attached to an instruction of a thread.
+///
+/// Annotations are stored with their session, so everyone looking at the session
+/// sees them. Clearing a thread's trace removes its annotations.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Annotation {
+	/// Identifies the annotation within its session.
+	pub id:         u64,
+	pub thread_id:  u32,
+	/// The absolute index of the annotated instruction.
+	pub index:      usize,
+	pub kind:       AnnotationKind,
+	/// The bookmark's name, or the note's text.
+	pub text:       String,
+	/// When the annotation was added, in seconds since the Unix epoch.
+	pub created_at: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum AnnotationKind {
+	/// A short name for the position (e.g. `"syscall entry"`).
+	Bookmark,
+	/// Free-form text about the position.
+	Note,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
 #[repr(usize)]
 pub enum ThreadStatus {
@@ -91,3 +551,22 @@ pub trait PacketDeserializer: io::Read + Sized {
 }
 
 impl<T: io::Read> PacketDeserializer for T {}
+
+/// Decodes a packet from the start of `buf`, returning it along with the number of bytes it
+/// took up, or `None` if `buf` doesn't hold a complete packet yet.
+///
+/// Useful for reading packets from non-blocking streams.
+pub fn decode_packet(buf: &[u8]) -> io::Result<Option<(Packet, usize)>> {
+	use rmp_serde::decode::Error;
+
+	let mut rd = buf;
+	match Packet::deserialize(&mut Deserializer::new(&mut rd)) {
+		Ok(packet) => Ok(Some((packet, buf.len() - rd.len()))),
+		Err(Error::InvalidMarkerRead(err) | Error::InvalidDataRead(err))
+			if err.kind() == io::ErrorKind::UnexpectedEof =>
+		{
+			Ok(None)
+		}
+		Err(err) => Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+	}
+}
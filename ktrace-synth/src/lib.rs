@@ -0,0 +1,302 @@
+//! Generates synthetic instruction traces, as streams of `ktrace-plugin-protocol` packets.
+//!
+//! Traces model a made-up program made of functions in both halves of the address
+//! space. Each function runs straight through, except for a loop in its body, and
+//! may call other functions along the way. Instructions are 1 to 7 bytes long.
+//!
+//! Traces are deterministic: the same [`Workload`] always produces the same
+//! addresses for a given vCPU, so consumers can check what they receive against
+//! [`Workload::addresses`].
+
+use ktrace_plugin_protocol::{Inst, Packet, VcpuInit};
+
+/// Where functions in the lower half of the address space start.
+pub const LOWER_HALF_BASE: u64 = 0x0000_0000_0040_0000;
+/// Where functions in the upper half of the address space start.
+pub const UPPER_HALF_BASE: u64 = 0xFFFF_FFFF_8000_0000;
+/// The distance between the starts of consecutive functions.
+pub const FUNCTION_STRIDE: u64 = 0x1000;
+
+/// Describes the traces to generate.
+#[derive(Debug, Clone)]
+pub struct Workload {
+	/// The number of instructions each vCPU executes.
+	pub instructions: u64,
+	/// Seeds the choices made while generating traces.
+	pub seed:         u64,
+	/// The number of functions in each half of the address space.
+	pub functions:    u32,
+	/// The number of instructions in each function.
+	pub function_len: u32,
+	/// The number of instructions in each function's loop body (0 for no loops).
+	pub loop_len:     u32,
+	/// The number of times each loop body runs.
+	pub loop_iters:   u32,
+	/// The chance that an instruction calls a function, from 0 to 1.
+	pub call_chance:  f64,
+	/// The deepest the call stack gets.
+	pub max_depth:    u32,
+	/// The chance that a called function is in the upper half, from 0 to 1.
+	pub upper_half:   f64,
+	/// Idles each vCPU (with a `VcpuIdle` and `VcpuResume`) after every this many instructions.
+	pub idle_every:   Option<u64>,
+}
+
+impl Default for Workload {
+	fn default() -> Self {
+		Self {
+			instructions: 1_000_000,
+			seed:         0,
+			functions:    64,
+			function_len: 64,
+			loop_len:     8,
+			loop_iters:   4,
+			call_chance:  0.05,
+			max_depth:    8,
+			upper_half:   0.25,
+			idle_every:   None,
+		}
+	}
+}
+
+impl Workload {
+	/// Returns the packets sent by a vCPU, from its `VcpuInit` to its `VcpuExit`.
+	pub fn vcpu(&self, producer: u64, id: u32) -> VcpuStream {
+		VcpuStream {
+			init:       Some(VcpuInit {
+				id,
+				producer: Some(producer),
+			}),
+			addresses:  self.addresses(id),
+			idle_every: self.idle_every,
+			sent:       0,
+			phase:      Phase::Resume,
+		}
+	}
+
+	/// Returns the addresses a vCPU executes, in order.
+	pub fn addresses(&self, id: u32) -> Addresses {
+		let mut rng = Rng(self.seed ^ mix(u64::from(id)));
+		let function = rng.below(self.functions.max(1));
+		let top = Frame::enter(self, Half::Lower, function);
+
+		Addresses {
+			workload: self.clone(),
+			rng,
+			stack: Vec::new(),
+			frame: top,
+			remaining: self.instructions,
+		}
+	}
+
+	fn loop_start(&self) -> u32 {
+		self.function_len / 4
+	}
+
+	fn loop_end(&self) -> u32 {
+		self.loop_start() + self.loop_len
+	}
+
+	fn has_loop(&self) -> bool {
+		self.loop_len > 0 && self.loop_iters > 1 && self.loop_end() <= self.function_len
+	}
+}
+
+/// The packets sent by a vCPU, as returned by [`Workload::vcpu`].
+pub struct VcpuStream {
+	init:       Option<VcpuInit>,
+	addresses:  Addresses,
+	idle_every: Option<u64>,
+	sent:       u64,
+	phase:      Phase,
+}
+
+enum Phase {
+	Resume,
+	Running,
+	Idle,
+	Exit,
+	Done,
+}
+
+impl Iterator for VcpuStream {
+	type Item = Packet;
+
+	fn next(&mut self) -> Option<Packet> {
+		if let Some(init) = self.init.take() {
+			return Some(Packet::VcpuInit(init));
+		}
+
+		loop {
+			match self.phase {
+				Phase::Resume => {
+					self.phase = Phase::Running;
+					return Some(Packet::VcpuResume);
+				}
+				Phase::Running => {
+					let Some(addr) = self.addresses.next() else {
+						self.phase = Phase::Exit;
+						return Some(Packet::VcpuIdle);
+					};
+
+					self.sent += 1;
+					if self.idle_every.is_some_and(|every| self.sent % every == 0) {
+						self.phase = Phase::Idle;
+					}
+
+					return Some(Packet::Inst(Inst { addr }));
+				}
+				Phase::Idle => {
+					// Don't idle twice at the end of the trace.
+					if self.addresses.remaining == 0 {
+						self.phase = Phase::Running;
+						continue;
+					}
+
+					self.phase = Phase::Resume;
+					return Some(Packet::VcpuIdle);
+				}
+				Phase::Exit => {
+					self.phase = Phase::Done;
+					return Some(Packet::VcpuExit);
+				}
+				Phase::Done => return None,
+			}
+		}
+	}
+}
+
+/// The addresses a vCPU executes, as returned by [`Workload::addresses`].
+pub struct Addresses {
+	workload:  Workload,
+	rng:       Rng,
+	/// The frames to return to, innermost last.
+	stack:     Vec<Frame>,
+	frame:     Frame,
+	remaining: u64,
+}
+
+#[derive(Clone, Copy)]
+enum Half {
+	Lower,
+	Upper,
+}
+
+/// A function being executed.
+#[derive(Clone, Copy)]
+struct Frame {
+	/// The index of the next instruction within the function.
+	index: u32,
+	pc: u64,
+	loop_start_pc: u64,
+	loop_remaining: u32,
+}
+
+impl Frame {
+	fn enter(workload: &Workload, half: Half, function: u32) -> Self {
+		let base = match half {
+			Half::Lower => LOWER_HALF_BASE,
+			Half::Upper => UPPER_HALF_BASE,
+		};
+		let pc = base + u64::from(function) * FUNCTION_STRIDE;
+
+		Self {
+			index: 0,
+			pc,
+			loop_start_pc: pc,
+			loop_remaining: workload.loop_iters.saturating_sub(1),
+		}
+	}
+
+	/// Moves on to the next instruction in the function.
+	fn advance(&mut self, workload: &Workload) {
+		self.index += 1;
+		self.pc += instruction_len(self.pc);
+
+		if workload.has_loop() {
+			if self.index == workload.loop_start() {
+				self.loop_start_pc = self.pc;
+			} else if self.index == workload.loop_end() && self.loop_remaining > 0 {
+				self.loop_remaining -= 1;
+				self.index = workload.loop_start();
+				self.pc = self.loop_start_pc;
+			}
+		}
+	}
+}
+
+impl Iterator for Addresses {
+	type Item = u64;
+
+	fn next(&mut self) -> Option<u64> {
+		if self.remaining == 0 {
+			return None;
+		}
+		self.remaining -= 1;
+
+		let workload = &self.workload;
+		let addr = self.frame.pc;
+		self.frame.advance(workload);
+
+		if self.frame.index >= workload.function_len {
+			// Return, or start over at another function if there's nothing to return to.
+			self.frame = match self.stack.pop() {
+				Some(frame) => frame,
+				None => {
+					Frame::enter(
+						workload,
+						Half::Lower,
+						self.rng.below(workload.functions.max(1)),
+					)
+				}
+			};
+		} else if self.stack.len() < workload.max_depth as usize && self.rng.chance(workload.call_chance) {
+			let half = if self.rng.chance(workload.upper_half) {
+				Half::Upper
+			} else {
+				Half::Lower
+			};
+			let callee = Frame::enter(workload, half, self.rng.below(workload.functions.max(1)));
+			self.stack.push(std::mem::replace(&mut self.frame, callee));
+		}
+
+		Some(addr)
+	}
+
+	fn size_hint(&self) -> (usize, Option<usize>) {
+		let remaining = usize::try_from(self.remaining).unwrap_or(usize::MAX);
+		(remaining, Some(remaining))
+	}
+}
+
+/// Returns the length of the instruction at `pc`, which is the same every time it's executed.
+fn instruction_len(pc: u64) -> u64 {
+	1 + mix(pc) % 7
+}
+
+/// A small, fast and deterministic random number generator (SplitMix64).
+struct Rng(u64);
+
+impl Rng {
+	fn next(&mut self) -> u64 {
+		self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+		mix(self.0)
+	}
+
+	/// Returns a number in `0..n`.
+	fn below(&mut self, n: u32) -> u32 {
+		(self.next() % u64::from(n)) as u32
+	}
+
+	/// Returns `true` with the given probability.
+	fn chance(&mut self, p: f64) -> bool {
+		p > 0.0 && ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < p
+	}
+}
+
+/// Scrambles the bits of a number (the SplitMix64 finalizer).
+fn mix(mut z: u64) -> u64 {
+	z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+	z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+	z ^ (z >> 31)
+}
@@ -0,0 +1,193 @@
+use std::{
+	io::{self, BufWriter, Write},
+	os::unix::net::UnixStream,
+	path::PathBuf,
+	thread,
+	time::{Duration, Instant},
+};
+
+use clap::Parser;
+use ktrace_config::Sockets;
+use ktrace_plugin_protocol::{Packet, TraceWrite};
+use ktrace_synth::Workload;
+use serde::Deserialize;
+
+/// How often paced streams flush what they've sent, per second.
+const PACE_STEPS_PER_SEC: u64 = 100;
+
+/// Streams synthetic instruction traces to `ktraced`, as if QEMU were running a made-up program.
+///
+/// The socket path, if not given on the command line, is read from the config file,
+/// `$XDG_CONFIG_HOME/ktrace/config.toml` by default.
+#[derive(Parser)]
+struct Args {
+	/// The config file to read (instead of the default one).
+	#[clap(short = 'c', long = "config")]
+	config:       Option<PathBuf>,
+	/// The socket `ktraced` listens on for trace connections [default: /tmp/ktrace.sock].
+	#[clap(short = 's', long = "trace-sock")]
+	sock_path:    Option<String>,
+	/// The number of vCPUs.
+	#[clap(short = 'n', long = "vcpus", default_value_t = 1)]
+	vcpus:        u32,
+	/// The number of instructions each vCPU executes.
+	#[clap(short = 'i', long = "instructions", default_value_t = Workload::default().instructions)]
+	instructions: u64,
+	/// Seeds the generated traces, which are otherwise the same every time.
+	#[clap(long = "seed", default_value_t = 0)]
+	seed:         u64,
+	/// The number of functions in each half of the address space.
+	#[clap(long = "functions", default_value_t = Workload::default().functions, value_parser = clap::value_parser!(u32).range(1..))]
+	functions:    u32,
+	/// The number of instructions in each function.
+	#[clap(long = "function-len", default_value_t = Workload::default().function_len, value_parser = clap::value_parser!(u32).range(1..))]
+	function_len: u32,
+	/// The number of instructions in each function's loop body (0 for no loops).
+	#[clap(long = "loop-len", default_value_t = Workload::default().loop_len)]
+	loop_len:     u32,
+	/// The number of times each loop body runs.
+	#[clap(long = "loop-iters", default_value_t = Workload::default().loop_iters)]
+	loop_iters:   u32,
+	/// The chance that an instruction calls a function, from 0 to 1.
+	#[clap(long = "call-chance", default_value_t = Workload::default().call_chance)]
+	call_chance:  f64,
+	/// The deepest the call stack gets.
+	#[clap(long = "max-depth", default_value_t = Workload::default().max_depth)]
+	max_depth:    u32,
+	/// The chance that a called function is in the upper half of the address space, from 0 to 1.
+	#[clap(long = "upper-half", default_value_t = Workload::default().upper_half)]
+	upper_half:   f64,
+	/// Idle each vCPU after every this many instructions.
+	#[clap(long = "idle-every", value_parser = clap::value_parser!(u64).range(1..))]
+	idle_every:   Option<u64>,
+	/// How long each vCPU stays idle, in milliseconds.
+	#[clap(long = "idle-ms", default_value_t = 100)]
+	idle_ms:      u64,
+	/// The number of instructions to send per second, per vCPU [default: as fast as possible].
+	#[clap(short = 'r', long = "rate", value_parser = clap::value_parser!(u64).range(1..))]
+	rate:         Option<u64>,
+}
+
+/// The tables of the config file (see [`ktrace_config`]) `ktrace-synth` reads.
+#[derive(Default, Deserialize)]
+struct Config {
+	#[serde(default)]
+	sockets: Sockets,
+}
+
+fn main() {
+	let args = Args::parse();
+
+	let Config { sockets } = ktrace_config::load(args.config.as_deref()).unwrap_or_else(|err| {
+		eprintln!("ktrace-synth: {err}");
+		std::process::exit(1);
+	});
+
+	let sock_path = args
+		.sock_path
+		.clone()
+		.or(sockets.trace)
+		.unwrap_or_else(|| ktrace_plugin_protocol::DEFAULT_SOCKET_PATH.to_string());
+
+	let workload = Workload {
+		instructions: args.instructions,
+		seed:         args.seed,
+		functions:    args.functions,
+		function_len: args.function_len,
+		loop_len:     args.loop_len,
+		loop_iters:   args.loop_iters,
+		call_chance:  args.call_chance,
+		max_depth:    args.max_depth,
+		upper_half:   args.upper_half,
+		idle_every:   args.idle_every,
+	};
+
+	// Shared by the vCPUs, so that they're recorded into one session.
+	let producer = ktrace_plugin_protocol::producer_id();
+
+	let start = Instant::now();
+	let ok = thread::scope(|scope| {
+		let vcpus = (0..args.vcpus)
+			.map(|id| {
+				let (args, workload, sock_path) = (&args, &workload, &sock_path);
+				let handle = scope.spawn(move || {
+					let mut stream = send_vcpu(sock_path, workload, producer, id, args)?;
+					stream.write_packet(&Packet::VcpuExit)?;
+					stream.flush()
+				});
+				(id, handle)
+			})
+			.collect::<Vec<_>>();
+
+		let mut ok = true;
+		for (id, handle) in vcpus {
+			if let Err(err) = handle.join().expect("vcpu thread panicked") {
+				eprintln!("ktrace-synth: vcpu {id}: {err}");
+				ok = false;
+			}
+		}
+		ok
+	});
+
+	let elapsed = start.elapsed().as_secs_f64();
+	let total = args.instructions * u64::from(args.vcpus);
+	println!(
+		"sent {total} instruction(s) across {} vcpu(s) in {elapsed:.2}s ({:.0}/s)",
+		args.vcpus,
+		total as f64 / elapsed
+	);
+
+	if !ok {
+		std::process::exit(1);
+	}
+}
+
+/// Sends a vCPU's stream up to (but not including) its `VcpuExit`, pacing it if asked to.
+fn send_vcpu(
+	sock_path: &str,
+	workload: &Workload,
+	producer: u64,
+	id: u32,
+	args: &Args,
+) -> io::Result<BufWriter<UnixStream>> {
+	let mut stream = BufWriter::new(UnixStream::connect(sock_path)?);
+
+	let step = args.rate.map(|rate| (rate / PACE_STEPS_PER_SEC).max(1));
+	let mut paced_since = Instant::now();
+	let mut paced_count = 0;
+	let mut sent = 0;
+
+	for packet in workload.vcpu(producer, id) {
+		if matches!(packet, Packet::VcpuExit) {
+			break;
+		}
+		stream.write_packet(&packet)?;
+
+		match packet {
+			Packet::Inst(_) => sent += 1,
+			// Only idle between instructions, not once they've all been sent.
+			Packet::VcpuIdle if sent < workload.instructions => {
+				stream.flush()?;
+				thread::sleep(Duration::from_millis(args.idle_ms));
+
+				// Don't make up for the time spent idle.
+				paced_since = Instant::now();
+				paced_count = 0;
+				continue;
+			}
+			_ => continue,
+		}
+
+		if let (Some(rate), Some(step)) = (args.rate, step) {
+			paced_count += 1;
+			if paced_count % step == 0 {
+				stream.flush()?;
+				let due = paced_since + Duration::from_secs_f64(paced_count as f64 / rate as f64);
+				thread::sleep(due.saturating_duration_since(Instant::now()));
+			}
+		}
+	}
+
+	stream.flush()?;
+	Ok(stream)
+}
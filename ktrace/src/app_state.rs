@@ -1,6 +1,11 @@
-use std::sync::{
-	Arc, Mutex,
-	atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed},
+use std::{
+	io::{self, BufReader},
+	net::Shutdown,
+	os::unix::net::UnixStream,
+	sync::{
+		Arc, Mutex,
+		atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering::Relaxed},
+	},
 };
 
 use circular_buffer::CircularBuffer;
@@ -8,7 +13,12 @@ use ktrace_protocol::ThreadStatus;
 use tinylfu_cached::cache::{cached::CacheD, config::ConfigBuilder};
 use tokio::sync::Mutex as AsyncMutex;
 
-use crate::symbol_resolver::{ResolverClient, Symbol};
+use crate::{
+	config::Filter,
+	query_client::Client,
+	symbol_resolver::{ResolverClient, Symbol},
+	view::trace_log::Pane,
+};
 
 pub struct AppState {
 	pub daemon_connected:     Flag,
@@ -18,10 +28,17 @@ pub struct AppState {
 	pub instruction_count:    AtomicUsize,
 	pub resolver_client:      ResolverClient,
 	pub resolution_cache:     CacheD<u64, Arc<AsyncMutex<Symbol>>>,
+
+	/// The thread (vCPU) being shown.
+	thread_id: AtomicU32,
+	/// The pre-filter applied to the right-hand trace log.
+	filter:    Mutex<Filter>,
+	/// The trace streams each pane follows, shut down to reopen them.
+	streams:   Mutex<[Option<UnixStream>; 2]>,
 }
 
 impl AppState {
-	pub fn new(resolver_client: ResolverClient) -> Self {
+	pub fn new(resolver_client: ResolverClient, filter: Filter) -> Self {
 		Self {
 			daemon_connected: Flag::new(false),
 			last_addresses: Mutex::new(CircularBuffer::new()),
@@ -30,12 +47,73 @@ impl AppState {
 			instruction_count: AtomicUsize::new(0),
 			resolver_client,
 			resolution_cache: CacheD::new(ConfigBuilder::new(10000, 1000, 1024 * 1024 * 10).build()),
+			thread_id: AtomicU32::new(0),
+			filter: Mutex::new(filter),
+			streams: Mutex::new([None, None]),
+		}
+	}
+
+	/// Returns the last addresses received for the given pane.
+	pub fn addresses(&self, pane: Pane) -> &Mutex<CircularBuffer<256, u64>> {
+		match pane {
+			Pane::All => &self.last_addresses,
+			Pane::Filtered => &self.last_lower_addresses,
 		}
 	}
 
-	fn resolve_all_for_list<const SZ: usize>(&self, list: &CircularBuffer<SZ, u64>) -> Vec<Symbol> {
+	#[inline]
+	pub fn thread_id(&self) -> u32 {
+		self.thread_id.load(Relaxed)
+	}
+
+	#[inline]
+	pub fn filter(&self) -> Filter {
+		*self.filter.lock().unwrap()
+	}
+
+	/// Shows another thread, reopening both panes' trace streams.
+	pub fn set_thread_id(&self, thread_id: u32) {
+		let mut streams = self.streams.lock().unwrap();
+		self.thread_id.store(thread_id, Relaxed);
+		for stream in streams.iter_mut().filter_map(Option::take) {
+			let _ = stream.shutdown(Shutdown::Both);
+		}
+	}
+
+	/// Changes the right-hand pane's filter, reopening its trace stream.
+	pub fn set_filter(&self, filter: Filter) {
+		let mut streams = self.streams.lock().unwrap();
+		*self.filter.lock().unwrap() = filter;
+		if let Some(stream) = streams[Pane::Filtered as usize].take() {
+			let _ = stream.shutdown(Shutdown::Both);
+		}
+	}
+
+	/// Opens the trace stream for a pane, of the current thread and with the
+	/// pane's filter. It ends when the thread or the filter is changed.
+	pub fn open_stream(
+		&self,
+		client: &Client,
+		session: Option<String>,
+		pane: Pane,
+	) -> io::Result<BufReader<UnixStream>> {
+		let mut streams = self.streams.lock().unwrap();
+		let filter = match pane {
+			Pane::All => None,
+			Pane::Filtered => self.filter().to_trace_filter(),
+		};
+
+		let stream = client.open_stream(session, self.thread_id(), filter)?;
+		streams[pane as usize] = Some(stream.get_ref().try_clone()?);
+		Ok(stream)
+	}
+
+	/// Returns the symbols of the given addresses, as far as they've been resolved.
+	/// Those not yet looked up are resolved in the background.
+	pub fn resolve_all<'a>(&self, addrs: impl IntoIterator<Item = &'a u64>) -> Vec<Symbol> {
 		// TODO(qix-): [internal screaming]
-		list.iter()
+		addrs
+			.into_iter()
 			.map(|addr| {
 				let entry = self.resolution_cache.get(addr);
 				if let Some(entry) = entry {
@@ -61,6 +139,11 @@ impl crate::widget::status_bar::StatusBarState for AppState {
 		self.daemon_connected.get()
 	}
 
+	#[inline]
+	fn thread_id(&self) -> u32 {
+		AppState::thread_id(self)
+	}
+
 	#[inline]
 	fn instruction_count(&self) -> usize {
 		self.instruction_count.load(Relaxed)
@@ -83,12 +166,12 @@ impl crate::widget::status_bar::StatusBarState for AppState {
 impl crate::view::trace_log::TraceLogState for AppState {
 	#[inline]
 	fn get_last_addresses(&self) -> Vec<Symbol> {
-		self.resolve_all_for_list(&self.last_addresses.lock().unwrap())
+		self.resolve_all(self.last_addresses.lock().unwrap().iter())
 	}
 
 	#[inline]
 	fn get_last_lower_addresses(&self) -> Vec<Symbol> {
-		self.resolve_all_for_list(&self.last_lower_addresses.lock().unwrap())
+		self.resolve_all(self.last_lower_addresses.lock().unwrap().iter())
 	}
 }
 
@@ -0,0 +1,185 @@
+//! The commands bound to keys, and dispatching key presses to them.
+
+use crossterm::event::KeyEvent;
+
+use crate::config::{KeyBinding, Keys};
+
+/// Something the user can do with a key press.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+	Quit,
+	Help,
+	Pause,
+	Up,
+	Down,
+	PageUp,
+	PageDown,
+	Home,
+	End,
+	Jump,
+	Search,
+	SearchNext,
+	SearchPrev,
+	NextThread,
+	PrevThread,
+	Filter,
+	Focus,
+}
+
+impl Command {
+	/// Every command, in the order they're listed in the help.
+	pub const ALL: [Command; 17] = [
+		Command::Quit,
+		Command::Help,
+		Command::Pause,
+		Command::Up,
+		Command::Down,
+		Command::PageUp,
+		Command::PageDown,
+		Command::Home,
+		Command::End,
+		Command::Jump,
+		Command::Search,
+		Command::SearchNext,
+		Command::SearchPrev,
+		Command::NextThread,
+		Command::PrevThread,
+		Command::Filter,
+		Command::Focus,
+	];
+
+	/// Returns the command for a key event, if it's bound to one.
+	pub fn for_key(keys: &Keys, event: &KeyEvent) -> Option<Command> {
+		Self::ALL.into_iter().find(|command| {
+			command
+				.bindings(keys)
+				.iter()
+				.any(|binding| binding.matches(event))
+		})
+	}
+
+	/// Returns the keys bound to the command.
+	pub fn bindings(self, keys: &Keys) -> &[KeyBinding] {
+		match self {
+			Command::Quit => &keys.quit,
+			Command::Help => &keys.help,
+			Command::Pause => &keys.pause,
+			Command::Up => &keys.up,
+			Command::Down => &keys.down,
+			Command::PageUp => &keys.page_up,
+			Command::PageDown => &keys.page_down,
+			Command::Home => &keys.home,
+			Command::End => &keys.end,
+			Command::Jump => &keys.jump,
+			Command::Search => &keys.search,
+			Command::SearchNext => &keys.search_next,
+			Command::SearchPrev => &keys.search_prev,
+			Command::NextThread => &keys.next_thread,
+			Command::PrevThread => &keys.prev_thread,
+			Command::Filter => &keys.filter,
+			Command::Focus => &keys.focus,
+		}
+	}
+
+	/// Returns what the command does, as shown in the help.
+	pub fn description(self) -> &'static str {
+		match self {
+			Command::Quit => "quit",
+			Command::Help => "show or hide this help",
+			Command::Pause => "pause to scroll back, or resume following the trace",
+			Command::Up => "move up one instruction",
+			Command::Down => "move down one instruction",
+			Command::PageUp => "move up one page",
+			Command::PageDown => "move down one page",
+			Command::Home => "move to the first instruction",
+			Command::End => "move to the last instruction",
+			Command::Jump => "jump to an instruction index",
+			Command::Search => "search for an address",
+			Command::SearchNext => "move to the next occurrence of the address",
+			Command::SearchPrev => "move to the previous occurrence of the address",
+			Command::NextThread => "show the next thread (vCPU)",
+			Command::PrevThread => "show the previous thread (vCPU)",
+			Command::Filter => "change the right-hand trace log's filter",
+			Command::Focus => "switch between the trace logs",
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crossterm::event::{KeyCode, KeyModifiers};
+
+	use super::*;
+
+	fn command(keys: &Keys, code: KeyCode, modifiers: KeyModifiers) -> Option<Command> {
+		Command::for_key(keys, &KeyEvent::new(code, modifiers))
+	}
+
+	#[test]
+	fn keys_are_dispatched_to_their_commands() {
+		let keys = Keys::default();
+		for (code, modifiers, expected) in [
+			(KeyCode::Char('q'), KeyModifiers::NONE, Some(Command::Quit)),
+			(
+				KeyCode::Char('c'),
+				KeyModifiers::CONTROL,
+				Some(Command::Quit),
+			),
+			(KeyCode::Char('c'), KeyModifiers::NONE, None),
+			(KeyCode::F(1), KeyModifiers::NONE, Some(Command::Help)),
+			(KeyCode::Char('j'), KeyModifiers::NONE, Some(Command::Down)),
+			(KeyCode::Up, KeyModifiers::NONE, Some(Command::Up)),
+			(
+				KeyCode::Char('n'),
+				KeyModifiers::NONE,
+				Some(Command::SearchNext),
+			),
+			(
+				KeyCode::Char('N'),
+				KeyModifiers::NONE,
+				Some(Command::SearchPrev),
+			),
+			// Terminals differ in whether shifted letters come with SHIFT.
+			(
+				KeyCode::Char('N'),
+				KeyModifiers::SHIFT,
+				Some(Command::SearchPrev),
+			),
+			(
+				KeyCode::Char('t'),
+				KeyModifiers::SHIFT,
+				Some(Command::PrevThread),
+			),
+			(KeyCode::Tab, KeyModifiers::NONE, Some(Command::Focus)),
+			(KeyCode::Char('x'), KeyModifiers::NONE, None),
+		] {
+			assert_eq!(
+				command(&keys, code, modifiers),
+				expected,
+				"{code:?} with {modifiers:?}"
+			);
+		}
+	}
+
+	#[test]
+	fn rebound_keys_are_dispatched() {
+		let keys = Keys {
+			search_next: vec![KeyBinding::new(KeyCode::Char('n'), KeyModifiers::CONTROL)],
+			..Keys::default()
+		};
+
+		assert_eq!(
+			command(&keys, KeyCode::Char('n'), KeyModifiers::CONTROL),
+			Some(Command::SearchNext)
+		);
+		assert_eq!(command(&keys, KeyCode::Char('n'), KeyModifiers::NONE), None);
+	}
+
+	#[test]
+	fn every_command_is_bound_by_default() {
+		let keys = Keys::default();
+		for command in Command::ALL {
+			assert!(!command.bindings(&keys).is_empty(), "{command:?}");
+		}
+	}
+}
@@ -0,0 +1,344 @@
+//! The optional configuration file, shared with `ktraced` and the QEMU plugin
+//! (see [`ktrace_config`]).
+//!
+//! `ktrace` reads the `[sockets]` and `[client]` tables. Command line flags take
+//! precedence over the values in the file.
+
+use std::fmt;
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use ktrace_config::Sockets;
+use ktrace_protocol::TraceFilter;
+use serde::Deserialize;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+	#[serde(default)]
+	pub sockets: Sockets,
+	#[serde(default)]
+	pub client:  ClientConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+	/// The binaries to load symbols from, in order.
+	pub binaries: Vec<String>,
+	/// The session to show.
+	pub session:  Option<String>,
+	/// The pre-filter applied to the right-hand trace log.
+	pub filter:   Option<Filter>,
+	pub keys:     Keys,
+}
+
+/// A pre-filter, as named on the command line and in the config file.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Filter {
+	/// Show every instruction.
+	None,
+	/// Show only instructions in the lower half of the address space.
+	LowerHalf,
+}
+
+impl Filter {
+	/// Returns the filter to send to `ktraced`.
+	pub fn to_trace_filter(self) -> Option<TraceFilter> {
+		match self {
+			Filter::None => None,
+			Filter::LowerHalf => Some(TraceFilter::LowerHalf),
+		}
+	}
+}
+
+/// The key bindings for each command (see [`crate::command::Command`]).
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Keys {
+	pub quit:        Vec<KeyBinding>,
+	pub help:        Vec<KeyBinding>,
+	pub pause:       Vec<KeyBinding>,
+	pub up:          Vec<KeyBinding>,
+	pub down:        Vec<KeyBinding>,
+	pub page_up:     Vec<KeyBinding>,
+	pub page_down:   Vec<KeyBinding>,
+	pub home:        Vec<KeyBinding>,
+	pub end:         Vec<KeyBinding>,
+	pub jump:        Vec<KeyBinding>,
+	pub search:      Vec<KeyBinding>,
+	pub search_next: Vec<KeyBinding>,
+	pub search_prev: Vec<KeyBinding>,
+	pub next_thread: Vec<KeyBinding>,
+	pub prev_thread: Vec<KeyBinding>,
+	pub filter:      Vec<KeyBinding>,
+	pub focus:       Vec<KeyBinding>,
+}
+
+impl Default for Keys {
+	fn default() -> Self {
+		const fn key(code: KeyCode) -> KeyBinding {
+			KeyBinding::new(code, KeyModifiers::NONE)
+		}
+
+		Self {
+			quit:        vec![
+				key(KeyCode::Char('q')),
+				key(KeyCode::Esc),
+				KeyBinding::new(KeyCode::Char('c'), KeyModifiers::CONTROL),
+			],
+			help:        vec![key(KeyCode::Char('?')), key(KeyCode::F(1))],
+			pause:       vec![key(KeyCode::Char('p')), key(KeyCode::Char(' '))],
+			up:          vec![key(KeyCode::Up), key(KeyCode::Char('k'))],
+			down:        vec![key(KeyCode::Down), key(KeyCode::Char('j'))],
+			page_up:     vec![key(KeyCode::PageUp)],
+			page_down:   vec![key(KeyCode::PageDown)],
+			home:        vec![key(KeyCode::Home)],
+			end:         vec![key(KeyCode::End)],
+			jump:        vec![key(KeyCode::Char('g'))],
+			search:      vec![key(KeyCode::Char('/'))],
+			search_next: vec![key(KeyCode::Char('n'))],
+			search_prev: vec![key(KeyCode::Char('N'))],
+			next_thread: vec![key(KeyCode::Char('t'))],
+			prev_thread: vec![key(KeyCode::Char('T'))],
+			filter:      vec![key(KeyCode::Char('f'))],
+			focus:       vec![key(KeyCode::Tab)],
+		}
+	}
+}
+
+/// A key along with the modifiers that must be held, written as
+/// e.g. `q`, `esc`, `f5` or `ctrl-c`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct KeyBinding {
+	code:      KeyCode,
+	modifiers: KeyModifiers,
+}
+
+impl KeyBinding {
+	pub const fn new(code: KeyCode, modifiers: KeyModifiers) -> Self {
+		Self { code, modifiers }
+	}
+
+	/// Returns whether or not the key event triggers this binding.
+	pub fn matches(&self, event: &KeyEvent) -> bool {
+		Self::new(event.code, event.modifiers).normalized() == *self
+	}
+
+	/// Folds shift into the key itself, the way terminals report it: `shift-n` is `N`
+	/// and `shift-tab` is the back tab key.
+	fn normalized(self) -> Self {
+		let Self { code, modifiers } = self;
+		let shift = modifiers.contains(KeyModifiers::SHIFT);
+		let code = match code {
+			KeyCode::Char(c) if shift => KeyCode::Char(c.to_ascii_uppercase()),
+			KeyCode::Tab if shift => KeyCode::BackTab,
+			code => code,
+		};
+
+		match code {
+			KeyCode::Char(_) | KeyCode::BackTab => Self::new(code, modifiers.difference(KeyModifiers::SHIFT)),
+			code => Self::new(code, modifiers),
+		}
+	}
+}
+
+/// Writes the binding the way it's written in the config file.
+impl fmt::Display for KeyBinding {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		for (modifier, name) in [
+			(KeyModifiers::CONTROL, "ctrl"),
+			(KeyModifiers::ALT, "alt"),
+			(KeyModifiers::SHIFT, "shift"),
+		] {
+			if self.modifiers.contains(modifier) {
+				write!(f, "{name}-")?;
+			}
+		}
+
+		match self.code {
+			KeyCode::Char(' ') => f.write_str("space"),
+			KeyCode::Char(c) => write!(f, "{c}"),
+			KeyCode::Esc => f.write_str("esc"),
+			KeyCode::Enter => f.write_str("enter"),
+			KeyCode::Tab => f.write_str("tab"),
+			KeyCode::BackTab => f.write_str("shift-tab"),
+			KeyCode::Backspace => f.write_str("backspace"),
+			KeyCode::Up => f.write_str("up"),
+			KeyCode::Down => f.write_str("down"),
+			KeyCode::Left => f.write_str("left"),
+			KeyCode::Right => f.write_str("right"),
+			KeyCode::Home => f.write_str("home"),
+			KeyCode::End => f.write_str("end"),
+			KeyCode::PageUp => f.write_str("pageup"),
+			KeyCode::PageDown => f.write_str("pagedown"),
+			KeyCode::F(n) => write!(f, "f{n}"),
+			code => write!(f, "{code:?}"),
+		}
+	}
+}
+
+impl TryFrom<String> for KeyBinding {
+	type Error = String;
+
+	fn try_from(s: String) -> Result<Self, Self::Error> {
+		let mut parts = s.split('-').collect::<Vec<_>>();
+		// Allows binding `-` itself (e.g. `-` or `ctrl--`).
+		if parts.ends_with(&["", ""]) {
+			parts.truncate(parts.len() - 2);
+			parts.push("-");
+		}
+
+		let (key, mods) = parts
+			.split_last()
+			.ok_or_else(|| format!("invalid key '{s}'"))?;
+
+		let mut modifiers = KeyModifiers::NONE;
+		for m in mods {
+			modifiers |= match m.to_ascii_lowercase().as_str() {
+				"ctrl" => KeyModifiers::CONTROL,
+				"alt" => KeyModifiers::ALT,
+				"shift" => KeyModifiers::SHIFT,
+				_ => return Err(format!("invalid modifier '{m}' in key '{s}'")),
+			};
+		}
+
+		let mut chars = key.chars();
+		let code = match (chars.next(), chars.next()) {
+			(Some(c), None) => KeyCode::Char(c),
+			_ => {
+				match key.to_ascii_lowercase().as_str() {
+					"esc" => KeyCode::Esc,
+					"enter" => KeyCode::Enter,
+					"tab" => KeyCode::Tab,
+					"backtab" => KeyCode::BackTab,
+					"backspace" => KeyCode::Backspace,
+					"space" => KeyCode::Char(' '),
+					"up" => KeyCode::Up,
+					"down" => KeyCode::Down,
+					"left" => KeyCode::Left,
+					"right" => KeyCode::Right,
+					"home" => KeyCode::Home,
+					"end" => KeyCode::End,
+					"pageup" => KeyCode::PageUp,
+					"pagedown" => KeyCode::PageDown,
+					f => {
+						f.strip_prefix('f')
+							.and_then(|n| n.parse().ok())
+							.map(KeyCode::F)
+							.ok_or_else(|| format!("invalid key '{s}'"))?
+					}
+				}
+			}
+		};
+
+		Ok(Self::new(code, modifiers).normalized())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse(s: &str) -> KeyBinding {
+		KeyBinding::try_from(s.to_string()).unwrap()
+	}
+
+	fn event(code: KeyCode, modifiers: KeyModifiers) -> KeyEvent {
+		KeyEvent::new(code, modifiers)
+	}
+
+	#[test]
+	fn bindings_are_parsed() {
+		assert_eq!(
+			parse("q"),
+			KeyBinding::new(KeyCode::Char('q'), KeyModifiers::NONE)
+		);
+		assert_eq!(
+			parse("-"),
+			KeyBinding::new(KeyCode::Char('-'), KeyModifiers::NONE)
+		);
+		assert_eq!(
+			parse("ctrl--"),
+			KeyBinding::new(KeyCode::Char('-'), KeyModifiers::CONTROL)
+		);
+		assert_eq!(
+			parse("space"),
+			KeyBinding::new(KeyCode::Char(' '), KeyModifiers::NONE)
+		);
+		assert_eq!(
+			parse("F5"),
+			KeyBinding::new(KeyCode::F(5), KeyModifiers::NONE)
+		);
+		assert_eq!(
+			parse("Ctrl-Alt-PageUp"),
+			KeyBinding::new(KeyCode::PageUp, KeyModifiers::CONTROL | KeyModifiers::ALT)
+		);
+		assert_eq!(
+			parse("shift-up"),
+			KeyBinding::new(KeyCode::Up, KeyModifiers::SHIFT)
+		);
+
+		for invalid in ["", "ctrl-", "meta-q", "fx", "bogus"] {
+			assert!(
+				KeyBinding::try_from(invalid.to_string()).is_err(),
+				"{invalid:?}"
+			);
+		}
+	}
+
+	#[test]
+	fn shift_is_folded_into_the_key() {
+		assert_eq!(parse("shift-n"), parse("N"));
+		assert_eq!(parse("shift-N"), parse("N"));
+		assert_eq!(
+			parse("ctrl-shift-n"),
+			KeyBinding::new(KeyCode::Char('N'), KeyModifiers::CONTROL)
+		);
+		assert_eq!(
+			parse("shift-tab"),
+			KeyBinding::new(KeyCode::BackTab, KeyModifiers::NONE)
+		);
+		assert_eq!(parse("shift-tab"), parse("backtab"));
+	}
+
+	#[test]
+	fn bindings_are_written_as_parsed() {
+		for s in [
+			"q",
+			"N",
+			"esc",
+			"ctrl-c",
+			"ctrl-alt-pageup",
+			"shift-up",
+			"shift-tab",
+			"f12",
+			"space",
+		] {
+			assert_eq!(parse(s).to_string(), s);
+			assert_eq!(parse(&parse(s).to_string()), parse(s));
+		}
+	}
+
+	#[test]
+	fn bindings_match_key_events() {
+		let n = parse("shift-n");
+		assert!(n.matches(&event(KeyCode::Char('N'), KeyModifiers::SHIFT)));
+		assert!(n.matches(&event(KeyCode::Char('N'), KeyModifiers::NONE)));
+		assert!(!n.matches(&event(KeyCode::Char('n'), KeyModifiers::NONE)));
+		assert!(!n.matches(&event(KeyCode::Char('N'), KeyModifiers::CONTROL)));
+
+		let back_tab = parse("shift-tab");
+		assert!(back_tab.matches(&event(KeyCode::BackTab, KeyModifiers::SHIFT)));
+		assert!(back_tab.matches(&event(KeyCode::Tab, KeyModifiers::SHIFT)));
+		assert!(!back_tab.matches(&event(KeyCode::Tab, KeyModifiers::NONE)));
+
+		let ctrl_c = parse("ctrl-c");
+		assert!(ctrl_c.matches(&event(KeyCode::Char('c'), KeyModifiers::CONTROL)));
+		assert!(!ctrl_c.matches(&event(KeyCode::Char('c'), KeyModifiers::NONE)));
+
+		let shift_up = parse("shift-up");
+		assert!(shift_up.matches(&event(KeyCode::Up, KeyModifiers::SHIFT)));
+		assert!(!shift_up.matches(&event(KeyCode::Up, KeyModifiers::NONE)));
+	}
+}
@@ -1,16 +1,22 @@
 use std::{
 	io::Read,
+	path::PathBuf,
 	sync::{Arc, Condvar, Mutex, atomic::Ordering::Relaxed},
 	time::Duration,
 };
 
 use app_state::AppState;
 use clap::Parser;
-use crossterm::event::{self, Event};
-use ktrace_protocol::{Packet, TraceFilter};
-use query_client::OobStream;
+use command::Command;
+use config::{Config, Filter};
+use crossterm::event::{self, Event, KeyEventKind};
+use ktrace_protocol::{Packet, STREAM_CLEARED};
+use query_client::{Client, OobStream};
+use view::trace_log::{Pane, Prompt};
 
 pub mod app_state;
+pub mod command;
+pub mod config;
 pub mod query_client;
 pub mod symbol_resolver;
 pub mod view;
@@ -32,12 +38,87 @@ fn wait_for_invalidation() {
 	*lock = false;
 }
 
+/// Follows the live tail of the trace in the given pane, forever.
+/// Reopens the stream whenever it ends (e.g. when switching threads).
+fn follow_trace(app_state: &AppState, client: &Client, session: Option<String>, pane: Pane) -> ! {
+	loop {
+		let Ok(mut stream) = app_state.open_stream(client, session.clone(), pane) else {
+			std::thread::sleep(Duration::from_millis(100));
+			continue;
+		};
+
+		{
+			app_state.addresses(pane).lock().unwrap().clear();
+		}
+
+		let mut buf = vec![0u8; 1024 * 1024 * 2];
+		let mut leftover = 0;
+
+		while let Ok(nread) = stream.read(&mut buf[leftover..]) {
+			if nread == 0 {
+				break;
+			}
+
+			let total = nread + leftover;
+			let addr_slice = unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u64, total / 8) };
+			{
+				let mut addrs = app_state.addresses(pane).lock().unwrap();
+				// Only what follows the last clear is still part of the trace.
+				let addr_slice = match addr_slice.iter().rposition(|&addr| addr == STREAM_CLEARED) {
+					Some(i) => {
+						addrs.clear();
+						&addr_slice[i + 1..]
+					}
+					None => addr_slice,
+				};
+				let push_base = addr_slice.len().saturating_sub(addrs.capacity());
+				addrs.extend_from_slice(&addr_slice[push_base..]);
+			}
+
+			leftover = total & 7;
+			if leftover != 0 {
+				let leftover_base = total - leftover;
+				buf.copy_within(leftover_base..total, 0);
+			}
+
+			invalidate();
+		}
+	}
+}
+
+/// Reflects the query connection's state in the app state.
+struct StateOobStream(Arc<AppState>);
+
+impl OobStream for StateOobStream {
+	fn on_connected(&self) {
+		self.0.daemon_connected.set(true);
+		invalidate();
+	}
+
+	fn on_disconnected(&self) {
+		self.0.daemon_connected.set(false);
+		invalidate();
+	}
+}
+
 /// Starts the ktrace TUI frontend.
+///
+/// Options not given on the command line are read from the config file,
+/// `$XDG_CONFIG_HOME/ktrace/config.toml` by default.
 #[derive(Parser)]
 struct Args {
-	/// The socket path to connect to.
-	#[clap(short = 's', long = "sock", default_value = ktrace_protocol::DEFAULT_SOCKET_PATH)]
-	sock_path: String,
+	/// The config file to read (instead of the default one).
+	#[clap(short = 'c', long = "config")]
+	config:    Option<PathBuf>,
+	/// The socket path to connect to [default: /tmp/ktrace-query.sock].
+	#[clap(short = 's', long = "sock")]
+	sock_path: Option<String>,
+	/// The session to show, if several are recorded at once [default: the most recently started one].
+	#[clap(short = 'S', long = "session")]
+	session:   Option<String>,
+	/// The pre-filter applied to the right-hand trace log [default: lower-half].
+	#[clap(short = 'f', long = "filter", value_enum)]
+	filter:    Option<Filter>,
 	/// The binaries to load. *Order matters*; symbols are resolved based on first-hit.
 	/// If none are provided, only addresses are shown.
 	binaries:  Vec<String>,
@@ -46,15 +127,33 @@ struct Args {
 fn main() {
 	let args = Args::parse();
 
+	let Config { sockets, client } = ktrace_config::load(args.config.as_deref()).unwrap_or_else(|err| {
+		eprintln!("ktrace: {err}");
+		std::process::exit(1);
+	});
+
+	let sock_path = args
+		.sock_path
+		.or(sockets.query)
+		.unwrap_or_else(|| ktrace_protocol::DEFAULT_SOCKET_PATH.to_string());
+	let session = args.session.or(client.session);
+	let filter = args.filter.or(client.filter).unwrap_or(Filter::LowerHalf);
+	let binaries = if args.binaries.is_empty() {
+		client.binaries
+	} else {
+		args.binaries
+	};
+	let keys = client.keys;
+
 	let mut terminal = ratatui::init();
 
 	let resolver_client = symbol_resolver::run();
 
-	for binary in args.binaries {
+	for binary in binaries {
 		resolver_client.add_binary(binary);
 	}
 
-	let app_state = Arc::new(AppState::new(resolver_client));
+	let app_state = Arc::new(AppState::new(resolver_client, filter));
 
 	std::thread::spawn(|| {
 		loop {
@@ -64,128 +163,40 @@ fn main() {
 		}
 	});
 
+	let client = Arc::new(query_client::run(
+		sock_path,
+		StateOobStream(app_state.clone()),
+	));
+
+	for pane in [Pane::All, Pane::Filtered] {
+		std::thread::spawn({
+			let app_state = app_state.clone();
+			let client = client.clone();
+			let session = session.clone();
+			move || follow_trace(&app_state, &client, session, pane)
+		});
+	}
+
 	std::thread::spawn({
 		let app_state = app_state.clone();
-		let sock_path = args.sock_path.clone();
+		let client = client.clone();
+		let session = session.clone();
 		move || {
-			struct StateOobStream(Arc<AppState>);
-
-			impl OobStream for StateOobStream {
-				fn on_connected(&self) {
-					self.0.daemon_connected.set(true);
-					invalidate();
-				}
-
-				fn on_disconnected(&self) {
-					self.0.daemon_connected.set(false);
-					invalidate();
-				}
-			}
-
-			let client = Arc::new(query_client::run(
-				sock_path,
-				StateOobStream(app_state.clone()),
-			));
-
-			std::thread::spawn({
-				let app_state = app_state.clone();
-				let client = client.clone();
-
-				move || {
-					loop {
-						let Ok(mut stream) = client.open_stream(0, None) else {
-							std::thread::sleep(Duration::from_millis(100));
-							continue;
-						};
-
-						{
-							app_state.last_addresses.lock().unwrap().clear();
-						}
-
-						let mut buf = Box::new([0u8; 1024 * 1024 * 2]);
-						let mut leftover = 0;
-
-						while let Ok(nread) = stream.read(&mut buf[leftover..]) {
-							if nread == 0 {
-								break;
-							}
-
-							let total = nread + leftover;
-							let addr_slice =
-								unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u64, total / 8) };
-							{
-								let mut addrs = app_state.last_addresses.lock().unwrap();
-								let push_base = addr_slice.len().saturating_sub(addrs.capacity());
-								addrs.extend_from_slice(&addr_slice[push_base..]);
-							}
-
-							leftover = total & 7;
-							if leftover != 0 {
-								let leftover_base = total - leftover;
-								buf.copy_within(leftover_base..total, 0);
-							}
-
-							invalidate();
-						}
-					}
-				}
-			});
-
-			std::thread::spawn({
-				let app_state = app_state.clone();
-				let client = client.clone();
-
-				move || {
-					loop {
-						let Ok(mut stream) = client.open_stream(0, Some(TraceFilter::LowerHalf)) else {
-							std::thread::sleep(Duration::from_millis(100));
-							continue;
-						};
-
-						{
-							app_state.last_addresses.lock().unwrap().clear();
-						}
-
-						let mut buf = Box::new([0u8; 1024 * 1024 * 2]);
-						let mut leftover = 0;
-
-						while let Ok(nread) = stream.read(&mut buf[leftover..]) {
-							if nread == 0 {
-								break;
-							}
-
-							let total = nread + leftover;
-							let addr_slice =
-								unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u64, total / 8) };
-							{
-								let mut addrs = app_state.last_lower_addresses.lock().unwrap();
-								let push_base = addr_slice.len().saturating_sub(addrs.capacity());
-								addrs.extend_from_slice(&addr_slice[push_base..]);
-							}
-
-							leftover = total & 7;
-							if leftover != 0 {
-								let leftover_base = total - leftover;
-								buf.copy_within(leftover_base..total, 0);
-							}
-
-							invalidate();
-						}
-					}
-				}
-			});
-
 			loop {
 				let mut should_invalidate = false;
 
-				if let Some(Packet::InstCount { count }) =
-					client.request(Packet::GetInstCount { thread_id: 0 })
-				{
+				if let Some(Packet::InstCount { count }) = client.request(Packet::GetInstCount {
+					session:   session.clone(),
+					thread_id: app_state.thread_id(),
+				}) {
 					app_state.instruction_count.store(count, Relaxed);
 					should_invalidate = true;
 				}
 
-				if let Some(Packet::Status { status }) = client.request(Packet::GetStatus { thread_id: 0 }) {
+				if let Some(Packet::Status { status }) = client.request(Packet::GetStatus {
+					session:   session.clone(),
+					thread_id: app_state.thread_id(),
+				}) {
 					app_state.thread_status.store(status as usize, Relaxed);
 					should_invalidate = true;
 				}
@@ -199,17 +210,54 @@ fn main() {
 		}
 	});
 
+	let mut trace_log = view::trace_log::TraceLogView::new(app_state, client, session);
+	let mut show_help = false;
+
 	loop {
 		let ev = event::poll(Duration::from_millis(1))
 			.unwrap_or_default()
 			.then(|| event::read().unwrap());
 
-		if matches!(ev, Some(Event::Key(_))) {
-			break;
+		if let Some(Event::Key(key)) = ev {
+			if key.kind != KeyEventKind::Press {
+				// Only presses run commands.
+			} else if trace_log.is_prompting() {
+				trace_log.prompt_key(&key);
+			} else if show_help {
+				// Any key closes the help.
+				show_help = false;
+			} else if let Some(command) = Command::for_key(&keys, &key) {
+				trace_log.clear_message();
+
+				match command {
+					Command::Quit => break,
+					Command::Help => show_help = true,
+					Command::Pause => trace_log.toggle_pause(),
+					Command::Up => trace_log.move_by(-1),
+					Command::Down => trace_log.move_by(1),
+					Command::PageUp => trace_log.page_up(),
+					Command::PageDown => trace_log.page_down(),
+					Command::Home => trace_log.home(),
+					Command::End => trace_log.end(),
+					Command::Jump => trace_log.prompt(Prompt::Jump),
+					Command::Search => trace_log.prompt(Prompt::Search),
+					Command::SearchNext => trace_log.search_next(),
+					Command::SearchPrev => trace_log.search_prev(),
+					Command::NextThread => trace_log.switch_thread(1),
+					Command::PrevThread => trace_log.switch_thread(-1),
+					Command::Filter => trace_log.prompt(Prompt::Filter),
+					Command::Focus => trace_log.toggle_focus(),
+				}
+			}
 		}
 
 		terminal
-			.draw(|f| view::trace_log::draw(f, &app_state))
+			.draw(|f| {
+				view::trace_log::draw(f, &mut trace_log);
+				if show_help {
+					f.render_widget(widget::help::Help(&keys), f.area());
+				}
+			})
 			.expect("failed to draw frame");
 
 		wait_for_invalidation();
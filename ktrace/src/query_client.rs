@@ -1,5 +1,5 @@
 use std::{
-	io::{self, BufReader, Read},
+	io::{self, BufReader},
 	os::unix::net::UnixStream,
 	sync::{Arc, OnceLock, mpsc::Sender},
 	time::Duration,
@@ -33,12 +33,21 @@ impl Client {
 				res: res.clone(),
 			})
 			.expect("failed to send request");
-		res.wait().clone().take()
+		res.wait().clone()
 	}
 
-	pub fn open_stream(&self, thread_id: u32, filter: Option<TraceFilter>) -> io::Result<impl Read> {
+	pub fn open_stream(
+		&self,
+		session: Option<String>,
+		thread_id: u32,
+		filter: Option<TraceFilter>,
+	) -> io::Result<BufReader<UnixStream>> {
 		let mut stream = UnixStream::connect(&self.socket_path)?;
-		stream.serialize_packet(&Packet::OpenStream { thread_id, filter })?;
+		stream.serialize_packet(&Packet::OpenStream {
+			session,
+			thread_id,
+			filter,
+		})?;
 		Ok(BufReader::with_capacity(1024 * 1024 * 128, stream))
 	}
 }
@@ -68,7 +77,7 @@ pub fn run<S: OobStream + Send + 'static>(sock_path: String, oob_stream: S) -> C
 			loop {
 				let req: Request = receiver.recv().expect("failed to receive request");
 
-				if let Err(_) = stream.serialize_packet(&req.req) {
+				if stream.serialize_packet(&req.req).is_err() {
 					req.res.set(None).unwrap();
 					break;
 				}
@@ -11,8 +11,9 @@ async fn run_async(mut receiver: UnboundedReceiver<ResolverRequest>) {
 
 	let mut symbol_maps = vec![];
 
-	loop {
-		match receiver.recv().await.expect("failed to receive request") {
+	// Runs until the client is dropped.
+	while let Some(request) = receiver.recv().await {
+		match request {
 			ResolverRequest::AddBinary { path } => {
 				symbol_maps.push(
 					symbol_library
@@ -30,7 +31,7 @@ async fn run_async(mut receiver: UnboundedReceiver<ResolverRequest>) {
 						entry.sym_addr = Some(u64::from(sym.symbol.address));
 						if let Some((f, l)) = sym
 							.frames
-							.and_then(|f| f.get(0).cloned())
+							.and_then(|f| f.first().cloned())
 							.map(|f| (f.file_path, f.line_number))
 						{
 							entry.file = f.map(|p| p.display_path());
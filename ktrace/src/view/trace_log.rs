@@ -1,50 +1,1087 @@
-use std::sync::Arc;
+use std::sync::{
+	Arc,
+	atomic::Ordering::Relaxed,
+	mpsc::{self, Receiver, Sender},
+};
 
+use clap::ValueEnum;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use ktrace_protocol::{AddrRange, InstEntry, Packet, SearchOp, TraceFilter};
 use ratatui::{
 	Frame,
 	layout::{
-		Constraint,
+		Alignment, Constraint,
 		Direction::{Horizontal, Vertical},
-		Layout,
+		Layout, Rect,
 	},
-	widgets::{Block, Borders},
+	style::{Color, Style},
+	text::{Line, Span},
+	widgets::{Block, Borders, Paragraph},
 };
 
-use crate::{app_state::AppState, symbol_resolver::Symbol, widget};
+use crate::{app_state::AppState, config::Filter, query_client::Client, symbol_resolver::Symbol, widget};
 
 pub trait TraceLogState {
 	fn get_last_addresses(&self) -> Vec<Symbol>;
 	fn get_last_lower_addresses(&self) -> Vec<Symbol>;
 }
 
-pub fn draw(frame: &mut Frame, state: &Arc<AppState>) {
+/// One of the two trace logs, side by side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pane {
+	/// The left-hand log, showing every instruction.
+	All,
+	/// The right-hand log, showing the instructions that pass the filter.
+	Filtered,
+}
+
+/// What the status bar prompt asks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Prompt {
+	/// An instruction index to move to.
+	Jump,
+	/// An address (in hex) to search for.
+	Search,
+	/// The right-hand log's filter, by name.
+	Filter,
+}
+
+/// The trace log view, which follows the live tail of the trace
+/// until it's paused to scroll back through it.
+///
+/// Requests to the daemon are sent by a worker thread (see [`run_worker`]), so that
+/// neither drawing nor handling key presses waits on the daemon. Their responses are
+/// applied the next time the view is drawn.
+pub struct TraceLogView {
+	state:         Arc<AppState>,
+	session:       Option<String>,
+	jobs:          Sender<Job>,
+	replies:       Receiver<Reply>,
+	/// The `(cursor, top, height)` and instruction count of the fetch sent to the
+	/// worker, until its entries arrive.
+	pending_fetch: Option<((usize, usize, usize), usize)>,
+	/// The number of rows the trace logs were last drawn with.
+	height:        usize,
+	paused:        Option<Paused>,
+	/// The open prompt, and what's been typed into it so far.
+	prompt:        Option<(Prompt, String)>,
+	/// The pane that cursor movement steps through.
+	focus:         Pane,
+	/// The addresses last searched for.
+	search:        Option<AddrRange>,
+	/// Shown in the status bar until the next command.
+	message:       Option<String>,
+}
+
+/// The position and contents of a paused trace log.
+struct Paused {
+	/// The index of the selected instruction.
+	cursor:        usize,
+	/// The index of the first instruction shown in the left-hand log.
+	top:           usize,
+	/// The `(cursor, top, height)` the entries were fetched for.
+	fetched_for:   Option<(usize, usize, usize)>,
+	/// The instruction count when the entries were fetched.
+	fetched_count: usize,
+	/// The instructions shown in the left-hand log, starting at `top`.
+	all:           Vec<InstEntry>,
+	/// The filtered instructions shown in the right-hand log, up to the cursor.
+	filtered:      Vec<InstEntry>,
+}
+
+/// Requests for the worker to send on the view's behalf.
+struct Job {
+	/// The thread shown when the requests were made.
+	thread_id: u32,
+	action:    Action,
+	requests:  Vec<Packet>,
+}
+
+/// The responses to a [`Job`]'s requests, in order, or `None` for those that failed.
+struct Reply {
+	thread_id: u32,
+	action:    Action,
+	responses: Vec<Option<Packet>>,
+}
+
+/// What the view does with a job's responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+	/// Shows the paused logs' entries: the left-hand log's, then the right-hand log's.
+	/// They were fetched for `(cursor, top, height)`, with `count` instructions recorded.
+	Fetch {
+		key:   (usize, usize, usize),
+		count: usize,
+	},
+	/// Moves to the first (or last) hit of a search, saying if there's none unless `quiet`.
+	MoveToHit { last: bool, quiet: bool },
+	/// Shows the thread `delta` threads after the shown one, among the listed sessions'.
+	SwitchThread { delta: isize },
+}
+
+impl TraceLogView {
+	pub fn new(state: Arc<AppState>, client: Arc<Client>, session: Option<String>) -> Self {
+		let (jobs, job_recv) = mpsc::channel();
+		let (reply_send, replies) = mpsc::channel();
+		std::thread::spawn({
+			let state = state.clone();
+			move || run_worker(&state, &client, &job_recv, &reply_send)
+		});
+
+		Self::with_worker(state, session, jobs, replies)
+	}
+
+	/// Creates the view with the channels to and from its worker.
+	fn with_worker(
+		state: Arc<AppState>,
+		session: Option<String>,
+		jobs: Sender<Job>,
+		replies: Receiver<Reply>,
+	) -> Self {
+		Self {
+			state,
+			session,
+			jobs,
+			replies,
+			pending_fetch: None,
+			height: 0,
+			paused: None,
+			prompt: None,
+			focus: Pane::All,
+			search: None,
+			message: None,
+		}
+	}
+
+	/// Pauses at the last instruction, or resumes following the live tail.
+	pub fn toggle_pause(&mut self) {
+		if self.paused.take().is_none() {
+			self.move_to(usize::MAX);
+		}
+	}
+
+	/// Moves the cursor by `delta` instructions of the focused pane,
+	/// pausing first if need be.
+	pub fn move_by(&mut self, delta: isize) {
+		if let Some(filter) = self.focused_filter() {
+			let limit = delta.unsigned_abs();
+			if delta < 0 {
+				let op = SearchOp::Prev {
+					before: self.search_start(),
+				};
+				self.find(
+					filter.range(),
+					op,
+					limit,
+					Action::MoveToHit {
+						last:  false,
+						quiet: true,
+					},
+				);
+			} else if let Some(paused) = &self.paused {
+				let op = SearchOp::Next {
+					after: paused.cursor,
+				};
+				self.find(
+					filter.range(),
+					op,
+					limit,
+					Action::MoveToHit {
+						last:  true,
+						quiet: true,
+					},
+				);
+			}
+
+			return;
+		}
+
+		let Some(cursor) = self
+			.paused
+			.as_ref()
+			.map(|p| p.cursor)
+			.or_else(|| self.last_index())
+		else {
+			return;
+		};
+
+		self.move_to(cursor.saturating_add_signed(delta));
+	}
+
+	pub fn page_up(&mut self) {
+		self.move_by(-(self.page_size() as isize));
+	}
+
+	pub fn page_down(&mut self) {
+		self.move_by(self.page_size() as isize);
+	}
+
+	/// Moves to the first instruction of the focused pane.
+	pub fn home(&mut self) {
+		match self.focused_filter() {
+			Some(filter) => self.move_to_hit(filter.range(), SearchOp::First),
+			None => self.move_to(0),
+		}
+	}
+
+	/// Moves to the last instruction of the focused pane.
+	pub fn end(&mut self) {
+		match self.focused_filter() {
+			Some(filter) => self.move_to_hit(filter.range(), SearchOp::Last),
+			None => self.move_to(usize::MAX),
+		}
+	}
+
+	/// Moves the cursor to the given instruction index (or the last instruction,
+	/// if past it), pausing first if need be. Does nothing if the trace is empty.
+	pub fn move_to(&mut self, index: usize) {
+		let Some(last) = self.last_index() else {
+			return;
+		};
+
+		let cursor = index.min(last);
+		let height = self.page_size();
+
+		let paused = self.paused.get_or_insert_with(|| {
+			Paused {
+				cursor,
+				top: (cursor + 1).saturating_sub(height),
+				fetched_for: None,
+				fetched_count: 0,
+				all: Vec::new(),
+				filtered: Vec::new(),
+			}
+		});
+
+		paused.cursor = cursor;
+		if cursor < paused.top {
+			paused.top = cursor;
+		} else if cursor >= paused.top + height {
+			paused.top = cursor + 1 - height;
+		}
+	}
+
+	/// Moves to the next occurrence of the addresses last searched for.
+	pub fn search_next(&mut self) {
+		let Some(range) = self.last_search() else {
+			return;
+		};
+
+		match self.paused.as_ref().map(|paused| paused.cursor) {
+			Some(cursor) => self.move_to_hit(range, SearchOp::Next { after: cursor }),
+			// Nothing follows the live tail.
+			None => self.message = Some("not found".into()),
+		}
+	}
+
+	/// Moves to the previous occurrence of the addresses last searched for.
+	pub fn search_prev(&mut self) {
+		let Some(range) = self.last_search() else {
+			return;
+		};
+
+		self.move_to_hit(
+			range,
+			SearchOp::Prev {
+				before: self.search_start(),
+			},
+		);
+	}
+
+	/// Shows the next (or, if `delta` is negative, a previous) thread of the session,
+	/// following its live tail, once the session's threads are listed.
+	pub fn switch_thread(&mut self, delta: isize) {
+		self.send(Action::SwitchThread { delta }, vec![Packet::ListSessions]);
+	}
+
+	fn switched_thread(&mut self, delta: isize, response: Option<Packet>) {
+		let Some(Packet::Sessions { sessions }) = response else {
+			self.message = Some("failed to list threads".into());
+			return;
+		};
+
+		let session = match &self.session {
+			Some(name) => sessions.into_iter().find(|session| &session.name == name),
+			// Sessions are listed oldest first.
+			None => sessions.into_iter().next_back(),
+		};
+
+		let Some(threads) = session
+			.map(|session| session.threads)
+			.filter(|threads| !threads.is_empty())
+		else {
+			self.message = Some("no threads".into());
+			return;
+		};
+
+		let next = match threads.iter().position(|&id| id == self.state.thread_id()) {
+			Some(i) => threads[(i as isize + delta).rem_euclid(threads.len() as isize) as usize],
+			None => threads[0],
+		};
+
+		self.state.set_thread_id(next);
+		self.state.instruction_count.store(0, Relaxed);
+		self.paused = None;
+		self.pending_fetch = None;
+		self.message = Some(format!("showing thread {next}"));
+	}
+
+	/// Switches which pane cursor movement steps through.
+	pub fn toggle_focus(&mut self) {
+		self.focus = match self.focus {
+			Pane::All => Pane::Filtered,
+			Pane::Filtered => Pane::All,
+		};
+	}
+
+	/// Opens a prompt in the status bar.
+	pub fn prompt(&mut self, prompt: Prompt) {
+		let input = match prompt {
+			Prompt::Filter => {
+				self.state
+					.filter()
+					.to_possible_value()
+					.map(|value| value.get_name().to_string())
+					.unwrap_or_default()
+			}
+			Prompt::Jump | Prompt::Search => String::new(),
+		};
+
+		self.prompt = Some((prompt, input));
+	}
+
+	/// Returns whether or not a prompt is open, in which case
+	/// key presses should go to [`Self::prompt_key`].
+	pub fn is_prompting(&self) -> bool {
+		self.prompt.is_some()
+	}
+
+	/// Handles a key press while a prompt is open.
+	pub fn prompt_key(&mut self, key: &KeyEvent) {
+		let Some((_, input)) = &mut self.prompt else {
+			return;
+		};
+
+		match key.code {
+			KeyCode::Char(c) if !key.modifiers.contains(KeyModifiers::CONTROL) => input.push(c),
+			KeyCode::Backspace => {
+				input.pop();
+			}
+			KeyCode::Enter => {
+				let (prompt, input) = self.prompt.take().unwrap();
+				self.message = None;
+				self.submit(prompt, input.trim());
+			}
+			KeyCode::Esc => self.prompt = None,
+			_ => {}
+		}
+	}
+
+	/// Clears the status bar message.
+	pub fn clear_message(&mut self) {
+		self.message = None;
+	}
+
+	fn submit(&mut self, prompt: Prompt, input: &str) {
+		match prompt {
+			Prompt::Jump => {
+				match input.parse() {
+					Ok(index) => self.move_to(index),
+					Err(_) => self.message = Some(format!("invalid index '{input}'")),
+				}
+			}
+			Prompt::Search => {
+				let hex = input
+					.strip_prefix("0x")
+					.or_else(|| input.strip_prefix("0X"))
+					.unwrap_or(input);
+
+				let Ok(addr) = u64::from_str_radix(hex, 16) else {
+					self.message = Some(format!("invalid address '{input}'"));
+					return;
+				};
+
+				let range = AddrRange::single(addr);
+				self.search = Some(range);
+				// The closest occurrence before the cursor (or the last one, while following).
+				self.move_to_hit(
+					range,
+					SearchOp::Prev {
+						before: self.search_start(),
+					},
+				);
+			}
+			Prompt::Filter => {
+				match Filter::from_str(input, true) {
+					Ok(filter) => {
+						self.state.set_filter(filter);
+						if let Some(paused) = &mut self.paused {
+							paused.fetched_for = None;
+						}
+					}
+					Err(_) => self.message = Some(format!("unknown filter '{input}'")),
+				}
+			}
+		}
+	}
+
+	/// Returns the filter of the focused pane, if it only shows some instructions.
+	fn focused_filter(&self) -> Option<TraceFilter> {
+		match self.focus {
+			Pane::All => None,
+			Pane::Filtered => self.state.filter().to_trace_filter(),
+		}
+	}
+
+	fn last_search(&mut self) -> Option<AddrRange> {
+		if self.search.is_none() {
+			self.message = Some("nothing searched for yet".into());
+		}
+
+		self.search
+	}
+
+	/// Returns the index backwards searches start before: the cursor,
+	/// or (while following) just past the last instruction.
+	fn search_start(&self) -> usize {
+		match &self.paused {
+			Some(paused) => paused.cursor,
+			None => self.state.instruction_count.load(Relaxed),
+		}
+	}
+
+	/// Moves to the first hit of a search, once it's found.
+	fn move_to_hit(&mut self, range: AddrRange, op: SearchOp) {
+		self.find(
+			range,
+			op,
+			1,
+			Action::MoveToHit {
+				last:  false,
+				quiet: false,
+			},
+		);
+	}
+
+	/// Searches the shown thread's trace for the given addresses, handing the hits to `action`.
+	fn find(&self, range: AddrRange, op: SearchOp, limit: usize, action: Action) {
+		self.send(action, vec![self.search_request(range, op, limit)]);
+	}
+
+	fn search_request(&self, range: AddrRange, op: SearchOp, limit: usize) -> Packet {
+		Packet::Search {
+			session: self.session.clone(),
+			thread_id: self.state.thread_id(),
+			range,
+			op,
+			limit,
+		}
+	}
+
+	/// Has the worker send requests, for `action` to handle their responses.
+	fn send(&self, action: Action, requests: Vec<Packet>) {
+		// The worker only stops once the view is dropped.
+		let _ = self.jobs.send(Job {
+			thread_id: self.state.thread_id(),
+			action,
+			requests,
+		});
+	}
+
+	/// Applies the responses that have arrived since the view was last drawn.
+	fn receive(&mut self) {
+		while let Ok(reply) = self.replies.try_recv() {
+			if let Action::Fetch { key, count } = reply.action {
+				if self.pending_fetch == Some((key, count)) {
+					self.pending_fetch = None;
+				}
+			}
+
+			// The responses are about a thread that's no longer shown.
+			if reply.thread_id != self.state.thread_id() {
+				continue;
+			}
+
+			let mut responses = reply.responses.into_iter();
+			match reply.action {
+				Action::Fetch { key, count } => {
+					let Some(paused) = &mut self.paused else {
+						continue;
+					};
+
+					// Moved since, so a fetch of where it moved to follows.
+					if (paused.cursor, paused.top, self.height) != key {
+						continue;
+					}
+
+					if let Some(Some(Packet::Range { entries })) = responses.next() {
+						paused.all = entries;
+					}
+
+					if let Some(Some(Packet::Range { entries } | Packet::SearchResult { hits: entries })) =
+						responses.next()
+					{
+						paused.filtered = entries;
+					}
+
+					paused.fetched_for = Some(key);
+					paused.fetched_count = count;
+				}
+				Action::MoveToHit { last, quiet } => {
+					let hits = match responses.next().flatten() {
+						Some(Packet::SearchResult { hits }) => hits,
+						_ => Vec::new(),
+					};
+
+					match if last { hits.last() } else { hits.first() } {
+						Some(hit) => self.move_to(hit.index),
+						None if !quiet => self.message = Some("not found".into()),
+						None => {}
+					}
+				}
+				Action::SwitchThread { delta } => self.switched_thread(delta, responses.next().flatten()),
+			}
+		}
+	}
+
+	fn last_index(&self) -> Option<usize> {
+		self.state.instruction_count.load(Relaxed).checked_sub(1)
+	}
+
+	fn page_size(&self) -> usize {
+		self.height.max(1)
+	}
+
+	/// Has the paused trace logs' entries fetched from the daemon, if they've moved
+	/// or more instructions have since been recorded to fill them.
+	fn refresh(&mut self) {
+		let count = self.state.instruction_count.load(Relaxed);
+
+		match &self.paused {
+			None => return,
+			// The trace was cleared (e.g. a new session was started).
+			Some(_) if count == 0 => {
+				self.paused = None;
+				return;
+			}
+			Some(paused) if paused.cursor >= count => self.move_to(count - 1),
+			Some(_) => {}
+		}
+
+		let Some(paused) = &self.paused else {
+			return;
+		};
+
+		let height = self.height;
+		let key = (paused.cursor, paused.top, height);
+		let filled = paused.all.len() >= height;
+		if paused.fetched_for == Some(key) && (filled || paused.fetched_count == count) {
+			return;
+		}
+
+		// Already on its way.
+		if self.pending_fetch == Some((key, count)) {
+			return;
+		}
+
+		// Fetched again once the daemon is back.
+		if !self.state.daemon_connected.get() {
+			return;
+		}
+
+		let all = Packet::GetRange {
+			session:   self.session.clone(),
+			thread_id: self.state.thread_id(),
+			start:     paused.top,
+			count:     height,
+			filter:    None,
+		};
+
+		let filtered = match self.state.filter().to_trace_filter() {
+			Some(filter) => {
+				self.search_request(
+					filter.range(),
+					SearchOp::Prev {
+						before: paused.cursor + 1,
+					},
+					height,
+				)
+			}
+			None => {
+				let start = (paused.cursor + 1).saturating_sub(height);
+				Packet::GetRange {
+					session: self.session.clone(),
+					thread_id: self.state.thread_id(),
+					start,
+					count: paused.cursor + 1 - start,
+					filter: None,
+				}
+			}
+		};
+
+		self.pending_fetch = Some((key, count));
+		self.send(Action::Fetch { key, count }, vec![all, filtered]);
+	}
+
+	/// Returns the text shown at the right of the status bar.
+	fn status(&self) -> Line<'static> {
+		if let Some((prompt, input)) = &self.prompt {
+			let label = match prompt {
+				Prompt::Jump => "jump to",
+				Prompt::Search => "search for",
+				Prompt::Filter => "filter",
+			};
+
+			return Line::styled(
+				format!("{label}: {input}_"),
+				Style::default().fg(Color::Cyan),
+			);
+		}
+
+		let mut line = Line::default();
+
+		if let Some(message) = &self.message {
+			line.push_span(Span::styled(
+				message.clone(),
+				Style::default().fg(Color::Magenta),
+			));
+			line.push_span(" | ");
+		}
+
+		line.push_span(match &self.paused {
+			Some(paused) => {
+				Span::styled(
+					format!("paused at {}", paused.cursor),
+					Style::default().fg(Color::Yellow),
+				)
+			}
+			None => Span::styled("following", Style::default().fg(Color::Green)),
+		});
+
+		line.push_span(match self.focus {
+			Pane::All => " | focus: all",
+			Pane::Filtered => " | focus: filtered",
+		});
+
+		line
+	}
+}
+
+pub fn draw(frame: &mut Frame, view: &mut TraceLogView) {
 	let layout = Layout::default()
 		.direction(Vertical)
-		.constraints(&[Constraint::Fill(1), Constraint::Length(2)])
+		.constraints([Constraint::Fill(1), Constraint::Length(2)])
 		.split(frame.area());
 
 	let trace_layout = Layout::default()
 		.direction(Horizontal)
-		.constraints(&[
+		.constraints([
 			Constraint::Percentage(50),
 			Constraint::Length(1),
 			Constraint::Percentage(50),
 		])
 		.split(layout[0]);
 
+	view.height = usize::from(layout[0].height);
+	view.receive();
+	view.refresh();
+
+	let state = view.state.clone();
+
+	if let Some(paused) = &view.paused {
+		draw_entries(
+			frame,
+			&state,
+			&paused.all,
+			paused.cursor,
+			view.focus == Pane::All,
+			trace_layout[0],
+		);
+		draw_entries(
+			frame,
+			&state,
+			&paused.filtered,
+			paused.cursor,
+			view.focus == Pane::Filtered,
+			trace_layout[2],
+		);
+	} else {
+		frame.render_widget(
+			widget::trace_log::TraceLog::new(&state.get_last_addresses()),
+			trace_layout[0],
+		);
+		frame.render_widget(
+			widget::trace_log::TraceLog::new(&state.get_last_lower_addresses()),
+			trace_layout[2],
+		);
+	}
+
+	let status_block = Block::default().borders(Borders::TOP);
+	let status_area = status_block.inner(layout[1]);
+	frame.render_widget(&status_block, layout[1]);
+
+	// The view's status (e.g. a prompt being typed) gets the room it needs, and the
+	// status bar what's left of it.
+	let status = view.status();
+	let status_layout = Layout::default()
+		.direction(Horizontal)
+		.constraints([
+			Constraint::Fill(1),
+			Constraint::Length(status.width() as u16),
+		])
+		.spacing(1)
+		.split(status_area);
 	frame.render_widget(
-		widget::trace_log::TraceLog(&state.get_last_addresses()),
-		trace_layout[0],
+		widget::status_bar::StatusBar(state.clone()),
+		status_layout[0],
 	);
 	frame.render_widget(
-		widget::trace_log::TraceLog(&state.get_last_lower_addresses()),
-		trace_layout[2],
+		Paragraph::new(status).alignment(Alignment::Right),
+		status_layout[1],
 	);
+}
+
+/// Sends a view's requests to the daemon, redrawing once each job's responses are in.
+fn run_worker(state: &AppState, client: &Client, jobs: &Receiver<Job>, replies: &Sender<Reply>) {
+	while let Ok(job) = jobs.recv() {
+		// Only the latest fetch is still wanted, if the view moved on while others were queued.
+		let mut queued = vec![job];
+		queued.extend(jobs.try_iter());
+		let latest_fetch = queued
+			.iter()
+			.rposition(|job| matches!(job.action, Action::Fetch { .. }));
+
+		for (i, job) in queued.into_iter().enumerate() {
+			if matches!(job.action, Action::Fetch { .. }) && Some(i) != latest_fetch {
+				continue;
+			}
+
+			let responses = job
+				.requests
+				.into_iter()
+				.map(|req| {
+					// Requests are held until the daemon is back, which would hold up later jobs.
+					if !state.daemon_connected.get() {
+						return None;
+					}
+
+					client.request(req)
+				})
+				.collect();
+
+			let reply = Reply {
+				thread_id: job.thread_id,
+				action: job.action,
+				responses,
+			};
+
+			// The view was dropped.
+			if replies.send(reply).is_err() {
+				return;
+			}
+
+			crate::invalidate();
+		}
+	}
+}
+
+/// Draws fetched entries, highlighting the one at the cursor.
+fn draw_entries(
+	frame: &mut Frame,
+	state: &AppState,
+	entries: &[InstEntry],
+	cursor: usize,
+	focused: bool,
+	area: Rect,
+) {
+	let symbols = state.resolve_all(entries.iter().map(|entry| &entry.addr));
+	let indices = entries.iter().map(|entry| entry.index).collect::<Vec<_>>();
+	let selected = indices.iter().position(|&index| index == cursor);
 
-	let status_block = Block::default().borders(Borders::TOP);
-	frame.render_widget(&status_block, layout[1]);
 	frame.render_widget(
-		widget::status_bar::StatusBar(state.clone()),
-		status_block.inner(layout[1]),
+		widget::trace_log::TraceLog::new(&symbols)
+			.indices(&indices)
+			.selected(selected)
+			.focused(focused),
+		area,
 	);
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::symbol_resolver;
+
+	/// A view of a trace of `count` instructions, drawn `height` rows high, along with
+	/// the ends of its worker's channels.
+	fn view(count: usize, height: usize) -> (TraceLogView, Receiver<Job>, Sender<Reply>) {
+		let state = Arc::new(AppState::new(symbol_resolver::run(), Filter::None));
+		state.daemon_connected.set(true);
+		state.instruction_count.store(count, Relaxed);
+
+		let (jobs, job_recv) = mpsc::channel();
+		let (reply_send, replies) = mpsc::channel();
+		let mut view = TraceLogView::with_worker(state, None, jobs, replies);
+		view.height = height;
+		(view, job_recv, reply_send)
+	}
+
+	fn cursor(view: &TraceLogView) -> Option<(usize, usize)> {
+		view.paused
+			.as_ref()
+			.map(|paused| (paused.cursor, paused.top))
+	}
+
+	/// Submits `input` to a prompt, in place of what it opens with.
+	fn type_into(view: &mut TraceLogView, prompt: Prompt, input: &str) {
+		view.prompt(prompt);
+		for _ in 0..view.prompt.as_ref().unwrap().1.len() {
+			view.prompt_key(&KeyEvent::new(KeyCode::Backspace, KeyModifiers::NONE));
+		}
+		for c in input.chars() {
+			view.prompt_key(&KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE));
+		}
+		view.prompt_key(&KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE));
+	}
+
+	/// Returns the search the view asked its worker for.
+	fn searched(jobs: &Receiver<Job>) -> (AddrRange, SearchOp, Action) {
+		let job = jobs.try_recv().expect("no job was sent");
+		match job.requests.as_slice() {
+			[Packet::Search { range, op, .. }] => (*range, *op, job.action),
+			_ => panic!("expected a single search"),
+		}
+	}
+
+	fn hits(action: Action, indices: &[usize]) -> Reply {
+		Reply {
+			thread_id: 0,
+			action,
+			responses: vec![Some(Packet::SearchResult {
+				hits: indices
+					.iter()
+					.map(|&index| InstEntry { index, addr: 0 })
+					.collect(),
+			})],
+		}
+	}
+
+	#[test]
+	fn pausing_moves_to_the_last_instruction() {
+		let (mut view, ..) = view(100, 10);
+		view.toggle_pause();
+		assert_eq!(cursor(&view), Some((99, 90)));
+		view.toggle_pause();
+		assert_eq!(cursor(&view), None);
+	}
+
+	#[test]
+	fn nothing_is_paused_in_an_empty_trace() {
+		let (mut view, ..) = view(0, 10);
+		view.toggle_pause();
+		view.move_to(5);
+		assert_eq!(cursor(&view), None);
+	}
+
+	#[test]
+	fn the_cursor_is_kept_on_the_page() {
+		let (mut view, ..) = view(100, 10);
+		view.move_to(50);
+		assert_eq!(cursor(&view), Some((50, 41)));
+
+		// Within the page, the page stays put.
+		view.move_to(45);
+		assert_eq!(cursor(&view), Some((45, 41)));
+
+		// Above it, the page starts at the cursor.
+		view.move_by(-5);
+		assert_eq!(cursor(&view), Some((40, 40)));
+
+		// Below it, the page ends at the cursor.
+		view.page_down();
+		assert_eq!(cursor(&view), Some((50, 41)));
+
+		view.move_to(usize::MAX);
+		assert_eq!(cursor(&view), Some((99, 90)));
+		view.home();
+		assert_eq!(cursor(&view), Some((0, 0)));
+		view.move_by(-1);
+		assert_eq!(cursor(&view), Some((0, 0)));
+		view.end();
+		assert_eq!(cursor(&view), Some((99, 90)));
+	}
+
+	#[test]
+	fn moving_pauses_at_the_live_tail() {
+		let (mut view, ..) = view(100, 10);
+		view.move_by(-3);
+		assert_eq!(cursor(&view), Some((96, 87)));
+	}
+
+	#[test]
+	fn jumps_are_submitted() {
+		let (mut view, ..) = view(100, 10);
+		type_into(&mut view, Prompt::Jump, " 20 ");
+		assert!(!view.is_prompting());
+		assert_eq!(cursor(&view), Some((20, 11)));
+		assert_eq!(view.message, None);
+
+		type_into(&mut view, Prompt::Jump, "2x");
+		assert_eq!(cursor(&view), Some((20, 11)));
+		assert_eq!(view.message.as_deref(), Some("invalid index '2x'"));
+	}
+
+	#[test]
+	fn prompts_are_edited_and_cancelled() {
+		let (mut view, ..) = view(100, 10);
+		view.prompt(Prompt::Jump);
+		for code in [
+			KeyCode::Char('1'),
+			KeyCode::Char('2'),
+			KeyCode::Backspace,
+			KeyCode::Char('5'),
+		] {
+			view.prompt_key(&KeyEvent::new(code, KeyModifiers::NONE));
+		}
+		assert_eq!(view.prompt, Some((Prompt::Jump, "15".to_string())));
+
+		view.prompt_key(&KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE));
+		assert!(!view.is_prompting());
+		assert_eq!(cursor(&view), None);
+	}
+
+	#[test]
+	fn searches_move_to_the_hit_once_found() {
+		let (mut view, jobs, replies) = view(100, 10);
+		type_into(&mut view, Prompt::Search, "0xFFFF8000");
+
+		// Searched for before the live tail.
+		let (range, op, action) = searched(&jobs);
+		assert_eq!(range, AddrRange::single(0xFFFF_8000));
+		assert_eq!(op, SearchOp::Prev { before: 100 });
+		assert_eq!(cursor(&view), None);
+
+		replies.send(hits(action, &[30])).unwrap();
+		view.receive();
+		assert_eq!(cursor(&view), Some((30, 21)));
+
+		view.search_next();
+		let (_, op, action) = searched(&jobs);
+		assert_eq!(op, SearchOp::Next { after: 30 });
+		replies.send(hits(action, &[])).unwrap();
+		view.receive();
+		assert_eq!(cursor(&view), Some((30, 21)));
+		assert_eq!(view.message.as_deref(), Some("not found"));
+	}
+
+	#[test]
+	fn invalid_searches_are_reported() {
+		let (mut view, jobs, _) = view(100, 10);
+		view.search_next();
+		assert_eq!(view.message.as_deref(), Some("nothing searched for yet"));
+
+		type_into(&mut view, Prompt::Search, "xyz");
+		assert_eq!(view.message.as_deref(), Some("invalid address 'xyz'"));
+		assert!(jobs.try_recv().is_err());
+	}
+
+	#[test]
+	fn filters_are_submitted() {
+		let (mut view, ..) = view(100, 10);
+		// Opened with the current filter.
+		view.prompt(Prompt::Filter);
+		assert_eq!(view.prompt, Some((Prompt::Filter, "none".to_string())));
+
+		type_into(&mut view, Prompt::Filter, "lower-half");
+		assert_eq!(view.state.filter(), Filter::LowerHalf);
+
+		type_into(&mut view, Prompt::Filter, "upper");
+		assert_eq!(view.state.filter(), Filter::LowerHalf);
+		assert_eq!(view.message.as_deref(), Some("unknown filter 'upper'"));
+	}
+
+	#[test]
+	fn stale_fetches_are_ignored() {
+		let (mut view, jobs, replies) = view(100, 10);
+		view.move_to(50);
+		view.refresh();
+
+		let job = jobs.try_recv().unwrap();
+		assert_eq!(
+			job.action,
+			Action::Fetch {
+				key:   (50, 41, 10),
+				count: 100,
+			}
+		);
+		// Not asked for again while it's on its way.
+		view.refresh();
+		assert!(jobs.try_recv().is_err());
+
+		let entries = |start| {
+			Some(Packet::Range {
+				entries: vec![InstEntry {
+					index: start,
+					addr:  0,
+				}],
+			})
+		};
+
+		// Moved before the entries arrived.
+		view.move_to(60);
+		replies
+			.send(Reply {
+				thread_id: 0,
+				action:    job.action,
+				responses: vec![entries(41), entries(41)],
+			})
+			.unwrap();
+		view.receive();
+		let paused = view.paused.as_ref().unwrap();
+		assert!(paused.all.is_empty());
+		assert_eq!(paused.fetched_for, None);
+
+		view.refresh();
+		let job = jobs.try_recv().unwrap();
+		replies
+			.send(Reply {
+				thread_id: 0,
+				action:    job.action,
+				responses: vec![entries(51), None],
+			})
+			.unwrap();
+		view.receive();
+		let paused = view.paused.as_ref().unwrap();
+		assert_eq!(
+			paused.all,
+			[InstEntry {
+				index: 51,
+				addr:  0,
+			}]
+		);
+		assert!(paused.filtered.is_empty());
+		assert_eq!(paused.fetched_for, Some((60, 51, 10)));
+	}
+
+	#[test]
+	fn the_status_bar_and_prompt_share_the_status_line() {
+		let (mut view, _jobs, _replies) = view(100, 10);
+		view.prompt(Prompt::Jump);
+		for c in "42".chars() {
+			view.prompt_key(&KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE));
+		}
+
+		// Too narrow for both to fit, so the status bar is cut short.
+		let mut terminal = ratatui::Terminal::new(ratatui::backend::TestBackend::new(50, 12)).unwrap();
+		terminal.draw(|frame| draw(frame, &mut view)).unwrap();
+
+		let buffer = terminal.backend().buffer();
+		let last_line = (0..buffer.area.width)
+			.map(|x| buffer[(x, buffer.area.height - 1)].symbol())
+			.collect::<String>();
+		assert_eq!(
+			last_line,
+			"daemon: online | thread: 0 | thread s jump to: 42_"
+		);
+	}
+}
@@ -0,0 +1,59 @@
+use ratatui::{
+	buffer::Buffer,
+	layout::Rect,
+	style::{Color, Style},
+	text::{Line, Span},
+	widgets::{Block, Clear, Padding, Paragraph, Widget},
+};
+
+use crate::{command::Command, config::Keys};
+
+/// A popup listing each command and the keys bound to it.
+pub struct Help<'a>(pub &'a Keys);
+
+const KEY_STYLE: Style = Style::new().fg(Color::Yellow);
+
+impl Widget for Help<'_> {
+	fn render(self, area: Rect, buf: &mut Buffer) {
+		let rows = Command::ALL.map(|command| {
+			let keys = command
+				.bindings(self.0)
+				.iter()
+				.map(ToString::to_string)
+				.collect::<Vec<_>>()
+				.join(", ");
+			(keys, command.description())
+		});
+
+		let key_width = rows.iter().map(|(keys, _)| keys.len()).max().unwrap_or(0);
+		let description_width = rows.iter().map(|(_, desc)| desc.len()).max().unwrap_or(0);
+
+		// The borders and padding take up 4 columns and 2 rows.
+		let width = ((key_width + 2 + description_width + 4) as u16).min(area.width);
+		let height = ((rows.len() + 2) as u16).min(area.height);
+		let popup = Rect {
+			x: area.x + (area.width - width) / 2,
+			y: area.y + (area.height - height) / 2,
+			width,
+			height,
+		};
+
+		let block = Block::bordered()
+			.title(" keys (press any key to close) ")
+			.padding(Padding::horizontal(1));
+
+		Clear.render(popup, buf);
+		Paragraph::new(
+			rows.into_iter()
+				.map(|(keys, description)| {
+					Line::from_iter([
+						Span::styled(format!("{keys:<key_width$}  "), KEY_STYLE),
+						Span::raw(description),
+					])
+				})
+				.collect::<Vec<_>>(),
+		)
+		.block(block)
+		.render(popup, buf);
+	}
+}
@@ -1,2 +1,3 @@
+pub mod help;
 pub mod status_bar;
 pub mod trace_log;
@@ -13,6 +13,7 @@ pub struct StatusBar<S>(pub Arc<S>);
 
 pub trait StatusBarState {
 	fn is_connected(&self) -> bool;
+	fn thread_id(&self) -> u32;
 	fn thread_status(&self) -> ThreadStatus;
 	fn instruction_count(&self) -> usize;
 }
@@ -31,6 +32,11 @@ impl<S: StatusBarState> Widget for StatusBar<S> {
 					Style::default().fg(Color::Red).slow_blink()
 				},
 			),
+			Span::from(" | thread: "),
+			Span::styled(
+				format!("{}", self.0.thread_id()),
+				Style::default().fg(Color::Cyan),
+			),
 			Span::from(" | thread status: "),
 			match self.0.thread_status() {
 				ThreadStatus::Idle => Span::styled("idle", Style::default().fg(Color::Yellow)),
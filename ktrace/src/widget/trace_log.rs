@@ -1,33 +1,79 @@
 use ratatui::{
 	buffer::Buffer,
 	layout::Rect,
-	style::{Color, Style},
+	style::{Color, Modifier, Style},
 	text::{Span, Text},
 	widgets::{List, ListItem, Widget},
 };
 
 use crate::symbol_resolver::Symbol;
 
-pub struct TraceLog<'a>(pub &'a Vec<Symbol>);
+/// A list of instructions, showing as many of the last ones as fit.
+pub struct TraceLog<'a> {
+	symbols:  &'a [Symbol],
+	/// The instructions' indices in the trace, shown alongside them if known.
+	indices:  Option<&'a [usize]>,
+	/// The position (in `symbols`) of the instruction to highlight.
+	selected: Option<usize>,
+	/// Whether the highlighted instruction is in the focused log.
+	focused:  bool,
+}
+
+impl<'a> TraceLog<'a> {
+	pub fn new(symbols: &'a [Symbol]) -> Self {
+		Self {
+			symbols,
+			indices: None,
+			selected: None,
+			focused: true,
+		}
+	}
+
+	pub fn indices(mut self, indices: &'a [usize]) -> Self {
+		self.indices = Some(indices);
+		self
+	}
+
+	pub fn selected(mut self, selected: Option<usize>) -> Self {
+		self.selected = selected;
+		self
+	}
 
+	pub fn focused(mut self, focused: bool) -> Self {
+		self.focused = focused;
+		self
+	}
+}
+
+const INDEX_STYLE: Style = Style::new().fg(Color::DarkGray);
+const SELECTED_STYLE: Style = Style::new().add_modifier(Modifier::REVERSED);
+const UNFOCUSED_SELECTED_STYLE: Style = Style::new().add_modifier(Modifier::UNDERLINED);
 const ADDR_STYLE: Style = Style::new().fg(Color::Yellow);
 const SYM_ADDR_STYLE: Style = Style::new().fg(Color::Yellow);
 const NAME_STYLE: Style = Style::new().fg(Color::White);
 const FILE_STYLE: Style = Style::new().fg(Color::DarkGray);
 const LINE_STYLE: Style = Style::new().fg(Color::Cyan);
 
-impl<'a> Widget for TraceLog<'a> {
+impl Widget for TraceLog<'_> {
 	fn render(self, area: Rect, buf: &mut Buffer)
 	where
 		Self: Sized,
 	{
 		let num_rows = usize::from(area.height);
-		let Some(sym_slice) = self.0.get(self.0.len().saturating_sub(num_rows)..) else {
-			return;
-		};
+		let first = self.symbols.len().saturating_sub(num_rows);
+		let index_width = self
+			.indices
+			.and_then(|indices| indices.iter().max())
+			.map_or(0, |max| max.to_string().len());
 
-		List::new(sym_slice.iter().map(|sym| {
+		List::new(self.symbols[first..].iter().enumerate().map(|(row, sym)| {
+			let row = first + row;
 			let mut text = Text::default();
+
+			if let Some(index) = self.indices.and_then(|indices| indices.get(row)) {
+				text.push_span(Span::styled(format!("{index:>index_width$} "), INDEX_STYLE));
+			}
+
 			text.push_span(Span::styled(format!("{:016X}", sym.addr), ADDR_STYLE));
 
 			if let Some(name) = &sym.name {
@@ -51,7 +97,14 @@ impl<'a> Widget for TraceLog<'a> {
 				text.push_span(Span::styled(format!("{}", line), LINE_STYLE));
 			}
 
-			ListItem::new(text)
+			let item = ListItem::new(text);
+			if self.selected != Some(row) {
+				item
+			} else if self.focused {
+				item.style(SELECTED_STYLE)
+			} else {
+				item.style(UNFOCUSED_SELECTED_STYLE)
+			}
 		}))
 		.render(area, buf);
 	}
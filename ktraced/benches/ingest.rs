@@ -0,0 +1,89 @@
+//! Measures how fast the daemon records synthetic traces, and how fast it reads them back.
+//!
+//! Run with `cargo bench -p ktraced`, optionally passing the number of instructions per
+//! vCPU and the number of vCPUs (e.g. `cargo bench -p ktraced -- 10000000 4`).
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use std::time::Instant;
+
+use common::{Daemon, record_workload, wait_for_exit, wait_until};
+use ktrace_protocol::{MAX_RANGE_COUNT, Packet};
+use ktrace_synth::Workload;
+
+fn inst_count(daemon: &Daemon, thread_id: u32) -> usize {
+	match daemon.request(Packet::GetInstCount {
+		session: None,
+		thread_id,
+	}) {
+		Packet::InstCount { count } => count,
+		packet => panic!("unexpected response: {packet:?}"),
+	}
+}
+
+fn report(what: &str, count: usize, start: Instant) {
+	let secs = start.elapsed().as_secs_f64();
+	println!(
+		"{what}: {count} instruction(s) in {secs:.2}s ({:.1}M/s)",
+		count as f64 / secs / 1e6
+	);
+}
+
+fn main() {
+	// Skip the `--bench` that cargo passes.
+	let args = std::env::args()
+		.skip(1)
+		.filter(|arg| !arg.starts_with("--"))
+		.collect::<Vec<_>>();
+	let instructions = args
+		.first()
+		.map_or(2_000_000, |n| n.parse().expect("bad instruction count"));
+	let vcpus = args
+		.get(1)
+		.map_or(2, |n| n.parse().expect("bad vcpu count"));
+
+	let workload = Workload {
+		instructions,
+		..Workload::default()
+	};
+	let count = instructions as usize;
+	let daemon = Daemon::start();
+
+	let start = Instant::now();
+	let producers = std::thread::scope(|scope| {
+		let producers = (0..vcpus)
+			.map(|id| {
+				let (daemon, workload) = (&daemon, &workload);
+				scope.spawn(move || record_workload(daemon, workload, 1, id))
+			})
+			.collect::<Vec<_>>();
+		producers
+			.into_iter()
+			.map(|producer| producer.join().unwrap())
+			.collect::<Vec<_>>()
+	});
+	for id in 0..vcpus {
+		wait_until(|| inst_count(&daemon, id) == count);
+	}
+	report("ingest", count * vcpus as usize, start);
+
+	drop(producers);
+	wait_for_exit(&daemon);
+
+	let start = Instant::now();
+	let mut read = 0;
+	while read < count {
+		let Packet::Range { entries } = daemon.request(Packet::GetRange {
+			session:   None,
+			thread_id: 0,
+			start:     read,
+			count:     MAX_RANGE_COUNT,
+			filter:    None,
+		}) else {
+			panic!("expected range");
+		};
+		read += entries.len();
+	}
+	report("read", read, start);
+}
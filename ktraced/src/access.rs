@@ -0,0 +1,197 @@
+//! Access control for the daemon's sockets.
+
+use std::{
+	ffi::{CString, OsString},
+	fs::{self, DirBuilder, Permissions},
+	io,
+	os::unix::fs::{DirBuilderExt, PermissionsExt, chown},
+	path::Path,
+};
+
+use tokio::net::{UnixListener, UnixStream};
+
+/// Who may connect to the daemon's sockets.
+#[derive(Debug, Clone, Default)]
+pub struct SocketAccess {
+	/// The file mode given to the sockets, if not left to the umask.
+	pub mode:         Option<u32>,
+	/// The group the sockets are owned by, if not the daemon's.
+	pub group:        Option<u32>,
+	/// The UIDs allowed to connect, in addition to the daemon's own.
+	/// If empty, any user that can open the sockets may connect.
+	pub allowed_uids: Vec<u32>,
+}
+
+impl SocketAccess {
+	/// Binds a socket at `path` (replacing any existing one) with the configured group and mode.
+	///
+	/// The socket is bound in a private directory next to `path` and only moved into place
+	/// once its permissions are set, so it's never reachable with looser permissions
+	/// (e.g. those left by the umask) than configured.
+	pub fn bind(&self, path: &Path) -> io::Result<UnixListener> {
+		let name = path
+			.file_name()
+			.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "socket path has no file name"))?;
+		let mut dir_name = OsString::from(".");
+		dir_name.push(name);
+		dir_name.push(format!(".{}", std::process::id()));
+		let dir = path.with_file_name(dir_name);
+
+		// Left behind by a daemon that died while binding, and happened to have the same
+		// PID (e.g. as PID 1 of a container).
+		match fs::remove_dir_all(&dir) {
+			Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+			_ => {}
+		}
+		DirBuilder::new().mode(0o700).create(&dir)?;
+		let tmp_path = dir.join("sock");
+		let bound = UnixListener::bind(&tmp_path).and_then(|sock| {
+			self.apply(&tmp_path)?;
+			fs::rename(&tmp_path, path)?;
+			Ok(sock)
+		});
+
+		let _ = fs::remove_file(&tmp_path);
+		let _ = fs::remove_dir(&dir);
+		bound
+	}
+
+	/// Applies the configured group and mode to a freshly bound socket.
+	fn apply(&self, path: &Path) -> io::Result<()> {
+		if let Some(gid) = self.group {
+			chown(path, None, Some(gid))?;
+		}
+		if let Some(mode) = self.mode {
+			fs::set_permissions(path, Permissions::from_mode(mode))?;
+		}
+		Ok(())
+	}
+
+	/// Checks the credentials of a connection's peer against the allowlist.
+	pub fn check_peer(&self, stream: &UnixStream) -> io::Result<()> {
+		if self.allowed_uids.is_empty() {
+			return Ok(());
+		}
+
+		let uid = stream.peer_cred()?.uid();
+		if self.allows(uid) {
+			Ok(())
+		} else {
+			Err(io::Error::new(
+				io::ErrorKind::PermissionDenied,
+				format!("uid {uid} is not allowed to connect"),
+			))
+		}
+	}
+
+	/// Returns whether or not a user may connect (if the allowlist is in use).
+	fn allows(&self, uid: u32) -> bool {
+		// SAFETY: `geteuid` has no memory safety requirements.
+		uid == unsafe { libc::geteuid() } || self.allowed_uids.contains(&uid)
+	}
+}
+
+/// Parses an octal file mode (e.g. `660` or `0o660`).
+pub fn parse_mode(s: &str) -> Result<u32, String> {
+	let digits = s.trim().trim_start_matches("0o");
+	match u32::from_str_radix(digits, 8) {
+		Ok(mode) if mode <= 0o7777 => Ok(mode),
+		_ => Err(format!("invalid file mode '{s}'")),
+	}
+}
+
+/// Parses a group, given either by name or by GID.
+pub fn parse_group(s: &str) -> Result<u32, String> {
+	if let Ok(gid) = s.parse() {
+		return Ok(gid);
+	}
+
+	let name = CString::new(s).map_err(|_| format!("invalid group '{s}'"))?;
+	// SAFETY: `libc::group` is plain old data, for which all zeroes is valid.
+	let mut group = unsafe { std::mem::zeroed::<libc::group>() };
+	let mut result = std::ptr::null_mut();
+	let mut buf = vec![0; 16 * 1024];
+
+	// SAFETY: All pointers are valid, and `buf`'s length is passed along with it.
+	let err = unsafe {
+		libc::getgrnam_r(
+			name.as_ptr(),
+			&mut group,
+			buf.as_mut_ptr(),
+			buf.len(),
+			&mut result,
+		)
+	};
+
+	if err != 0 {
+		Err(format!(
+			"failed to look up group '{s}': {}",
+			io::Error::from_raw_os_error(err)
+		))
+	} else if result.is_null() {
+		Err(format!("no such group '{s}'"))
+	} else {
+		Ok(group.gr_gid)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::os::unix::fs::MetadataExt;
+
+	use super::*;
+
+	fn own_uid() -> u32 {
+		// SAFETY: `geteuid` has no memory safety requirements.
+		unsafe { libc::geteuid() }
+	}
+
+	#[test]
+	fn uids_not_on_the_allowlist_are_rejected() {
+		let uid = own_uid();
+		let access = SocketAccess {
+			allowed_uids: vec![uid.wrapping_add(1)],
+			..Default::default()
+		};
+
+		assert!(access.allows(uid));
+		assert!(access.allows(uid.wrapping_add(1)));
+		assert!(!access.allows(uid.wrapping_add(2)));
+	}
+
+	#[tokio::test]
+	async fn sockets_are_bound_with_their_final_mode() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("query.sock");
+		std::fs::write(&path, "stale").unwrap();
+
+		let access = SocketAccess {
+			mode: Some(0o600),
+			..Default::default()
+		};
+		let _sock = access.bind(&path).unwrap();
+
+		assert_eq!(path.metadata().unwrap().mode() & 0o7777, 0o600);
+		assert!(UnixStream::connect(&path).await.is_ok());
+
+		// The private directory it was bound in is gone.
+		let entries = std::fs::read_dir(dir.path()).unwrap().count();
+		assert_eq!(entries, 1);
+	}
+
+	#[tokio::test]
+	async fn stale_private_directories_are_replaced() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("query.sock");
+		let stale = dir
+			.path()
+			.join(format!(".query.sock.{}", std::process::id()));
+		std::fs::create_dir(&stale).unwrap();
+		std::fs::write(stale.join("sock"), "stale").unwrap();
+
+		let _sock = SocketAccess::default().bind(&path).unwrap();
+
+		assert!(UnixStream::connect(&path).await.is_ok());
+		assert!(!stale.exists());
+	}
+}
@@ -0,0 +1,60 @@
+//! The optional configuration file, shared with `ktrace` and the QEMU plugin
+//! (see [`ktrace_config`]).
+//!
+//! Each program reads the tables it cares about (here, `[sockets]` and `[daemon]`).
+//! Values take the same form as the corresponding command line flags, which take
+//! precedence over them.
+
+use std::path::PathBuf;
+
+use ktrace_config::Sockets;
+use serde::{Deserialize, Deserializer, de::Error as _};
+
+use crate::{
+	access,
+	retention::{self, RetentionPolicy},
+};
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+	#[serde(default)]
+	pub sockets: Sockets,
+	#[serde(default)]
+	pub daemon:  DaemonConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct DaemonConfig {
+	pub storage_dir:      Option<PathBuf>,
+	#[serde(deserialize_with = "size")]
+	pub max_thread_size:  Option<u64>,
+	#[serde(deserialize_with = "size")]
+	pub max_session_size: Option<u64>,
+	#[serde(deserialize_with = "size")]
+	pub max_total_size:   Option<u64>,
+	pub retention:        Option<RetentionPolicy>,
+	#[serde(deserialize_with = "mode")]
+	pub sock_mode:        Option<u32>,
+	#[serde(deserialize_with = "group")]
+	pub sock_group:       Option<u32>,
+	pub allow_uids:       Vec<u32>,
+	pub metrics_sock:     Option<String>,
+}
+
+fn size<'de, D: Deserializer<'de>>(de: D) -> Result<Option<u64>, D::Error> {
+	let s = String::deserialize(de)?;
+	retention::parse_size(&s)
+		.map(Some)
+		.map_err(D::Error::custom)
+}
+
+fn mode<'de, D: Deserializer<'de>>(de: D) -> Result<Option<u32>, D::Error> {
+	let s = String::deserialize(de)?;
+	access::parse_mode(&s).map(Some).map_err(D::Error::custom)
+}
+
+fn group<'de, D: Deserializer<'de>>(de: D) -> Result<Option<u32>, D::Error> {
+	let s = String::deserialize(de)?;
+	access::parse_group(&s).map(Some).map_err(D::Error::custom)
+}
@@ -0,0 +1,61 @@
+//! Guards against multiple daemons using the same paths.
+
+use std::{
+	fs::File,
+	io::{self, Read, Seek, Write},
+	os::fd::AsRawFd,
+	path::PathBuf,
+};
+
+/// An exclusive lock on a file holding the PID of the daemon that owns it.
+///
+/// The lock is released (and the PID cleared) when dropped. Since it's an `flock`,
+/// it's also released if the daemon dies, so stale lock files don't block restarts.
+///
+/// The file itself is never removed: another daemon may already have opened it,
+/// and would end up holding a lock on a file that no longer exists.
+pub struct PidLock {
+	file: File,
+}
+
+impl PidLock {
+	/// Locks the file at `path`, creating it if necessary. Fails if another
+	/// process holds the lock.
+	pub fn acquire(path: PathBuf) -> io::Result<Self> {
+		let mut file = File::options()
+			.read(true)
+			.write(true)
+			.create(true)
+			.truncate(false)
+			.open(&path)?;
+
+		// SAFETY: The file descriptor is valid for the lifetime of `file`.
+		if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+			let err = io::Error::last_os_error();
+			if err.kind() != io::ErrorKind::WouldBlock {
+				return Err(err);
+			}
+
+			let mut pid = String::new();
+			let _ = file.read_to_string(&mut pid);
+			return Err(io::Error::other(format!(
+				"'{}' is locked by another ktraced (pid {})",
+				path.display(),
+				pid.trim()
+			)));
+		}
+
+		file.set_len(0)?;
+		file.rewind()?;
+		writeln!(file, "{}", std::process::id())?;
+
+		Ok(Self { file })
+	}
+}
+
+impl Drop for PidLock {
+	fn drop(&mut self) {
+		// Still locked, so no other daemon can have written its PID yet.
+		let _ = self.file.set_len(0);
+	}
+}
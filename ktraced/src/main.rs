@@ -1,39 +1,106 @@
-use std::{
-	io::{self, BufWriter, Write},
-	os::unix::net::{UnixListener, UnixStream},
+use std::{future::Future, io, path::PathBuf, sync::Arc};
+
+mod access;
+mod config;
+mod lock;
+mod metrics;
+mod query_server;
+mod replay;
+mod retention;
+mod session;
+mod trace_file;
+
+use access::SocketAccess;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, error::ErrorKind, parser::ValueSource};
+use config::Config;
+use ktrace_plugin_protocol::{EnDec, Packet};
+use ktrace_protocol::ProducerStats;
+use lock::PidLock;
+use log::{error, info, trace, warn};
+use query_server::{ClearRequest, QueryServer, QueryServerThread};
+use retention::{RetentionLimits, RetentionPolicy};
+use tokio::{
+	io::AsyncReadExt,
+	net::UnixStream,
+	signal::unix::{SignalKind, signal},
 	sync::{
-		Arc,
-		atomic::{AtomicUsize, Ordering::Relaxed},
+		mpsc::{self, UnboundedReceiver},
+		watch,
 	},
+	task::JoinSet,
 };
+use trace_file::TraceWriter;
 
-mod query_server;
-
-use byteorder::{LittleEndian, WriteBytesExt};
-use clap::Parser;
-use ktrace_plugin_protocol::{Packet, TraceRead};
-use log::{error, info, trace};
-use query_server::ThreadState;
+/// The size of the buffer trace connections are read into.
+const READ_BUFFER_SIZE: usize = 64 * 1024;
 
 /// Runs the Kflame daemon, to which the QEMU plugin connects.
+///
+/// Options not given on the command line are read from the config file,
+/// `$XDG_CONFIG_HOME/ktrace/config.toml` by default.
 #[derive(Parser, Debug)]
 struct Args {
+	#[clap(subcommand)]
+	command:           Option<Command>,
+	/// The config file to read (instead of the default one).
+	#[clap(short = 'c', long = "config", global = true)]
+	config_path:       Option<PathBuf>,
 	/// The path of the unix domain socket to listen on for trace connections (e.g. from QEMU or other plugins)
-	#[clap(short = 's', long = "trace-sock", default_value = ktrace_plugin_protocol::DEFAULT_SOCKET_PATH)]
-	socket_path: String,
+	/// [default: /tmp/ktrace.sock]
+	#[clap(short = 's', long = "trace-sock", global = true)]
+	socket_path:       Option<String>,
 	/// The path of the unix domain socket to listen on for query connections (e.g. the ktrace client)
-	#[clap(short = 'b', long = "sock", default_value = ktrace_protocol::DEFAULT_SOCKET_PATH)]
-	query_socket_path: String,
-	/// The root directory for temporary trace files.
-	#[clap(short = 'T', long = "tmpdir")]
-	tmpdir: Option<String>,
+	/// [default: /tmp/ktrace-query.sock]
+	#[clap(short = 'b', long = "sock")]
+	query_socket_path: Option<String>,
+	/// The directory in which trace sessions are stored.
+	/// Defaults to `$XDG_DATA_HOME/ktrace/sessions`.
+	#[clap(
+		short = 'd',
+		long = "storage-dir",
+		visible_alias = "tmpdir",
+		short_alias = 'T'
+	)]
+	storage_dir:       Option<PathBuf>,
+	/// The maximum size of each thread's trace on disk (e.g. `512M`).
+	#[clap(long = "max-thread-size", value_parser = retention::parse_size)]
+	max_thread_size:   Option<u64>,
+	/// The maximum size of each session on disk (e.g. `2G`).
+	#[clap(long = "max-session-size", value_parser = retention::parse_size)]
+	max_session_size:  Option<u64>,
+	/// The maximum size of all stored sessions combined (e.g. `20G`).
+	#[clap(long = "max-total-size", value_parser = retention::parse_size)]
+	max_total_size:    Option<u64>,
+	/// What to do once a storage limit is reached [default: ring].
+	#[clap(long = "retention", value_enum)]
+	retention:         Option<RetentionPolicy>,
+	/// The file mode to give both sockets, in octal (e.g. `660`).
+	#[clap(long = "sock-mode", value_parser = access::parse_mode)]
+	sock_mode:         Option<u32>,
+	/// The group (name or GID) to give both sockets.
+	#[clap(long = "sock-group", value_parser = access::parse_group)]
+	sock_group:        Option<u32>,
+	/// Only accept connections on either socket from these UIDs (and the daemon's own).
+	/// May be given multiple times, or as a comma-separated list.
+	#[clap(long = "allow-uid", value_delimiter = ',')]
+	allow_uids:        Vec<u32>,
+	/// The path of a unix domain socket on which to serve metrics, in the Prometheus text format.
+	/// Disabled by default.
+	#[clap(long = "metrics-sock")]
+	metrics_sock_path: Option<String>,
 	/// Show verbose logs.
-	#[clap(short = 'v', long = "verbose", action = clap::ArgAction::Count)]
-	verbose: usize,
+	#[clap(short = 'v', long = "verbose", action = clap::ArgAction::Count, global = true)]
+	verbose:           u8,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+	Replay(replay::ReplayArgs),
 }
 
-fn main() {
-	let args = Args::parse();
+#[tokio::main]
+async fn main() {
+	let args = parse_args();
 
 	env_logger::builder()
 		.filter_level(match args.verbose {
@@ -43,94 +110,392 @@ fn main() {
 		})
 		.init();
 
+	let Config { sockets, daemon } = ktrace_config::load(args.config_path.as_deref()).unwrap_or_else(|err| {
+		error!("{err}");
+		std::process::exit(1);
+	});
+
+	let socket_path = args
+		.socket_path
+		.or(sockets.trace)
+		.unwrap_or_else(|| ktrace_plugin_protocol::DEFAULT_SOCKET_PATH.to_string());
+
+	if let Some(Command::Replay(replay)) = args.command {
+		let ok = tokio::task::block_in_place(|| replay::run(&socket_path, replay));
+		std::process::exit(if ok { 0 } else { 1 });
+	}
+
+	let query_socket_path = args
+		.query_socket_path
+		.or(sockets.query)
+		.unwrap_or_else(|| ktrace_protocol::DEFAULT_SOCKET_PATH.to_string());
+
+	let storage_dir = args
+		.storage_dir
+		.or(daemon.storage_dir)
+		.unwrap_or_else(default_storage_dir);
+	std::fs::create_dir_all(&storage_dir).expect("failed to create storage directory");
+
+	let metrics_sock_path = args.metrics_sock_path.or(daemon.metrics_sock);
+
+	// Refuse to start if another daemon is using any of our paths, before touching them.
+	let _locks = [
+		Some(PathBuf::from(format!("{}.lock", socket_path))),
+		Some(PathBuf::from(format!("{}.lock", query_socket_path))),
+		metrics_sock_path
+			.as_ref()
+			.map(|path| PathBuf::from(format!("{path}.lock"))),
+		Some(storage_dir.join(".lock")),
+	]
+	.map(|path| {
+		path.map(|path| {
+			PidLock::acquire(path).unwrap_or_else(|err| {
+				error!("{err}");
+				std::process::exit(1);
+			})
+		})
+	});
+
+	// Installed before the sockets are bound, so that a signal sent once they're
+	// connectable doesn't kill the daemon outright.
+	let shutdown = shutdown_signal();
+	tokio::pin!(shutdown);
+
 	// Try to unlink it
-	std::fs::remove_file(&args.socket_path).ok();
+	std::fs::remove_file(&socket_path).ok();
 
-	let server_sock = UnixListener::bind(&args.socket_path).expect("failed to bind to socket");
+	let access = Arc::new(SocketAccess {
+		mode:         args.sock_mode.or(daemon.sock_mode),
+		group:        args.sock_group.or(daemon.sock_group),
+		allowed_uids: if args.allow_uids.is_empty() {
+			daemon.allow_uids
+		} else {
+			args.allow_uids
+		},
+	});
 
-	info!("listening for trace connections at '{}'", args.socket_path);
+	let server_sock = access
+		.bind(socket_path.as_ref())
+		.expect("failed to bind to socket");
 
-	let query_serv = Arc::new(query_server::spawn(args.query_socket_path.clone()));
+	info!("listening for trace connections at '{}'", socket_path);
 
-	info!(
-		"listening for query connections at '{}'",
-		args.query_socket_path
+	let limits = RetentionLimits {
+		policy:      args.retention.or(daemon.retention).unwrap_or_default(),
+		max_thread:  args.max_thread_size.or(daemon.max_thread_size),
+		max_session: args.max_session_size.or(daemon.max_session_size),
+		max_total:   args.max_total_size.or(daemon.max_total_size),
+	};
+
+	let query_serv = Arc::new(
+		query_server::spawn(
+			query_socket_path.clone(),
+			storage_dir.clone(),
+			limits,
+			access.clone(),
+		)
+		.expect("failed to start query server"),
 	);
 
-	for stream in server_sock.incoming() {
-		let stream = stream.expect("failed to accept connection");
+	info!("listening for query connections at '{}'", query_socket_path);
+
+	if let Some(path) = &metrics_sock_path {
+		metrics::spawn(path, access.clone(), query_serv.clone()).expect("failed to start metrics server");
+		info!("serving metrics at '{path}'");
+	}
+
+	info!("storing sessions in '{}'", storage_dir.display());
+
+	let (shutdown_send, shutdown_recv) = watch::channel(false);
+	let mut producers = JoinSet::new();
+
+	loop {
+		let accepted = tokio::select! {
+			accepted = server_sock.accept() => accepted,
+			() = &mut shutdown => break,
+		};
+
+		let stream = match accepted {
+			Ok((stream, _)) => stream,
+			Err(err) => {
+				error!("failed to accept connection: {err:?}");
+				continue;
+			}
+		};
+
+		if let Err(err) = access.check_peer(&stream) {
+			warn!("rejected trace connection: {err}");
+			continue;
+		}
 
-		trace!("accepted connection");
-		std::thread::spawn({
-			let tmpdir = args.tmpdir.clone();
+		// Recorded in the session metadata.
+		let pid = stream.peer_cred().ok().and_then(|cred| cred.pid());
+
+		// Reap producers that are done.
+		while producers.try_join_next().is_some() {}
+
+		trace!(pid:?; "accepted connection");
+		producers.spawn({
 			let query_serv = query_serv.clone();
-			move || {
-				if let Err(err) = handle_vcpu_stream(stream, tmpdir, query_serv) {
-					error!("error handling stream: {err:?}");
+			let shutdown = shutdown_recv.clone();
+			async move {
+				if let Err(err) = handle_vcpu_stream(stream, pid, query_serv, shutdown).await {
+					warn!(pid:?; "producer connection failed: {err}");
 				}
 			}
 		});
 	}
+
+	info!("shutting down");
+
+	drop(server_sock);
+	std::fs::remove_file(&socket_path).ok();
+	if let Some(path) = &metrics_sock_path {
+		std::fs::remove_file(path).ok();
+	}
+
+	// Have every producer finalize its trace, and wait for the sessions to be updated.
+	shutdown_send.send_replace(true);
+	while producers.join_next().await.is_some() {}
+	query_serv.shutdown().await;
+}
+
+/// Parses the command line, refusing the daemon's own options along with a subcommand
+/// (which would ignore them). Global options apply to both.
+fn parse_args() -> Args {
+	let matches = Args::command().get_matches();
+	let args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+
+	if let Some((name, _)) = matches.subcommand() {
+		let command = Args::command();
+		let daemon_only = command.get_arguments().find(|arg| {
+			!arg.is_global_set()
+				&& matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine)
+		});
+
+		if let Some(arg) = daemon_only {
+			let flag = arg
+				.get_long()
+				.map_or_else(|| arg.get_id().to_string(), |long| format!("--{long}"));
+			Args::command()
+				.error(
+					ErrorKind::ArgumentConflict,
+					format!("'{flag}' can't be used with the '{name}' subcommand"),
+				)
+				.exit();
+		}
+	}
+
+	args
 }
 
-fn handle_vcpu_stream(
-	stream: UnixStream,
-	tmpdir: Option<String>,
-	query_serv: Arc<query_server::QueryServer>,
+/// Installs the SIGINT and SIGTERM handlers, returning a future that resolves
+/// once the daemon is asked to stop by either.
+fn shutdown_signal() -> impl Future<Output = ()> {
+	let mut interrupt = signal(SignalKind::interrupt()).expect("failed to install SIGINT handler");
+	let mut terminate = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+
+	async move {
+		tokio::select! {
+			_ = interrupt.recv() => {}
+			_ = terminate.recv() => {}
+		}
+	}
+}
+
+/// Returns the default session storage directory.
+fn default_storage_dir() -> PathBuf {
+	let data_dir = std::env::var_os("XDG_DATA_HOME")
+		.map(PathBuf::from)
+		.or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")))
+		.unwrap_or_else(std::env::temp_dir);
+
+	data_dir.join("ktrace").join("sessions")
+}
+
+/// Handles a producer connection. Errors are only returned if they occur before
+/// recording starts; afterwards, the trace is kept (and the thread marked as exited)
+/// however the connection ends.
+async fn handle_vcpu_stream(
+	mut stream: UnixStream,
+	pid: Option<i32>,
+	query_serv: Arc<QueryServer>,
+	mut shutdown: watch::Receiver<bool>,
 ) -> io::Result<()> {
-	let mut tf = tempfile::Builder::new();
-	tf.append(true);
+	let mut buf = Vec::with_capacity(READ_BUFFER_SIZE);
+
+	let vcpu = loop {
+		if let Some((msg, len)) = decode_plugin_packet(&buf)? {
+			buf.drain(..len);
+			let Packet::VcpuInit(vcpu) = msg else {
+				return Err(io::Error::new(
+					io::ErrorKind::InvalidData,
+					format!("expected VcpuInit, got {msg:?}"),
+				));
+			};
+			break vcpu;
+		}
 
-	let addr_file = if let Some(tmpdir) = tmpdir {
-		tf.tempfile_in(tmpdir)?
-	} else {
-		tf.tempfile()?
+		tokio::select! {
+			read = stream.read_buf(&mut buf) => {
+				if read? == 0 {
+					return Err(io::Error::new(
+						io::ErrorKind::UnexpectedEof,
+						"disconnected before VcpuInit",
+					));
+				}
+			}
+			_ = shutdown.wait_for(|&shutdown| shutdown) => return Ok(()),
+		}
 	};
 
-	let mut out_file = BufWriter::new(addr_file.reopen()?);
+	let (clear_send, mut clear_recv) = mpsc::unbounded_channel();
 
-	let mut rd = std::io::BufReader::new(stream);
+	let (client, mut out_file) = query_serv
+		.new_thread(pid, vcpu.producer, vcpu.id, clear_send)
+		.await?;
 
-	let msg = rd.read_packet()?;
-	let Packet::VcpuInit(vcpu) = msg else {
-		panic!("expected VcpuInit, got {msg:?}");
-	};
+	let session = client.session();
+	info!(session, vcpu = vcpu.id, producer:? = vcpu.producer, pid:?; "vcpu connected");
 
-	let addr_counter = Arc::new(AtomicUsize::new(0));
+	let recording = record_vcpu(
+		&mut stream,
+		&mut buf,
+		&mut out_file,
+		&client,
+		&mut clear_recv,
+		&mut shutdown,
+	);
 
-	let client = query_serv.new_thread(ThreadState {
-		id:           vcpu.id,
-		addr_counter: addr_counter.clone(),
-		temp_file:    addr_file.reopen()?,
-		status:       Default::default(),
-	});
+	match recording.await {
+		Ok(RecordingEnd::Exited) => info!(session, vcpu = vcpu.id; "vcpu exited"),
+		Ok(RecordingEnd::Shutdown) => info!(session, vcpu = vcpu.id; "stopped recording for shutdown"),
+		Err(err) => warn!(session, vcpu = vcpu.id; "producer connection failed: {err}"),
+	}
 
-	info!("received VcpuInit for vcpu {}", vcpu.id);
+	// Make everything received so far readable, even if the producer went away.
+	if let Err(err) = tokio::task::block_in_place(|| out_file.finalize()) {
+		error!(session, vcpu = vcpu.id; "failed to finalize trace: {err}");
+	}
 
+	client.exit();
+
+	Ok(())
+}
+
+/// Why recording a vCPU ended (other than due to an error).
+enum RecordingEnd {
+	/// The vCPU exited.
+	Exited,
+	/// The daemon is shutting down.
+	Shutdown,
+}
+
+/// Records a vCPU's packets until it exits or the daemon shuts down, clearing
+/// the trace whenever asked to.
+async fn record_vcpu(
+	stream: &mut UnixStream,
+	buf: &mut Vec<u8>,
+	out_file: &mut TraceWriter,
+	client: &QueryServerThread,
+	clear: &mut UnboundedReceiver<ClearRequest>,
+	shutdown: &mut watch::Receiver<bool>,
+) -> io::Result<RecordingEnd> {
 	loop {
-		match rd.read_packet()? {
-			Packet::VcpuResume => {
-				out_file.flush()?;
-				client.resume();
+		// Recording may compress and write out chunks, which blocks.
+		let (len, exited) = tokio::task::block_in_place(|| record_packets(buf, out_file, client))?;
+		buf.drain(..len);
+
+		// Let clients see what was received without waiting for the chunk to fill up.
+		out_file.publish();
+
+		if exited {
+			return Ok(RecordingEnd::Exited);
+		}
+
+		tokio::select! {
+			read = stream.read_buf(buf) => {
+				if read? == 0 {
+					return Err(io::Error::new(
+						io::ErrorKind::UnexpectedEof,
+						"disconnected without VcpuExit",
+					));
+				}
 			}
-			Packet::VcpuIdle => {
-				out_file.flush()?;
-				client.idle();
+			Some(done) = clear.recv() => {
+				let result = tokio::task::block_in_place(|| out_file.clear());
+				if result.is_ok() {
+					info!(session = client.session(), vcpu = client.thread_id(); "trace cleared");
+				}
+				let _ = done.send(result);
 			}
-			Packet::VcpuExit => {
-				out_file.flush()?;
-				client.exit();
-				break;
+			_ = shutdown.wait_for(|&shutdown| shutdown) => return Ok(RecordingEnd::Shutdown),
+		}
+	}
+}
+
+/// Records the complete packets at the start of `buf`, returning the number of bytes
+/// they took up and whether or not the vCPU exited.
+fn record_packets(
+	buf: &[u8],
+	out_file: &mut TraceWriter,
+	client: &QueryServerThread,
+) -> io::Result<(usize, bool)> {
+	let mut consumed = 0;
+
+	while let Some((msg, len)) = decode_plugin_packet(&buf[consumed..])? {
+		consumed += len;
+
+		match msg {
+			Packet::VcpuResume => client.resume(),
+			Packet::VcpuIdle => client.idle(),
+			Packet::VcpuExit => return Ok((consumed, true)),
+			Packet::VcpuStats(stats) => {
+				client.producer_stats(ProducerStats {
+					callbacks:     stats.callbacks,
+					instructions:  stats.instructions,
+					bytes_written: stats.bytes_written,
+					blocked_ns:    stats.blocked_ns,
+					dropped:       stats.dropped,
+				});
 			}
 			Packet::Inst(inst) => {
-				out_file.write_u64::<LittleEndian>(inst.addr)?;
-				addr_counter.fetch_add(1, Relaxed);
+				// Streams would mistake it for a cleared trace.
+				if inst.addr == ktrace_plugin_protocol::RESERVED_ADDR {
+					return Err(io::Error::new(
+						io::ErrorKind::InvalidData,
+						format!("reserved instruction address {:#x}", inst.addr),
+					));
+				}
+
+				if out_file.index().is_stopped() {
+					out_file.index().discard();
+					continue;
+				}
+
+				if out_file.push(inst.addr)? {
+					client.flushed();
+				}
 			}
 			msg => {
-				panic!("unexpected message: {:?}", msg);
+				return Err(io::Error::new(
+					io::ErrorKind::InvalidData,
+					format!("unexpected message: {msg:?}"),
+				));
 			}
 		}
 	}
 
-	Ok(())
+	Ok((consumed, false))
+}
+
+/// Decodes a plugin protocol packet from the start of `buf`, returning it along with the number
+/// of bytes it took up, or `None` if `buf` doesn't hold a complete packet yet.
+fn decode_plugin_packet(buf: &[u8]) -> io::Result<Option<(Packet, usize)>> {
+	let mut rd = buf;
+	match Packet::read(&mut rd) {
+		Ok(packet) => Ok(Some((packet, buf.len() - rd.len()))),
+		Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+		Err(err) => Err(err),
+	}
 }
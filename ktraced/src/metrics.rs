@@ -0,0 +1,217 @@
+//! An optional Prometheus text-format endpoint, served over HTTP on a unix domain socket
+//! (e.g. `curl --unix-socket /tmp/ktraced-metrics.sock http://localhost/metrics`).
+
+use std::{fmt::Write as _, io, path::Path, sync::Arc, time::Duration};
+
+use ktrace_protocol::{DaemonStats, ThreadStats, ThreadStatus};
+use log::{debug, warn};
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	net::UnixStream,
+};
+
+use crate::{access::SocketAccess, query_server::QueryServer};
+
+/// The most a client may send as its request, which is otherwise ignored.
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+
+/// How long a client may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A metric reported for each thread.
+struct ThreadMetric {
+	name:  &'static str,
+	kind:  &'static str,
+	help:  &'static str,
+	/// Returns the thread's value, if it has one.
+	value: fn(&ThreadStats) -> Option<f64>,
+}
+
+const THREAD_METRICS: [ThreadMetric; 13] = [
+	ThreadMetric {
+		name:  "ktraced_thread_live",
+		kind:  "gauge",
+		help:  "Whether the thread is still being recorded.",
+		value: |t| Some(f64::from(u8::from(t.status != ThreadStatus::Dead))),
+	},
+	ThreadMetric {
+		name:  "ktraced_thread_instructions_total",
+		kind:  "counter",
+		help:  "Instructions recorded.",
+		value: |t| Some(t.instructions as f64),
+	},
+	ThreadMetric {
+		name:  "ktraced_thread_ingest_rate",
+		kind:  "gauge",
+		help:  "Instructions recorded per second, over the last second.",
+		value: |t| Some(t.ingest_rate),
+	},
+	ThreadMetric {
+		name:  "ktraced_thread_stored_bytes",
+		kind:  "gauge",
+		help:  "Bytes the trace takes up on disk.",
+		value: |t| Some(t.stored_bytes as f64),
+	},
+	ThreadMetric {
+		name:  "ktraced_thread_open_streams",
+		kind:  "gauge",
+		help:  "Streams open on the thread.",
+		value: |t| Some(t.open_streams as f64),
+	},
+	ThreadMetric {
+		name:  "ktraced_thread_stream_lag_instructions",
+		kind:  "gauge",
+		help:  "Recorded instructions the slowest stream has yet to be sent.",
+		value: |t| Some(t.stream_lag as f64),
+	},
+	ThreadMetric {
+		name:  "ktraced_thread_dropped_instructions_total",
+		kind:  "counter",
+		help:  "Instructions dropped to stay within the storage limits.",
+		value: |t| Some(t.dropped as f64),
+	},
+	ThreadMetric {
+		name:  "ktraced_thread_reconnects_total",
+		kind:  "counter",
+		help:  "Times the producer connected the thread again.",
+		value: |t| Some(f64::from(t.reconnects)),
+	},
+	ThreadMetric {
+		name:  "ktraced_producer_callbacks_total",
+		kind:  "counter",
+		help:  "Plugin callbacks fired for the thread, as reported by its producer.",
+		value: |t| t.producer.map(|p| p.callbacks as f64),
+	},
+	ThreadMetric {
+		name:  "ktraced_producer_instructions_total",
+		kind:  "counter",
+		help:  "Instructions sent, as reported by the producer.",
+		value: |t| t.producer.map(|p| p.instructions as f64),
+	},
+	ThreadMetric {
+		name:  "ktraced_producer_written_bytes_total",
+		kind:  "counter",
+		help:  "Bytes written to the trace socket, as reported by the producer.",
+		value: |t| t.producer.map(|p| p.bytes_written as f64),
+	},
+	ThreadMetric {
+		name:  "ktraced_producer_blocked_seconds_total",
+		kind:  "counter",
+		help:  "Time spent blocked writing to the trace socket, as reported by the producer.",
+		value: |t| t.producer.map(|p| p.blocked_ns as f64 / 1e9),
+	},
+	ThreadMetric {
+		name:  "ktraced_producer_dropped_records_total",
+		kind:  "counter",
+		help:  "Records the producer could not send.",
+		value: |t| t.producer.map(|p| p.dropped as f64),
+	},
+];
+
+/// Binds the metrics socket and serves it until the daemon exits.
+pub fn spawn(sock_path: &str, access: Arc<SocketAccess>, query_serv: Arc<QueryServer>) -> io::Result<()> {
+	let _ = std::fs::remove_file(sock_path);
+	let sock = access.bind(Path::new(sock_path))?;
+
+	tokio::spawn(async move {
+		loop {
+			let stream = match sock.accept().await {
+				Ok((stream, _)) => stream,
+				Err(err) => {
+					warn!("failed to accept metrics connection: {err:?}");
+					continue;
+				}
+			};
+
+			if let Err(err) = access.check_peer(&stream) {
+				warn!("rejected metrics connection: {err}");
+				continue;
+			}
+
+			let query_serv = query_serv.clone();
+			tokio::spawn(async move {
+				if let Err(err) = serve(stream, &query_serv).await {
+					debug!("metrics connection failed: {err}");
+				}
+			});
+		}
+	});
+
+	Ok(())
+}
+
+/// Responds to a single request with the current metrics, whatever was requested.
+async fn serve(mut stream: UnixStream, query_serv: &QueryServer) -> io::Result<()> {
+	let mut buf = Vec::new();
+	tokio::time::timeout(REQUEST_TIMEOUT, async {
+		while !buf.ends_with(b"\r\n\r\n") && !buf.ends_with(b"\n\n") && buf.len() < MAX_REQUEST_BYTES {
+			if stream.read_buf(&mut buf).await? == 0 {
+				break;
+			}
+		}
+		io::Result::Ok(())
+	})
+	.await
+	.map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "timed out reading request"))??;
+
+	let body = render(&query_serv.daemon_stats().await?);
+	let head = format!(
+		"HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: \
+		 close\r\n\r\n",
+		body.len()
+	);
+
+	stream.write_all(head.as_bytes()).await?;
+	stream.write_all(body.as_bytes()).await?;
+	stream.shutdown().await
+}
+
+/// Renders the daemon's statistics in the Prometheus text exposition format.
+fn render(stats: &DaemonStats) -> String {
+	let mut out = String::new();
+
+	metric(
+		&mut out,
+		"ktraced_uptime_seconds",
+		"gauge",
+		"How long the daemon has been running.",
+	);
+	let _ = writeln!(out, "ktraced_uptime_seconds {}", stats.uptime_secs);
+
+	for ThreadMetric {
+		name,
+		kind,
+		help,
+		value,
+	} in THREAD_METRICS
+	{
+		metric(&mut out, name, kind, help);
+		for thread in &stats.threads {
+			let Some(value) = value(thread) else {
+				continue;
+			};
+
+			let _ = writeln!(
+				out,
+				"{name}{{session=\"{}\",thread=\"{}\"}} {}",
+				escape_label(&thread.session),
+				thread.thread_id,
+				value
+			);
+		}
+	}
+
+	out
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+	let _ = writeln!(out, "# HELP {name} {help}");
+	let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn escape_label(value: &str) -> String {
+	value
+		.replace('\\', "\\\\")
+		.replace('"', "\\\"")
+		.replace('\n', "\\n")
+}
@@ -1,266 +1,1222 @@
 use std::{
 	collections::HashMap,
-	fs::File,
-	io::{Cursor, ErrorKind, Read, Seek, SeekFrom, Write},
-	os::unix::net::{UnixListener, UnixStream},
+	io,
+	ops::ControlFlow,
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+
+use ktrace_protocol::{
+	AddrRange, AnnotationKind, DaemonStats, Error as PacketError, HistogramEntry, InstEntry,
+	MAX_ANNOTATION_LEN, MAX_RANGE_COUNT, Packet, PacketSerializer, ProducerStats, STREAM_CLEARED, SearchOp,
+	ThreadStats, ThreadStatus, TraceFilter, decode_packet,
+};
+use log::{debug, trace, warn};
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	net::{UnixListener, UnixStream},
 	sync::{
-		Arc, OnceLock,
-		atomic::{AtomicUsize, Ordering::Relaxed},
-		mpsc::Sender,
+		mpsc::{self, UnboundedReceiver, UnboundedSender},
+		oneshot,
 	},
-	time::Duration,
+	task::block_in_place,
 };
 
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use ktrace_protocol::{
-	Error as PacketError, Packet, PacketDeserializer, PacketSerializer, ThreadStatus, TraceFilter,
+use crate::{
+	access::SocketAccess,
+	retention::RetentionLimits,
+	session::SessionStore,
+	trace_file::{ChunkInfo, TraceReader, TraceWriter},
 };
-use log::trace;
 
-pub fn spawn(sock_path: String) -> QueryServer {
-	let (master_send, master_recv) = std::sync::mpsc::channel();
+/// The maximum number of bytes a client may send ahead while waiting for a response.
+const MAX_PENDING_REQUEST_BYTES: usize = 64 * 1024;
 
-	let this = QueryServer {
-		master_send: master_send.clone(),
-	};
+pub fn spawn(
+	sock_path: String,
+	storage_dir: PathBuf,
+	limits: RetentionLimits,
+	access: Arc<SocketAccess>,
+) -> io::Result<QueryServer> {
+	let store = SessionStore::open(storage_dir, limits)?;
 
-	std::thread::spawn({
-		move || {
-			std::thread::spawn({
-				let master_send = master_send.clone();
-				move || {
-					// Best-effort remove the socket file
-					let _ = std::fs::remove_file(&sock_path);
-					let sock = UnixListener::bind(&sock_path).expect("failed to bind to socket");
-
-					for stream in sock.incoming() {
-						let mut stream = stream.expect("failed to accept connection");
-						let master_send = master_send.clone();
+	// Best-effort remove the socket file
+	let _ = std::fs::remove_file(&sock_path);
+	let sock = access.bind(Path::new(&sock_path))?;
 
-						std::thread::spawn(move || {
-							loop {
-								let req = stream
-									.deserialize_packet()
-									.expect("failed to deserialize packet");
-
-								if let Packet::OpenStream { thread_id, filter } = &req {
-									master_send
-										.send(MasterMessage::OpenStream(OpenStreamMessage {
-											stream,
-											thread_id: *thread_id,
-											filter: *filter,
-										}))
-										.expect("failed to send message to master");
-									return;
-								}
+	let (master_send, master_recv) = mpsc::unbounded_channel();
 
-								let res = Arc::new(OnceLock::new());
+	tokio::spawn(accept_clients(sock, access, master_send.clone()));
+	tokio::spawn(run_master(store, master_recv, master_send.clone()));
 
-								master_send
-									.send(MasterMessage::Client(ClientMessage {
-										req,
-										res: res.clone(),
-									}))
-									.expect("failed to send message to master");
+	Ok(QueryServer {
+		master_send,
+		sock_path,
+	})
+}
 
-								let res = res.wait();
+/// Accepts query connections, serving each client in its own task.
+async fn accept_clients(
+	sock: UnixListener,
+	access: Arc<SocketAccess>,
+	master_send: UnboundedSender<MasterMessage>,
+) {
+	// Identifies clients in logs.
+	let mut next_client = 0u64;
 
-								stream
-									.serialize_packet(res)
-									.expect("failed to serialize packet");
-							}
-						});
+	loop {
+		let stream = match sock.accept().await {
+			Ok((stream, _)) => stream,
+			Err(err) => {
+				warn!("failed to accept query connection: {err:?}");
+				continue;
+			}
+		};
+
+		let client = next_client;
+		next_client += 1;
+
+		if let Err(err) = access.check_peer(&stream) {
+			warn!(client; "rejected query connection: {err}");
+			continue;
+		}
+
+		let master_send = master_send.clone();
+		tokio::spawn(async move {
+			debug!(client; "client connected");
+			if let Err(err) = handle_client(stream, client, master_send.clone()).await {
+				warn!(client; "client connection failed: {err}");
+			}
+
+			let _ = master_send.send(MasterMessage::Disconnected(client));
+		});
+	}
+}
+
+/// Serves a client's requests until it disconnects (or turns the connection into a stream).
+async fn handle_client(
+	mut stream: UnixStream,
+	client: u64,
+	master_send: UnboundedSender<MasterMessage>,
+) -> io::Result<()> {
+	// The session opened by the client, if any.
+	let mut session = None;
+	let mut buf = Vec::with_capacity(4096);
+
+	loop {
+		let Some(req) = read_request(&mut stream, &mut buf).await? else {
+			debug!(client; "client disconnected");
+			return Ok(());
+		};
+
+		if let Packet::OpenStream {
+			session: name,
+			thread_id,
+			filter,
+		} = req
+		{
+			let _ = master_send.send(MasterMessage::OpenStream(OpenStreamMessage {
+				client,
+				stream,
+				session: name.or(session),
+				thread_id,
+				filter,
+			}));
+			return Ok(());
+		}
+
+		let (res, mut recv) = oneshot::channel();
+
+		let sent = master_send.send(MasterMessage::Client(ClientMessage {
+			client,
+			session: session.clone(),
+			req,
+			res,
+		}));
+
+		if sent.is_err() {
+			return Ok(());
+		}
+
+		// Wait for the response, reading ahead so that a disconnect is noticed (which drops
+		// `recv`, cancelling the request). Stop reading if the client sends too much at once.
+		let res = loop {
+			tokio::select! {
+				res = &mut recv => break res,
+				read = stream.read_buf(&mut buf), if buf.len() < MAX_PENDING_REQUEST_BYTES => {
+					if !matches!(read, Ok(n) if n > 0) {
+						debug!(client; "client disconnected while waiting for a response");
+						return Ok(());
 					}
 				}
-			});
+			}
+		};
+
+		let Ok(res) = res else {
+			return Ok(());
+		};
+
+		if let Packet::SessionOpened { session: info } = &res {
+			session = Some(info.name.clone());
+		}
+
+		let mut out = Vec::new();
+		out.serialize_packet(&res)?;
+
+		// Failing to write means the client went away while its request was served.
+		if stream.write_all(&out).await.is_err() {
+			return Ok(());
+		}
+	}
+}
 
-			let mut threads = HashMap::new();
+/// Reads the next request from a client, returning `None` if it disconnected.
+async fn read_request(stream: &mut UnixStream, buf: &mut Vec<u8>) -> io::Result<Option<Packet>> {
+	loop {
+		if let Some((req, len)) = decode_packet(buf)? {
+			buf.drain(..len);
+			return Ok(Some(req));
+		}
+
+		if stream.read_buf(buf).await? == 0 {
+			return Ok(None);
+		}
+	}
+}
 
-			loop {
-				let req = master_recv
-					.recv()
-					.expect("failed to receive master message");
+/// Owns the session store, handling messages from producers and clients in order.
+///
+/// Store operations that touch the disk (creating and loading sessions, writing their
+/// metadata, enforcing the storage limits) run in [`block_in_place`], so that they don't
+/// hold up the other tasks scheduled on the same worker thread.
+async fn run_master(
+	mut store: SessionStore,
+	mut master_recv: UnboundedReceiver<MasterMessage>,
+	master_send: UnboundedSender<MasterMessage>,
+) {
+	let started = Instant::now();
+	let mut sample_rates = tokio::time::interval(Duration::from_secs(1));
 
+	loop {
+		let req = tokio::select! {
+			req = master_recv.recv() => {
 				match req {
-					MasterMessage::Connection(ConnectionMessage { res, thread_state }) => {
-						threads.insert(thread_state.id, thread_state);
+					Some(req) => req,
+					None => break,
+				}
+			}
+			now = sample_rates.tick() => {
+				sample_ingest_rates(&mut store, now.into_std());
+				continue;
+			}
+		};
+
+		match req {
+			MasterMessage::Connection(ConnectionMessage {
+				pid,
+				producer,
+				thread_id,
+				clear,
+				res,
+			}) => {
+				let result = block_in_place(|| store.add_thread(pid, producer, thread_id, clear)).map(
+					|(session, writer)| {
+						let client = QueryServerThread {
+							session,
+							thread_id,
+							sender: master_send.clone(),
+						};
+
+						(client, writer)
+					},
+				);
 
-						res.set(master_send.clone())
-							.expect("failed to set connection");
+				let _ = res.send(result);
+			}
+			MasterMessage::Thread(ThreadMessage {
+				session,
+				message,
+				thread,
+			}) => {
+				if let Message::Flushed = message {
+					block_in_place(|| store.enforce_retention(&session, thread));
+					continue;
+				}
+
+				let Some(session) = store.get_mut(Some(&session)) else {
+					continue;
+				};
+
+				match message {
+					Message::Exit => {
+						if let Err(err) = block_in_place(|| session.thread_exited(thread)) {
+							warn!("failed to update session '{}': {err:?}", session.name());
+						}
+					}
+					Message::Idle => {
+						if let Some(state) = session.threads.get_mut(&thread) {
+							state.status = ThreadStatus::Idle;
+						}
+					}
+					Message::Resume => {
+						if let Some(state) = session.threads.get_mut(&thread) {
+							state.status = ThreadStatus::Running;
+						}
+					}
+					Message::ProducerStats(stats) => {
+						if let Some(state) = session.threads.get_mut(&thread) {
+							state.producer_stats = Some(stats);
+						}
 					}
-					MasterMessage::Thread(ThreadMessage { message, thread }) => {
-						match message {
-							Message::Exit => {
-								let _ = threads.remove(&thread);
+					Message::Flushed => unreachable!(),
+				}
+			}
+			MasterMessage::Cleared(ClearedMessage {
+				client,
+				session,
+				threads,
+				failed,
+				res,
+			}) => {
+				// The annotated instructions were discarded.
+				if let Some(session) = store.get_mut(Some(&session)) {
+					if let Err(err) = block_in_place(|| session.remove_thread_annotations(&threads)) {
+						warn!("failed to update session '{}': {err:?}", session.name());
+					}
+				}
+
+				let packet = if failed {
+					Packet::Error(PacketError::Io)
+				} else {
+					Packet::TraceCleared { threads }
+				};
+				trace!(client; "--> {packet:?}");
+				let _ = res.send(packet);
+			}
+			MasterMessage::OpenStream(OpenStreamMessage {
+				client,
+				stream,
+				session,
+				thread_id,
+				filter,
+			}) => {
+				let session = match session {
+					Some(name) => block_in_place(|| store.open_session(&name)).ok().flatten(),
+					None => store.get(None),
+				};
+
+				let Some((trace, streams)) = session
+					.and_then(|session| session.threads.get(&thread_id))
+					.map(|state| (state.trace.clone(), state.streams.clone()))
+				else {
+					// Just disconnect.
+					debug!(client, thread = thread_id; "stream requested for unknown thread");
+					continue;
+				};
+
+				let tracked = streams.open(client);
+
+				tokio::spawn(async move {
+					debug!(client, thread = thread_id; "stream opened");
+					match stream_trace(stream, trace, filter, tracked).await {
+						Ok(()) => debug!(client, thread = thread_id; "stream closed"),
+						Err(err) => warn!(client, thread = thread_id; "stream failed: {err}"),
+					}
+				});
+			}
+			MasterMessage::Disconnected(client) => {
+				store.close_session(client);
+				store.unload_unused();
+			}
+			MasterMessage::Sync(res) => {
+				let _ = res.send(());
+			}
+			MasterMessage::Stats(res) => {
+				let _ = res.send(daemon_stats(&store, started));
+			}
+			MasterMessage::Client(ClientMessage {
+				client,
+				session,
+				req,
+				res,
+			}) => {
+				trace!(client; "<-- {req:?}");
+
+				macro_rules! respond {
+					($res:expr, $packet:expr) => {
+						let packet = $packet;
+						trace!(client; "--> {packet:?}");
+						// The client may have disconnected in the meantime.
+						let _ = $res.send(packet);
+					};
+				}
+
+				// Requests about a thread may name a session other than the connection's.
+				let session = match requested_session(&req) {
+					Some(name) => {
+						match block_in_place(|| store.open_session(name)) {
+							Ok(Some(_)) => {
+								store.keep_named(client, name);
+								Some(name.to_owned())
 							}
-							Message::Idle => {
-								threads.get_mut(&thread).map(|state| {
-									state.status = ThreadStatus::Idle;
-								});
+							Ok(None) => {
+								respond!(res, Packet::Error(PacketError::BadSession));
+								continue;
 							}
-							Message::Resume => {
-								threads.get_mut(&thread).map(|state| {
-									state.status = ThreadStatus::Running;
-								});
+							Err(err) => {
+								warn!("failed to open session '{name}': {err:?}");
+								respond!(res, Packet::Error(PacketError::Io));
+								continue;
 							}
 						}
 					}
-					MasterMessage::OpenStream(OpenStreamMessage {
-						mut stream,
+					None => session,
+				};
+
+				let thread = |thread_id| {
+					store
+						.get(session.as_deref())
+						.and_then(|session| session.threads.get(&thread_id))
+				};
+
+				match req {
+					Packet::GetStatus { thread_id, .. } => {
+						let status = thread(thread_id)
+							.map(|state| state.status)
+							.unwrap_or(ThreadStatus::Dead);
+
+						respond!(res, Packet::Status { status });
+					}
+					Packet::GetInstCount { thread_id, .. } => {
+						let count = thread(thread_id)
+							.map(|state| state.trace.len())
+							.unwrap_or(0);
+
+						respond!(res, Packet::InstCount { count });
+					}
+					Packet::GetRange {
 						thread_id,
+						start,
+						count,
 						filter,
-					}) => {
-						let Some(mut file) = threads
-							.get(&thread_id)
-							.map(|state| state.temp_file.try_clone().expect("failed to clone file"))
+						..
+					} => {
+						let Some(trace) = thread(thread_id).map(|state| state.trace.clone()) else {
+							respond!(res, Packet::Error(PacketError::BadThread));
+							continue;
+						};
+
+						// Reads may have to scan a large part of the file when filtering,
+						// so don't hold up the master loop while doing so.
+						tokio::task::spawn_blocking(move || {
+							let count = count.min(MAX_RANGE_COUNT);
+							let cancelled = || res.is_closed();
+							let packet = match read_range(&trace, start, count, filter, cancelled) {
+								Ok(entries) => Packet::Range { entries },
+								Err(err) => {
+									warn!("failed to read range from trace file: {err:?}");
+									Packet::Error(PacketError::Io)
+								}
+							};
+
+							respond!(res, packet);
+						});
+					}
+					Packet::Search {
+						thread_id,
+						range,
+						op,
+						limit,
+						..
+					} => {
+						let Some(trace) = thread(thread_id).map(|state| state.trace.clone()) else {
+							respond!(res, Packet::Error(PacketError::BadThread));
+							continue;
+						};
+
+						tokio::task::spawn_blocking(move || {
+							let limit = limit.min(MAX_RANGE_COUNT);
+							let cancelled = || res.is_closed();
+							let packet = match search(&trace, range, op, limit, cancelled) {
+								Ok(packet) => packet,
+								Err(err) => {
+									warn!("failed to search trace file: {err:?}");
+									Packet::Error(PacketError::Io)
+								}
+							};
+
+							respond!(res, packet);
+						});
+					}
+					Packet::GetHistogram {
+						thread_id,
+						start,
+						end,
+						buckets,
+						..
+					} => {
+						let Some((trace, cache)) =
+							thread(thread_id).map(|state| (state.trace.clone(), state.histogram.clone()))
 						else {
-							// Just disconnect.
+							respond!(res, Packet::Error(PacketError::BadThread));
 							continue;
 						};
 
-						std::thread::spawn(move || {
-							let mut counter = 0;
+						tokio::task::spawn_blocking(move || {
+							let cancelled = || res.is_closed();
+							let packet = match histogram(&trace, &cache, start, end, buckets, cancelled) {
+								Ok(entries) => Packet::Histogram { entries },
+								Err(err) => {
+									warn!("failed to build histogram from trace file: {err:?}");
+									Packet::Error(PacketError::Io)
+								}
+							};
 
-							file.seek(SeekFrom::Start(0))
-								.expect("failed to seek to start");
+							respond!(res, packet);
+						});
+					}
+					Packet::GetStorageStats { thread_id, .. } => {
+						let Some(stats) = thread(thread_id).map(|state| state.trace.index().stats()) else {
+							respond!(res, Packet::Error(PacketError::BadThread));
+							continue;
+						};
 
-							const BUFFER_SIZE: usize = 4096 * 4096 * 16;
-							let mut buffer = Box::new([0u8; BUFFER_SIZE]);
-							let mut write_buffer = Box::new([0u8; BUFFER_SIZE]);
+						respond!(res, Packet::StorageStats { stats });
+					}
+					Packet::ListSessions => {
+						let packet = match block_in_place(|| store.list()) {
+							Ok(sessions) => Packet::Sessions { sessions },
+							Err(err) => {
+								warn!("failed to list sessions: {err:?}");
+								Packet::Error(PacketError::Io)
+							}
+						};
 
-							let mut eofcount = 0;
+						respond!(res, packet);
+					}
+					Packet::OpenSession { name } => {
+						let packet = match block_in_place(|| store.open_session(&name)) {
+							Ok(Some(session)) => {
+								Packet::SessionOpened {
+									session: session.info(),
+								}
+							}
+							Ok(None) => Packet::Error(PacketError::BadSession),
+							Err(err) => {
+								warn!("failed to open session '{name}': {err:?}");
+								Packet::Error(PacketError::Io)
+							}
+						};
 
-							loop {
-								let size = file.metadata().map(|m| m.len()).unwrap() / 8;
-								let available = (size - counter).min((buffer.len() as u64) / 8);
+						if let Packet::SessionOpened { .. } = packet {
+							store.keep_open(client, &name);
+							// The session it had open before, if no one else does.
+							store.unload_unused();
+						}
 
-								if available == 0 {
-									std::thread::sleep(Duration::from_millis(50));
+						respond!(res, packet);
+					}
+					Packet::GetDaemonStats => {
+						respond!(
+							res,
+							Packet::DaemonStats {
+								stats: daemon_stats(&store, started),
+							}
+						);
+					}
+					Packet::ClearTrace { thread_id, .. } => {
+						let Some(target) = store.get(session.as_deref()) else {
+							respond!(res, Packet::Error(PacketError::BadSession));
+							continue;
+						};
+
+						let mut threads = match thread_id {
+							Some(thread_id) => {
+								let Some(state) = target.threads.get(&thread_id) else {
+									respond!(res, Packet::Error(PacketError::BadThread));
 									continue;
-								}
+								};
 
-								file.seek(SeekFrom::Start(counter * 8))
-									.expect("failed to seek to start");
-
-								if let Err(err) = file.read_exact(&mut buffer[..(available as usize * 8)]) {
-									if err.kind() == ErrorKind::UnexpectedEof {
-										eofcount += 1;
-										if eofcount > 10 {
-											log::warn!("reached EOF 10 times, closing stream");
-											break;
-										}
-									} else {
-										panic!("failed to read from file: {err:?}");
-									}
-								}
+								vec![(thread_id, state)]
+							}
+							None => {
+								target
+									.threads
+									.iter()
+									.map(|(&id, state)| (id, state))
+									.collect()
+							}
+						};
+						threads.retain(|(_, state)| state.status != ThreadStatus::Dead);
+						threads.sort_by_key(|(id, _)| *id);
 
-								// At least 1 so that we block until one is available.
-								counter += available;
-
-								let mut cursor = Cursor::new(&buffer[..(available as usize * 8)]);
-								let mut write_cursor = Cursor::new(&mut write_buffer[..]);
-
-								for _ in 0..available {
-									if let Ok(addr) = cursor.read_u64::<LittleEndian>() {
-										let include = match filter {
-											None => true,
-											Some(TraceFilter::LowerHalf) => addr & 0x8000_0000_0000_0000 == 0,
-										};
-
-										if include {
-											write_cursor
-												.write_u64::<LittleEndian>(addr)
-												.expect("failed to write to buffer");
-										}
-									} else {
-										break;
-									}
-								}
+						if thread_id.is_some() && threads.is_empty() {
+							respond!(res, Packet::Error(PacketError::NotRecording));
+							continue;
+						}
 
-								let byte_count = write_cursor.position() as usize;
-								drop(write_cursor);
+						// Traces are cleared by the tasks recording them, which reply once done.
+						let pending = threads
+							.into_iter()
+							.filter_map(|(id, state)| {
+								let (done, recv) = oneshot::channel();
+								state.clear.as_ref()?.send(done).ok()?;
+								Some((id, recv))
+							})
+							.collect::<Vec<_>>();
 
-								if byte_count > 0 {
-									stream
-										.write_all(&write_buffer[..byte_count])
-										.expect("failed to send trace log");
-									log::debug!("sent {} bytes to trace stream", byte_count);
+						// The master removes the cleared threads' annotations before replying.
+						let session = target.name().to_string();
+						let master_send = master_send.clone();
+						tokio::spawn(async move {
+							let mut threads = Vec::with_capacity(pending.len());
+							let mut failed = false;
+							for (id, recv) in pending {
+								match recv.await {
+									Ok(Ok(())) => threads.push(id),
+									Ok(Err(err)) => {
+										warn!(thread = id; "failed to clear trace: {err:?}");
+										failed = true;
+									}
+									// The thread exited before its trace was cleared.
+									Err(_) => {}
 								}
 							}
+
+							let _ = master_send.send(MasterMessage::Cleared(ClearedMessage {
+								client,
+								session,
+								threads,
+								failed,
+								res,
+							}));
 						});
 					}
-					MasterMessage::Client(ClientMessage { req, res }) => {
-						trace!("<-- {req:?}");
-
-						macro_rules! respond {
-							($res:expr, $packet:expr) => {
-								let packet = $packet;
-								trace!("--> {packet:?}");
-								$res.set(packet).expect("failed to set response");
-							};
+					Packet::AddAnnotation {
+						thread_id,
+						index,
+						kind,
+						text,
+						..
+					} => {
+						let Some(target) = store.get_mut(session.as_deref()) else {
+							respond!(res, Packet::Error(PacketError::BadSession));
+							continue;
+						};
+
+						if !target.threads.contains_key(&thread_id) {
+							respond!(res, Packet::Error(PacketError::BadThread));
+							continue;
 						}
 
-						match req {
-							Packet::GetStatus { thread_id } => {
-								let status = threads
-									.get(&thread_id)
-									.map(|state| state.status)
-									.unwrap_or(ThreadStatus::Dead);
+						// Bookmarks are useless without a name.
+						if text.len() > MAX_ANNOTATION_LEN
+							|| (kind == AnnotationKind::Bookmark && text.trim().is_empty())
+						{
+							respond!(res, Packet::Error(PacketError::BadAnnotation));
+							continue;
+						}
 
-								respond!(res, Packet::Status { status });
-							}
-							Packet::GetInstCount { thread_id } => {
-								let count = threads
-									.get(&thread_id)
-									.map(|state| state.addr_counter.load(Relaxed))
-									.unwrap_or(0);
+						let packet =
+							match block_in_place(|| target.add_annotation(thread_id, index, kind, text)) {
+								Ok(annotation) => Packet::AnnotationAdded { annotation },
+								Err(err) => {
+									warn!("failed to update session '{}': {err:?}", target.name());
+									Packet::Error(PacketError::Io)
+								}
+							};
 
-								respond!(res, Packet::InstCount { count });
-							}
-							Packet::OpenStream { .. } => {
-								unreachable!()
+						respond!(res, packet);
+					}
+					Packet::ListAnnotations { thread_id, .. } => {
+						let Some(target) = store.get(session.as_deref()) else {
+							respond!(res, Packet::Error(PacketError::BadSession));
+							continue;
+						};
+
+						respond!(
+							res,
+							Packet::Annotations {
+								annotations: target.annotations(thread_id),
 							}
-							_ => {
-								res.set(Packet::Error(PacketError::BadPacket))
-									.expect("failed to set response");
+						);
+					}
+					Packet::RemoveAnnotation { id, .. } => {
+						let Some(target) = store.get_mut(session.as_deref()) else {
+							respond!(res, Packet::Error(PacketError::BadSession));
+							continue;
+						};
+
+						let packet = match block_in_place(|| target.remove_annotation(id)) {
+							Ok(true) => Packet::AnnotationRemoved { id },
+							Ok(false) => Packet::Error(PacketError::BadAnnotation),
+							Err(err) => {
+								warn!("failed to update session '{}': {err:?}", target.name());
+								Packet::Error(PacketError::Io)
 							}
-						}
+						};
+
+						respond!(res, packet);
+					}
+					Packet::OpenStream { .. } => {
+						unreachable!()
+					}
+					_ => {
+						respond!(res, Packet::Error(PacketError::BadPacket));
 					}
 				}
 			}
 		}
+	}
+}
+
+/// Returns the session named by a request about a thread, if any.
+fn requested_session(req: &Packet) -> Option<&str> {
+	match req {
+		Packet::GetStatus { session, .. }
+		| Packet::GetInstCount { session, .. }
+		| Packet::OpenStream { session, .. }
+		| Packet::GetRange { session, .. }
+		| Packet::Search { session, .. }
+		| Packet::GetHistogram { session, .. }
+		| Packet::GetStorageStats { session, .. }
+		| Packet::ClearTrace { session, .. }
+		| Packet::AddAnnotation { session, .. }
+		| Packet::ListAnnotations { session, .. }
+		| Packet::RemoveAnnotation { session, .. } => session.as_deref(),
+		_ => None,
+	}
+}
+
+/// Updates the ingest rate of every thread in memory.
+fn sample_ingest_rates(store: &mut SessionStore, now: Instant) {
+	for session in store.loaded_mut() {
+		for state in session.threads.values_mut() {
+			let count = state.trace.len();
+			state.ingest.sample(now, count);
+		}
+	}
+}
+
+/// Collects statistics about every thread in memory.
+fn daemon_stats(store: &SessionStore, started: Instant) -> DaemonStats {
+	let mut threads = store
+		.loaded()
+		.flat_map(|session| {
+			session.threads.iter().map(|(&thread_id, state)| {
+				let instructions = state.trace.len();
+				ThreadStats {
+					session: session.name().to_string(),
+					thread_id,
+					status: state.status,
+					instructions,
+					ingest_rate: state.ingest.per_sec,
+					stored_bytes: state.trace.index().stored_bytes(),
+					open_streams: state.streams.count(),
+					stream_lag: state.streams.lag(state.trace.len()),
+					dropped: state.trace.index().dropped(),
+					reconnects: state.reconnects,
+					producer: state.producer_stats,
+				}
+			})
+		})
+		.collect::<Vec<_>>();
+
+	threads.sort_by(|a, b| {
+		a.session
+			.cmp(&b.session)
+			.then_with(|| a.thread_id.cmp(&b.thread_id))
 	});
 
-	this
+	DaemonStats {
+		uptime_secs: started.elapsed().as_secs(),
+		threads,
+	}
+}
+
+/// Streams a thread's trace to a client, starting from the oldest retained chunk
+/// and following the writer until the client disconnects.
+async fn stream_trace(
+	mut stream: UnixStream,
+	trace: TraceReader,
+	filter: Option<TraceFilter>,
+	tracked: TrackedStream,
+) -> io::Result<()> {
+	let mut next_chunk = trace.index().first_chunk();
+	let (mut clears, _) = trace.index().cleared();
+	// The index of the next instruction to send.
+	let mut sent: usize = 0;
+	let mut addrs = Vec::new();
+
+	loop {
+		let chunk = trace.index().chunk(next_chunk);
+
+		// Checked after looking up the chunk, so that no chunk written after the trace
+		// was cleared is sent before the client is told about it.
+		let (latest_clears, cleared_at) = trace.index().cleared();
+		if latest_clears != clears {
+			clears = latest_clears;
+			next_chunk = next_chunk.max(cleared_at);
+			sent = 0;
+			tracked.advance(0);
+
+			if stream
+				.write_all(&STREAM_CLEARED.to_le_bytes())
+				.await
+				.is_err()
+			{
+				return Ok(());
+			}
+			continue;
+		}
+
+		if let Some(chunk) = chunk {
+			if let Err(err) = block_in_place(|| trace.read_chunk(&chunk, &mut addrs)) {
+				// The chunk may have been dropped while reading it.
+				if next_chunk < trace.index().first_chunk() {
+					continue;
+				}
+
+				return Err(err);
+			}
+
+			next_chunk += 1;
+
+			// Part of the chunk may have been sent before it was written out.
+			addrs.drain(..sent.saturating_sub(chunk.first_index).min(addrs.len()));
+			sent = sent.max(chunk.end_index());
+		} else {
+			// Skip ahead if the chunk was dropped due to storage limits.
+			let first_chunk = trace.index().first_chunk();
+			if next_chunk < first_chunk {
+				next_chunk = first_chunk;
+				continue;
+			}
+
+			// Send whatever has been published since, without waiting for it to fill a chunk.
+			let Some(first_index) = trace
+				.index()
+				.read_tail(next_chunk, clears, sent, &mut addrs)
+			else {
+				// Written out (or cleared) in the meantime.
+				continue;
+			};
+
+			if addrs.is_empty() {
+				// Everything recorded so far has been sent.
+				tracked.advance(sent);
+
+				// Clients never send anything once a stream is open, so a read only
+				// completes when they disconnect (or misbehave).
+				let mut probe = [0; 1];
+				tokio::select! {
+					() = trace.index().wait_for_more(next_chunk, clears, sent) => continue,
+					_ = stream.read(&mut probe) => return Ok(()),
+				}
+			}
+
+			sent = first_index + addrs.len();
+		}
+
+		tracked.advance(sent);
+
+		if let Some(filter) = filter {
+			addrs.retain(|addr| filter.matches(*addr));
+		}
+
+		if !addrs.is_empty() {
+			let bytes = addr_bytes(&mut addrs);
+			// Failing to write means the client closed the stream.
+			if stream.write_all(bytes).await.is_err() {
+				return Ok(());
+			}
+			trace!("sent {} bytes to trace stream", bytes.len());
+		}
+	}
+}
+
+/// Converts addresses to the stream's wire format (little endian) in place,
+/// returning them as bytes that can be written out without copying.
+fn addr_bytes(addrs: &mut [u64]) -> &[u8] {
+	for addr in addrs.iter_mut() {
+		*addr = addr.to_le();
+	}
+
+	// SAFETY: `u64` has no padding, and any byte is a valid `u8`.
+	unsafe { std::slice::from_raw_parts(addrs.as_ptr().cast(), size_of_val(addrs)) }
+}
+
+/// Returns whether or not a chunk may contain instructions passing the filter.
+fn filter_may_match(filter: Option<TraceFilter>, chunk: &ChunkInfo) -> bool {
+	match filter {
+		None => true,
+		// The lowest address is the most likely to be in the lower half.
+		Some(f @ TraceFilter::LowerHalf) => f.matches(chunk.min_addr),
+	}
+}
+
+/// Returns whether or not a chunk may contain addresses within the range.
+fn range_may_match(range: AddrRange, chunk: &ChunkInfo) -> bool {
+	chunk.min_addr < range.end && chunk.max_addr >= range.start
+}
+
+/// Reads up to `count` instructions from a trace, starting at the absolute
+/// instruction index `start`. If a filter is given, non-matching instructions
+/// are skipped (and do not count towards `count`).
+///
+/// Like the other queries, stops scanning early (returning a partial result) once
+/// `cancelled` returns `true`.
+fn read_range(
+	trace: &TraceReader,
+	start: usize,
+	count: usize,
+	filter: Option<TraceFilter>,
+	cancelled: impl Fn() -> bool,
+) -> io::Result<Vec<InstEntry>> {
+	let available = trace.len();
+	let mut entries = Vec::with_capacity(count.min(available.saturating_sub(start)));
+
+	if count > 0 {
+		trace.scan_forward(
+			start,
+			available,
+			|chunk| !cancelled() && filter_may_match(filter, chunk),
+			|index, addr| {
+				if filter.is_none_or(|f| f.matches(addr)) {
+					entries.push(InstEntry { index, addr });
+					if entries.len() == count {
+						return ControlFlow::Break(());
+					}
+				}
+
+				ControlFlow::Continue(())
+			},
+		)?;
+	}
+
+	Ok(entries)
+}
+
+/// Searches a trace for executions of addresses within `range`, returning
+/// either a [`Packet::SearchResult`] or a [`Packet::SearchCount`] depending on `op`.
+fn search(
+	trace: &TraceReader,
+	range: AddrRange,
+	op: SearchOp,
+	limit: usize,
+	cancelled: impl Fn() -> bool,
+) -> io::Result<Packet> {
+	let available = trace.len();
+	let may_match = |chunk: &ChunkInfo| !cancelled() && range_may_match(range, chunk);
+	let mut hits = Vec::new();
+
+	let mut collect = |index, addr| {
+		if range.contains(addr) {
+			hits.push(InstEntry { index, addr });
+			if hits.len() >= limit {
+				return ControlFlow::Break(());
+			}
+		}
+
+		ControlFlow::Continue(())
+	};
+
+	match op {
+		SearchOp::First if limit > 0 => trace.scan_forward(0, available, may_match, &mut collect)?,
+		SearchOp::Next { after } if limit > 0 => {
+			trace.scan_forward(after.saturating_add(1), available, may_match, &mut collect)?;
+		}
+		SearchOp::Last if limit > 0 => {
+			trace.scan_backward(available, may_match, &mut collect)?;
+			hits.reverse();
+		}
+		SearchOp::Prev { before } if limit > 0 => {
+			trace.scan_backward(before.min(available), may_match, &mut collect)?;
+			hits.reverse();
+		}
+		SearchOp::First | SearchOp::Next { .. } | SearchOp::Last | SearchOp::Prev { .. } => {}
+		SearchOp::Nth { n } => {
+			let mut seen = 0;
+			trace.scan_forward(0, available, may_match, |index, addr| {
+				if range.contains(addr) {
+					if seen == n {
+						hits.push(InstEntry { index, addr });
+						return ControlFlow::Break(());
+					}
+
+					seen += 1;
+				}
+
+				ControlFlow::Continue(())
+			})?;
+		}
+		SearchOp::Count => {
+			let mut count = 0;
+			trace.scan_forward(0, available, may_match, |_, addr| {
+				if range.contains(addr) {
+					count += 1;
+				}
+
+				ControlFlow::Continue(())
+			})?;
+
+			return Ok(Packet::SearchCount { count });
+		}
+	}
+
+	Ok(Packet::SearchResult { hits })
+}
+
+/// Builds a histogram of executed addresses between the instruction indices `start`
+/// and `end` (exclusive; defaults to the end of the trace).
+///
+/// Histograms starting at the beginning of the trace are served from (and extend)
+/// the thread's cache, so that repeated queries only scan newly recorded instructions.
+/// Cached scans are never cancelled, since they'd leave the cache incomplete.
+///
+/// Without buckets, there's an entry for each address, up to [`MAX_RANGE_COUNT`].
+fn histogram(
+	trace: &TraceReader,
+	cache: &Mutex<HistogramCache>,
+	start: usize,
+	end: Option<usize>,
+	buckets: Option<Vec<AddrRange>>,
+	cancelled: impl Fn() -> bool,
+) -> io::Result<Vec<HistogramEntry>> {
+	let (clears, _) = trace.index().cleared();
+	let first_index = trace.index().first_index();
+	let available = trace.len();
+	let end = end.map_or(available, |end| end.min(available));
+
+	let mut cache = cache.lock().unwrap();
+
+	// Start over if the trace was cleared, or chunks were dropped from it, since the
+	// cache was built, so that it doesn't count instructions that can't be scanned.
+	if (cache.clears, cache.first_index) != (clears, first_index) {
+		*cache = HistogramCache {
+			clears,
+			first_index,
+			..Default::default()
+		};
+	}
+
+	if start == 0 && end >= cache.scanned {
+		let HistogramCache {
+			scanned, counts, ..
+		} = &mut *cache;
+		count_addresses(trace, *scanned, end, counts, || false)?;
+		*scanned = end;
+		Ok(fold_histogram(counts, buckets))
+	} else {
+		drop(cache);
+		let mut counts = HashMap::new();
+		count_addresses(trace, start, end, &mut counts, cancelled)?;
+		Ok(fold_histogram(&counts, buckets))
+	}
+}
+
+/// Adds the execution counts of each address between `start` and `end` (exclusive) to `counts`.
+fn count_addresses(
+	trace: &TraceReader,
+	start: usize,
+	end: usize,
+	counts: &mut HashMap<u64, usize>,
+	cancelled: impl Fn() -> bool,
+) -> io::Result<()> {
+	trace.scan_forward(
+		start,
+		end,
+		|_| !cancelled(),
+		|_, addr| {
+			*counts.entry(addr).or_default() += 1;
+			ControlFlow::Continue(())
+		},
+	)
+}
+
+/// Turns per-address counts into histogram entries, either one per address (sorted
+/// by address) or one per bucket (in the order given).
+fn fold_histogram(counts: &HashMap<u64, usize>, buckets: Option<Vec<AddrRange>>) -> Vec<HistogramEntry> {
+	let Some(buckets) = buckets else {
+		let mut entries = counts
+			.iter()
+			.map(|(addr, count)| {
+				HistogramEntry {
+					range: AddrRange::single(*addr),
+					count: *count,
+				}
+			})
+			.collect::<Vec<_>>();
+		entries.sort_unstable_by_key(|entry| entry.range.start);
+		entries.truncate(MAX_RANGE_COUNT);
+		return entries;
+	};
+
+	let mut bucket_counts = vec![0; buckets.len()];
+	for (addr, count) in counts {
+		for (bucket, bucket_count) in buckets.iter().zip(bucket_counts.iter_mut()) {
+			if bucket.contains(*addr) {
+				*bucket_count += count;
+			}
+		}
+	}
+
+	buckets
+		.into_iter()
+		.zip(bucket_counts)
+		.map(|(range, count)| HistogramEntry { range, count })
+		.collect()
+}
+
+/// Per-address execution counts for the instructions of a thread from `first_index`
+/// (the oldest retained one) up to `scanned`, since its trace was last cleared.
+#[derive(Default)]
+pub struct HistogramCache {
+	clears:      usize,
+	first_index: usize,
+	scanned:     usize,
+	counts:      HashMap<u64, usize>,
+}
+
+/// The recent ingest rate of a thread, sampled once a second.
+#[derive(Default)]
+pub struct IngestRate {
+	last_sample: Option<(Instant, usize)>,
+	per_sec:     f64,
+}
+
+impl IngestRate {
+	fn sample(&mut self, now: Instant, count: usize) {
+		if let Some((at, last_count)) = self.last_sample {
+			let secs = now.duration_since(at).as_secs_f64();
+			if secs > 0.0 {
+				self.per_sec = count.saturating_sub(last_count) as f64 / secs;
+			}
+		}
+
+		self.last_sample = Some((now, count));
+	}
+}
+
+/// The streams open on a thread, along with how much of the trace each has been sent.
+#[derive(Default)]
+pub struct StreamTracker {
+	/// The index of the next instruction to be sent, for each streaming client.
+	positions: Mutex<HashMap<u64, usize>>,
+}
+
+impl StreamTracker {
+	/// Tracks a client's stream until the returned handle is dropped.
+	fn open(self: &Arc<Self>, client: u64) -> TrackedStream {
+		self.positions.lock().unwrap().insert(client, 0);
+		TrackedStream {
+			tracker: self.clone(),
+			client,
+		}
+	}
+
+	pub fn count(&self) -> usize {
+		self.positions.lock().unwrap().len()
+	}
+
+	/// Returns how many of the first `len` instructions the slowest stream has yet to be sent.
+	fn lag(&self, len: usize) -> usize {
+		self.positions
+			.lock()
+			.unwrap()
+			.values()
+			.min()
+			.map_or(0, |&position| len.saturating_sub(position))
+	}
+}
+
+struct TrackedStream {
+	tracker: Arc<StreamTracker>,
+	client:  u64,
+}
+
+impl TrackedStream {
+	/// Records that every instruction before `position` has been sent.
+	fn advance(&self, position: usize) {
+		self.tracker
+			.positions
+			.lock()
+			.unwrap()
+			.insert(self.client, position);
+	}
+}
+
+impl Drop for TrackedStream {
+	fn drop(&mut self) {
+		self.tracker.positions.lock().unwrap().remove(&self.client);
+	}
 }
 
 pub struct ThreadState {
-	pub id:           u32,
-	pub temp_file:    File,
-	pub addr_counter: Arc<AtomicUsize>,
-	pub status:       ThreadStatus,
+	pub trace:          TraceReader,
+	pub status:         ThreadStatus,
+	pub histogram:      Arc<Mutex<HistogramCache>>,
+	pub streams:        Arc<StreamTracker>,
+	pub ingest:         IngestRate,
+	/// The number of times the thread's producer connected it before, in earlier sessions.
+	pub reconnects:     u32,
+	/// The latest statistics sent by the producer.
+	pub producer_stats: Option<ProducerStats>,
+	/// Asks the task recording the thread to clear its trace, if it's being recorded.
+	pub clear:          Option<UnboundedSender<ClearRequest>>,
 }
 
+/// Sent to the task recording a thread to clear its trace, replying once it's done.
+pub type ClearRequest = oneshot::Sender<io::Result<()>>;
+
 pub struct QueryServer {
-	master_send: Sender<MasterMessage>,
+	master_send: UnboundedSender<MasterMessage>,
+	sock_path:   String,
 }
 
 impl QueryServer {
-	pub fn new_thread(&self, thread_state: ThreadState) -> QueryServerThread {
-		let res = Arc::new(OnceLock::new());
-		let thread_id = thread_state.id;
+	/// Starts recording a new thread for the given producer, whose process has the given PID.
+	/// Requests to clear the thread's trace are sent to `clear`.
+	pub async fn new_thread(
+		&self,
+		pid: Option<i32>,
+		producer: Option<u64>,
+		thread_id: u32,
+		clear: UnboundedSender<ClearRequest>,
+	) -> io::Result<(QueryServerThread, TraceWriter)> {
+		let (res, recv) = oneshot::channel();
 
-		self.master_send
+		let _ = self
+			.master_send
 			.send(MasterMessage::Connection(ConnectionMessage {
-				thread_state,
-				res: res.clone(),
-			}))
-			.expect("failed to send message to master");
+				pid,
+				producer,
+				thread_id,
+				clear,
+				res,
+			}));
 
-		QueryServerThread {
-			thread_id,
-			sender: res.wait().clone(),
-		}
+		recv.await
+			.unwrap_or_else(|_| Err(io::Error::other("query server is shutting down")))
+	}
+
+	/// Returns statistics about the daemon and every thread in memory.
+	pub async fn daemon_stats(&self) -> io::Result<DaemonStats> {
+		let (res, recv) = oneshot::channel();
+		let _ = self.master_send.send(MasterMessage::Stats(res));
+		recv.await
+			.map_err(|_| io::Error::other("query server is shutting down"))
+	}
+
+	/// Waits for all messages from producers sent so far to be handled (so that
+	/// session metadata is up to date), then removes the socket.
+	pub async fn shutdown(&self) {
+		let (res, recv) = oneshot::channel();
+		let _ = self.master_send.send(MasterMessage::Sync(res));
+		let _ = recv.await;
+
+		let _ = std::fs::remove_file(&self.sock_path);
 	}
 }
 
@@ -269,32 +1225,69 @@ enum MasterMessage {
 	Thread(ThreadMessage),
 	Client(ClientMessage),
 	OpenStream(OpenStreamMessage),
+	/// A client's request to clear traces was carried out by the threads recording them.
+	Cleared(ClearedMessage),
+	/// A client disconnected (or turned its connection into a stream).
+	Disconnected(u64),
+	/// Replied to once all previous messages have been handled.
+	Sync(oneshot::Sender<()>),
+	Stats(oneshot::Sender<DaemonStats>),
 }
 
 struct OpenStreamMessage {
+	client:    u64,
+	session:   Option<String>,
 	thread_id: u32,
 	stream:    UnixStream,
 	filter:    Option<TraceFilter>,
 }
 
+struct ClearedMessage {
+	client:  u64,
+	session: String,
+	/// The threads whose traces were cleared.
+	threads: Vec<u32>,
+	/// Whether or not clearing any of the traces failed.
+	failed:  bool,
+	res:     oneshot::Sender<Packet>,
+}
+
 struct ConnectionMessage {
-	thread_state: ThreadState,
-	res:          Arc<OnceLock<Sender<MasterMessage>>>,
+	pid:       Option<i32>,
+	producer:  Option<u64>,
+	thread_id: u32,
+	clear:     UnboundedSender<ClearRequest>,
+	res:       oneshot::Sender<io::Result<(QueryServerThread, TraceWriter)>>,
 }
 
 struct ClientMessage {
-	req: Packet,
-	res: Arc<OnceLock<Packet>>,
+	client:  u64,
+	session: Option<String>,
+	req:     Packet,
+	res:     oneshot::Sender<Packet>,
 }
 
 pub struct QueryServerThread {
+	session:   String,
 	thread_id: u32,
-	sender:    Sender<MasterMessage>,
+	sender:    UnboundedSender<MasterMessage>,
 }
 
 impl QueryServerThread {
+	/// Returns the name of the session the thread is recorded into.
+	#[inline]
+	pub fn session(&self) -> &str {
+		&self.session
+	}
+
+	#[inline]
+	pub fn thread_id(&self) -> u32 {
+		self.thread_id
+	}
+
 	fn send(&self, msg: Message) {
 		let _ = self.sender.send(MasterMessage::Thread(ThreadMessage {
+			session: self.session.clone(),
 			thread:  self.thread_id,
 			message: msg,
 		}));
@@ -311,6 +1304,15 @@ impl QueryServerThread {
 	pub fn exit(&self) {
 		self.send(Message::Exit);
 	}
+
+	/// Notifies the master that a chunk was written to the thread's trace.
+	pub fn flushed(&self) {
+		self.send(Message::Flushed);
+	}
+
+	pub fn producer_stats(&self, stats: ProducerStats) {
+		self.send(Message::ProducerStats(stats));
+	}
 }
 
 impl Drop for QueryServerThread {
@@ -320,6 +1322,7 @@ impl Drop for QueryServerThread {
 }
 
 struct ThreadMessage {
+	session: String,
 	thread:  u32,
 	message: Message,
 }
@@ -328,4 +1331,306 @@ enum Message {
 	Exit,
 	Idle,
 	Resume,
+	Flushed,
+	ProducerStats(ProducerStats),
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Creates an empty trace, returning its writer and a reader over it.
+	fn writer() -> (TraceWriter, TraceReader) {
+		let file = tempfile::tempfile().unwrap();
+		let writer = TraceWriter::create(file.try_clone().unwrap(), 0).unwrap();
+		let reader = TraceReader::new(file, writer.index().clone()).unwrap();
+		(writer, reader)
+	}
+
+	/// Appends the given addresses to a trace as a single chunk.
+	fn write_chunk(writer: &mut TraceWriter, addrs: &[u64]) {
+		for &addr in addrs {
+			writer.push(addr).unwrap();
+		}
+		writer.flush().unwrap();
+	}
+
+	/// Writes a trace made up of the given chunks, returning a reader over it.
+	fn trace(chunks: &[&[u64]]) -> TraceReader {
+		let (mut writer, reader) = writer();
+		for chunk in chunks {
+			write_chunk(&mut writer, chunk);
+		}
+
+		reader
+	}
+
+	fn entries(entries: &[(usize, u64)]) -> Vec<InstEntry> {
+		entries
+			.iter()
+			.map(|&(index, addr)| InstEntry { index, addr })
+			.collect()
+	}
+
+	const HIGH: u64 = 0xFFFF_8000_0000_0000;
+
+	#[test]
+	fn read_range_spans_chunks() {
+		let trace = trace(&[&[10, 11, 12], &[13, 14], &[15]]);
+
+		let range = read_range(&trace, 1, 4, None, || false).unwrap();
+		assert_eq!(range, entries(&[(1, 11), (2, 12), (3, 13), (4, 14)]));
+	}
+
+	#[test]
+	fn read_range_stops_at_end_of_trace() {
+		let trace = trace(&[&[10, 11], &[12]]);
+
+		assert_eq!(
+			read_range(&trace, 1, 10, None, || false).unwrap(),
+			entries(&[(1, 11), (2, 12)])
+		);
+		assert!(
+			read_range(&trace, 3, 10, None, || false)
+				.unwrap()
+				.is_empty()
+		);
+		assert!(read_range(&trace, 0, 0, None, || false).unwrap().is_empty());
+	}
+
+	#[test]
+	fn read_range_filter_skips_non_matching() {
+		let trace = trace(&[&[HIGH, 1, HIGH + 1], &[HIGH, HIGH], &[2, 3]]);
+
+		// Filtered-out instructions don't count towards the count.
+		let range = read_range(&trace, 0, 2, Some(TraceFilter::LowerHalf), || false).unwrap();
+		assert_eq!(range, entries(&[(1, 1), (5, 2)]));
+
+		let range = read_range(&trace, 2, 10, Some(TraceFilter::LowerHalf), || false).unwrap();
+		assert_eq!(range, entries(&[(5, 2), (6, 3)]));
+	}
+
+	#[test]
+	fn read_range_cancelled_returns_partial() {
+		let trace = trace(&[&[10, 11], &[12, 13]]);
+
+		assert!(read_range(&trace, 0, 4, None, || true).unwrap().is_empty());
+	}
+
+	fn hits(packet: Packet) -> Vec<InstEntry> {
+		let Packet::SearchResult { hits } = packet else {
+			panic!("expected a search result, got {packet:?}");
+		};
+		hits
+	}
+
+	/// `0x10` is executed at indices 0, 3, 5 and 7, spread over several chunks.
+	fn search_trace() -> TraceReader {
+		trace(&[&[0x10, 0x20, 0x30], &[0x10, 0x20], &[0x10, 0x30, 0x10]])
+	}
+
+	#[test]
+	fn search_first_and_last() {
+		let trace = search_trace();
+		let range = AddrRange::single(0x10);
+
+		let first = hits(search(&trace, range, SearchOp::First, 2, || false).unwrap());
+		assert_eq!(first, entries(&[(0, 0x10), (3, 0x10)]));
+
+		// Hits are returned in ascending order even when searching backwards.
+		let last = hits(search(&trace, range, SearchOp::Last, 2, || false).unwrap());
+		assert_eq!(last, entries(&[(5, 0x10), (7, 0x10)]));
+	}
+
+	#[test]
+	fn search_next_and_prev_exclude_the_given_index() {
+		let trace = search_trace();
+		let range = AddrRange::single(0x10);
+
+		let next = hits(search(&trace, range, SearchOp::Next { after: 3 }, 1, || false).unwrap());
+		assert_eq!(next, entries(&[(5, 0x10)]));
+
+		let prev = hits(search(&trace, range, SearchOp::Prev { before: 5 }, 1, || false).unwrap());
+		assert_eq!(prev, entries(&[(3, 0x10)]));
+	}
+
+	#[test]
+	fn search_prev_edges() {
+		let trace = search_trace();
+		let range = AddrRange::single(0x10);
+
+		// Nothing precedes the first instruction.
+		let prev = SearchOp::Prev { before: 0 };
+		assert!(hits(search(&trace, range, prev, 1, || false).unwrap()).is_empty());
+
+		// Indices past the end of the trace search from the end.
+		let prev = SearchOp::Prev { before: usize::MAX };
+		let hits = hits(search(&trace, range, prev, 1, || false).unwrap());
+		assert_eq!(hits, entries(&[(7, 0x10)]));
+	}
+
+	#[test]
+	fn search_next_edges() {
+		let trace = search_trace();
+		let range = AddrRange::single(0x10);
+
+		let next = SearchOp::Next { after: 7 };
+		assert!(hits(search(&trace, range, next, 1, || false).unwrap()).is_empty());
+
+		// Must not overflow.
+		let next = SearchOp::Next { after: usize::MAX };
+		assert!(hits(search(&trace, range, next, 1, || false).unwrap()).is_empty());
+	}
+
+	#[test]
+	fn search_nth_edges() {
+		let trace = search_trace();
+		let range = AddrRange::single(0x10);
+
+		let nth = |n| hits(search(&trace, range, SearchOp::Nth { n }, 0, || false).unwrap());
+		assert_eq!(nth(0), entries(&[(0, 0x10)]));
+		assert_eq!(nth(3), entries(&[(7, 0x10)]));
+		assert!(nth(4).is_empty());
+	}
+
+	#[test]
+	fn search_zero_limit_returns_nothing() {
+		let trace = search_trace();
+		let range = AddrRange::single(0x10);
+
+		for op in [
+			SearchOp::First,
+			SearchOp::Last,
+			SearchOp::Next { after: 0 },
+			SearchOp::Prev { before: 8 },
+		] {
+			assert!(hits(search(&trace, range, op, 0, || false).unwrap()).is_empty());
+		}
+	}
+
+	#[test]
+	fn search_range_is_half_open() {
+		let trace = search_trace();
+		let range = AddrRange {
+			start: 0x10,
+			end:   0x30,
+		};
+
+		let Packet::SearchCount { count } = search(&trace, range, SearchOp::Count, 0, || false).unwrap()
+		else {
+			panic!("expected a search count");
+		};
+		assert_eq!(count, 6);
+
+		let range = AddrRange {
+			start: 0x11,
+			end:   0x30,
+		};
+		let first = hits(search(&trace, range, SearchOp::First, 10, || false).unwrap());
+		assert_eq!(first, entries(&[(1, 0x20), (4, 0x20)]));
+	}
+
+	fn counts(entries: &[HistogramEntry]) -> Vec<(u64, usize)> {
+		entries
+			.iter()
+			.map(|entry| (entry.range.start, entry.count))
+			.collect()
+	}
+
+	#[test]
+	fn histogram_cache_extends_with_new_instructions() {
+		let (mut writer, trace) = writer();
+		let cache = Mutex::new(HistogramCache::default());
+		write_chunk(&mut writer, &[0x10, 0x20, 0x10]);
+
+		let entries = histogram(&trace, &cache, 0, None, None, || false).unwrap();
+		assert_eq!(counts(&entries), [(0x10, 2), (0x20, 1)]);
+		assert_eq!(cache.lock().unwrap().scanned, 3);
+
+		write_chunk(&mut writer, &[0x20, 0x30]);
+
+		let entries = histogram(&trace, &cache, 0, None, None, || false).unwrap();
+		assert_eq!(counts(&entries), [(0x10, 2), (0x20, 2), (0x30, 1)]);
+		assert_eq!(cache.lock().unwrap().scanned, 5);
+	}
+
+	#[test]
+	fn histogram_partial_ranges_bypass_cache() {
+		let (mut writer, trace) = writer();
+		let cache = Mutex::new(HistogramCache::default());
+		write_chunk(&mut writer, &[0x10, 0x20, 0x10, 0x30]);
+
+		let entries = histogram(&trace, &cache, 1, Some(3), None, || false).unwrap();
+		assert_eq!(counts(&entries), [(0x10, 1), (0x20, 1)]);
+
+		// Ending before the cached prefix can't be served from the cache either.
+		histogram(&trace, &cache, 0, None, None, || false).unwrap();
+		let entries = histogram(&trace, &cache, 0, Some(2), None, || false).unwrap();
+		assert_eq!(counts(&entries), [(0x10, 1), (0x20, 1)]);
+		assert_eq!(cache.lock().unwrap().scanned, 4);
+	}
+
+	#[test]
+	fn histogram_cache_resets_when_cleared() {
+		let (mut writer, trace) = writer();
+		let cache = Mutex::new(HistogramCache::default());
+		write_chunk(&mut writer, &[0x10, 0x10]);
+		histogram(&trace, &cache, 0, None, None, || false).unwrap();
+
+		writer.clear().unwrap();
+		write_chunk(&mut writer, &[0x20]);
+
+		let entries = histogram(&trace, &cache, 0, None, None, || false).unwrap();
+		assert_eq!(counts(&entries), [(0x20, 1)]);
+	}
+
+	#[test]
+	fn histogram_cache_resets_when_chunks_are_dropped() {
+		let (mut writer, trace) = writer();
+		let cache = Mutex::new(HistogramCache::default());
+		write_chunk(&mut writer, &[0x10, 0x10]);
+		write_chunk(&mut writer, &[0x20]);
+		histogram(&trace, &cache, 0, None, None, || false).unwrap();
+
+		trace.drop_oldest_chunk().unwrap();
+
+		// The same as if nothing had been cached.
+		let entries = histogram(&trace, &cache, 0, None, None, || false).unwrap();
+		assert_eq!(counts(&entries), [(0x20, 1)]);
+		let uncached = histogram(&trace, &Mutex::default(), 0, None, None, || false).unwrap();
+		assert_eq!(counts(&uncached), counts(&entries));
+	}
+
+	#[test]
+	fn histogram_addresses_are_capped() {
+		let (mut writer, trace) = writer();
+		let cache = Mutex::new(HistogramCache::default());
+		let addrs = (0..MAX_RANGE_COUNT as u64 + 10).rev().collect::<Vec<_>>();
+		write_chunk(&mut writer, &addrs);
+
+		let entries = histogram(&trace, &cache, 0, None, None, || false).unwrap();
+		assert_eq!(entries.len(), MAX_RANGE_COUNT);
+		assert_eq!(entries[0].range.start, 0);
+	}
+
+	#[test]
+	fn histogram_buckets_keep_their_order() {
+		let (mut writer, trace) = writer();
+		let cache = Mutex::new(HistogramCache::default());
+		write_chunk(&mut writer, &[0x10, 0x20, 0x10, 0x30]);
+
+		let buckets = vec![
+			AddrRange {
+				start: 0x20,
+				end:   0x40,
+			},
+			AddrRange {
+				start: 0x00,
+				end:   0x20,
+			},
+			AddrRange::single(0x50),
+		];
+		let entries = histogram(&trace, &cache, 0, None, Some(buckets), || false).unwrap();
+		assert_eq!(counts(&entries), [(0x20, 2), (0x00, 2), (0x50, 0)]);
+	}
 }
@@ -0,0 +1,196 @@
+//! Replays recorded traces into a daemon over the plugin protocol, as if their
+//! producer (e.g. QEMU) were running again. Useful for demoing and testing the
+//! daemon and frontends without QEMU.
+//!
+//! Traces only hold the instructions executed, not when their vCPU went idle or
+//! resumed, so those events aren't replayed: each vCPU is initialized and resumed,
+//! runs through its instructions, then exits.
+
+use std::{
+	collections::HashMap,
+	fs,
+	io::{self, BufWriter, Write},
+	os::unix::net::UnixStream,
+	path::{Path, PathBuf},
+	thread,
+	time::{Duration, Instant},
+};
+
+use ktrace_plugin_protocol::{Inst, Packet, TraceWrite, VcpuInit};
+use log::{error, info};
+
+use crate::trace_file::TraceReader;
+
+/// How often paced replays flush what they've sent, per second.
+const PACE_STEPS_PER_SEC: u64 = 100;
+
+/// Replays recorded traces into a running daemon.
+#[derive(clap::Args, Debug)]
+pub struct ReplayArgs {
+	/// The session directories and trace files (`cpuN.ktrace`) to replay. Each trace
+	/// is replayed as the vCPU it was recorded from, all into one new session.
+	#[clap(required = true)]
+	paths: Vec<PathBuf>,
+	/// The number of instructions to send per second, per vCPU [default: as fast as possible].
+	#[clap(short = 'r', long = "rate", value_parser = clap::value_parser!(u64).range(1..))]
+	rate:  Option<u64>,
+	/// Leave the vCPUs connected once replayed, until interrupted, instead of exiting them.
+	#[clap(long = "hold")]
+	hold:  bool,
+}
+
+/// Replays the traces to the daemon listening on `sock_path`, returning whether
+/// or not every trace was replayed.
+pub fn run(sock_path: &str, args: ReplayArgs) -> bool {
+	let traces = match open_traces(&args.paths) {
+		Ok(traces) => traces,
+		Err(err) => {
+			error!("{err}");
+			return false;
+		}
+	};
+
+	// Shared by the vCPUs, so that they're recorded into one session.
+	let producer = ktrace_plugin_protocol::producer_id();
+
+	thread::scope(|scope| {
+		let replays = traces
+			.into_iter()
+			.map(|(id, trace)| {
+				let args = &args;
+				let handle = scope.spawn(move || {
+					let (stream, sent) = replay_vcpu(sock_path, producer, id, &trace, args)?;
+					exit_vcpu(stream, id, sent, args)
+				});
+				(id, handle)
+			})
+			.collect::<Vec<_>>();
+
+		let mut ok = true;
+		for (id, handle) in replays {
+			match handle.join().expect("replay thread panicked") {
+				Ok(count) => info!(vcpu = id; "replayed {count} instruction(s)"),
+				Err(err) => {
+					error!(vcpu = id; "replay failed: {err}");
+					ok = false;
+				}
+			}
+		}
+
+		ok
+	})
+}
+
+/// Opens the trace files given directly or within session directories, by thread ID.
+fn open_traces(paths: &[PathBuf]) -> io::Result<Vec<(u32, TraceReader)>> {
+	let mut traces = HashMap::new();
+
+	for path in paths {
+		let files = if path.is_dir() {
+			let mut files = fs::read_dir(path)?
+				.map(|entry| entry.map(|entry| entry.path()))
+				.filter(|path| {
+					path.as_ref().map_or(true, |path| {
+						path.extension().is_some_and(|ext| ext == "ktrace")
+					})
+				})
+				.collect::<io::Result<Vec<_>>>()?;
+			files.sort();
+			files
+		} else {
+			vec![path.clone()]
+		};
+
+		for file in files {
+			let (trace, id) = TraceReader::open(&file).map_err(|err| with_path(&file, err))?;
+			if traces.insert(id, trace).is_some() {
+				return Err(with_path(
+					&file,
+					io::Error::new(
+						io::ErrorKind::InvalidInput,
+						format!("more than one trace of thread {id} given"),
+					),
+				));
+			}
+		}
+	}
+
+	if traces.is_empty() {
+		return Err(io::Error::new(
+			io::ErrorKind::NotFound,
+			"no trace files found",
+		));
+	}
+
+	let mut traces = traces.into_iter().collect::<Vec<_>>();
+	traces.sort_by_key(|(id, _)| *id);
+	Ok(traces)
+}
+
+fn with_path(path: &Path, err: io::Error) -> io::Error {
+	io::Error::new(err.kind(), format!("'{}': {err}", path.display()))
+}
+
+/// Replays a single vCPU's trace, returning its stream and the number of instructions sent.
+fn replay_vcpu(
+	sock_path: &str,
+	producer: u64,
+	id: u32,
+	trace: &TraceReader,
+	args: &ReplayArgs,
+) -> io::Result<(BufWriter<UnixStream>, u64)> {
+	let mut stream = BufWriter::new(UnixStream::connect(sock_path)?);
+	stream.write_packet(&Packet::VcpuInit(VcpuInit {
+		id,
+		producer: Some(producer),
+	}))?;
+	stream.write_packet(&Packet::VcpuResume)?;
+	stream.flush()?;
+
+	let step = args.rate.map(|rate| (rate / PACE_STEPS_PER_SEC).max(1));
+	let paced_since = Instant::now();
+	let mut paced_count = 0;
+	let mut sent = 0;
+	let mut addrs = Vec::new();
+
+	for n in trace.index().first_chunk()..trace.index().chunk_count() {
+		let Some(chunk) = trace.index().chunk(n) else {
+			continue;
+		};
+
+		trace.read_chunk(&chunk, &mut addrs)?;
+
+		for &addr in &addrs {
+			stream.write_packet(&Packet::Inst(Inst { addr }))?;
+			sent += 1;
+
+			if let (Some(rate), Some(step)) = (args.rate, step) {
+				paced_count += 1;
+				if paced_count % step == 0 {
+					stream.flush()?;
+					let due = paced_since + Duration::from_secs_f64(paced_count as f64 / rate as f64);
+					thread::sleep(due.saturating_duration_since(Instant::now()));
+				}
+			}
+		}
+	}
+
+	stream.flush()?;
+
+	Ok((stream, sent))
+}
+
+/// Exits a replayed vCPU, unless it's to be held, returning the number of instructions sent.
+fn exit_vcpu(mut stream: BufWriter<UnixStream>, id: u32, sent: u64, args: &ReplayArgs) -> io::Result<u64> {
+	if args.hold {
+		info!(vcpu = id; "replayed {sent} instruction(s); holding");
+		loop {
+			thread::park();
+		}
+	}
+
+	stream.write_packet(&Packet::VcpuExit)?;
+	stream.flush()?;
+
+	Ok(sent)
+}
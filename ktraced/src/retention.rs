@@ -0,0 +1,45 @@
+//! Storage limits for recorded traces.
+
+use serde::Deserialize;
+
+/// What to do once a storage limit is reached.
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RetentionPolicy {
+	/// Keep only the most recent data, dropping the oldest recorded
+	/// instructions (and the oldest finished sessions) to make room.
+	#[default]
+	Ring,
+	/// Stop recording, keeping everything recorded so far.
+	Stop,
+}
+
+/// The storage limits enforced by the daemon. Limits are in bytes of stored trace chunks
+/// (as counted by [`TraceIndex::stored_bytes`](crate::trace_file::TraceIndex::stored_bytes)),
+/// both for sessions in memory and those only on disk.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RetentionLimits {
+	pub policy:      RetentionPolicy,
+	pub max_thread:  Option<u64>,
+	pub max_session: Option<u64>,
+	pub max_total:   Option<u64>,
+}
+
+/// Parses a size in bytes, with an optional (binary) `K`, `M`, `G` or `T` suffix.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+	let s = s.trim();
+	let (digits, shift) = match s.char_indices().last() {
+		Some((i, 'k' | 'K')) => (&s[..i], 10),
+		Some((i, 'm' | 'M')) => (&s[..i], 20),
+		Some((i, 'g' | 'G')) => (&s[..i], 30),
+		Some((i, 't' | 'T')) => (&s[..i], 40),
+		_ => (s, 0),
+	};
+
+	digits
+		.trim()
+		.parse::<u64>()
+		.map_err(|e| format!("invalid size '{s}': {e}"))?
+		.checked_mul(1 << shift)
+		.ok_or_else(|| format!("size '{s}' is too large"))
+}
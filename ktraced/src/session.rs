@@ -0,0 +1,831 @@
+//! Persistent trace sessions.
+//!
+//! Each session lives in its own directory under the storage root, holding a
+//! `session.toml` metadata file (which also holds the session's annotations) and one
+//! trace file per thread (vCPU). Sessions outlive both the producer and the daemon, and
+//! can be reopened later.
+
+use std::{
+	cmp::Ordering,
+	collections::{HashMap, HashSet},
+	fs::{self, File},
+	io, mem,
+	os::unix::fs::MetadataExt,
+	path::{Path, PathBuf},
+	time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use ktrace_protocol::{Annotation, AnnotationKind, SessionInfo, ThreadStatus};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{
+	query_server::{ClearRequest, ThreadState},
+	retention::{RetentionLimits, RetentionPolicy},
+	trace_file::{TraceReader, TraceWriter},
+};
+
+/// The name of the metadata file within a session directory.
+const METADATA_FILE: &str = "session.toml";
+
+/// How long after its last thread exits a session is still joined by new threads of
+/// its producer, so that a producer whose threads don't overlap (e.g. a replay whose
+/// first vCPU finishes before the last one connects) still records one session.
+const REJOIN_PERIOD: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Serialize, Deserialize)]
+struct SessionMetadata {
+	started_at: u64,
+	ended_at: Option<u64>,
+	pid: Option<i32>,
+	command_line: Vec<String>,
+	threads: Vec<u32>,
+	#[serde(default)]
+	annotations: Vec<Annotation>,
+	/// The ID of the next annotation, so that IDs aren't reused once removed.
+	#[serde(default)]
+	next_annotation_id: u64,
+}
+
+impl SessionMetadata {
+	fn read(dir: &Path) -> io::Result<Self> {
+		let mut metadata: Self = toml::from_str(&fs::read_to_string(dir.join(METADATA_FILE))?)
+			.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+		// Sessions annotated before the next ID was stored.
+		if let Some(max) = metadata.annotations.iter().map(|a| a.id).max() {
+			metadata.next_annotation_id = metadata.next_annotation_id.max(max + 1);
+		}
+
+		Ok(metadata)
+	}
+
+	fn write(&self, dir: &Path) -> io::Result<()> {
+		let contents = toml::to_string(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+		let tmp_path = dir.join(format!("{METADATA_FILE}.tmp"));
+		fs::write(&tmp_path, contents)?;
+		fs::rename(tmp_path, dir.join(METADATA_FILE))
+	}
+}
+
+pub struct Session {
+	name:         String,
+	dir:          PathBuf,
+	metadata:     SessionMetadata,
+	/// The producer recording into the session, if it's been recorded since the daemon started.
+	producer:     Option<Producer>,
+	live_threads: usize,
+	/// When the last live thread exited, if the session was recorded since the daemon started.
+	ended:        Option<Instant>,
+	pub threads:  HashMap<u32, ThreadState>,
+}
+
+impl Session {
+	/// Creates a new, empty session directory under `root` for a producer, whose process
+	/// has the given PID.
+	fn create(root: &Path, pid: Option<i32>, producer: Producer) -> io::Result<Self> {
+		let started_at = unix_time();
+		let base_name = match pid {
+			Some(pid) => format!("{started_at}-{pid}"),
+			None => format!("{started_at}"),
+		};
+
+		let mut name = base_name.clone();
+		for n in 2.. {
+			match fs::create_dir(root.join(&name)) {
+				Ok(()) => break,
+				Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+					name = format!("{base_name}-{n}");
+				}
+				Err(err) => return Err(err),
+			}
+		}
+
+		let command_line = pid
+			.and_then(|pid| fs::read(format!("/proc/{pid}/cmdline")).ok())
+			.map(|cmdline| {
+				cmdline
+					.split(|b| *b == 0)
+					.filter(|arg| !arg.is_empty())
+					.map(|arg| String::from_utf8_lossy(arg).into_owned())
+					.collect()
+			})
+			.unwrap_or_default();
+
+		let this = Self {
+			dir: root.join(&name),
+			name,
+			metadata: SessionMetadata {
+				started_at,
+				ended_at: None,
+				pid,
+				command_line,
+				threads: Vec::new(),
+				annotations: Vec::new(),
+				next_annotation_id: 0,
+			},
+			producer: Some(producer),
+			live_threads: 0,
+			ended: None,
+			threads: HashMap::new(),
+		};
+
+		this.metadata.write(&this.dir)?;
+
+		Ok(this)
+	}
+
+	/// Loads a previously recorded session from its directory.
+	fn load(name: &str, dir: PathBuf) -> io::Result<Self> {
+		let metadata = SessionMetadata::read(&dir)?;
+
+		let mut threads = HashMap::new();
+		for &id in &metadata.threads {
+			// Keep whatever can be read, rather than losing the whole session to one bad trace.
+			let trace = match TraceReader::open(trace_path(&dir, id)) {
+				Ok((trace, _)) => trace,
+				Err(err) => {
+					log::warn!("skipping unreadable trace of thread {id} in session '{name}': {err}");
+					continue;
+				}
+			};
+
+			threads.insert(
+				id,
+				ThreadState {
+					trace,
+					status: ThreadStatus::Dead,
+					histogram: Default::default(),
+					streams: Default::default(),
+					ingest: Default::default(),
+					reconnects: 0,
+					producer_stats: None,
+					clear: None,
+				},
+			);
+		}
+
+		Ok(Self {
+			name: name.to_string(),
+			dir,
+			metadata,
+			producer: None,
+			live_threads: 0,
+			ended: None,
+			threads,
+		})
+	}
+
+	/// Returns the unique name of the session.
+	#[inline]
+	pub fn name(&self) -> &str {
+		&self.name
+	}
+
+	/// Returns whether or not any of the session's threads are still being recorded.
+	#[inline]
+	pub fn is_live(&self) -> bool {
+		self.live_threads > 0
+	}
+
+	/// Returns whether or not new threads of the session's producer join it, rather than
+	/// starting a new session.
+	fn is_joinable(&self, now: Instant) -> bool {
+		self.is_live()
+			|| self
+				.ended
+				.is_some_and(|ended| now.duration_since(ended) < REJOIN_PERIOD)
+	}
+
+	/// Orders sessions by when they were started, as they're listed.
+	fn cmp_age(a: &Self, b: &Self) -> Ordering {
+		a.metadata
+			.started_at
+			.cmp(&b.metadata.started_at)
+			.then_with(|| a.name.cmp(&b.name))
+	}
+
+	/// Returns a description of the session for clients.
+	pub fn info(&self) -> SessionInfo {
+		SessionInfo {
+			name:         self.name.clone(),
+			started_at:   self.metadata.started_at,
+			ended_at:     self.metadata.ended_at,
+			command_line: self.metadata.command_line.clone(),
+			// Threads whose trace couldn't be loaded are left out.
+			threads:      self
+				.metadata
+				.threads
+				.iter()
+				.copied()
+				.filter(|id| self.threads.contains_key(id))
+				.collect(),
+			live:         self.is_live(),
+		}
+	}
+
+	/// Creates the trace file for a new thread and starts tracking it.
+	fn add_thread(
+		&mut self,
+		id: u32,
+		clear: UnboundedSender<ClearRequest>,
+		reconnects: u32,
+	) -> io::Result<TraceWriter> {
+		let path = trace_path(&self.dir, id);
+		let writer = TraceWriter::create(File::create_new(&path)?, id)?;
+
+		// The reader needs write access to deallocate chunks dropped by the retention policy.
+		let file = File::options().read(true).write(true).open(&path)?;
+
+		self.threads.insert(
+			id,
+			ThreadState {
+				trace: TraceReader::new(file, writer.index().clone())?,
+				status: Default::default(),
+				histogram: Default::default(),
+				streams: Default::default(),
+				ingest: Default::default(),
+				reconnects,
+				producer_stats: None,
+				clear: Some(clear),
+			},
+		);
+
+		self.live_threads += 1;
+		self.ended = None;
+		self.metadata.ended_at = None;
+		// Keep thread IDs sorted, whatever order the threads connected in.
+		let position = self.metadata.threads.partition_point(|&other| other < id);
+		self.metadata.threads.insert(position, id);
+		self.metadata.write(&self.dir)?;
+
+		Ok(writer)
+	}
+
+	/// Marks a thread as exited, ending the session if it was the last live thread.
+	pub fn thread_exited(&mut self, id: u32) -> io::Result<()> {
+		let Some(state) = self.threads.get_mut(&id) else {
+			return Ok(());
+		};
+
+		if state.status == ThreadStatus::Dead {
+			return Ok(());
+		}
+
+		state.status = ThreadStatus::Dead;
+		state.clear = None;
+		self.live_threads -= 1;
+
+		if self.live_threads == 0 {
+			self.ended = Some(Instant::now());
+			self.metadata.ended_at = Some(unix_time());
+			self.metadata.write(&self.dir)?;
+		}
+
+		Ok(())
+	}
+
+	/// Attaches an annotation to an instruction of one of the session's threads.
+	pub fn add_annotation(
+		&mut self,
+		thread_id: u32,
+		index: usize,
+		kind: AnnotationKind,
+		text: String,
+	) -> io::Result<Annotation> {
+		let annotation = Annotation {
+			id: self.metadata.next_annotation_id,
+			thread_id,
+			index,
+			kind,
+			text,
+			created_at: unix_time(),
+		};
+
+		self.update_metadata(|metadata| {
+			metadata.next_annotation_id += 1;
+			metadata.annotations.push(annotation.clone());
+		})?;
+
+		Ok(annotation)
+	}
+
+	/// Returns the session's annotations (of one thread, or of all of them), ordered
+	/// by thread and instruction index.
+	pub fn annotations(&self, thread_id: Option<u32>) -> Vec<Annotation> {
+		let mut annotations = self
+			.metadata
+			.annotations
+			.iter()
+			.filter(|a| thread_id.is_none_or(|id| a.thread_id == id))
+			.cloned()
+			.collect::<Vec<_>>();
+
+		annotations.sort_by_key(|a| (a.thread_id, a.index, a.id));
+		annotations
+	}
+
+	/// Removes an annotation, returning whether or not it existed.
+	pub fn remove_annotation(&mut self, id: u64) -> io::Result<bool> {
+		if !self.metadata.annotations.iter().any(|a| a.id == id) {
+			return Ok(false);
+		}
+
+		self.update_metadata(|metadata| metadata.annotations.retain(|a| a.id != id))?;
+		Ok(true)
+	}
+
+	/// Removes the annotations of the given threads (e.g. because their traces were cleared).
+	pub fn remove_thread_annotations(&mut self, threads: &[u32]) -> io::Result<()> {
+		if !self
+			.metadata
+			.annotations
+			.iter()
+			.any(|a| threads.contains(&a.thread_id))
+		{
+			return Ok(());
+		}
+
+		self.update_metadata(|metadata| {
+			metadata
+				.annotations
+				.retain(|a| !threads.contains(&a.thread_id));
+		})
+	}
+
+	/// Applies `update` to the metadata, keeping the change only once it's been written.
+	fn update_metadata(&mut self, update: impl FnOnce(&mut SessionMetadata)) -> io::Result<()> {
+		let mut metadata = self.metadata.clone();
+		update(&mut metadata);
+		metadata.write(&self.dir)?;
+
+		self.metadata = metadata;
+		Ok(())
+	}
+
+	/// Returns the number of bytes the session's traces take up on disk.
+	pub fn stored_bytes(&self) -> u64 {
+		self.threads
+			.values()
+			.map(|state| state.trace.index().stored_bytes())
+			.sum()
+	}
+
+	/// Stops recording all of the session's threads.
+	fn stop_recording(&self) {
+		for state in self.threads.values() {
+			state.trace.index().stop();
+		}
+	}
+
+	/// Drops the oldest chunks of the session's largest traces until at least
+	/// `excess` bytes have been freed (or nothing is left to drop).
+	fn trim(&self, mut excess: u64) {
+		while excess > 0 {
+			let Some(state) = self
+				.threads
+				.values()
+				.max_by_key(|state| state.trace.index().stored_bytes())
+			else {
+				return;
+			};
+
+			match state.trace.drop_oldest_chunk() {
+				Ok(Some(freed)) => excess = excess.saturating_sub(freed),
+				Ok(None) => return,
+				Err(err) => {
+					log::warn!("failed to drop chunk from session '{}': {err:?}", self.name);
+					return;
+				}
+			}
+		}
+	}
+}
+
+/// A session that's on disk but not loaded into memory.
+struct OfflineSession {
+	started_at: u64,
+	bytes:      u64,
+}
+
+/// What a producer's threads are grouped into sessions by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Producer {
+	/// The ID the producer sent with its threads.
+	Id(u64),
+	/// The PID of a producer that didn't send an ID.
+	Pid(Option<i32>),
+}
+
+/// Holds all sessions known to the daemon.
+///
+/// Sessions are kept in memory while they're recorded, and once opened, until
+/// they're no longer in use (see [`SessionStore::unload_unused`]). All others
+/// are only read from disk when listed or opened.
+pub struct SessionStore {
+	root:     PathBuf,
+	limits:   RetentionLimits,
+	sessions: Vec<Session>,
+	/// Sessions only on disk, along with their size, for enforcing the total storage limit.
+	offline:  HashMap<String, OfflineSession>,
+	/// The number of times each producer's threads have connected.
+	connects: HashMap<(Producer, u32), u32>,
+	/// The session each client has opened, which is kept from being deleted.
+	opened:   HashMap<u64, String>,
+	/// The sessions each client has named in its queries, which are kept likewise.
+	named:    HashMap<u64, HashSet<String>>,
+}
+
+impl SessionStore {
+	/// Opens (creating if necessary) the storage directory.
+	pub fn open(root: PathBuf, limits: RetentionLimits) -> io::Result<Self> {
+		fs::create_dir_all(&root)?;
+
+		let mut offline = HashMap::new();
+		for entry in fs::read_dir(&root)? {
+			let entry = entry?;
+			let Ok(name) = entry.file_name().into_string() else {
+				continue;
+			};
+
+			let Ok(metadata) = SessionMetadata::read(&entry.path()) else {
+				continue;
+			};
+
+			let bytes = stored_bytes(&name, &entry.path(), &metadata);
+			offline.insert(
+				name,
+				OfflineSession {
+					started_at: metadata.started_at,
+					bytes,
+				},
+			);
+		}
+
+		Ok(Self {
+			root,
+			limits,
+			sessions: Vec::new(),
+			offline,
+			connects: HashMap::new(),
+			opened: HashMap::new(),
+			named: HashMap::new(),
+		})
+	}
+
+	/// Starts recording a thread for a producer (whose process has the given PID), creating
+	/// a new session if the producer doesn't have a live (or [just ended](REJOIN_PERIOD)) one,
+	/// or if it already recorded a thread with that ID there. Producers that don't send an
+	/// ID are told apart by their PID.
+	///
+	/// Returns the name of the session along with the thread's trace writer.
+	pub fn add_thread(
+		&mut self,
+		pid: Option<i32>,
+		producer: Option<u64>,
+		id: u32,
+		clear: UnboundedSender<ClearRequest>,
+	) -> io::Result<(String, TraceWriter)> {
+		let producer = match producer {
+			Some(producer) => Producer::Id(producer),
+			None => Producer::Pid(pid),
+		};
+
+		let now = Instant::now();
+		let existing = self.sessions.iter().position(|session| {
+			session.is_joinable(now)
+				&& session.producer == Some(producer)
+				&& !session.threads.contains_key(&id)
+		});
+
+		let session = match existing {
+			Some(i) => &mut self.sessions[i],
+			None => {
+				let session = Session::create(&self.root, pid, producer)?;
+				log::info!("started session '{}'", session.name);
+				self.sessions.push(session);
+				self.sessions.last_mut().unwrap()
+			}
+		};
+
+		let connects = self.connects.entry((producer, id)).or_default();
+		let reconnects = *connects;
+		*connects += 1;
+
+		let writer = session.add_thread(id, clear, reconnects)?;
+		Ok((session.name.clone(), writer))
+	}
+
+	/// Returns the session with the given name, if it's in memory, or the most
+	/// recently started session if no name is given (the last one listed, of those
+	/// started in the same second).
+	pub fn get(&self, name: Option<&str>) -> Option<&Session> {
+		match name {
+			Some(name) => self.sessions.iter().find(|session| session.name == name),
+			None => self.sessions.iter().max_by(|a, b| Session::cmp_age(a, b)),
+		}
+	}
+
+	/// Returns the sessions in memory.
+	pub fn loaded(&self) -> impl Iterator<Item = &Session> {
+		self.sessions.iter()
+	}
+
+	/// Returns the sessions in memory, mutably.
+	pub fn loaded_mut(&mut self) -> impl Iterator<Item = &mut Session> {
+		self.sessions.iter_mut()
+	}
+
+	/// Returns the in-memory session with the given name, or the most recently
+	/// started session if no name is given, mutably.
+	pub fn get_mut(&mut self, name: Option<&str>) -> Option<&mut Session> {
+		match name {
+			Some(name) => {
+				self.sessions
+					.iter_mut()
+					.find(|session| session.name == name)
+			}
+			None => {
+				self.sessions
+					.iter_mut()
+					.max_by(|a, b| Session::cmp_age(a, b))
+			}
+		}
+	}
+
+	/// Lists all sessions, both in memory and on disk, oldest first.
+	pub fn list(&self) -> io::Result<Vec<SessionInfo>> {
+		let mut sessions = self.sessions.iter().map(Session::info).collect::<Vec<_>>();
+
+		for entry in fs::read_dir(&self.root)? {
+			let entry = entry?;
+			let Ok(name) = entry.file_name().into_string() else {
+				continue;
+			};
+
+			if self.sessions.iter().any(|session| session.name == name) {
+				continue;
+			}
+
+			let Ok(metadata) = SessionMetadata::read(&entry.path()) else {
+				continue;
+			};
+
+			sessions.push(SessionInfo {
+				name,
+				started_at: metadata.started_at,
+				ended_at: metadata.ended_at,
+				command_line: metadata.command_line,
+				threads: metadata.threads,
+				live: false,
+			});
+		}
+
+		sessions.sort_by(|a, b| {
+			a.started_at
+				.cmp(&b.started_at)
+				.then_with(|| a.name.cmp(&b.name))
+		});
+
+		Ok(sessions)
+	}
+
+	/// Returns the session with the given name, loading it from disk if necessary.
+	/// Returns `None` if no such session exists.
+	pub fn open_session(&mut self, name: &str) -> io::Result<Option<&Session>> {
+		if let Some(i) = self
+			.sessions
+			.iter()
+			.position(|session| session.name == name)
+		{
+			return Ok(Some(&self.sessions[i]));
+		}
+
+		if name.is_empty() || name.starts_with('.') || name.contains('/') {
+			return Ok(None);
+		}
+
+		let dir = self.root.join(name);
+		if !dir.join(METADATA_FILE).exists() {
+			return Ok(None);
+		}
+
+		let session = Session::load(name, dir)?;
+		log::info!("opened session '{name}'");
+		self.offline.remove(name);
+		self.sessions.push(session);
+		Ok(self.sessions.last())
+	}
+
+	/// Keeps a (loaded) session from being deleted to stay within the storage limits
+	/// until the client that opened it disconnects or opens another session.
+	pub fn keep_open(&mut self, client: u64, name: &str) {
+		self.opened.insert(client, name.to_string());
+	}
+
+	/// Keeps a (loaded) session a client named in a query from being deleted, or
+	/// unloaded, until the client disconnects.
+	pub fn keep_named(&mut self, client: u64, name: &str) {
+		let named = self.named.entry(client).or_default();
+		if !named.contains(name) {
+			named.insert(name.to_string());
+		}
+	}
+
+	/// Forgets the sessions used by a client, which may then be deleted (unless another
+	/// client is using them).
+	pub fn close_session(&mut self, client: u64) {
+		self.opened.remove(&client);
+		self.named.remove(&client);
+	}
+
+	/// Unloads the sessions that were opened from disk, but that no client is using
+	/// any more. The newest session stays loaded, as it's the one queries that don't
+	/// name a session are answered from.
+	pub fn unload_unused(&mut self) {
+		let newest = self.get(None).map(|session| session.name.clone());
+		let (unused, used) = mem::take(&mut self.sessions)
+			.into_iter()
+			.partition::<Vec<_>, _>(|session| {
+				session.producer.is_none() && Some(&session.name) != newest.as_ref() && !self.in_use(session)
+			});
+
+		self.sessions = used;
+		for session in unused {
+			log::debug!("unloaded session '{}'", session.name);
+			self.offline.insert(
+				session.name.clone(),
+				OfflineSession {
+					started_at: session.metadata.started_at,
+					bytes:      session.stored_bytes(),
+				},
+			);
+		}
+	}
+
+	/// Returns whether or not a session is in use by a client, either because they
+	/// opened it, named it in a query, or are streaming one of its threads.
+	fn in_use(&self, session: &Session) -> bool {
+		self.opened.values().any(|name| *name == session.name)
+			|| self
+				.named
+				.values()
+				.any(|names| names.contains(&session.name))
+			|| session
+				.threads
+				.values()
+				.any(|state| state.streams.count() > 0)
+	}
+
+	/// Returns the number of bytes all sessions take up on disk.
+	fn stored_bytes(&self) -> u64 {
+		self.sessions.iter().map(Session::stored_bytes).sum::<u64>()
+			+ self
+				.offline
+				.values()
+				.map(|session| session.bytes)
+				.sum::<u64>()
+	}
+
+	/// Enforces the storage limits after a thread of the given session has written to its trace.
+	pub fn enforce_retention(&mut self, name: &str, thread_id: u32) {
+		let limits = self.limits;
+
+		let Some(session) = self.get(Some(name)) else {
+			return;
+		};
+
+		if let (Some(max), Some(state)) = (limits.max_thread, session.threads.get(&thread_id)) {
+			let trace = &state.trace;
+			while trace.index().stored_bytes() > max && !trace.index().is_stopped() {
+				match limits.policy {
+					RetentionPolicy::Ring => {
+						match trace.drop_oldest_chunk() {
+							Ok(Some(_)) => {}
+							Ok(None) => break,
+							Err(err) => {
+								log::warn!("failed to drop chunk from session '{name}': {err:?}");
+								break;
+							}
+						}
+					}
+					RetentionPolicy::Stop => {
+						log::warn!(
+							"thread {thread_id} of session '{name}' reached its storage limit; recording \
+							 stopped"
+						);
+						trace.index().stop();
+					}
+				}
+			}
+		}
+
+		if let Some(max) = limits.max_session {
+			let bytes = session.stored_bytes();
+			if bytes > max {
+				match limits.policy {
+					RetentionPolicy::Ring => session.trim(bytes - max),
+					RetentionPolicy::Stop => {
+						if session
+							.threads
+							.values()
+							.any(|state| !state.trace.index().is_stopped())
+						{
+							log::warn!("session '{name}' reached its storage limit; recording stopped");
+							session.stop_recording();
+						}
+					}
+				}
+			}
+		}
+
+		if let Some(max) = limits.max_total {
+			if self.stored_bytes() > max {
+				match limits.policy {
+					RetentionPolicy::Ring => {
+						self.evict_sessions(name, max);
+
+						let total = self.stored_bytes();
+						if total > max {
+							if let Some(session) = self.get(Some(name)) {
+								session.trim(total - max);
+							}
+						}
+					}
+					RetentionPolicy::Stop => {
+						if let Some(session) = self.get(Some(name)) {
+							if session
+								.threads
+								.values()
+								.any(|state| !state.trace.index().is_stopped())
+							{
+								log::warn!("storage limit reached; recording of session '{name}' stopped");
+								session.stop_recording();
+							}
+						}
+					}
+				}
+			}
+		}
+	}
+
+	/// Deletes the oldest finished sessions (other than `keep`, and those in use by clients)
+	/// until the total storage used is at most `max` bytes, or no such sessions are left.
+	fn evict_sessions(&mut self, keep: &str, max: u64) {
+		while self.stored_bytes() > max {
+			let loaded = self
+				.sessions
+				.iter()
+				.filter(|session| session.name != keep && !session.is_live() && !self.in_use(session))
+				.map(|session| (session.metadata.started_at, session.name.clone()));
+
+			let offline = self
+				.offline
+				.iter()
+				.map(|(name, session)| (session.started_at, name.clone()));
+
+			let Some((_, oldest)) = loaded.chain(offline).min() else {
+				return;
+			};
+
+			self.sessions.retain(|session| session.name != oldest);
+			self.offline.remove(&oldest);
+
+			match fs::remove_dir_all(self.root.join(&oldest)) {
+				Ok(()) => log::info!("deleted session '{oldest}' to stay within the storage limit"),
+				Err(err) => log::warn!("failed to delete session '{oldest}': {err:?}"),
+			}
+		}
+	}
+}
+
+/// Returns the path of a thread's trace file within a session directory.
+fn trace_path(dir: &Path, id: u32) -> PathBuf {
+	dir.join(format!("cpu{id}.ktrace"))
+}
+
+/// Returns the number of bytes a session's traces take up on disk, without reading
+/// them. That's the space allocated to the files, so that dropped chunks (which were
+/// deallocated) aren't counted, much like for loaded sessions. Missing traces are skipped.
+fn stored_bytes(name: &str, dir: &Path, metadata: &SessionMetadata) -> u64 {
+	metadata
+		.threads
+		.iter()
+		.filter_map(|&id| {
+			match fs::metadata(trace_path(dir, id)) {
+				Ok(file) => Some(file.blocks() * 512),
+				Err(err) => {
+					log::warn!("failed to stat trace of thread {id} in session '{name}': {err}");
+					None
+				}
+			}
+		})
+		.sum()
+}
+
+/// Returns the current time, in seconds since the Unix epoch.
+fn unix_time() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map_or(0, |d| d.as_secs())
+}
@@ -0,0 +1,1382 @@
+//! The on-disk trace format used by `ktraced`.
+//!
+//! A trace file holds the instruction addresses of a single thread, and is laid out as:
+//!
+//! - A fixed-size [header](HEADER_SIZE) identifying the file and pointing to the first
+//!   retained chunk and the footer.
+//! - A sequence of chunks, each made up of a [chunk header](CHUNK_HEADER_SIZE) and a payload.
+//!   Chunks hold at most [`CHUNK_CAPACITY`] instructions. The header records the absolute index
+//!   of the chunk's first instruction and the minimum / maximum address within it, so that
+//!   queries can skip chunks that cannot match. Payloads are delta- and varint-encoded, then
+//!   compressed with zstd.
+//! - An index footer listing every chunk, written when the trace is finalized.
+//!
+//! Traces that were never finalized (e.g. because the daemon crashed) can still be opened;
+//! the index is rebuilt by walking the chunk headers, stopping at the first incomplete chunk.
+//!
+//! The oldest chunks of a trace can be dropped to limit its size, in which case their space
+//! is deallocated ("hole punched") and the header is updated to point past them. A trace
+//! can also be cleared, dropping every chunk; instruction indices then start over at 0.
+//!
+//! Readers memory-map the trace file and decompress chunks straight out of the mapping.
+//! Instructions that don't fill a chunk yet can be [published](TraceWriter::publish) to
+//! readers without writing them out, so that live traces don't end up as many tiny chunks.
+//! Readers of a trace that's still being written can [wait](TraceIndex::wait_for_more)
+//! (asynchronously) for the writer to publish more instructions.
+//!
+//! Version 1 of the format had a shorter header, without the first chunk offset (its chunks
+//! always started right after the header). Such traces can still be read.
+//!
+//! All integers are little endian.
+
+use std::{
+	collections::VecDeque,
+	fs::File,
+	io::{self, Write},
+	ops::{ControlFlow, Range},
+	os::{fd::AsRawFd, unix::fs::FileExt},
+	path::Path,
+	sync::{
+		Arc, RwLock,
+		atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering::Relaxed},
+	},
+};
+
+use byteorder::{ByteOrder, LittleEndian};
+use memmap2::{Mmap, MmapOptions};
+use tokio::sync::watch;
+
+/// Identifies a trace file.
+const FILE_MAGIC: [u8; 8] = *b"KTRACE\0\0";
+/// The current version of the trace format.
+const FORMAT_VERSION: u32 = 2;
+/// The size of the file header.
+///
+/// `magic: [u8; 8], version: u32, thread_id: u32, chunk_capacity: u32, reserved: u32,
+/// footer_offset: u64, first_chunk_offset: u64`
+const HEADER_SIZE: u64 = 40;
+/// The offset of the footer offset field within the file header.
+const FOOTER_OFFSET_FIELD: u64 = 24;
+/// The offset of the first chunk offset field within the file header.
+const FIRST_CHUNK_OFFSET_FIELD: u64 = 32;
+/// The size of the file header in version 1 of the format, which ended before the
+/// first chunk offset field.
+const V1_HEADER_SIZE: u64 = 32;
+
+/// Identifies a chunk header.
+const CHUNK_MAGIC: u32 = u32::from_le_bytes(*b"KTCK");
+/// The size of a chunk header.
+///
+/// `magic: u32, count: u32, first_index: u64, min_addr: u64, max_addr: u64,
+/// payload_len: u32, raw_len: u32`
+const CHUNK_HEADER_SIZE: u64 = 40;
+
+/// Identifies the index footer.
+const FOOTER_MAGIC: u32 = u32::from_le_bytes(*b"KTIX");
+/// The size of the footer preamble (`magic: u32, reserved: u32, chunk_count: u64`).
+const FOOTER_HEADER_SIZE: usize = 16;
+/// The size of a single footer entry (`offset: u64` followed by the chunk header
+/// fields after the magic).
+const FOOTER_ENTRY_SIZE: usize = 44;
+
+/// The maximum number of instructions held by a single chunk.
+pub const CHUNK_CAPACITY: usize = 64 * 1024;
+
+/// The zstd compression level used for chunk payloads.
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// Readers map trace files in multiples of this size, so that a growing trace
+/// doesn't need to be remapped every time a chunk is written.
+const MAP_GRANULARITY: usize = 64 * 1024 * 1024;
+
+/// The most bytes an address can take up once encoded (see [`encode_addresses`]).
+const MAX_ENCODED_ADDR_LEN: u64 = 10;
+
+/// Describes a single chunk in a trace file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChunkInfo {
+	/// The file offset of the chunk header.
+	pub offset:      u64,
+	/// The absolute index of the first instruction in the chunk.
+	pub first_index: usize,
+	/// The number of instructions in the chunk.
+	pub count:       u32,
+	/// The lowest address in the chunk.
+	pub min_addr:    u64,
+	/// The highest address in the chunk.
+	pub max_addr:    u64,
+	/// The size of the compressed payload, in bytes.
+	pub payload_len: u32,
+	/// The size of the encoded (but uncompressed) payload, in bytes.
+	pub raw_len:     u32,
+}
+
+impl ChunkInfo {
+	/// Returns the absolute index one past the last instruction in the chunk.
+	#[inline]
+	pub fn end_index(&self) -> usize {
+		self.first_index + self.count as usize
+	}
+
+	/// Returns the number of bytes the chunk takes up in the file.
+	#[inline]
+	pub fn stored_len(&self) -> u64 {
+		CHUNK_HEADER_SIZE + u64::from(self.payload_len)
+	}
+
+	/// Returns whether or not the chunk's header could have been written by a
+	/// [`TraceWriter`], given that the chunk must end by `end_offset`. Chunks read
+	/// from disk are checked before they're trusted, so that a corrupt trace can't
+	/// have readers map past the end of the file or allocate more than a chunk's worth.
+	fn is_plausible(&self, end_offset: u64) -> bool {
+		let count = u64::from(self.count);
+		(1..=CHUNK_CAPACITY as u64).contains(&count)
+			&& u64::from(self.raw_len) <= count * MAX_ENCODED_ADDR_LEN
+			&& self.min_addr <= self.max_addr
+			&& self
+				.offset
+				.checked_add(self.stored_len())
+				.is_some_and(|end| end <= end_offset)
+	}
+
+	fn read_fields(offset: u64, buf: &[u8]) -> Self {
+		Self {
+			offset,
+			count: LittleEndian::read_u32(&buf[0..4]),
+			first_index: LittleEndian::read_u64(&buf[4..12]) as usize,
+			min_addr: LittleEndian::read_u64(&buf[12..20]),
+			max_addr: LittleEndian::read_u64(&buf[20..28]),
+			payload_len: LittleEndian::read_u32(&buf[28..32]),
+			raw_len: LittleEndian::read_u32(&buf[32..36]),
+		}
+	}
+
+	fn write_fields(&self, buf: &mut Vec<u8>) {
+		let mut fields = [0u8; 36];
+		LittleEndian::write_u32(&mut fields[0..4], self.count);
+		LittleEndian::write_u64(&mut fields[4..12], self.first_index as u64);
+		LittleEndian::write_u64(&mut fields[12..20], self.min_addr);
+		LittleEndian::write_u64(&mut fields[20..28], self.max_addr);
+		LittleEndian::write_u32(&mut fields[28..32], self.payload_len);
+		LittleEndian::write_u32(&mut fields[32..36], self.raw_len);
+		buf.extend_from_slice(&fields);
+	}
+}
+
+/// The chunk index of a trace, shared between its writer and any readers.
+///
+/// Chunks are only added to the index once they've been fully written to disk.
+/// Chunks are identified by their position in the trace, which stays the same
+/// when older chunks are dropped.
+pub struct TraceIndex {
+	chunks:       RwLock<ChunkList>,
+	stored_bytes: AtomicU64,
+	stopped:      AtomicBool,
+	/// The number of instructions received after recording was stopped.
+	discarded:    AtomicUsize,
+	/// Publishes the chunk count, the number of clears and the number of readable
+	/// instructions whenever any of them changes.
+	flushed:      watch::Sender<(usize, usize, usize)>,
+}
+
+impl Default for TraceIndex {
+	fn default() -> Self {
+		Self {
+			chunks:       Default::default(),
+			stored_bytes: Default::default(),
+			stopped:      Default::default(),
+			discarded:    Default::default(),
+			flushed:      watch::Sender::new((0, 0, 0)),
+		}
+	}
+}
+
+#[derive(Default)]
+struct ChunkList {
+	/// The retained chunks, oldest first.
+	chunks:     VecDeque<ChunkInfo>,
+	/// The number of chunks that have been dropped from the front of the trace.
+	dropped:    usize,
+	/// The index of the next instruction to be written.
+	next_index: usize,
+	/// Instructions published by the writer that haven't been written out as a chunk yet,
+	/// starting at `next_index`.
+	tail:       Vec<u64>,
+	/// The number of times the trace has been cleared.
+	clears:     usize,
+	/// The position of the first chunk written since the trace was last cleared.
+	cleared_at: usize,
+}
+
+impl ChunkList {
+	fn len(&self) -> usize {
+		self.next_index + self.tail.len()
+	}
+
+	fn published(&self) -> (usize, usize, usize) {
+		(self.dropped + self.chunks.len(), self.clears, self.len())
+	}
+}
+
+impl TraceIndex {
+	/// Returns the number of instructions readable from the trace (including any
+	/// that have since been dropped).
+	pub fn len(&self) -> usize {
+		self.chunks.read().unwrap().len()
+	}
+
+	/// Returns the index of the oldest instruction still available.
+	pub fn first_index(&self) -> usize {
+		let chunks = self.chunks.read().unwrap();
+		chunks
+			.chunks
+			.front()
+			.map_or(chunks.next_index, |chunk| chunk.first_index)
+	}
+
+	/// Returns the position one past the last chunk in the trace.
+	pub fn chunk_count(&self) -> usize {
+		let chunks = self.chunks.read().unwrap();
+		chunks.dropped + chunks.chunks.len()
+	}
+
+	/// Returns the position of the oldest chunk still available.
+	pub fn first_chunk(&self) -> usize {
+		self.chunks.read().unwrap().dropped
+	}
+
+	/// Returns the chunk at the given position, if it exists and hasn't been dropped.
+	pub fn chunk(&self, n: usize) -> Option<ChunkInfo> {
+		let chunks = self.chunks.read().unwrap();
+		n.checked_sub(chunks.dropped)
+			.and_then(|n| chunks.chunks.get(n))
+			.copied()
+	}
+
+	/// Returns the position of the chunk containing the given instruction index.
+	/// If the index is past the end of the trace, returns the chunk count. If it
+	/// has been dropped, returns the position of the oldest available chunk.
+	pub fn chunk_for_index(&self, index: usize) -> usize {
+		let chunks = self.chunks.read().unwrap();
+		chunks.dropped
+			+ chunks
+				.chunks
+				.partition_point(|chunk| chunk.end_index() <= index)
+	}
+
+	/// Returns the number of times the trace has been cleared, along with the
+	/// position of the first chunk written since.
+	pub fn cleared(&self) -> (usize, usize) {
+		let chunks = self.chunks.read().unwrap();
+		(chunks.clears, chunks.cleared_at)
+	}
+
+	/// Waits until the chunk at position `n` has been written, more than `len` instructions
+	/// are readable, or the trace is cleared again after having been cleared `clears` times.
+	pub async fn wait_for_more(&self, n: usize, clears: usize, len: usize) {
+		let mut flushed = self.flushed.subscribe();
+		// The sender lives as long as the index, so this can't fail.
+		let _ = flushed
+			.wait_for(|&(count, cleared, readable)| count > n || cleared != clears || readable > len)
+			.await;
+	}
+
+	/// Copies the published instructions that haven't been written out as a chunk yet into
+	/// `out` (replacing its contents), skipping any before the absolute index `start`.
+	/// Returns the index of the first instruction copied.
+	///
+	/// Returns `None` if the chunk at position `n` has been written (so should be read
+	/// first), or the trace has been cleared since it was cleared `clears` times.
+	pub fn read_tail(&self, n: usize, clears: usize, start: usize, out: &mut Vec<u64>) -> Option<usize> {
+		out.clear();
+
+		let chunks = self.chunks.read().unwrap();
+		if n < chunks.dropped + chunks.chunks.len() || chunks.clears != clears {
+			return None;
+		}
+
+		let skip = start
+			.saturating_sub(chunks.next_index)
+			.min(chunks.tail.len());
+		out.extend_from_slice(&chunks.tail[skip..]);
+		Some(chunks.next_index + skip)
+	}
+
+	/// Returns the number of bytes the trace's chunks take up on disk.
+	#[inline]
+	pub fn stored_bytes(&self) -> u64 {
+		self.stored_bytes.load(Relaxed)
+	}
+
+	/// Stops recording; any further instructions written to the trace are discarded.
+	#[inline]
+	pub fn stop(&self) {
+		self.stopped.store(true, Relaxed);
+	}
+
+	/// Returns whether or not recording has been stopped.
+	#[inline]
+	pub fn is_stopped(&self) -> bool {
+		self.stopped.load(Relaxed)
+	}
+
+	/// Counts an instruction received (and discarded) after recording was stopped.
+	#[inline]
+	pub fn discard(&self) {
+		self.discarded.fetch_add(1, Relaxed);
+	}
+
+	/// Returns the number of instructions that were dropped from the front of the
+	/// trace, or discarded after recording was stopped.
+	pub fn dropped(&self) -> usize {
+		let chunks = self.chunks.read().unwrap();
+		let first_index = chunks
+			.chunks
+			.front()
+			.map_or(chunks.next_index, |chunk| chunk.first_index);
+		first_index + self.discarded.load(Relaxed)
+	}
+
+	/// Returns storage statistics for the trace.
+	pub fn stats(&self) -> ktrace_protocol::StorageStats {
+		let chunks = self.chunks.read().unwrap();
+		ktrace_protocol::StorageStats {
+			instructions: chunks.len(),
+			first_index: chunks
+				.chunks
+				.front()
+				.map_or(chunks.next_index, |chunk| chunk.first_index),
+			chunks: chunks.chunks.len(),
+			raw_bytes: chunks.chunks.iter().map(|c| u64::from(c.count) * 8).sum(),
+			stored_bytes: self.stored_bytes(),
+			recording_stopped: self.is_stopped(),
+		}
+	}
+
+	fn push(&self, chunk: ChunkInfo) {
+		self.stored_bytes.fetch_add(chunk.stored_len(), Relaxed);
+
+		let published = {
+			let mut chunks = self.chunks.write().unwrap();
+			chunks.next_index = chunk.end_index();
+			// The chunk holds every published instruction.
+			chunks.tail.clear();
+			chunks.chunks.push_back(chunk);
+			chunks.published()
+		};
+
+		self.flushed.send_replace(published);
+	}
+
+	/// Drops every chunk, once `remove` has removed them from the file. Recording resumes
+	/// (if it was stopped), starting over at instruction index 0.
+	///
+	/// `remove` is called with the chunks while holding the lock, so readers that fail
+	/// to read a chunk it deallocated see that it was dropped, and so that the header
+	/// isn't updated by anything else at the same time.
+	fn clear(&self, remove: impl FnOnce(&VecDeque<ChunkInfo>) -> io::Result<()>) -> io::Result<()> {
+		let published = {
+			let mut chunks = self.chunks.write().unwrap();
+			remove(&chunks.chunks)?;
+
+			let cleared = std::mem::take(&mut chunks.chunks);
+			chunks.dropped += cleared.len();
+			chunks.next_index = 0;
+			chunks.tail.clear();
+			chunks.clears += 1;
+			chunks.cleared_at = chunks.dropped;
+
+			let freed = cleared.iter().map(ChunkInfo::stored_len).sum();
+			self.stored_bytes.fetch_sub(freed, Relaxed);
+
+			chunks.published()
+		};
+
+		self.stopped.store(false, Relaxed);
+		self.discarded.store(0, Relaxed);
+		self.flushed.send_replace(published);
+
+		Ok(())
+	}
+
+	/// Drops the oldest chunk (if any), once `remove` has removed it from the file.
+	/// `remove` is called with the chunk and the offset of the chunk after it (or
+	/// where it will be written) while holding the lock, as in [`Self::clear`].
+	fn pop_front(
+		&self,
+		remove: impl FnOnce(&ChunkInfo, u64) -> io::Result<()>,
+	) -> io::Result<Option<ChunkInfo>> {
+		let mut chunks = self.chunks.write().unwrap();
+		let Some(chunk) = chunks.chunks.front().copied() else {
+			return Ok(None);
+		};
+
+		// If no chunks are left, the next chunk will be written right after this one.
+		let next_offset = chunks
+			.chunks
+			.get(1)
+			.map_or(chunk.offset + chunk.stored_len(), |next| next.offset);
+		remove(&chunk, next_offset)?;
+
+		chunks.chunks.pop_front();
+		chunks.dropped += 1;
+		self.stored_bytes.fetch_sub(chunk.stored_len(), Relaxed);
+		Ok(Some(chunk))
+	}
+}
+
+/// Appends instructions to a trace file.
+pub struct TraceWriter {
+	file:       File,
+	offset:     u64,
+	pending:    Vec<u64>,
+	index:      Arc<TraceIndex>,
+	compressor: zstd::bulk::Compressor<'static>,
+	encoded:    Vec<u8>,
+	buffer:     Vec<u8>,
+}
+
+impl TraceWriter {
+	/// Creates a new trace in the given (empty) file.
+	pub fn create(mut file: File, thread_id: u32) -> io::Result<Self> {
+		let mut header = [0u8; HEADER_SIZE as usize];
+		header[0..8].copy_from_slice(&FILE_MAGIC);
+		LittleEndian::write_u32(&mut header[8..12], FORMAT_VERSION);
+		LittleEndian::write_u32(&mut header[12..16], thread_id);
+		LittleEndian::write_u32(&mut header[16..20], CHUNK_CAPACITY as u32);
+		LittleEndian::write_u64(&mut header[32..40], HEADER_SIZE);
+		file.write_all(&header)?;
+
+		Ok(Self {
+			file,
+			offset: HEADER_SIZE,
+			pending: Vec::with_capacity(CHUNK_CAPACITY),
+			index: Arc::new(TraceIndex::default()),
+			compressor: zstd::bulk::Compressor::new(COMPRESSION_LEVEL)?,
+			encoded: Vec::new(),
+			buffer: Vec::new(),
+		})
+	}
+
+	/// Returns the index shared with readers of this trace.
+	#[inline]
+	pub fn index(&self) -> &Arc<TraceIndex> {
+		&self.index
+	}
+
+	/// Appends an instruction address, writing out a chunk if it fills up.
+	/// Returns whether or not a chunk was written.
+	///
+	/// If recording has been [stopped](TraceIndex::stop), the address is discarded.
+	pub fn push(&mut self, addr: u64) -> io::Result<bool> {
+		if self.index.is_stopped() {
+			return Ok(false);
+		}
+
+		self.pending.push(addr);
+		if self.pending.len() == CHUNK_CAPACITY {
+			return self.flush();
+		}
+
+		Ok(false)
+	}
+
+	/// Makes pending instructions visible to readers, without writing them out as a chunk.
+	pub fn publish(&mut self) {
+		let published = {
+			let mut chunks = self.index.chunks.write().unwrap();
+			let published = chunks.tail.len();
+			if published == self.pending.len() {
+				return;
+			}
+
+			chunks.tail.extend_from_slice(&self.pending[published..]);
+			chunks.published()
+		};
+
+		self.index.flushed.send_replace(published);
+	}
+
+	/// Writes out any pending instructions as a (possibly partial) chunk,
+	/// making them visible to readers. Returns whether or not a chunk was written.
+	pub fn flush(&mut self) -> io::Result<bool> {
+		if self.index.is_stopped() {
+			// Instructions that readers have already seen are kept.
+			let published = self.index.chunks.read().unwrap().tail.len();
+			self.pending.truncate(published);
+		}
+
+		if self.pending.is_empty() {
+			return Ok(false);
+		}
+
+		self.encoded.clear();
+		encode_addresses(&self.pending, &mut self.encoded);
+		let payload = self.compressor.compress(&self.encoded)?;
+
+		let chunk = ChunkInfo {
+			offset:      self.offset,
+			first_index: self.index.chunks.read().unwrap().next_index,
+			count:       self.pending.len() as u32,
+			min_addr:    self.pending.iter().copied().min().unwrap(),
+			max_addr:    self.pending.iter().copied().max().unwrap(),
+			payload_len: payload.len() as u32,
+			raw_len:     self.encoded.len() as u32,
+		};
+
+		self.buffer.clear();
+		self.buffer.extend_from_slice(&CHUNK_MAGIC.to_le_bytes());
+		chunk.write_fields(&mut self.buffer);
+		self.buffer.extend_from_slice(&payload);
+		self.file.write_all(&self.buffer)?;
+
+		self.offset += self.buffer.len() as u64;
+		self.pending.clear();
+		self.index.push(chunk);
+
+		Ok(true)
+	}
+
+	/// Discards everything recorded so far (including pending instructions), deallocating
+	/// the space it took up in the file. Instruction indices start over at 0.
+	pub fn clear(&mut self) -> io::Result<()> {
+		self.index.clear(|cleared| {
+			// The next chunk is the first one to keep.
+			self.file
+				.write_all_at(&self.offset.to_le_bytes(), FIRST_CHUNK_OFFSET_FIELD)?;
+
+			if let (Some(first), Some(last)) = (cleared.front(), cleared.back()) {
+				deallocate(
+					&self.file,
+					first.offset,
+					last.offset + last.stored_len() - first.offset,
+				)?;
+			}
+
+			Ok(())
+		})?;
+
+		self.pending.clear();
+		Ok(())
+	}
+
+	/// Flushes any pending instructions and writes the index footer, after which
+	/// the trace can be reopened without scanning it.
+	pub fn finalize(mut self) -> io::Result<()> {
+		self.flush()?;
+
+		let chunks = &self.index.chunks.read().unwrap().chunks;
+		let mut footer = Vec::with_capacity(FOOTER_HEADER_SIZE + chunks.len() * FOOTER_ENTRY_SIZE);
+		footer.extend_from_slice(&FOOTER_MAGIC.to_le_bytes());
+		footer.extend_from_slice(&0u32.to_le_bytes());
+		footer.extend_from_slice(&(chunks.len() as u64).to_le_bytes());
+		for chunk in chunks.iter() {
+			footer.extend_from_slice(&chunk.offset.to_le_bytes());
+			chunk.write_fields(&mut footer);
+		}
+
+		self.file.write_all(&footer)?;
+		self.file.sync_data()?;
+		self.file
+			.write_all_at(&self.offset.to_le_bytes(), FOOTER_OFFSET_FIELD)?;
+
+		Ok(())
+	}
+}
+
+/// Reads instructions from a trace file.
+///
+/// Readers are cheap to clone, and can be used while the trace is still being written.
+#[derive(Clone)]
+pub struct TraceReader {
+	file:  Arc<File>,
+	map:   Arc<RwLock<Mapping>>,
+	index: Arc<TraceIndex>,
+}
+
+/// A reader's mapping of its trace file.
+struct Mapping {
+	map:      Mmap,
+	/// The length of the file when it was last checked. The mapping may extend
+	/// past it, but pages past the end of the file must never be touched.
+	file_len: usize,
+}
+
+impl TraceReader {
+	/// Creates a reader over a trace that is (possibly) still being written.
+	pub fn new(file: File, index: Arc<TraceIndex>) -> io::Result<Self> {
+		let file_len = file.metadata()?.len() as usize;
+		let map = Mapping {
+			map: map_file(&file, file_len)?,
+			file_len,
+		};
+
+		Ok(Self {
+			file: Arc::new(file),
+			map: Arc::new(RwLock::new(map)),
+			index,
+		})
+	}
+
+	/// Opens an existing trace file, returning the reader and the thread ID
+	/// recorded in its header.
+	pub fn open(path: impl AsRef<Path>) -> io::Result<(Self, u32)> {
+		let file = File::open(path)?;
+
+		let mut header = [0u8; HEADER_SIZE as usize];
+		file.read_exact_at(&mut header[..V1_HEADER_SIZE as usize], 0)?;
+
+		if header[0..8] != FILE_MAGIC {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidData,
+				"not a trace file",
+			));
+		}
+
+		let first_chunk_offset = match LittleEndian::read_u32(&header[8..12]) {
+			1 => V1_HEADER_SIZE,
+			FORMAT_VERSION => {
+				file.read_exact_at(&mut header[V1_HEADER_SIZE as usize..], V1_HEADER_SIZE)?;
+				LittleEndian::read_u64(&header[32..40])
+			}
+			version => {
+				return Err(io::Error::new(
+					io::ErrorKind::InvalidData,
+					format!("unsupported trace format version {version}"),
+				));
+			}
+		};
+
+		let thread_id = LittleEndian::read_u32(&header[12..16]);
+		let footer_offset = LittleEndian::read_u64(&header[24..32]);
+
+		let chunks = if footer_offset == 0 {
+			recover_chunks(&file, first_chunk_offset)?
+		} else {
+			match read_footer(&file, first_chunk_offset, footer_offset) {
+				Ok(chunks) => chunks,
+				Err(err) => {
+					log::warn!("failed to read trace index footer, rebuilding it: {err}");
+					recover_chunks(&file, first_chunk_offset)?
+				}
+			}
+		};
+
+		let index = TraceIndex::default();
+		for chunk in chunks {
+			index.push(chunk);
+		}
+
+		Ok((Self::new(file, Arc::new(index))?, thread_id))
+	}
+
+	/// Returns the trace's chunk index.
+	#[inline]
+	pub fn index(&self) -> &TraceIndex {
+		&self.index
+	}
+
+	/// Returns the number of instructions readable from the trace.
+	#[inline]
+	pub fn len(&self) -> usize {
+		self.index.len()
+	}
+
+	/// Drops the oldest chunk of the trace, deallocating its space in the file.
+	/// Returns the number of bytes freed, or `None` if the trace has no chunks.
+	///
+	/// Requires the reader's file to have been opened for writing.
+	pub fn drop_oldest_chunk(&self) -> io::Result<Option<u64>> {
+		let chunk = self.index.pop_front(|chunk, next_offset| {
+			self.file
+				.write_all_at(&next_offset.to_le_bytes(), FIRST_CHUNK_OFFSET_FIELD)?;
+			deallocate(&self.file, chunk.offset, chunk.stored_len())
+		})?;
+
+		Ok(chunk.map(|chunk| chunk.stored_len()))
+	}
+
+	/// Decodes the addresses in a chunk into `out`, replacing its contents.
+	pub fn read_chunk(&self, chunk: &ChunkInfo, out: &mut Vec<u64>) -> io::Result<()> {
+		let start = (chunk.offset + CHUNK_HEADER_SIZE) as usize;
+		let encoded = self.with_mapped(start..start + chunk.payload_len as usize, |payload| {
+			zstd::bulk::decompress(payload, chunk.raw_len as usize)
+		})??;
+
+		out.clear();
+		out.reserve(chunk.count as usize);
+		decode_addresses(&encoded, out)?;
+
+		if out.len() != chunk.count as usize {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidData,
+				"chunk instruction count mismatch",
+			));
+		}
+
+		Ok(())
+	}
+
+	/// Reads the chunk at position `n` like [`Self::read_chunk`], returning `false` if it
+	/// was dropped while reading it (so that scans skip it, as if it had been dropped
+	/// before they got to it).
+	fn read_retained_chunk(&self, n: usize, chunk: &ChunkInfo, out: &mut Vec<u64>) -> io::Result<bool> {
+		match self.read_chunk(chunk, out) {
+			Ok(()) => Ok(true),
+			Err(_) if n < self.index.first_chunk() => Ok(false),
+			Err(err) => Err(err),
+		}
+	}
+
+	/// Calls `f` with the given range of the trace file, remapping the file if it
+	/// has grown past the current mapping. Fails if the range extends past the end
+	/// of the file, which would fault rather than read.
+	fn with_mapped<R>(&self, range: Range<usize>, f: impl FnOnce(&[u8]) -> R) -> io::Result<R> {
+		{
+			let mapping = self.map.read().unwrap();
+			if range.end <= mapping.file_len {
+				return Ok(f(&mapping.map[range]));
+			}
+		}
+
+		let mut mapping = self.map.write().unwrap();
+		if range.end > mapping.file_len {
+			mapping.file_len = self.file.metadata()?.len() as usize;
+			if range.end > mapping.file_len {
+				return Err(io::Error::new(
+					io::ErrorKind::UnexpectedEof,
+					"chunk extends past the end of the trace file",
+				));
+			}
+
+			if range.end > mapping.map.len() {
+				mapping.map = map_file(&self.file, range.end)?;
+			}
+		}
+
+		Ok(f(&mapping.map[range]))
+	}
+
+	/// Calls `f` with each `(index, address)` pair in the trace, in ascending order,
+	/// starting at `start` and ending at `end` (exclusive), until `f` returns
+	/// [`ControlFlow::Break`]. Chunks for which `may_match` returns `false` are skipped.
+	pub fn scan_forward(
+		&self,
+		start: usize,
+		end: usize,
+		may_match: impl Fn(&ChunkInfo) -> bool,
+		mut f: impl FnMut(usize, u64) -> ControlFlow<()>,
+	) -> io::Result<()> {
+		let (clears, _) = self.index.cleared();
+		let mut addrs = Vec::new();
+		let mut n = self.index.chunk_for_index(start);
+
+		loop {
+			let Some(chunk) = self.index.chunk(n) else {
+				// Instructions that haven't been written out as a chunk yet come last.
+				let Some(first_index) = self.index.read_tail(n, clears, start, &mut addrs) else {
+					// Either a chunk was written in the meantime, or older chunks were dropped.
+					if self.index.cleared().0 != clears {
+						break;
+					}
+
+					n = n.max(self.index.first_chunk());
+					continue;
+				};
+
+				let take = end.saturating_sub(first_index).min(addrs.len());
+				for (i, addr) in addrs[..take].iter().enumerate() {
+					if f(first_index + i, *addr).is_break() {
+						break;
+					}
+				}
+
+				break;
+			};
+
+			if chunk.first_index >= end {
+				break;
+			}
+
+			n += 1;
+
+			if !may_match(&chunk) || !self.read_retained_chunk(n - 1, &chunk, &mut addrs)? {
+				continue;
+			}
+
+			let skip = start.saturating_sub(chunk.first_index);
+			let take = end.min(chunk.end_index()) - chunk.first_index;
+			for (i, addr) in addrs[..take].iter().enumerate().skip(skip) {
+				if f(chunk.first_index + i, *addr).is_break() {
+					return Ok(());
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Calls `f` with each `(index, address)` pair in the trace, in descending order,
+	/// starting just before `end` and ending at index 0, until `f` returns
+	/// [`ControlFlow::Break`]. Chunks for which `may_match` returns `false` are skipped.
+	pub fn scan_backward(
+		&self,
+		end: usize,
+		may_match: impl Fn(&ChunkInfo) -> bool,
+		mut f: impl FnMut(usize, u64) -> ControlFlow<()>,
+	) -> io::Result<()> {
+		if end == 0 {
+			return Ok(());
+		}
+
+		let mut addrs = Vec::new();
+
+		// Instructions that haven't been written out as a chunk yet come last. Retry
+		// if a chunk is written in between, so that none are skipped.
+		let chunk_count = loop {
+			let (clears, _) = self.index.cleared();
+			let chunk_count = self.index.chunk_count();
+			let Some(first_index) = self.index.read_tail(chunk_count, clears, 0, &mut addrs) else {
+				continue;
+			};
+
+			let take = end.saturating_sub(first_index).min(addrs.len());
+			for (i, addr) in addrs[..take].iter().enumerate().rev() {
+				if f(first_index + i, *addr).is_break() {
+					return Ok(());
+				}
+			}
+
+			break chunk_count;
+		};
+
+		if chunk_count == 0 {
+			return Ok(());
+		}
+
+		let last = self.index.chunk_for_index(end - 1).min(chunk_count - 1);
+
+		for n in (self.index.first_chunk()..=last).rev() {
+			let Some(chunk) = self.index.chunk(n) else {
+				continue;
+			};
+
+			if !may_match(&chunk) || !self.read_retained_chunk(n, &chunk, &mut addrs)? {
+				continue;
+			}
+
+			let take = end.min(chunk.end_index()) - chunk.first_index;
+			for (i, addr) in addrs[..take].iter().enumerate().rev() {
+				if f(chunk.first_index + i, *addr).is_break() {
+					return Ok(());
+				}
+			}
+		}
+
+		Ok(())
+	}
+}
+
+/// Maps a trace file for reading, covering at least `min_len` bytes (or the
+/// whole file if it's larger), rounded up to [`MAP_GRANULARITY`].
+fn map_file(file: &File, min_len: usize) -> io::Result<Mmap> {
+	let len = (file.metadata()?.len() as usize)
+		.max(min_len)
+		.next_multiple_of(MAP_GRANULARITY);
+
+	// SAFETY: Trace files are only ever appended to, and only the oldest chunks are
+	// deallocated (which reads back as zeroes, not a fault), so the mapped memory
+	// never becomes invalid. Pages past the end of the file are never accessed, since
+	// readers check ranges against the file's length first (see `with_mapped`).
+	unsafe { MmapOptions::new().len(len).map(file) }
+}
+
+/// Deallocates ("hole punches") a range of a file, which then reads back as zeroes.
+fn deallocate(file: &File, offset: u64, len: u64) -> io::Result<()> {
+	// SAFETY: The file descriptor is valid for the lifetime of `file`.
+	let res = unsafe {
+		libc::fallocate(
+			file.as_raw_fd(),
+			libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+			offset as libc::off_t,
+			len as libc::off_t,
+		)
+	};
+
+	if res != 0 {
+		return Err(io::Error::last_os_error());
+	}
+
+	Ok(())
+}
+
+/// Reads the chunk list from a finalized trace's footer, checking that it describes
+/// contiguous chunks between the first chunk offset and the footer.
+fn read_footer(file: &File, first_chunk_offset: u64, footer_offset: u64) -> io::Result<Vec<ChunkInfo>> {
+	let mut preamble = [0u8; FOOTER_HEADER_SIZE];
+	file.read_exact_at(&mut preamble, footer_offset)?;
+
+	if LittleEndian::read_u32(&preamble[0..4]) != FOOTER_MAGIC {
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidData,
+			"invalid trace index footer",
+		));
+	}
+
+	// Check the chunk count against the file's length before allocating anything for it.
+	let file_len = file.metadata()?.len();
+	let chunk_count = LittleEndian::read_u64(&preamble[8..16]);
+	let entries_len = chunk_count
+		.checked_mul(FOOTER_ENTRY_SIZE as u64)
+		.filter(|&len| {
+			(footer_offset + FOOTER_HEADER_SIZE as u64)
+				.checked_add(len)
+				.is_some_and(|end| end <= file_len)
+		})
+		.ok_or_else(|| {
+			io::Error::new(
+				io::ErrorKind::InvalidData,
+				"trace index footer extends past the end of the file",
+			)
+		})?;
+
+	let mut entries = vec![0u8; entries_len as usize];
+	file.read_exact_at(&mut entries, footer_offset + FOOTER_HEADER_SIZE as u64)?;
+
+	let chunks = entries
+		.chunks_exact(FOOTER_ENTRY_SIZE)
+		.map(|entry| ChunkInfo::read_fields(LittleEndian::read_u64(&entry[0..8]), &entry[8..]))
+		.collect::<Vec<_>>();
+
+	// The first chunk can start anywhere past the first chunk offset, and its index
+	// anywhere (if older chunks were dropped). Each of the others follows the last.
+	let mut expected = chunks
+		.first()
+		.map(|first| (first.offset.max(first_chunk_offset), first.first_index));
+
+	for chunk in &chunks {
+		if expected != Some((chunk.offset, chunk.first_index)) || !chunk.is_plausible(footer_offset) {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidData,
+				"trace index footer lists an invalid chunk",
+			));
+		}
+
+		expected = Some((chunk.offset + chunk.stored_len(), chunk.end_index()));
+	}
+
+	Ok(chunks)
+}
+
+/// Rebuilds the chunk list of a trace that was never finalized by walking its
+/// chunk headers, starting at the given offset. Stops at the first chunk that
+/// is incomplete or corrupt.
+fn recover_chunks(file: &File, first_chunk_offset: u64) -> io::Result<Vec<ChunkInfo>> {
+	let file_len = file.metadata()?.len();
+	let mut chunks = Vec::<ChunkInfo>::new();
+	let mut offset = first_chunk_offset;
+	let mut header = [0u8; CHUNK_HEADER_SIZE as usize];
+
+	while offset + CHUNK_HEADER_SIZE <= file_len {
+		file.read_exact_at(&mut header, offset)?;
+
+		if LittleEndian::read_u32(&header[0..4]) != CHUNK_MAGIC {
+			break;
+		}
+
+		let chunk = ChunkInfo::read_fields(offset, &header[4..]);
+		let expected_index = chunks
+			.last()
+			.map_or(chunk.first_index, ChunkInfo::end_index);
+		let chunk_end = offset + chunk.stored_len();
+
+		if chunk.first_index != expected_index || !chunk.is_plausible(file_len) {
+			break;
+		}
+
+		chunks.push(chunk);
+		offset = chunk_end;
+	}
+
+	log::debug!("recovered {} chunk(s) from unfinalized trace", chunks.len());
+
+	Ok(chunks)
+}
+
+/// Delta-encodes a list of addresses as zigzag LEB128 varints.
+fn encode_addresses(addrs: &[u64], out: &mut Vec<u8>) {
+	let mut prev = 0u64;
+	for &addr in addrs {
+		let delta = addr.wrapping_sub(prev) as i64;
+		let mut zigzag = ((delta << 1) ^ (delta >> 63)) as u64;
+		prev = addr;
+
+		while zigzag >= 0x80 {
+			out.push((zigzag as u8) | 0x80);
+			zigzag >>= 7;
+		}
+		out.push(zigzag as u8);
+	}
+}
+
+/// Decodes a list of addresses produced by [`encode_addresses`].
+fn decode_addresses(mut buf: &[u8], out: &mut Vec<u64>) -> io::Result<()> {
+	let mut prev = 0u64;
+	while !buf.is_empty() {
+		let mut zigzag = 0u64;
+		let mut shift = 0;
+		loop {
+			let Some((&byte, rest)) = buf.split_first() else {
+				return Err(io::Error::new(
+					io::ErrorKind::InvalidData,
+					"truncated address varint",
+				));
+			};
+			buf = rest;
+
+			zigzag |= u64::from(byte & 0x7F) << shift;
+			if byte & 0x80 == 0 {
+				break;
+			}
+
+			shift += 7;
+			if shift >= 64 {
+				return Err(io::Error::new(
+					io::ErrorKind::InvalidData,
+					"overlong address varint",
+				));
+			}
+		}
+
+		let delta = ((zigzag >> 1) as i64) ^ -((zigzag & 1) as i64);
+		prev = prev.wrapping_add(delta as u64);
+		out.push(prev);
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{os::unix::fs::MetadataExt, path::PathBuf};
+
+	use super::*;
+
+	/// Creates a trace file in `dir`, writing each of `chunks` out as a chunk.
+	fn write_trace(dir: &Path, chunks: &[Vec<u64>]) -> (TraceWriter, PathBuf) {
+		let path = dir.join("cpu0.ktrace");
+		let mut writer = TraceWriter::create(File::create_new(&path).unwrap(), 7).unwrap();
+		for chunk in chunks {
+			for &addr in chunk {
+				writer.push(addr).unwrap();
+			}
+			writer.flush().unwrap();
+		}
+
+		(writer, path)
+	}
+
+	/// Opens a reader that's allowed to drop chunks from the writer's trace.
+	fn writable_reader(writer: &TraceWriter, path: &Path) -> TraceReader {
+		let file = File::options().read(true).write(true).open(path).unwrap();
+		TraceReader::new(file, writer.index().clone()).unwrap()
+	}
+
+	fn read_all(reader: &TraceReader) -> Vec<u64> {
+		let mut addrs = Vec::new();
+		reader
+			.scan_forward(
+				0,
+				usize::MAX,
+				|_| true,
+				|_, addr| {
+					addrs.push(addr);
+					ControlFlow::Continue(())
+				},
+			)
+			.unwrap();
+		addrs
+	}
+
+	fn chunks(index: &TraceIndex) -> Vec<ChunkInfo> {
+		index
+			.chunks
+			.read()
+			.unwrap()
+			.chunks
+			.iter()
+			.copied()
+			.collect()
+	}
+
+	/// Addresses that don't compress well, so that chunks span several blocks.
+	fn noisy(seed: u64, count: usize) -> Vec<u64> {
+		let mut state = seed;
+		(0..count)
+			.map(|_| {
+				state = state
+					.wrapping_mul(6364136223846793005)
+					.wrapping_add(1442695040888963407);
+				state >> 16
+			})
+			.collect()
+	}
+
+	#[test]
+	fn addresses_round_trip() {
+		let addrs = [
+			0,
+			1,
+			0x1000,
+			0xFFF,
+			u64::MAX,
+			0,
+			0x8000_0000_0000_0000,
+			42,
+			42,
+		];
+		let mut encoded = Vec::new();
+		encode_addresses(&addrs, &mut encoded);
+
+		let mut decoded = Vec::new();
+		decode_addresses(&encoded, &mut decoded).unwrap();
+		assert_eq!(decoded, addrs);
+	}
+
+	#[test]
+	fn small_deltas_take_one_byte() {
+		let mut encoded = Vec::new();
+		encode_addresses(&[0x1000, 0x1004, 0x1000, 0x1020], &mut encoded);
+		// The first address is a large delta from 0.
+		assert_eq!(encoded.len(), 2 + 3);
+	}
+
+	#[test]
+	fn malformed_addresses_are_rejected() {
+		let mut out = Vec::new();
+		assert!(decode_addresses(&[0x80], &mut out).is_err());
+		assert!(decode_addresses(&[0xFF; 11], &mut out).is_err());
+	}
+
+	#[test]
+	fn footer_round_trip() {
+		let dir = tempfile::tempdir().unwrap();
+		let (writer, path) = write_trace(dir.path(), &[vec![1, 2, 3], vec![0x20, 0x10]]);
+		let written = chunks(writer.index());
+		writer.finalize().unwrap();
+
+		let file = File::open(&path).unwrap();
+		let mut header = [0u8; HEADER_SIZE as usize];
+		file.read_exact_at(&mut header, 0).unwrap();
+		let footer_offset = LittleEndian::read_u64(&header[24..32]);
+		assert_ne!(footer_offset, 0);
+		assert_eq!(
+			read_footer(&file, HEADER_SIZE, footer_offset).unwrap(),
+			written
+		);
+
+		let (reader, thread_id) = TraceReader::open(&path).unwrap();
+		assert_eq!(thread_id, 7);
+		assert_eq!(reader.len(), 5);
+		assert_eq!(read_all(&reader), [1, 2, 3, 0x20, 0x10]);
+		assert_eq!(chunks(reader.index()), written);
+	}
+
+	#[test]
+	fn corrupt_footers_are_rebuilt() {
+		let dir = tempfile::tempdir().unwrap();
+		let (writer, path) = write_trace(dir.path(), &[vec![1, 2], vec![3]]);
+		let footer_offset = writer.offset;
+		writer.finalize().unwrap();
+
+		// A chunk count that would need more memory than there is.
+		let file = File::options().read(true).write(true).open(&path).unwrap();
+		file.write_all_at(&u64::MAX.to_le_bytes(), footer_offset + 8)
+			.unwrap();
+
+		assert!(read_footer(&file, HEADER_SIZE, footer_offset).is_err());
+		let (reader, _) = TraceReader::open(&path).unwrap();
+		assert_eq!(read_all(&reader), [1, 2, 3]);
+	}
+
+	#[test]
+	fn implausible_footer_entries_are_rebuilt() {
+		// The offsets of each field of the second chunk's footer entry.
+		const OFFSET: u64 = FOOTER_HEADER_SIZE as u64 + FOOTER_ENTRY_SIZE as u64;
+		const COUNT: u64 = OFFSET + 8;
+		const FIRST_INDEX: u64 = OFFSET + 12;
+		const PAYLOAD_LEN: u64 = OFFSET + 36;
+		const RAW_LEN: u64 = OFFSET + 40;
+
+		for (field, value) in [
+			// Past the end of the file.
+			(OFFSET, 1 << 40),
+			// Overlapping the first chunk.
+			(OFFSET, HEADER_SIZE),
+			(FIRST_INDEX, 0),
+			(COUNT, 0),
+			(COUNT, CHUNK_CAPACITY as u64 + 1),
+			// Running into the footer.
+			(PAYLOAD_LEN, 1 << 20),
+			// More than the chunk's instructions could take up.
+			(RAW_LEN, u64::from(u32::MAX)),
+		] {
+			let dir = tempfile::tempdir().unwrap();
+			let (writer, path) = write_trace(dir.path(), &[vec![1, 2], vec![3]]);
+			let footer_offset = writer.offset;
+			writer.finalize().unwrap();
+
+			let file = File::options().read(true).write(true).open(&path).unwrap();
+			let bytes = value.to_le_bytes();
+			let len = if field == OFFSET || field == FIRST_INDEX {
+				8
+			} else {
+				4
+			};
+			file.write_all_at(&bytes[..len], footer_offset + field)
+				.unwrap();
+
+			assert!(
+				read_footer(&file, HEADER_SIZE, footer_offset).is_err(),
+				"field at {field} set to {value}"
+			);
+			let (reader, _) = TraceReader::open(&path).unwrap();
+			assert_eq!(read_all(&reader), [1, 2, 3]);
+		}
+	}
+
+	#[test]
+	fn chunks_past_the_end_of_the_file_are_errors() {
+		let dir = tempfile::tempdir().unwrap();
+		let (writer, path) = write_trace(dir.path(), &[vec![1, 2]]);
+		let reader = writable_reader(&writer, &path);
+		let mut chunk = chunks(writer.index())[0];
+		chunk.offset += 1 << 20;
+
+		let mut out = Vec::new();
+		assert_eq!(
+			reader.read_chunk(&chunk, &mut out).unwrap_err().kind(),
+			io::ErrorKind::UnexpectedEof
+		);
+	}
+
+	#[test]
+	fn truncated_traces_are_recovered() {
+		let dir = tempfile::tempdir().unwrap();
+		let (writer, path) = write_trace(dir.path(), &[vec![1, 2], vec![3, 4], vec![5, 6]]);
+		let written = chunks(writer.index());
+		drop(writer);
+
+		// Cut the last chunk short, as if the daemon crashed while writing it.
+		let file = File::options().write(true).open(&path).unwrap();
+		file.set_len(written[2].offset + CHUNK_HEADER_SIZE + 1)
+			.unwrap();
+
+		let file = File::open(&path).unwrap();
+		assert_eq!(recover_chunks(&file, HEADER_SIZE).unwrap(), written[..2]);
+
+		let (reader, _) = TraceReader::open(&path).unwrap();
+		assert_eq!(read_all(&reader), [1, 2, 3, 4]);
+	}
+
+	#[test]
+	fn version_1_traces_can_be_read() {
+		let dir = tempfile::tempdir().unwrap();
+		let (writer, path) = write_trace(dir.path(), &[vec![1, 2], vec![3]]);
+		let written = chunks(writer.index());
+		drop(writer);
+
+		// Version 1 traces are laid out the same way, just with a shorter header.
+		let v2 = std::fs::read(&path).unwrap();
+		let shift = HEADER_SIZE - V1_HEADER_SIZE;
+		let mut v1 = v2[..V1_HEADER_SIZE as usize].to_vec();
+		LittleEndian::write_u32(&mut v1[8..12], 1);
+		v1.extend_from_slice(&v2[HEADER_SIZE as usize..]);
+
+		let v1_path = dir.path().join("v1.ktrace");
+		std::fs::write(&v1_path, &v1).unwrap();
+		let (reader, thread_id) = TraceReader::open(&v1_path).unwrap();
+		assert_eq!(thread_id, 7);
+		assert_eq!(read_all(&reader), [1, 2, 3]);
+
+		// Finalized ones too.
+		let footer_offset = v1.len() as u64;
+		v1.extend_from_slice(&FOOTER_MAGIC.to_le_bytes());
+		v1.extend_from_slice(&0u32.to_le_bytes());
+		v1.extend_from_slice(&(written.len() as u64).to_le_bytes());
+		for chunk in &written {
+			v1.extend_from_slice(&(chunk.offset - shift).to_le_bytes());
+			chunk.write_fields(&mut v1);
+		}
+		LittleEndian::write_u64(&mut v1[24..32], footer_offset);
+
+		std::fs::write(&v1_path, &v1).unwrap();
+		let (reader, _) = TraceReader::open(&v1_path).unwrap();
+		assert_eq!(reader.index().chunk(0).unwrap().offset, V1_HEADER_SIZE);
+		assert_eq!(read_all(&reader), [1, 2, 3]);
+	}
+
+	#[test]
+	fn dropped_chunks_are_deallocated() {
+		let dir = tempfile::tempdir().unwrap();
+		let (writer, path) = write_trace(
+			dir.path(),
+			&[noisy(1, CHUNK_CAPACITY), noisy(2, CHUNK_CAPACITY)],
+		);
+		let reader = writable_reader(&writer, &path);
+		let [first, second] = chunks(writer.index())[..] else {
+			panic!("expected two chunks");
+		};
+
+		let blocks = std::fs::metadata(&path).unwrap().blocks();
+		assert_eq!(
+			reader.drop_oldest_chunk().unwrap(),
+			Some(first.stored_len())
+		);
+		assert!(std::fs::metadata(&path).unwrap().blocks() < blocks);
+
+		// The hole reads back as zeroes, and the header points past it.
+		let file = File::open(&path).unwrap();
+		let mut punched = vec![0xAA; first.stored_len() as usize];
+		file.read_exact_at(&mut punched, first.offset).unwrap();
+		assert!(punched.iter().all(|&byte| byte == 0));
+
+		let (reopened, _) = TraceReader::open(&path).unwrap();
+		assert_eq!(chunks(reopened.index()), [second]);
+		assert_eq!(read_all(&reopened), noisy(2, CHUNK_CAPACITY));
+	}
+
+	#[test]
+	fn published_instructions_are_readable() {
+		let dir = tempfile::tempdir().unwrap();
+		let (mut writer, path) = write_trace(dir.path(), &[vec![1, 2]]);
+		let reader = writable_reader(&writer, &path);
+
+		writer.push(3).unwrap();
+		writer.push(4).unwrap();
+		assert_eq!(reader.len(), 2);
+
+		writer.publish();
+		assert_eq!(reader.len(), 4);
+		assert_eq!(chunks(writer.index()).len(), 1);
+		assert_eq!(read_all(&reader), [1, 2, 3, 4]);
+
+		let mut backwards = Vec::new();
+		reader
+			.scan_backward(
+				3,
+				|_| true,
+				|index, addr| {
+					backwards.push((index, addr));
+					ControlFlow::Continue(())
+				},
+			)
+			.unwrap();
+		assert_eq!(backwards, [(2, 3), (1, 2), (0, 1)]);
+
+		// Writing the chunk out doesn't change what's readable.
+		writer.flush().unwrap();
+		assert_eq!(reader.len(), 4);
+		assert_eq!(read_all(&reader), [1, 2, 3, 4]);
+	}
+}
@@ -0,0 +1,41 @@
+//! Checks the access controls on the daemon's sockets.
+
+mod common;
+
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+use common::{Daemon, record, wait_until};
+use ktrace_protocol::Packet;
+
+#[test]
+fn socket_mode_and_group() {
+	// SAFETY: `getgid` has no memory safety requirements.
+	let gid = unsafe { libc::getgid() };
+	let daemon = Daemon::start_with(
+		tempfile::tempdir().unwrap(),
+		&["--sock-mode", "600", "--sock-group", &gid.to_string()],
+	);
+
+	for sock in [daemon.trace_sock(), daemon.query_sock()] {
+		let meta = sock.metadata().unwrap();
+		assert_eq!(meta.permissions().mode() & 0o7777, 0o600);
+		assert_eq!(meta.gid(), gid);
+	}
+}
+
+#[test]
+fn own_uid_is_always_allowed() {
+	// SAFETY: `geteuid` has no memory safety requirements.
+	let other = unsafe { libc::geteuid() }.wrapping_add(1);
+	let daemon = Daemon::start_with(
+		tempfile::tempdir().unwrap(),
+		&["--allow-uid", &other.to_string()],
+	);
+
+	let _producer = record(&daemon, 10);
+	wait_until(|| daemon.inst_count() == 10);
+	assert!(matches!(
+		daemon.request(Packet::ListSessions),
+		Packet::Sessions { .. }
+	));
+}
@@ -0,0 +1,232 @@
+//! Checks that bookmarks and notes are stored with their session.
+
+mod common;
+
+use common::{Daemon, record, record_vcpu, wait_for_exit, wait_until};
+use ktrace_protocol::{Annotation, AnnotationKind, Error, MAX_ANNOTATION_LEN, Packet};
+
+fn annotate(daemon: &Daemon, thread_id: u32, index: usize, kind: AnnotationKind, text: &str) -> Packet {
+	daemon.request(Packet::AddAnnotation {
+		session: None,
+		thread_id,
+		index,
+		kind,
+		text: text.to_string(),
+	})
+}
+
+fn annotations(daemon: &Daemon, thread_id: Option<u32>) -> Vec<Annotation> {
+	match daemon.request(Packet::ListAnnotations {
+		session: None,
+		thread_id,
+	}) {
+		Packet::Annotations { annotations } => annotations,
+		packet => panic!("unexpected response: {packet:?}"),
+	}
+}
+
+/// Returns the `(thread, index, text)` of each annotation.
+fn positions(annotations: &[Annotation]) -> Vec<(u32, usize, &str)> {
+	annotations
+		.iter()
+		.map(|a| (a.thread_id, a.index, a.text.as_str()))
+		.collect()
+}
+
+#[test]
+fn annotations_are_listed_in_order() {
+	let daemon = Daemon::start();
+	let _cpu0 = record_vcpu(&daemon, 1, 0, 100);
+	let _cpu1 = record_vcpu(&daemon, 1, 1, 100);
+	wait_until(|| daemon.inst_count() == 100);
+	wait_until(|| {
+		matches!(
+			daemon.request(Packet::GetInstCount {
+				session:   None,
+				thread_id: 1,
+			}),
+			Packet::InstCount { count: 100 }
+		)
+	});
+
+	let Packet::AnnotationAdded { annotation } =
+		annotate(&daemon, 1, 50, AnnotationKind::Note, "faults here")
+	else {
+		panic!("expected annotation");
+	};
+	assert_eq!(annotation.thread_id, 1);
+	assert_eq!(annotation.index, 50);
+	assert_eq!(annotation.kind, AnnotationKind::Note);
+	assert_eq!(annotation.text, "faults here");
+
+	for (thread_id, index, text) in [(0, 20, "b"), (1, 10, "c"), (0, 5, "a")] {
+		assert!(matches!(
+			annotate(&daemon, thread_id, index, AnnotationKind::Bookmark, text),
+			Packet::AnnotationAdded { .. }
+		));
+	}
+
+	assert_eq!(
+		positions(&annotations(&daemon, None)),
+		[
+			(0, 5, "a"),
+			(0, 20, "b"),
+			(1, 10, "c"),
+			(1, 50, "faults here")
+		]
+	);
+	assert_eq!(
+		positions(&annotations(&daemon, Some(1))),
+		[(1, 10, "c"), (1, 50, "faults here")]
+	);
+
+	assert!(matches!(
+		daemon.request(Packet::RemoveAnnotation {
+			session: None,
+			id:      annotation.id,
+		}),
+		Packet::AnnotationRemoved { id } if id == annotation.id
+	));
+	assert_eq!(annotations(&daemon, Some(1)).len(), 1);
+}
+
+#[test]
+fn annotations_persist_with_the_session() {
+	let daemon = Daemon::start();
+	drop(record(&daemon, 10));
+	wait_for_exit(&daemon);
+
+	assert!(matches!(
+		annotate(&daemon, 0, 3, AnnotationKind::Bookmark, "entry"),
+		Packet::AnnotationAdded { .. }
+	));
+
+	let daemon = Daemon::start_in(daemon.stop());
+	let Packet::Sessions { sessions } = daemon.request(Packet::ListSessions) else {
+		panic!("expected sessions");
+	};
+
+	let Packet::Annotations { annotations } = daemon.request(Packet::ListAnnotations {
+		session:   Some(sessions[0].name.clone()),
+		thread_id: None,
+	}) else {
+		panic!("expected annotations");
+	};
+	assert_eq!(positions(&annotations), [(0, 3, "entry")]);
+	assert_eq!(annotations[0].kind, AnnotationKind::Bookmark);
+}
+
+#[test]
+fn annotation_ids_are_not_reused() {
+	let daemon = Daemon::start();
+	drop(record(&daemon, 10));
+	wait_for_exit(&daemon);
+
+	let id = |packet| {
+		match packet {
+			Packet::AnnotationAdded { annotation } => annotation.id,
+			packet => panic!("unexpected response: {packet:?}"),
+		}
+	};
+
+	let first = id(annotate(&daemon, 0, 1, AnnotationKind::Note, "first"));
+	let second = id(annotate(&daemon, 0, 2, AnnotationKind::Note, "second"));
+	assert!(matches!(
+		daemon.request(Packet::RemoveAnnotation {
+			session: None,
+			id:      second,
+		}),
+		Packet::AnnotationRemoved { .. }
+	));
+
+	// Not even once the daemon restarts.
+	let daemon = Daemon::start_in(daemon.stop());
+	let Packet::Sessions { sessions } = daemon.request(Packet::ListSessions) else {
+		panic!("expected sessions");
+	};
+	let Packet::AnnotationAdded { annotation } = daemon.request(Packet::AddAnnotation {
+		session:   Some(sessions[0].name.clone()),
+		thread_id: 0,
+		index:     3,
+		kind:      AnnotationKind::Note,
+		text:      "third".to_string(),
+	}) else {
+		panic!("expected annotation");
+	};
+	assert_ne!(annotation.id, first);
+	assert_ne!(annotation.id, second);
+}
+
+#[test]
+fn annotations_that_cannot_be_stored_are_not_added() {
+	let daemon = Daemon::start();
+	drop(record(&daemon, 10));
+	wait_for_exit(&daemon);
+
+	// Keeps the session's metadata from being written.
+	let Packet::Sessions { sessions } = daemon.request(Packet::ListSessions) else {
+		panic!("expected sessions");
+	};
+	let dir = daemon.dir().join("sessions").join(&sessions[0].name);
+	std::fs::create_dir(dir.join("session.toml.tmp")).unwrap();
+
+	assert!(matches!(
+		annotate(&daemon, 0, 3, AnnotationKind::Note, "lost"),
+		Packet::Error(Error::Io)
+	));
+	assert!(annotations(&daemon, None).is_empty());
+}
+
+#[test]
+fn invalid_annotations_are_rejected() {
+	let daemon = Daemon::start();
+	let _producer = record(&daemon, 10);
+	wait_until(|| daemon.inst_count() == 10);
+
+	assert!(matches!(
+		annotate(&daemon, 7, 0, AnnotationKind::Note, "no such thread"),
+		Packet::Error(Error::BadThread)
+	));
+	assert!(matches!(
+		annotate(&daemon, 0, 0, AnnotationKind::Bookmark, " "),
+		Packet::Error(Error::BadAnnotation)
+	));
+	assert!(matches!(
+		annotate(
+			&daemon,
+			0,
+			0,
+			AnnotationKind::Note,
+			&"x".repeat(MAX_ANNOTATION_LEN + 1)
+		),
+		Packet::Error(Error::BadAnnotation)
+	));
+	assert!(matches!(
+		daemon.request(Packet::RemoveAnnotation {
+			session: None,
+			id:      42,
+		}),
+		Packet::Error(Error::BadAnnotation)
+	));
+	assert!(annotations(&daemon, None).is_empty());
+}
+
+#[test]
+fn clearing_a_trace_removes_its_annotations() {
+	let daemon = Daemon::start();
+	let _producer = record(&daemon, 10);
+	wait_until(|| daemon.inst_count() == 10);
+
+	assert!(matches!(
+		annotate(&daemon, 0, 3, AnnotationKind::Note, "stale"),
+		Packet::AnnotationAdded { .. }
+	));
+	assert!(matches!(
+		daemon.request(Packet::ClearTrace {
+			session:   None,
+			thread_id: Some(0),
+		}),
+		Packet::TraceCleared { .. }
+	));
+	assert!(annotations(&daemon, None).is_empty());
+}
@@ -0,0 +1,166 @@
+//! Checks that traces can be cleared while they're being recorded.
+
+mod common;
+
+use std::io::{BufWriter, Read, Write};
+
+use common::{Daemon, record, record_vcpu, wait_for_exit, wait_until};
+use ktrace_plugin_protocol::{self as plugin, TraceWrite};
+use ktrace_protocol::{Error, InstEntry, Packet, PacketSerializer, STREAM_CLEARED};
+
+/// The address of the `i`th instruction recorded after a clear, distinct from [`common::addr`].
+fn new_addr(i: usize) -> u64 {
+	0x9000 + i as u64 * 4
+}
+
+fn clear(daemon: &Daemon, thread_id: Option<u32>) -> Packet {
+	daemon.request(Packet::ClearTrace {
+		session: None,
+		thread_id,
+	})
+}
+
+/// Records `count` more instructions (at [`new_addr`]) and makes them readable.
+fn record_more(producer: &mut BufWriter<impl Write>, count: usize) {
+	for i in 0..count {
+		producer
+			.write_packet(&plugin::Packet::Inst(plugin::Inst { addr: new_addr(i) }))
+			.unwrap();
+	}
+	producer.write_packet(&plugin::Packet::VcpuIdle).unwrap();
+	producer.flush().unwrap();
+}
+
+#[test]
+fn clearing_starts_the_trace_over() {
+	let daemon = Daemon::start();
+	let mut producer = record(&daemon, 100_000);
+	wait_until(|| daemon.inst_count() == 100_000);
+
+	assert!(matches!(
+		clear(&daemon, Some(0)),
+		Packet::TraceCleared { threads } if threads == [0]
+	));
+	assert_eq!(daemon.inst_count(), 0);
+
+	let Packet::StorageStats { stats } = daemon.request(Packet::GetStorageStats {
+		session:   None,
+		thread_id: 0,
+	}) else {
+		panic!("expected storage stats");
+	};
+	assert_eq!(stats.instructions, 0);
+	assert_eq!(stats.stored_bytes, 0);
+
+	record_more(&mut producer, 10);
+	wait_until(|| daemon.inst_count() == 10);
+
+	let Packet::Range { entries } = daemon.request(Packet::GetRange {
+		session:   None,
+		thread_id: 0,
+		start:     0,
+		count:     100,
+		filter:    None,
+	}) else {
+		panic!("expected range");
+	};
+	let expected = (0..10)
+		.map(|index| {
+			InstEntry {
+				index,
+				addr: new_addr(index),
+			}
+		})
+		.collect::<Vec<_>>();
+	assert_eq!(entries, expected);
+}
+
+#[test]
+fn streams_are_told_about_clears() {
+	let daemon = Daemon::start();
+	let mut producer = record(&daemon, 100);
+	producer.write_packet(&plugin::Packet::VcpuIdle).unwrap();
+	producer.flush().unwrap();
+	wait_until(|| daemon.inst_count() == 100);
+
+	let mut stream = daemon.client();
+	stream
+		.serialize_packet(&Packet::OpenStream {
+			session:   None,
+			thread_id: 0,
+			filter:    None,
+		})
+		.unwrap();
+	let mut buf = vec![0; 100 * 8];
+	stream.read_exact(&mut buf).unwrap();
+
+	assert!(matches!(clear(&daemon, None), Packet::TraceCleared { .. }));
+	record_more(&mut producer, 10);
+
+	let mut buf = vec![0; 11 * 8];
+	stream.read_exact(&mut buf).unwrap();
+	let addrs = buf
+		.chunks_exact(8)
+		.map(|addr| u64::from_le_bytes(addr.try_into().unwrap()))
+		.collect::<Vec<_>>();
+
+	assert_eq!(addrs[0], STREAM_CLEARED);
+	assert_eq!(addrs[1..], (0..10).map(new_addr).collect::<Vec<_>>());
+}
+
+#[test]
+fn clearing_all_threads() {
+	let daemon = Daemon::start();
+	let mut cpu0 = record_vcpu(&daemon, 1, 0, 100);
+	let _cpu1 = record_vcpu(&daemon, 1, 1, 200);
+	wait_until(|| daemon.inst_count() == 100);
+	wait_until(|| {
+		matches!(
+			daemon.request(Packet::GetInstCount {
+				session:   None,
+				thread_id: 1,
+			}),
+			Packet::InstCount { count: 200 }
+		)
+	});
+
+	assert!(matches!(
+		clear(&daemon, None),
+		Packet::TraceCleared { threads } if threads == [0, 1]
+	));
+
+	for thread_id in [0, 1] {
+		assert!(matches!(
+			daemon.request(Packet::GetInstCount {
+				session: None,
+				thread_id,
+			}),
+			Packet::InstCount { count: 0 }
+		));
+	}
+
+	// Recording continues.
+	record_more(&mut cpu0, 10);
+	wait_until(|| daemon.inst_count() == 10);
+}
+
+#[test]
+fn finished_threads_cannot_be_cleared() {
+	let daemon = Daemon::start();
+	drop(record(&daemon, 10));
+	wait_for_exit(&daemon);
+
+	assert!(matches!(
+		clear(&daemon, Some(0)),
+		Packet::Error(Error::NotRecording)
+	));
+	assert!(matches!(
+		clear(&daemon, Some(7)),
+		Packet::Error(Error::BadThread)
+	));
+	assert!(matches!(
+		clear(&daemon, None),
+		Packet::TraceCleared { threads } if threads.is_empty()
+	));
+	assert_eq!(daemon.inst_count(), 10);
+}
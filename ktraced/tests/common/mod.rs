@@ -0,0 +1,275 @@
+//! Helpers for running the daemon in integration tests.
+
+// Not every test uses every helper.
+#![allow(dead_code)]
+
+use std::{
+	io::{BufWriter, Read, Write},
+	os::unix::net::UnixStream,
+	path::{Path, PathBuf},
+	process::{Child, Command, ExitStatus, Stdio},
+	thread,
+	time::{Duration, Instant},
+};
+
+use ktrace_plugin_protocol::{self as plugin, TraceWrite};
+use ktrace_protocol::{InstEntry, Packet, PacketDeserializer, PacketSerializer, SessionInfo};
+use ktrace_synth::Workload;
+use tempfile::TempDir;
+
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A daemon running in a temporary directory, killed when dropped.
+pub struct Daemon {
+	child: Child,
+	dir:   TempDir,
+}
+
+impl Daemon {
+	pub fn start() -> Self {
+		Self::start_in(tempfile::tempdir().unwrap())
+	}
+
+	pub fn start_in(dir: TempDir) -> Self {
+		Self::start_with(dir, &[])
+	}
+
+	/// Starts a daemon in `dir`, passing it extra arguments.
+	pub fn start_with(dir: TempDir, args: &[&str]) -> Self {
+		let child = Self::command(dir.path()).args(args).spawn().unwrap();
+
+		let this = Self { child, dir };
+		wait_until(|| {
+			UnixStream::connect(this.trace_sock()).is_ok() && UnixStream::connect(this.query_sock()).is_ok()
+		});
+		this
+	}
+
+	/// Returns the command that runs a daemon with its sockets and storage in `dir`.
+	pub fn command(dir: &Path) -> Command {
+		let mut command = Command::new(env!("CARGO_BIN_EXE_ktraced"));
+		command
+			.arg("--trace-sock")
+			.arg(dir.join("trace.sock"))
+			.arg("--sock")
+			.arg(dir.join("query.sock"))
+			.arg("--storage-dir")
+			.arg(dir.join("sessions"))
+			// Keep the user's config file out of the way.
+			.env("XDG_CONFIG_HOME", dir)
+			.stderr(Stdio::null());
+		command
+	}
+
+	pub fn dir(&self) -> &Path {
+		self.dir.path()
+	}
+
+	/// Sends a signal to the daemon and waits for it to exit.
+	pub fn signal(&mut self, signal: i32) -> ExitStatus {
+		// SAFETY: `kill` has no memory safety requirements.
+		assert_eq!(unsafe { libc::kill(self.child.id() as i32, signal) }, 0);
+
+		let start = Instant::now();
+		loop {
+			if let Some(status) = self.child.try_wait().unwrap() {
+				return status;
+			}
+			assert!(start.elapsed() < TIMEOUT, "timed out");
+			thread::sleep(Duration::from_millis(10));
+		}
+	}
+
+	/// Stops the daemon, returning its directory so it can be restarted.
+	pub fn stop(mut self) -> TempDir {
+		self.child.kill().unwrap();
+		self.child.wait().unwrap();
+		let dir = tempfile::tempdir().unwrap();
+		std::mem::replace(&mut self.dir, dir)
+	}
+
+	pub fn trace_sock(&self) -> PathBuf {
+		self.dir.path().join("trace.sock")
+	}
+
+	pub fn query_sock(&self) -> PathBuf {
+		self.dir.path().join("query.sock")
+	}
+
+	pub fn producer(&self) -> UnixStream {
+		UnixStream::connect(self.trace_sock()).unwrap()
+	}
+
+	pub fn client(&self) -> UnixStream {
+		let stream = UnixStream::connect(self.query_sock()).unwrap();
+		stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+		stream
+	}
+
+	pub fn request(&self, packet: Packet) -> Packet {
+		request(&mut self.client(), packet)
+	}
+
+	pub fn inst_count(&self) -> usize {
+		match self.request(Packet::GetInstCount {
+			session:   None,
+			thread_id: 0,
+		}) {
+			Packet::InstCount { count } => count,
+			packet => panic!("unexpected response: {packet:?}"),
+		}
+	}
+
+	pub fn is_live(&self) -> bool {
+		match self.request(Packet::ListSessions) {
+			Packet::Sessions { sessions } => sessions.iter().any(|session| session.live),
+			packet => panic!("unexpected response: {packet:?}"),
+		}
+	}
+
+	/// Checks that the daemon is still running and serving clients.
+	pub fn assert_alive(&mut self) {
+		assert!(self.child.try_wait().unwrap().is_none(), "daemon exited");
+		assert!(matches!(
+			self.request(Packet::ListSessions),
+			Packet::Sessions { .. }
+		));
+	}
+}
+
+impl Drop for Daemon {
+	fn drop(&mut self) {
+		let _ = self.child.kill();
+		let _ = self.child.wait();
+	}
+}
+
+pub fn request(stream: &mut UnixStream, packet: Packet) -> Packet {
+	stream.serialize_packet(&packet).unwrap();
+	stream.deserialize_packet().unwrap()
+}
+
+pub fn wait_until(mut condition: impl FnMut() -> bool) {
+	let start = Instant::now();
+	while !condition() {
+		assert!(start.elapsed() < TIMEOUT, "timed out");
+		thread::sleep(Duration::from_millis(10));
+	}
+}
+
+/// Asserts that the peer closes the connection without sending anything.
+pub fn assert_closed(stream: &mut UnixStream) {
+	stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+	let mut buf = [0; 1];
+	assert!(matches!(stream.read(&mut buf), Ok(0) | Err(_)));
+}
+
+pub fn addr(i: usize) -> u64 {
+	0x1000 + (i as u64 % 777) * 4
+}
+
+/// Starts a producer for vCPU 0 and records `count` instructions.
+pub fn record(daemon: &Daemon, count: usize) -> BufWriter<UnixStream> {
+	record_vcpu(daemon, 1, 0, count)
+}
+
+/// Starts a vCPU for the given producer and records `count` instructions.
+pub fn record_vcpu(daemon: &Daemon, producer_id: u64, id: u32, count: usize) -> BufWriter<UnixStream> {
+	let mut producer = BufWriter::new(daemon.producer());
+	producer
+		.write_packet(&plugin::Packet::VcpuInit(plugin::VcpuInit {
+			id,
+			producer: Some(producer_id),
+		}))
+		.unwrap();
+	producer.write_packet(&plugin::Packet::VcpuResume).unwrap();
+	for i in 0..count {
+		producer
+			.write_packet(&plugin::Packet::Inst(plugin::Inst { addr: addr(i) }))
+			.unwrap();
+	}
+	producer.flush().unwrap();
+	producer
+}
+
+/// Starts a vCPU for the given producer and sends its whole synthetic trace, except for
+/// its `VcpuExit`, so that the vCPU stays connected until it's sent or the stream is dropped.
+pub fn record_workload(
+	daemon: &Daemon,
+	workload: &Workload,
+	producer_id: u64,
+	id: u32,
+) -> BufWriter<UnixStream> {
+	let mut producer = BufWriter::new(daemon.producer());
+	for packet in workload.vcpu(producer_id, id) {
+		if !matches!(packet, plugin::Packet::VcpuExit) {
+			producer.write_packet(&packet).unwrap();
+		}
+	}
+	producer.flush().unwrap();
+	producer
+}
+
+/// Checks that the first `count` recorded instructions can be read back.
+pub fn assert_recorded(daemon: &Daemon, count: usize) {
+	assert_eq!(daemon.inst_count(), count);
+
+	let start = count.saturating_sub(10);
+	let Packet::Range { entries } = daemon.request(Packet::GetRange {
+		session: None,
+		thread_id: 0,
+		start,
+		count: 10,
+		filter: None,
+	}) else {
+		panic!("expected range");
+	};
+
+	let expected = (start..count)
+		.map(|index| {
+			InstEntry {
+				index,
+				addr: addr(index),
+			}
+		})
+		.collect::<Vec<_>>();
+	assert_eq!(entries, expected);
+}
+
+/// Waits until every producer that connected so far has been recorded and exited.
+pub fn wait_for_exit(daemon: &Daemon) {
+	wait_until(|| {
+		let sessions = sessions(daemon);
+		!sessions.is_empty() && sessions.iter().all(|s| !s.live)
+	});
+}
+
+/// Lists the daemon's sessions.
+pub fn sessions(daemon: &Daemon) -> Vec<SessionInfo> {
+	match daemon.request(Packet::ListSessions) {
+		Packet::Sessions { sessions } => sessions,
+		packet => panic!("unexpected response: {packet:?}"),
+	}
+}
+
+/// Requests the instruction count of a thread of a named session.
+pub fn inst_count(daemon: &Daemon, session: &str, thread_id: u32) -> Packet {
+	daemon.request(Packet::GetInstCount {
+		session: Some(session.to_string()),
+		thread_id,
+	})
+}
+
+/// Reads the first `count` instructions of a thread of a named session.
+pub fn range(daemon: &Daemon, session: &str, thread_id: u32, count: usize) -> Vec<InstEntry> {
+	match daemon.request(Packet::GetRange {
+		session: Some(session.to_string()),
+		thread_id,
+		start: 0,
+		count,
+		filter: None,
+	}) {
+		Packet::Range { entries } => entries,
+		packet => panic!("unexpected response: {packet:?}"),
+	}
+}
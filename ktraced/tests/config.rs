@@ -0,0 +1,109 @@
+//! Checks that the daemon reads its config file, and that flags override it.
+
+mod common;
+
+use std::{
+	fs,
+	os::unix::{fs::PermissionsExt, net::UnixStream},
+	process::{Command, Stdio},
+};
+
+use common::{Daemon, wait_until};
+
+#[test]
+fn default_config_file() {
+	let dir = tempfile::tempdir().unwrap();
+	fs::create_dir(dir.path().join("ktrace")).unwrap();
+	fs::write(
+		dir.path().join("ktrace/config.toml"),
+		"[daemon]\nsock-mode = \"600\"\nretention = \"stop\"\n",
+	)
+	.unwrap();
+
+	let daemon = Daemon::start_in(dir);
+	for sock in [daemon.trace_sock(), daemon.query_sock()] {
+		let mode = sock.metadata().unwrap().permissions().mode();
+		assert_eq!(mode & 0o7777, 0o600);
+	}
+}
+
+#[test]
+fn flags_override_config() {
+	let dir = tempfile::tempdir().unwrap();
+	let config = dir.path().join("custom.toml");
+	fs::write(
+		&config,
+		format!(
+			"[sockets]\ntrace = '{}'\nquery = '{}'\n\n[daemon]\nsock-mode = \"600\"\n",
+			dir.path().join("config-trace.sock").display(),
+			dir.path().join("config-query.sock").display(),
+		),
+	)
+	.unwrap();
+
+	let daemon = Daemon::start_with(
+		dir,
+		&["--config", config.to_str().unwrap(), "--sock-mode", "640"],
+	);
+
+	// The socket paths given as flags win over those in the config file.
+	assert!(!daemon.dir().join("config-trace.sock").exists());
+	assert!(!daemon.dir().join("config-query.sock").exists());
+	for sock in [daemon.trace_sock(), daemon.query_sock()] {
+		let mode = sock.metadata().unwrap().permissions().mode();
+		assert_eq!(mode & 0o7777, 0o640);
+	}
+}
+
+#[test]
+fn config_socket_paths() {
+	let dir = tempfile::tempdir().unwrap();
+	let config = dir.path().join("custom.toml");
+	fs::write(
+		&config,
+		format!(
+			"[sockets]\ntrace = '{}'\nquery = '{}'\n\n[daemon]\nstorage-dir = '{}'\n",
+			dir.path().join("config-trace.sock").display(),
+			dir.path().join("config-query.sock").display(),
+			dir.path().join("config-sessions").display(),
+		),
+	)
+	.unwrap();
+
+	let mut child = Command::new(env!("CARGO_BIN_EXE_ktraced"))
+		.arg("--config")
+		.arg(&config)
+		.stderr(Stdio::null())
+		.spawn()
+		.unwrap();
+
+	wait_until(|| {
+		UnixStream::connect(dir.path().join("config-trace.sock")).is_ok()
+			&& UnixStream::connect(dir.path().join("config-query.sock")).is_ok()
+	});
+	assert!(dir.path().join("config-sessions").is_dir());
+
+	child.kill().unwrap();
+	child.wait().unwrap();
+}
+
+#[test]
+fn invalid_config_is_refused() {
+	let dir = tempfile::tempdir().unwrap();
+	let config = dir.path().join("custom.toml");
+	fs::write(&config, "[daemon]\nmax-total-size = \"lots\"\n").unwrap();
+
+	let status = Daemon::command(dir.path())
+		.arg("--config")
+		.arg(&config)
+		.status()
+		.unwrap();
+	assert!(!status.success());
+
+	let status = Daemon::command(dir.path())
+		.arg("--config")
+		.arg(dir.path().join("missing.toml"))
+		.status()
+		.unwrap();
+	assert!(!status.success());
+}
@@ -0,0 +1,263 @@
+//! Connects to a running daemon, misbehaves and disconnects at every protocol stage,
+//! and checks that only the offending connection is affected.
+
+mod common;
+
+use std::io::{Read, Write};
+
+use common::{Daemon, addr, assert_closed, assert_recorded, record, request, wait_for_exit, wait_until};
+use ktrace_plugin_protocol::{self as plugin, TraceWrite};
+use ktrace_protocol::{AddrRange, Error, Packet, PacketSerializer, SearchOp, ThreadStatus};
+
+#[test]
+fn producer_disconnects_before_init() {
+	let mut daemon = Daemon::start();
+	drop(daemon.producer());
+	daemon.assert_alive();
+	assert!(!daemon.is_live());
+}
+
+#[test]
+fn producer_sends_garbage_before_init() {
+	let mut daemon = Daemon::start();
+	let mut producer = daemon.producer();
+	producer.write_all(&[0xFF; 16]).unwrap();
+	assert_closed(&mut producer);
+	daemon.assert_alive();
+}
+
+#[test]
+fn producer_sends_wrong_first_packet() {
+	let mut daemon = Daemon::start();
+	let mut producer = daemon.producer();
+	producer.write_packet(&plugin::Packet::VcpuResume).unwrap();
+	assert_closed(&mut producer);
+	daemon.assert_alive();
+	assert!(!daemon.is_live());
+}
+
+#[test]
+fn producer_disconnects_mid_packet() {
+	let mut daemon = Daemon::start();
+	let mut producer = record(&daemon, 100).into_inner().unwrap();
+	// The first few bytes of an `Inst` packet.
+	producer.write_all(&[5, 0x34, 0x12]).unwrap();
+	drop(producer);
+
+	wait_for_exit(&daemon);
+	assert_recorded(&daemon, 100);
+	daemon.assert_alive();
+}
+
+#[test]
+fn producer_sends_garbage_after_init() {
+	let mut daemon = Daemon::start();
+	let mut producer = record(&daemon, 10).into_inner().unwrap();
+	producer.write_all(&[0xFF]).unwrap();
+	assert_closed(&mut producer);
+
+	wait_for_exit(&daemon);
+	assert_recorded(&daemon, 10);
+	daemon.assert_alive();
+}
+
+#[test]
+fn producer_sends_reserved_address() {
+	let mut daemon = Daemon::start();
+	let mut producer = record(&daemon, 10).into_inner().unwrap();
+	producer
+		.write_packet(&plugin::Packet::Inst(plugin::Inst {
+			addr: plugin::RESERVED_ADDR,
+		}))
+		.unwrap();
+	assert_closed(&mut producer);
+
+	wait_for_exit(&daemon);
+	assert_recorded(&daemon, 10);
+	daemon.assert_alive();
+}
+
+#[test]
+fn producer_disconnects_without_exit() {
+	const COUNT: usize = 100_000;
+
+	let daemon = Daemon::start();
+	drop(record(&daemon, COUNT));
+
+	wait_for_exit(&daemon);
+	assert_recorded(&daemon, COUNT);
+
+	// The trace was finalized, so it can be reopened.
+	let daemon = Daemon::start_in(daemon.stop());
+	let Packet::Sessions { sessions } = daemon.request(Packet::ListSessions) else {
+		panic!("expected sessions");
+	};
+	let mut client = daemon.client();
+	assert!(matches!(
+		request(
+			&mut client,
+			Packet::OpenSession {
+				name: sessions[0].name.clone(),
+			}
+		),
+		Packet::SessionOpened { .. }
+	));
+	assert!(matches!(
+		request(
+			&mut client,
+			Packet::GetInstCount {
+				session:   None,
+				thread_id: 0,
+			}
+		),
+		Packet::InstCount { count: COUNT }
+	));
+}
+
+#[test]
+fn producer_exits_cleanly() {
+	let mut daemon = Daemon::start();
+	let mut producer = record(&daemon, 1000);
+	producer.write_packet(&plugin::Packet::VcpuExit).unwrap();
+	producer.flush().unwrap();
+	assert_closed(producer.get_mut());
+
+	wait_for_exit(&daemon);
+	assert_recorded(&daemon, 1000);
+	assert!(matches!(
+		daemon.request(Packet::GetStatus {
+			session:   None,
+			thread_id: 0,
+		}),
+		Packet::Status {
+			status: ThreadStatus::Dead,
+		}
+	));
+	daemon.assert_alive();
+}
+
+#[test]
+fn client_disconnects_immediately() {
+	let mut daemon = Daemon::start();
+	drop(daemon.client());
+	daemon.assert_alive();
+}
+
+#[test]
+fn client_disconnects_mid_request() {
+	let mut daemon = Daemon::start();
+
+	let mut encoded = Vec::new();
+	encoded
+		.serialize_packet(&Packet::OpenSession {
+			name: "some-session".into(),
+		})
+		.unwrap();
+
+	let mut client = daemon.client();
+	client.write_all(&encoded[..encoded.len() / 2]).unwrap();
+	drop(client);
+
+	daemon.assert_alive();
+}
+
+#[test]
+fn client_sends_garbage() {
+	let mut daemon = Daemon::start();
+	let mut client = daemon.client();
+	// 0xc1 is never used in msgpack.
+	client.write_all(&[0xC1; 4]).unwrap();
+	assert_closed(&mut client);
+	daemon.assert_alive();
+}
+
+#[test]
+fn client_sends_unexpected_packet() {
+	let mut daemon = Daemon::start();
+	let mut client = daemon.client();
+	assert!(matches!(
+		request(&mut client, Packet::InstCount { count: 1 }),
+		Packet::Error(Error::BadPacket)
+	));
+	// The connection is still usable.
+	assert!(matches!(
+		request(&mut client, Packet::ListSessions),
+		Packet::Sessions { .. }
+	));
+	daemon.assert_alive();
+}
+
+#[test]
+fn client_disconnects_before_response() {
+	let mut daemon = Daemon::start();
+	drop(record(&daemon, 200_000));
+	wait_for_exit(&daemon);
+
+	for _ in 0..10 {
+		let mut client = daemon.client();
+		client
+			.serialize_packet(&Packet::Search {
+				session:   None,
+				thread_id: 0,
+				range:     AddrRange {
+					start: 0,
+					end:   u64::MAX,
+				},
+				op:        SearchOp::Count,
+				limit:     0,
+			})
+			.unwrap();
+		drop(client);
+	}
+
+	daemon.assert_alive();
+	assert_recorded(&daemon, 200_000);
+}
+
+#[test]
+fn stream_reader_disconnects() {
+	let mut daemon = Daemon::start();
+	let mut producer = record(&daemon, 100_000);
+	wait_until(|| daemon.inst_count() > 0);
+
+	let mut stream = daemon.client();
+	stream
+		.serialize_packet(&Packet::OpenStream {
+			session:   None,
+			thread_id: 0,
+			filter:    None,
+		})
+		.unwrap();
+	let mut buf = [0; 8];
+	stream.read_exact(&mut buf).unwrap();
+	assert_eq!(u64::from_le_bytes(buf), addr(0));
+	drop(stream);
+
+	// Recording carries on unaffected.
+	for i in 100_000..200_000 {
+		producer
+			.write_packet(&plugin::Packet::Inst(plugin::Inst { addr: addr(i) }))
+			.unwrap();
+	}
+	producer.write_packet(&plugin::Packet::VcpuExit).unwrap();
+	producer.flush().unwrap();
+
+	wait_for_exit(&daemon);
+	assert_recorded(&daemon, 200_000);
+	daemon.assert_alive();
+}
+
+#[test]
+fn stream_for_unknown_thread() {
+	let mut daemon = Daemon::start();
+	let mut stream = daemon.client();
+	stream
+		.serialize_packet(&Packet::OpenStream {
+			session:   None,
+			thread_id: 7,
+			filter:    None,
+		})
+		.unwrap();
+	assert_closed(&mut stream);
+	daemon.assert_alive();
+}
@@ -0,0 +1,256 @@
+//! Checks that traces logged by other tools are imported into sessions.
+
+mod common;
+
+use common::{Daemon, sessions, wait_for_exit};
+use ktrace_import::{
+	Importer, LineTrace, ParseLine, Record, bochs, perf, ptxed,
+	qemu::{QemuLog, QemuLogStats},
+};
+use ktrace_protocol::{InstEntry, Packet};
+
+/// Two vCPUs, logged with `-d exec,nochain,in_asm`.
+const EXEC_IN_ASM: &str = "\
+----------------
+IN: _start
+0x0000000000400000:  48 31 c0                 xorq     %rax, %rax
+0x0000000000400003:  48 ff c0                 incq     %rax
+0x0000000000400006:  eb 08                    jmp      0x400010
+
+Trace 0: 0x7f2c40000100 [00000000/0000000000400000/00000000/ff000000] _start
+----------------
+IN: loop
+0x0000000000400010:  48 ff c8                 decq     %rax
+0x0000000000400013:  75 fb                    jne      0x400010
+
+Trace 0: 0x7f2c40000200 [00000000/0000000000400010/00000000/ff000000] loop
+Trace 1: 0x7f2c40000100 [00000000/0000000000400000/00000000/ff000000] _start
+Linking TBs 0x7f2c40000100 index 0 -> 0x7f2c40000200
+Trace 0: 0x7f2c40000200 [00000000/0000000000400010/00000000/ff000000] loop
+Trace 1: 0x7f2c40000200 [00000000/0000000000400010/00000000/ff000000] loop
+";
+
+/// An older QEMU's `-d exec,cpu` (without vCPU numbers or `in_asm`), in real mode.
+const EXEC_CPU: &str = "\
+Trace 0x7f0a4c000040 [00000000000f0000/00000000000ffff0/0xb0] 
+EAX=00000000 EBX=00000000 ECX=00000000 EDX=00000663
+ESI=00000000 EDI=00000000 EBP=00000000 ESP=00000000
+EIP=0000fff0 EFL=00000002 [-------] CPL=0 II=0 A20=1 SMM=0 HLT=0
+ES =0000 00000000 0000ffff 00009300
+CS =f000 000f0000 0000ffff 00009b00
+Trace 0x7f0a4c000100 [00000000000f0000/00000000000fe05b/0xb0] 
+EIP=0000e05b EFL=00000002 [-------] CPL=0 II=0 A20=1 SMM=0 HLT=0
+CS =f000 000f0000 0000ffff 00009b00
+";
+
+/// `-d cpu,in_asm` on AArch64, with no `exec` lines at all.
+const CPU_IN_ASM: &str = "\
+----------------
+IN: 
+0x40000000:  d2800000  movz     x0, #0
+0x40000004:  91000400  add      x0, x0, #1
+
+ PC=0000000040000000 X00=0000000000000000 X01=0000000000000000
+X02=0000000000000000 X03=0000000000000000 X04=0000000000000000
+ PC=0000000040000000 X00=0000000000000001 X01=0000000000000000
+";
+
+/// Two CPUs, traced by Bochs' debugger.
+const BOCHS: &str = "\
+Next at t=0
+(0) [0x0000fffffff0] f000:fff0 (unk. ctxt): jmpf 0xf000:e05b          ; ea5be000f0
+<bochs:1> trace on
+Tracing enabled for CPU0
+(0).[1] [0x0000000fe05b] f000:e05b (unk. ctxt): xor ax, ax              ; 31c0
+(1).[1] [0x0000000fe05b] f000:e05b (unk. ctxt): xor ax, ax              ; 31c0
+(0).[2] [0x0000000fe05d] f000:e05d (unk. ctxt): out 0x0d, al            ; e60d
+";
+
+/// `perf script` output, with and without extra fields.
+const PERF: &str = "\
+# ========
+# captured on    : Sat Oct 18 12:00:00 2026
+# ========
+#
+[000]     ffffffff81000000
+[000]     ffffffff81000004 native_irq_return_iret+0x0 ([kernel.kallsyms])
+         kernel  4242/4242  [001]  1234.567890:          1  instructions:k:  ffffffff81000010 do_idle+0x0 \
+                    ([kernel.kallsyms])
+";
+
+/// `ptxed` output.
+const PTXED: &str = "\
+[enabled]
+[exec mode: 64-bit]
+0000000000401000  mov rax, rbx
+0000000000401003  ret
+[disabled]
+";
+
+fn import_lines(daemon: &Daemon, traces: &[&str], parse: ParseLine) {
+	let mut importer = Importer::new(daemon.trace_sock().to_str().unwrap());
+	for (vcpu, trace) in traces.iter().enumerate() {
+		importer
+			.send_all(LineTrace::new(trace.as_bytes(), vcpu as u32, parse))
+			.unwrap();
+	}
+	importer.finish().unwrap();
+
+	wait_for_exit(daemon);
+}
+
+fn import(daemon: &Daemon, log: &str) -> QemuLogStats {
+	let mut importer = Importer::new(daemon.trace_sock().to_str().unwrap());
+	let mut log = QemuLog::new(log.as_bytes());
+	importer.send_all(&mut log).unwrap();
+	importer.finish().unwrap();
+
+	wait_for_exit(daemon);
+	log.stats()
+}
+
+fn addrs(daemon: &Daemon, thread_id: u32) -> Vec<u64> {
+	match daemon.request(Packet::GetRange {
+		session: None,
+		thread_id,
+		start: 0,
+		count: 100,
+		filter: None,
+	}) {
+		Packet::Range { entries } => entries.iter().map(|InstEntry { addr, .. }| *addr).collect(),
+		packet => panic!("unexpected response: {packet:?}"),
+	}
+}
+
+#[test]
+fn executions_are_expanded_into_instructions() {
+	let daemon = Daemon::start();
+	let stats = import(&daemon, EXEC_IN_ASM);
+	assert_eq!(
+		stats,
+		QemuLogStats {
+			executions:   5,
+			untranslated: 0,
+			from_dumps:   0,
+		}
+	);
+
+	let sessions = sessions(&daemon);
+	assert_eq!(sessions.len(), 1);
+	assert_eq!(sessions[0].threads, [0, 1]);
+
+	let start = [0x40_0000, 0x40_0003, 0x40_0006];
+	let body = [0x40_0010, 0x40_0013];
+	assert_eq!(addrs(&daemon, 0), [&start[..], &body, &body].concat());
+	assert_eq!(addrs(&daemon, 1), [&start[..], &body].concat());
+}
+
+#[test]
+fn register_dumps_of_exec_lines_are_not_recorded_twice() {
+	let daemon = Daemon::start();
+	let stats = import(&daemon, EXEC_CPU);
+	assert_eq!(
+		stats,
+		QemuLogStats {
+			executions:   2,
+			untranslated: 2,
+			from_dumps:   0,
+		}
+	);
+	assert_eq!(addrs(&daemon, 0), [0xF_FFF0, 0xF_E05B]);
+}
+
+#[test]
+fn register_dumps_stand_in_for_exec_lines() {
+	let daemon = Daemon::start();
+	let stats = import(&daemon, CPU_IN_ASM);
+	assert_eq!(
+		stats,
+		QemuLogStats {
+			executions:   2,
+			untranslated: 0,
+			from_dumps:   2,
+		}
+	);
+	assert_eq!(
+		addrs(&daemon, 0),
+		[0x4000_0000, 0x4000_0004, 0x4000_0000, 0x4000_0004]
+	);
+}
+
+#[test]
+fn malformed_exec_lines_are_rejected() {
+	let err = QemuLog::new("Trace 0: 0x7f2c40000100 [00000000/zzzz/00000000/ff000000]\n".as_bytes())
+		.collect::<Result<Vec<Record>, _>>()
+		.unwrap_err();
+	assert!(err.to_string().starts_with("line 1: bad exec line"));
+}
+
+#[test]
+fn bochs_traces_are_imported() {
+	let daemon = Daemon::start();
+	import_lines(&daemon, &[BOCHS], bochs::parse_line);
+
+	assert_eq!(addrs(&daemon, 0), [0xFFFF_FFF0, 0xF_E05B, 0xF_E05D]);
+	assert_eq!(addrs(&daemon, 1), [0xF_E05B]);
+}
+
+#[test]
+fn perf_script_output_is_imported() {
+	let daemon = Daemon::start();
+	import_lines(&daemon, &[PERF], perf::parse_line);
+
+	assert_eq!(
+		addrs(&daemon, 0),
+		[0xFFFF_FFFF_8100_0000, 0xFFFF_FFFF_8100_0004]
+	);
+	assert_eq!(addrs(&daemon, 1), [0xFFFF_FFFF_8100_0010]);
+}
+
+#[test]
+fn ptxed_traces_are_imported_as_a_vcpu_each() {
+	let daemon = Daemon::start();
+	import_lines(&daemon, &[PTXED, PTXED], ptxed::parse_line);
+
+	for thread_id in [0, 1] {
+		assert_eq!(addrs(&daemon, thread_id), [0x40_1000, 0x40_1003]);
+	}
+}
+
+#[test]
+fn malformed_lines_are_skipped() {
+	for (trace, parse, bad_line) in [
+		(
+			"(0).[1] [0xzz] f000:e05b\n(0) [0x1000] f000:1000\n",
+			bochs::parse_line as ParseLine,
+			1,
+		),
+		(
+			"[000] 1234.5: instructions: nothex\n[000] 1000\n",
+			perf::parse_line,
+			1,
+		),
+		("[enabled]\nmov rax, rbx\n1000  nop\n", ptxed::parse_line, 2),
+	] {
+		let mut lines = LineTrace::new(trace.as_bytes(), 0, parse);
+		let records = lines.by_ref().collect::<Result<Vec<Record>, _>>().unwrap();
+		assert_eq!(
+			records,
+			[Record {
+				vcpu: 0,
+				addr: 0x1000,
+			}],
+			"{trace}"
+		);
+
+		let stats = lines.stats();
+		assert_eq!((stats.instructions, stats.skipped), (1, 1), "{trace}");
+		// Including those that don't record instructions.
+		assert_eq!(stats.lines, trace.lines().count() as u64, "{trace}");
+		assert_eq!(
+			stats.first_skipped.map(|(line_no, _)| line_no),
+			Some(bad_line),
+			"{trace}"
+		);
+	}
+}
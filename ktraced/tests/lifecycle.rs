@@ -0,0 +1,180 @@
+//! Checks that the daemon shuts down cleanly and guards its paths against other daemons.
+
+mod common;
+
+use std::{os::unix::net::UnixStream, time::Duration};
+
+use common::{Daemon, assert_recorded, record, record_vcpu, sessions, wait_for_exit, wait_until};
+use ktrace_protocol::{Packet, PacketDeserializer, PacketSerializer, SessionInfo};
+
+#[test]
+fn sigterm_finalizes_traces() {
+	const COUNT: usize = 100_000;
+
+	let mut daemon = Daemon::start();
+	// Keep the producer connected, so the daemon has to stop it.
+	let _producer = record(&daemon, COUNT);
+	wait_until(|| daemon.inst_count() == COUNT);
+
+	assert!(daemon.signal(libc::SIGTERM).success());
+	assert!(!daemon.trace_sock().exists());
+	assert!(!daemon.query_sock().exists());
+	// The lock file is left behind (for the next daemon to lock), just without a PID.
+	let lock = std::fs::read_to_string(daemon.dir().join("trace.sock.lock")).unwrap();
+	assert!(lock.is_empty());
+
+	let daemon = Daemon::start_in(daemon.stop());
+	let Packet::Sessions { sessions } = daemon.request(Packet::ListSessions) else {
+		panic!("expected sessions");
+	};
+	assert_eq!(sessions.len(), 1);
+	assert!(sessions[0].ended_at.is_some());
+
+	let mut client = daemon.client();
+	client
+		.serialize_packet(&Packet::OpenSession {
+			name: sessions[0].name.clone(),
+		})
+		.unwrap();
+	assert!(matches!(
+		client.deserialize_packet().unwrap(),
+		Packet::SessionOpened { .. }
+	));
+	drop(client);
+
+	assert_recorded(&daemon, COUNT);
+}
+
+#[test]
+fn unreadable_traces_are_skipped() {
+	let daemon = Daemon::start();
+	let producers = [
+		record_vcpu(&daemon, 1, 0, 10),
+		record_vcpu(&daemon, 1, 1, 10),
+	];
+	wait_until(|| daemon.inst_count() == 10);
+	drop(producers);
+	wait_for_exit(&daemon);
+
+	let Packet::Sessions { sessions } = daemon.request(Packet::ListSessions) else {
+		panic!("expected sessions");
+	};
+	let name = sessions[0].name.clone();
+	let dir = daemon.stop();
+	std::fs::write(
+		dir.path().join("sessions").join(&name).join("cpu1.ktrace"),
+		b"garbage",
+	)
+	.unwrap();
+
+	let daemon = Daemon::start_in(dir);
+	let Packet::SessionOpened { session } = daemon.request(Packet::OpenSession { name }) else {
+		panic!("expected the session to open");
+	};
+	assert_eq!(session.threads, [0]);
+	assert_recorded(&daemon, 10);
+}
+
+#[test]
+fn sigint_while_idle() {
+	let mut daemon = Daemon::start();
+	assert!(daemon.signal(libc::SIGINT).success());
+	assert!(!daemon.trace_sock().exists());
+	assert!(!daemon.query_sock().exists());
+}
+
+#[test]
+fn second_daemon_is_refused() {
+	let mut daemon = Daemon::start();
+
+	let mut second = Daemon::command(daemon.dir()).spawn().unwrap();
+	let status = second.wait().unwrap();
+	assert!(!status.success());
+
+	// The first daemon still owns its sockets.
+	std::thread::sleep(Duration::from_millis(100));
+	assert!(daemon.trace_sock().exists());
+	daemon.assert_alive();
+}
+
+/// Records two sessions, then restarts the daemon, so that neither is loaded.
+fn restarted_with_two_sessions() -> (Daemon, Vec<SessionInfo>) {
+	let daemon = Daemon::start();
+	drop(record_vcpu(&daemon, 1, 0, 10));
+	wait_for_exit(&daemon);
+	drop(record_vcpu(&daemon, 2, 0, 10));
+	wait_until(|| {
+		let sessions = sessions(&daemon);
+		sessions.len() == 2 && sessions.iter().all(|s| !s.live)
+	});
+
+	let daemon = Daemon::start_in(daemon.stop());
+	let sessions = sessions(&daemon);
+	assert_eq!(loaded_threads(&daemon), 0);
+	(daemon, sessions)
+}
+
+/// Returns the number of threads the daemon has in memory.
+fn loaded_threads(daemon: &Daemon) -> usize {
+	match daemon.request(Packet::GetDaemonStats) {
+		Packet::DaemonStats { stats } => stats.threads.len(),
+		packet => panic!("unexpected response: {packet:?}"),
+	}
+}
+
+/// Sends a request on a new connection, returning the connection.
+fn request_on(daemon: &Daemon, req: Packet) -> UnixStream {
+	let mut client = daemon.client();
+	client.serialize_packet(&req).unwrap();
+	assert!(!matches!(
+		client.deserialize_packet().unwrap(),
+		Packet::Error(_)
+	));
+	client
+}
+
+#[test]
+fn opened_sessions_are_unloaded_once_closed() {
+	let (daemon, sessions) = restarted_with_two_sessions();
+	let open = |name: &String| request_on(&daemon, Packet::OpenSession { name: name.clone() });
+
+	// The newest session stays loaded, for queries that don't name one.
+	drop(open(&sessions[1].name));
+	assert_eq!(loaded_threads(&daemon), 1);
+
+	let client = open(&sessions[0].name);
+	assert_eq!(loaded_threads(&daemon), 2);
+	drop(client);
+	wait_until(|| loaded_threads(&daemon) == 1);
+}
+
+#[test]
+fn sessions_named_in_queries_are_kept_until_closed() {
+	let (daemon, sessions) = restarted_with_two_sessions();
+	drop(request_on(
+		&daemon,
+		Packet::OpenSession {
+			name: sessions[1].name.clone(),
+		},
+	));
+
+	let client = request_on(
+		&daemon,
+		Packet::GetInstCount {
+			session:   Some(sessions[0].name.clone()),
+			thread_id: 0,
+		},
+	);
+	// Other clients disconnecting doesn't unload it.
+	drop(daemon.client());
+	drop(request_on(
+		&daemon,
+		Packet::OpenSession {
+			name: sessions[1].name.clone(),
+		},
+	));
+	assert_eq!(loaded_threads(&daemon), 2);
+
+	drop(client);
+	wait_until(|| loaded_threads(&daemon) == 1);
+}
@@ -0,0 +1,135 @@
+//! Checks that one daemon can record several producers at once.
+
+mod common;
+
+use std::io::{BufWriter, Write};
+
+use common::{Daemon, addr, inst_count, record_vcpu, sessions, wait_for_exit, wait_until};
+use ktrace_plugin_protocol::{self as plugin, TraceWrite};
+use ktrace_protocol::{Error, Packet};
+
+#[test]
+fn producers_get_their_own_sessions() {
+	let daemon = Daemon::start();
+
+	// Both connect from this process, so only the producer ID tells them apart.
+	let _a0 = record_vcpu(&daemon, 1, 0, 100);
+	let _b1 = record_vcpu(&daemon, 2, 1, 200);
+	let _a1 = record_vcpu(&daemon, 1, 1, 300);
+	let _b0 = record_vcpu(&daemon, 2, 0, 400);
+
+	wait_until(|| {
+		sessions(&daemon)
+			.iter()
+			.filter(|s| s.threads.len() == 2)
+			.count() == 2
+	});
+	let sessions = sessions(&daemon);
+	assert_eq!(sessions.len(), 2);
+	assert!(sessions.iter().all(|session| session.live));
+
+	// Both sessions have threads 0 and 1, so tell them apart by how much thread 0 recorded.
+	let count = |session: &str| {
+		match inst_count(&daemon, session, 0) {
+			Packet::InstCount { count } => count,
+			packet => panic!("unexpected response: {packet:?}"),
+		}
+	};
+	wait_until(|| {
+		let mut counts = [count(&sessions[0].name), count(&sessions[1].name)];
+		counts.sort();
+		counts == [100, 400]
+	});
+	let (a, b) = if count(&sessions[0].name) == 100 {
+		(&sessions[0].name, &sessions[1].name)
+	} else {
+		(&sessions[1].name, &sessions[0].name)
+	};
+
+	for (session, thread_id, count) in [(a, 0, 100), (a, 1, 300), (b, 1, 200), (b, 0, 400)] {
+		wait_until(
+			|| matches!(inst_count(&daemon, session, thread_id), Packet::InstCount { count: c } if c == count),
+		);
+	}
+}
+
+#[test]
+fn producers_without_an_id_are_grouped_by_pid() {
+	let daemon = Daemon::start();
+
+	let _vcpus = [0, 1].map(|id| {
+		let mut producer = BufWriter::new(daemon.producer());
+		// Encoded as packet code 1, the way producers that predate producer IDs send it.
+		producer
+			.write_packet(&plugin::Packet::VcpuInit(plugin::VcpuInit {
+				id,
+				producer: None,
+			}))
+			.unwrap();
+		producer.write_packet(&plugin::Packet::VcpuResume).unwrap();
+		for i in 0..10 {
+			producer
+				.write_packet(&plugin::Packet::Inst(plugin::Inst { addr: addr(i) }))
+				.unwrap();
+		}
+		producer.flush().unwrap();
+		producer
+	});
+
+	wait_until(|| sessions(&daemon).iter().any(|s| s.threads.len() == 2));
+	let sessions = sessions(&daemon);
+	assert_eq!(sessions.len(), 1);
+
+	for thread_id in [0, 1] {
+		wait_until(|| {
+			matches!(
+				inst_count(&daemon, &sessions[0].name, thread_id),
+				Packet::InstCount { count: 10 }
+			)
+		});
+	}
+}
+
+#[test]
+fn threads_connecting_just_after_the_others_exit_join_their_session() {
+	let daemon = Daemon::start();
+	drop(record_vcpu(&daemon, 1, 0, 10));
+	wait_for_exit(&daemon);
+
+	let _cpu1 = record_vcpu(&daemon, 1, 1, 20);
+	wait_until(|| sessions(&daemon).iter().any(|s| s.live));
+	let listed = sessions(&daemon);
+	assert_eq!(listed.len(), 1);
+	assert_eq!(listed[0].threads, [0, 1]);
+	assert_eq!(listed[0].ended_at, None);
+
+	// The same thread connecting again starts a new session, as before.
+	let _cpu0 = record_vcpu(&daemon, 1, 0, 30);
+	wait_until(|| sessions(&daemon).len() == 2);
+}
+
+#[test]
+fn unknown_session_is_rejected() {
+	let daemon = Daemon::start();
+	let _producer = record_vcpu(&daemon, 1, 0, 10);
+
+	assert!(matches!(
+		inst_count(&daemon, "no-such-session", 0),
+		Packet::Error(Error::BadSession)
+	));
+}
+
+#[test]
+fn named_session_is_loaded_from_disk() {
+	let daemon = Daemon::start();
+	drop(record_vcpu(&daemon, 1, 0, 50));
+	wait_until(|| sessions(&daemon).iter().any(|session| !session.live));
+	let name = sessions(&daemon)[0].name.clone();
+
+	// No session is in memory after a restart, and none was opened on the connection.
+	let daemon = Daemon::start_in(daemon.stop());
+	assert!(matches!(
+		inst_count(&daemon, &name, 0),
+		Packet::InstCount { count: 50 }
+	));
+}
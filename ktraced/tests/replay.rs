@@ -0,0 +1,114 @@
+//! Checks that recorded sessions can be replayed into a daemon.
+
+mod common;
+
+use std::{
+	path::Path,
+	process::{Command, Output, Stdio},
+};
+
+use common::{Daemon, addr, inst_count, range, record_vcpu, sessions, wait_for_exit, wait_until};
+use ktrace_protocol::{InstEntry, Packet};
+
+fn replay(daemon: &Daemon, args: &[&str], paths: &[&Path]) -> Output {
+	Command::new(env!("CARGO_BIN_EXE_ktraced"))
+		.arg("replay")
+		.arg("--trace-sock")
+		.arg(daemon.trace_sock())
+		.args(args)
+		.args(paths)
+		.env("XDG_CONFIG_HOME", daemon.dir())
+		.stderr(Stdio::piped())
+		.output()
+		.unwrap()
+}
+
+/// Records two vCPUs into a session, returning the daemon and the session's directory.
+fn recorded_session() -> (Daemon, std::path::PathBuf) {
+	let daemon = Daemon::start();
+	let cpu0 = record_vcpu(&daemon, 1, 0, 100_000);
+	let cpu1 = record_vcpu(&daemon, 1, 1, 500);
+	wait_until(|| sessions(&daemon).iter().any(|s| s.threads.len() == 2));
+	drop((cpu0, cpu1));
+	wait_for_exit(&daemon);
+
+	let name = sessions(&daemon)[0].name.clone();
+	let dir = daemon.dir().join("sessions").join(name);
+	(daemon, dir)
+}
+
+#[test]
+fn sessions_are_replayed() {
+	let (_recorded, dir) = recorded_session();
+	let daemon = Daemon::start();
+
+	let output = replay(&daemon, &[], &[&dir]);
+	assert!(output.status.success());
+
+	wait_for_exit(&daemon);
+	let mut sessions = sessions(&daemon);
+	assert_eq!(sessions.len(), 1);
+	// The vCPUs are replayed at once, so may connect in either order.
+	sessions[0].threads.sort();
+	assert_eq!(sessions[0].threads, [0, 1]);
+
+	let name = &sessions[0].name;
+	for (thread_id, count) in [(0, 100_000), (1, 500)] {
+		assert!(matches!(
+			inst_count(&daemon, name, thread_id),
+			Packet::InstCount { count: c } if c == count
+		));
+	}
+
+	let expected = (0..100)
+		.map(|index| {
+			InstEntry {
+				index,
+				addr: addr(index),
+			}
+		})
+		.collect::<Vec<_>>();
+	assert_eq!(range(&daemon, name, 0, 100), expected);
+}
+
+#[test]
+fn single_traces_are_replayed_at_a_rate() {
+	let (_recorded, dir) = recorded_session();
+	let daemon = Daemon::start();
+
+	let output = replay(&daemon, &["--rate", "2000"], &[&dir.join("cpu1.ktrace")]);
+	assert!(output.status.success());
+
+	wait_for_exit(&daemon);
+	let sessions = sessions(&daemon);
+	assert_eq!(sessions[0].threads, [1]);
+	assert_eq!(range(&daemon, &sessions[0].name, 1, 1000).len(), 500);
+}
+
+#[test]
+fn missing_traces_are_reported() {
+	let daemon = Daemon::start();
+	let empty = tempfile::tempdir().unwrap();
+
+	let output = replay(&daemon, &[], &[empty.path()]);
+	assert!(!output.status.success());
+	assert!(String::from_utf8_lossy(&output.stderr).contains("no trace files found"));
+	assert!(sessions(&daemon).is_empty());
+}
+
+#[test]
+fn daemon_options_are_refused() {
+	let daemon = Daemon::start();
+	let output = Command::new(env!("CARGO_BIN_EXE_ktraced"))
+		.args(["--max-thread-size", "1G", "replay", "--trace-sock"])
+		.arg(daemon.trace_sock())
+		.arg(daemon.dir())
+		.env("XDG_CONFIG_HOME", daemon.dir())
+		.stderr(Stdio::piped())
+		.output()
+		.unwrap();
+
+	assert!(!output.status.success());
+	assert!(String::from_utf8_lossy(&output.stderr).contains("--max-thread-size"));
+	assert!(sessions(&daemon).is_empty());
+}
@@ -0,0 +1,64 @@
+//! Checks that the daemon stays within its storage limits.
+
+mod common;
+
+use std::io::Write;
+
+use common::{Daemon, addr, record_vcpu, wait_for_exit, wait_until};
+use ktrace_plugin_protocol::{self as plugin, TraceWrite};
+use ktrace_protocol::{Packet, PacketDeserializer, PacketSerializer};
+
+/// The number of instructions in a full chunk.
+const CHUNK_CAPACITY: usize = 64 * 1024;
+
+#[test]
+fn open_sessions_are_not_deleted() {
+	let daemon = Daemon::start_with(tempfile::tempdir().unwrap(), &["--max-total-size", "1"]);
+	drop(record_vcpu(&daemon, 1, 0, 10));
+	wait_for_exit(&daemon);
+
+	let Packet::Sessions { sessions } = daemon.request(Packet::ListSessions) else {
+		panic!("expected sessions");
+	};
+	let name = sessions[0].name.clone();
+	let dir = daemon.dir().join("sessions").join(&name);
+
+	let mut client = daemon.client();
+	client
+		.serialize_packet(&Packet::OpenSession { name: name.clone() })
+		.unwrap();
+	assert!(matches!(
+		client.deserialize_packet().unwrap(),
+		Packet::SessionOpened { .. }
+	));
+
+	// Each full chunk makes the daemon enforce the limit.
+	let mut producer = record_vcpu(&daemon, 2, 0, 0);
+	let mut write_chunk = || {
+		for i in 0..CHUNK_CAPACITY {
+			producer
+				.write_packet(&plugin::Packet::Inst(plugin::Inst { addr: addr(i) }))
+				.unwrap();
+		}
+		producer.flush().unwrap();
+	};
+
+	write_chunk();
+	wait_until(|| {
+		match daemon.request(Packet::GetStorageStats {
+			session:   None,
+			thread_id: 0,
+		}) {
+			// The new session had to be trimmed, since the old one couldn't be deleted.
+			Packet::StorageStats { stats } => stats.first_index == CHUNK_CAPACITY,
+			packet => panic!("unexpected response: {packet:?}"),
+		}
+	});
+	assert!(dir.exists());
+
+	drop(client);
+	wait_until(|| {
+		write_chunk();
+		!dir.exists()
+	});
+}
@@ -0,0 +1,167 @@
+//! Checks the daemon's self-diagnostics.
+
+mod common;
+
+use std::{
+	io::{Read, Write},
+	os::unix::net::UnixStream,
+};
+
+use common::{Daemon, addr, record, record_vcpu, wait_for_exit, wait_until};
+use ktrace_plugin_protocol::{self as plugin, TraceWrite};
+use ktrace_protocol::{DaemonStats, Packet, PacketSerializer, ThreadStats};
+
+/// The number of instructions in a full chunk.
+const CHUNK_CAPACITY: usize = 64 * 1024;
+
+fn daemon_stats(daemon: &Daemon) -> DaemonStats {
+	match daemon.request(Packet::GetDaemonStats) {
+		Packet::DaemonStats { stats } => stats,
+		packet => panic!("unexpected response: {packet:?}"),
+	}
+}
+
+fn thread_stats(daemon: &Daemon) -> ThreadStats {
+	let mut stats = daemon_stats(daemon);
+	assert_eq!(stats.threads.len(), 1);
+	stats.threads.remove(0)
+}
+
+#[test]
+fn thread_stats_follow_recording_and_streams() {
+	let daemon = Daemon::start();
+	let mut producer = record(&daemon, 1000);
+	producer.write_packet(&plugin::Packet::VcpuIdle).unwrap();
+	producer.flush().unwrap();
+
+	wait_until(|| daemon.inst_count() == 1000);
+	let stats = thread_stats(&daemon);
+	assert_eq!(stats.instructions, 1000);
+	assert_eq!(stats.open_streams, 0);
+	assert_eq!(stats.dropped, 0);
+	assert_eq!(stats.reconnects, 0);
+
+	let mut stream = daemon.client();
+	stream
+		.serialize_packet(&Packet::OpenStream {
+			session:   None,
+			thread_id: 0,
+			filter:    None,
+		})
+		.unwrap();
+	let mut buf = vec![0; 1000 * 8];
+	stream.read_exact(&mut buf).unwrap();
+
+	wait_until(|| {
+		let stats = thread_stats(&daemon);
+		stats.open_streams == 1 && stats.stream_lag == 0
+	});
+
+	drop(stream);
+	wait_until(|| thread_stats(&daemon).open_streams == 0);
+}
+
+#[test]
+fn reconnects_are_counted() {
+	let daemon = Daemon::start();
+	drop(record_vcpu(&daemon, 1, 0, 10));
+	wait_for_exit(&daemon);
+	let _producer = record_vcpu(&daemon, 1, 0, 10);
+	wait_until(|| daemon.is_live());
+
+	let stats = daemon_stats(&daemon);
+	let mut reconnects = stats
+		.threads
+		.iter()
+		.map(|t| t.reconnects)
+		.collect::<Vec<_>>();
+	reconnects.sort();
+	assert_eq!(reconnects, [0, 1]);
+}
+
+#[test]
+fn discarded_instructions_are_dropped() {
+	let daemon = Daemon::start_with(
+		tempfile::tempdir().unwrap(),
+		&["--max-thread-size", "1", "--retention", "stop"],
+	);
+
+	// Storage limits are checked once a chunk has been written.
+	let mut producer = record(&daemon, CHUNK_CAPACITY);
+	wait_until(|| daemon.inst_count() == CHUNK_CAPACITY);
+
+	let stopped = || {
+		match daemon.request(Packet::GetStorageStats {
+			session:   None,
+			thread_id: 0,
+		}) {
+			Packet::StorageStats { stats } => stats.recording_stopped,
+			packet => panic!("unexpected response: {packet:?}"),
+		}
+	};
+	wait_until(stopped);
+
+	for i in 0..50 {
+		producer
+			.write_packet(&plugin::Packet::Inst(plugin::Inst { addr: addr(i) }))
+			.unwrap();
+	}
+	producer.flush().unwrap();
+
+	wait_until(|| thread_stats(&daemon).dropped == 50);
+	assert_eq!(thread_stats(&daemon).instructions, CHUNK_CAPACITY);
+}
+
+#[test]
+fn producer_stats_are_reported() {
+	let daemon = Daemon::start();
+	let mut producer = record(&daemon, 10);
+	wait_until(|| daemon.inst_count() == 10);
+	assert_eq!(thread_stats(&daemon).producer, None);
+
+	let stats = plugin::VcpuStats {
+		callbacks:     12,
+		instructions:  10,
+		bytes_written: 120,
+		blocked_ns:    3000,
+		dropped:       1,
+	};
+	producer
+		.write_packet(&plugin::Packet::VcpuStats(stats))
+		.unwrap();
+	producer.flush().unwrap();
+
+	wait_until(|| thread_stats(&daemon).producer.is_some());
+	let reported = thread_stats(&daemon).producer.unwrap();
+	assert_eq!(reported.callbacks, 12);
+	assert_eq!(reported.instructions, 10);
+	assert_eq!(reported.bytes_written, 120);
+	assert_eq!(reported.blocked_ns, 3000);
+	assert_eq!(reported.dropped, 1);
+	assert_eq!(daemon.inst_count(), 10);
+}
+
+#[test]
+fn prometheus_endpoint() {
+	let dir = tempfile::tempdir().unwrap();
+	let metrics_sock = dir.path().join("metrics.sock");
+	let daemon = Daemon::start_with(dir, &["--metrics-sock", metrics_sock.to_str().unwrap()]);
+
+	let _producer = record(&daemon, 10);
+	wait_until(|| daemon.inst_count() == 10);
+	let session = thread_stats(&daemon).session;
+
+	let mut client = UnixStream::connect(&metrics_sock).unwrap();
+	client
+		.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+		.unwrap();
+	let mut response = String::new();
+	client.read_to_string(&mut response).unwrap();
+
+	assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+	assert!(response.contains("# TYPE ktraced_thread_instructions_total counter\n"));
+	assert!(response.contains(&format!(
+		"ktraced_thread_instructions_total{{session=\"{session}\",thread=\"0\"}} 10\n"
+	)));
+	assert!(!response.contains("ktraced_producer_callbacks_total{"));
+}
@@ -0,0 +1,150 @@
+//! Checks synthetic traces, with calls, loops and both halves of the address space,
+//! against what the daemon records and answers queries with.
+
+mod common;
+
+use common::{Daemon, record_workload, wait_for_exit, wait_until};
+use ktrace_protocol::{AddrRange, HistogramEntry, InstEntry, MAX_RANGE_COUNT, Packet, SearchOp, TraceFilter};
+use ktrace_synth::Workload;
+
+fn workload() -> Workload {
+	Workload {
+		instructions: 200_000,
+		seed: 7,
+		idle_every: Some(30_000),
+		..Workload::default()
+	}
+}
+
+/// The address ranges of each half of the address space.
+const HALVES: [AddrRange; 2] = [
+	AddrRange {
+		start: 0,
+		end:   0x8000_0000_0000_0000,
+	},
+	AddrRange {
+		start: 0x8000_0000_0000_0000,
+		end:   u64::MAX,
+	},
+];
+
+fn inst_count(daemon: &Daemon, thread_id: u32) -> usize {
+	match daemon.request(Packet::GetInstCount {
+		session: None,
+		thread_id,
+	}) {
+		Packet::InstCount { count } => count,
+		packet => panic!("unexpected response: {packet:?}"),
+	}
+}
+
+/// Reads a thread's whole trace, a page at a time.
+fn read_all(daemon: &Daemon, thread_id: u32, filter: Option<TraceFilter>) -> Vec<InstEntry> {
+	let mut all = Vec::new();
+	loop {
+		let start = all.last().map_or(0, |entry: &InstEntry| entry.index + 1);
+		let Packet::Range { entries } = daemon.request(Packet::GetRange {
+			session: None,
+			thread_id,
+			start,
+			count: MAX_RANGE_COUNT,
+			filter,
+		}) else {
+			panic!("expected range");
+		};
+
+		if entries.is_empty() {
+			return all;
+		}
+		all.extend(entries);
+	}
+}
+
+/// Records a synthetic trace on two vCPUs and waits for both to finish.
+fn record_two_vcpus(workload: &Workload) -> Daemon {
+	let daemon = Daemon::start();
+	let cpu0 = record_workload(&daemon, workload, 1, 0);
+	let cpu1 = record_workload(&daemon, workload, 1, 1);
+
+	let count = workload.instructions as usize;
+	wait_until(|| inst_count(&daemon, 0) == count);
+	wait_until(|| inst_count(&daemon, 1) == count);
+	drop((cpu0, cpu1));
+	wait_for_exit(&daemon);
+	daemon
+}
+
+#[test]
+fn synthetic_traces_are_recorded_exactly() {
+	let workload = workload();
+	let daemon = record_two_vcpus(&workload);
+
+	for thread_id in [0, 1] {
+		let expected = workload
+			.addresses(thread_id)
+			.enumerate()
+			.map(|(index, addr)| InstEntry { index, addr })
+			.collect::<Vec<_>>();
+		assert_eq!(read_all(&daemon, thread_id, None), expected);
+	}
+
+	// The vCPUs don't execute the same program.
+	assert!(workload.addresses(0).ne(workload.addresses(1)));
+}
+
+#[test]
+fn queries_match_the_generator() {
+	let workload = workload();
+	let daemon = record_two_vcpus(&workload);
+	let addrs = workload.addresses(0).collect::<Vec<_>>();
+
+	// The loop body of the function the vCPU starts in runs more than once.
+	let hot = addrs[workload.function_len as usize / 4];
+	let expected = addrs.iter().filter(|&&addr| addr == hot).count();
+	assert!(expected > 1);
+	assert!(matches!(
+		daemon.request(Packet::Search {
+			session:   None,
+			thread_id: 0,
+			range:     AddrRange::single(hot),
+			op:        SearchOp::Count,
+			limit:     0,
+		}),
+		Packet::SearchCount { count } if count == expected
+	));
+
+	let Packet::Histogram { entries } = daemon.request(Packet::GetHistogram {
+		session:   None,
+		thread_id: 0,
+		start:     0,
+		end:       None,
+		buckets:   Some(HALVES.to_vec()),
+	}) else {
+		panic!("expected histogram");
+	};
+	let expected = HALVES
+		.map(|range| {
+			HistogramEntry {
+				range,
+				count: addrs.iter().filter(|&&addr| range.contains(addr)).count(),
+			}
+		})
+		.to_vec();
+	assert!(expected.iter().all(|entry| entry.count > 0));
+	assert_eq!(entries, expected);
+}
+
+#[test]
+fn lower_half_filter_skips_the_upper_half() {
+	let workload = workload();
+	let daemon = record_two_vcpus(&workload);
+
+	let expected = workload
+		.addresses(1)
+		.enumerate()
+		.filter(|&(_, addr)| TraceFilter::LowerHalf.matches(addr))
+		.map(|(index, addr)| InstEntry { index, addr })
+		.collect::<Vec<_>>();
+	assert!(expected.len() < workload.instructions as usize);
+	assert_eq!(read_all(&daemon, 1, Some(TraceFilter::LowerHalf)), expected);
+}